@@ -0,0 +1,513 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use fields;
+use layout::{self, Field};
+use radix::{self, Radix};
+use ucode::{control_address, CWord, Microcode, ADDRESS_BITS};
+
+//
+// Microassembler.
+//
+// Source is one microinstruction per line:
+//
+//     ; A comment
+//             .org 1000
+//     start:  amra=100 amwa=7777 alu=x+1 cpc-sel=naf naf=start
+//             .equ scratch 7777
+//     01001:  spec=halt amwa=scratch
+//
+// Numbers are octal unless they end with a decimal point or give
+// their radix with a prefix (#o, #d or #x). The ".radix 10" and
+// ".radix 16" directives change the radix of the numbers that follow.
+// A label that is a number sets the location counter, which is how
+// the disassembler writes addresses. Field names, values and defaults
+// come from the microword layout; fields that are not given take
+// their default, which for most is zero. Parity is computed unless
+// given explicitly.
+//
+
+//
+// Error handling
+//
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AsmError {
+    Io(io::Error),
+    Syntax(usize, String),
+}
+
+impl From<io::Error> for AsmError {
+    fn from(err: io::Error) -> AsmError {
+        AsmError::Io(err)
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsmError::Io(ref err) => err.fmt(f),
+            AsmError::Syntax(line, ref msg) => write!(f, "line {}: {}", line, msg),
+        }
+    }
+}
+
+impl error::Error for AsmError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            AsmError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+//
+// Assembler
+//
+
+/// The result of assembling a source file.
+pub struct Assembly {
+    pub words: Vec<CWord>,
+    pub labels: HashMap<String, u16>,
+}
+
+/// One instruction line, between the two passes.
+struct Statement<'a> {
+    line: usize,
+    radix: Radix,
+    address: u16,
+    fields: Vec<(&'a Field, String)>,
+}
+
+/// Parse a number as files give them: octal, decimal with a trailing
+/// point, or in the radix a prefix gives.
+pub fn parse_number(token: &str) -> Option<u64> {
+    Radix::Octal.parse(token)
+}
+
+/// Parse a field value: one of the field's symbolic names, a name
+/// from `fields` (which also names reserved codes), or a number in
+/// the given radix.
+pub fn parse_value(field: &Field, token: &str, radix: Radix) -> Option<u64> {
+    match field.value_named(token) {
+        Some(v) => Some(v),
+        None => match fields::code_named(&field.name, token) {
+            Some(code) => Some(code as u64),
+            None => radix.parse(token),
+        },
+    }
+}
+
+pub fn assemble_file(path: &str) -> Result<Assembly, AsmError> {
+    let mut source = String::new();
+    File::open(shellexpand::tilde(path).to_string())?.read_to_string(&mut source)?;
+    assemble(&source)
+}
+
+/// An image holding the words of `source`, for tests.
+#[cfg(test)]
+pub fn image(source: &str) -> Microcode {
+    match assemble(source) {
+        Ok(a) => {
+            let mut ucode = Microcode::new();
+            for w in a.words {
+                ucode.c_mem.push(w);
+            }
+            ucode
+        }
+        Err(e) => panic!("{}", e),
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let layout = layout::current();
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut equates: HashMap<String, u64> = HashMap::new();
+    let mut statements: Vec<Statement> = Vec::new();
+    let mut addresses: HashSet<u16> = HashSet::new();
+    let mut location: u16 = 0;
+    let mut radix = Radix::Octal;
+
+    // Pass one: labels, directives and field assignments.
+    for (n, text) in source.lines().enumerate() {
+        let line = n + 1;
+        let syntax = |msg: String| AsmError::Syntax(line, msg);
+
+        let mut text = match text.find(';') {
+            Some(i) => &text[..i],
+            None => text,
+        }
+        .trim();
+
+        // An address, a label, or both, as in "00100: start: ...".
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if let Some(address) = radix.parse(label) {
+                location = control_address(address).ok_or_else(|| {
+                    syntax(format!("address '{}' is outside control memory", label))
+                })?;
+            } else if labels.insert(label.to_string(), location).is_some() {
+                return Err(syntax(format!("duplicate label '{}'", label)));
+            }
+            text = text[colon + 1..].trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let mut tokens = text.split_whitespace();
+
+        if text.starts_with('.') {
+            let directive = tokens.next().unwrap_or("");
+            let args: Vec<&str> = tokens.collect();
+            match (directive, args.len()) {
+                (".org", 1) => {
+                    let address = radix
+                        .parse(args[0])
+                        .ok_or_else(|| syntax(format!("bad address '{}'", args[0])))?;
+                    location = control_address(address).ok_or_else(|| {
+                        syntax(format!("address '{}' is outside control memory", args[0]))
+                    })?;
+                }
+                (".equ", 2) => {
+                    let value = radix
+                        .parse(args[1])
+                        .ok_or_else(|| syntax(format!("bad number '{}'", args[1])))?;
+                    equates.insert(args[0].to_string(), value);
+                }
+                (".radix", 1) => {
+                    radix = args[0]
+                        .parse::<u32>()
+                        .ok()
+                        .and_then(Radix::from_base)
+                        .ok_or_else(|| syntax(format!("bad radix '{}'", args[0])))?;
+                }
+                _ => return Err(syntax(format!("bad directive '{}'", text))),
+            }
+            continue;
+        }
+
+        let mut fields = Vec::new();
+        for token in tokens {
+            let mut parts = token.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = match parts.next() {
+                Some(v) if !v.is_empty() => v,
+                _ => return Err(syntax(format!("expected field=value, got '{}'", token))),
+            };
+            let field = layout
+                .field(name)
+                .ok_or_else(|| syntax(format!("unknown field '{}'", name)))?;
+            fields.push((field, value.to_string()));
+        }
+
+        if control_address(location as u64).is_none() {
+            return Err(syntax("word past the end of control memory".to_string()));
+        }
+        if !addresses.insert(location) {
+            return Err(syntax(format!(
+                "address {} assembled twice",
+                radix::address(location)
+            )));
+        }
+
+        statements.push(Statement {
+            line,
+            radix,
+            address: location,
+            fields,
+        });
+        location = location.wrapping_add(1);
+    }
+
+    // Pass two: resolve values and encode.
+    let mut words = Vec::new();
+    for statement in &statements {
+        let mut word = layout.blank_word();
+
+        let mut explicit_parity = false;
+        for &(field, ref token) in &statement.fields {
+            let value =
+                resolve(field, token, statement.radix, &labels, &equates).ok_or_else(|| {
+                    AsmError::Syntax(
+                        statement.line,
+                        format!("bad value '{}' for {}", token, field.name),
+                    )
+                })?;
+            if !field.fits(value) {
+                return Err(AsmError::Syntax(
+                    statement.line,
+                    format!("{} does not fit in {}", token, field.name),
+                ));
+            }
+            word = field.set(word, value);
+            explicit_parity |= field.name == "parity";
+        }
+
+        if !explicit_parity {
+            word = layout.with_parity(word);
+        }
+
+        words.push(CWord::from_bits(statement.address, word));
+    }
+
+    Ok(Assembly { words, labels })
+}
+
+fn resolve(
+    field: &Field,
+    token: &str,
+    radix: Radix,
+    labels: &HashMap<String, u16>,
+    equates: &HashMap<String, u64>,
+) -> Option<u64> {
+    if let Some(v) = parse_value(field, token, radix) {
+        return Some(v);
+    }
+    if let Some(v) = equates.get(token) {
+        return Some(*v);
+    }
+    labels.get(token).map(|a| *a as u64)
+}
+
+//
+// Disassembler
+//
+
+/// Disassemble one word into a line the assembler reads back, with
+/// numbers in the current radix.
+pub fn disassemble_word(cword: &CWord) -> String {
+    word_line(cword, radix::current())
+}
+
+fn word_line(cword: &CWord, radix: Radix) -> String {
+    let layout = layout::current();
+    let word = cword.bits();
+    let mut line = format!(
+        "{}:",
+        radix.format_width(cword.address as u64, ADDRESS_BITS)
+    );
+
+    for field in layout.primary() {
+        let value = field.get(word);
+        let wanted = if field.name == "parity" {
+            layout.parity(word)
+        } else {
+            field.default
+        };
+        if value == wanted {
+            continue;
+        }
+        match field.value_name(value) {
+            Some(name) => line.push_str(&format!(" {}={}", field.name, name)),
+            None => line.push_str(&format!(" {}={}", field.name, radix.format(value))),
+        }
+    }
+
+    line
+}
+
+/// Disassemble one word of an image with its label and comment.
+pub fn disassemble_annotated(ucode: &Microcode, cword: &CWord) -> String {
+    annotate(ucode, cword, disassemble_word(cword))
+}
+
+fn annotate(ucode: &Microcode, cword: &CWord, line: String) -> String {
+    let notes = &ucode.annotations;
+    let mut text = match notes.label(cword.address) {
+        Some(label) => line.replacen(':', &format!(": {}:", label), 1),
+        None => line,
+    };
+    if let Some(comment) = notes.comment(cword.address) {
+        text.push_str(&format!(" ; {}", comment));
+    }
+    text
+}
+
+/// Disassemble all of control memory, with labels and comments, in
+/// the current radix.
+pub fn disassemble(ucode: &Microcode) -> String {
+    disassemble_in(ucode, radix::current())
+}
+
+pub fn disassemble_in(ucode: &Microcode, radix: Radix) -> String {
+    let mut text = String::new();
+    if radix != Radix::Octal {
+        text.push_str(&format!("        .radix {}\n", radix.base()));
+    }
+    for cword in &ucode.c_mem.mem {
+        text.push_str(&annotate(ucode, cword, word_line(cword, radix)));
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_one(source: &str) -> u128 {
+        match assemble(source) {
+            Ok(a) => a.words[0].bits(),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn encodes_fields_at_decoded_positions() {
+        let word = assemble_one("amra=1234 naf=17777 alu=xor spec=halt");
+        assert_eq!(word & 0xfff, 0o1234);
+        assert_eq!((word >> 80) & 0x3fff, 0o17777);
+        assert_eq!((word >> 64) & 0xf, 0o12);
+        assert_eq!((word >> 48) & 0x1f, 0o31);
+    }
+
+    #[test]
+    fn names_reserved_codes() {
+        let word = assemble_one("spec=reserved-26 abus-src=md-advance");
+        assert_eq!((word >> 48) & 0x1f, 0o26);
+        assert_eq!((word >> 6) & 0x7, 4);
+    }
+
+    #[test]
+    fn aliases_write_overlapping_bits() {
+        let word = assemble_one("amra=0 r-base=3 abus-src=2");
+        assert_eq!(word & 0xfff, (3 << 9) | (2 << 6));
+    }
+
+    #[test]
+    fn computes_odd_parity() {
+        let word = assemble_one("amra=1");
+        assert_eq!(word.count_ones() % 2, 1);
+    }
+
+    #[test]
+    fn resolves_labels_and_org() {
+        let asm = assemble(
+            "        .org 100\n\
+             start:  cpc-sel=naf naf=done\n\
+             done:   spec=halt\n",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(asm.labels["start"], 0o100);
+        assert_eq!(asm.words[0].address, 0o100);
+        assert_eq!(asm.words[1].address, 0o101);
+        assert_eq!((asm.words[0].bits() >> 80) & 0x3fff, 0o101);
+    }
+
+    #[test]
+    fn reads_numbers_in_the_file_radix() {
+        let asm = assemble(
+            "        .org #x40\n\
+                     amra=#d10 naf=10.\n\
+                     .radix 16\n\
+             0a0:    amra=0ff naf=#o17\n",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(asm.words[0].address, 0x40);
+        assert_eq!(asm.words[0].bits() & 0xfff, 10);
+        assert_eq!((asm.words[0].bits() >> 80) & 0x3fff, 10);
+        assert_eq!(asm.words[1].address, 0xa0);
+        assert_eq!(asm.words[1].bits() & 0xfff, 0xff);
+        assert_eq!((asm.words[1].bits() >> 80) & 0x3fff, 0o17);
+        assert!(assemble(".radix 7\n").is_err());
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        match assemble("spec=none\nbogus=1\n") {
+            Err(AsmError::Syntax(2, _)) => {}
+            _ => panic!("expected a syntax error on line 2"),
+        }
+        match assemble("amra=10000\n") {
+            Err(AsmError::Syntax(1, _)) => {}
+            _ => panic!("expected an overflow error"),
+        }
+        for source in &[".org 200010\n", ".org 40000\n", "40000: spec=halt\n"] {
+            assert!(assemble(source).is_err(), "{}", source);
+        }
+        assert!(assemble(".org 37777\nspec=halt\nspec=halt\n").is_err());
+        assert!(assemble(".org 37777\nspec=halt\n").is_ok());
+    }
+
+    #[test]
+    fn disassembly_carries_annotations() {
+        let mut ucode = Microcode::new();
+        let layout = layout::current();
+        ucode.c_mem.push(CWord::from_bits(
+            0o100,
+            layout.with_parity(layout.blank_word() | 1),
+        ));
+        ucode.c_mem.push(CWord::from_bits(
+            0o101,
+            layout.with_parity(layout.blank_word() | 2),
+        ));
+        assert!(ucode.annotations.set_label(0o101, Some("next")).is_ok());
+        ucode.annotations.set_comment(0o101, Some("the end"));
+
+        let text = disassemble(&ucode);
+        assert!(text.contains("00101: next: "));
+        assert!(text.ends_with(" ; the end\n"));
+
+        let asm = assemble(&text).ok().unwrap();
+        assert_eq!(asm.labels["next"], 0o101);
+        assert_eq!(asm.words[1].address, 0o101);
+    }
+
+    #[test]
+    fn disassembly_round_trips() {
+        let mut ucode = Microcode::new();
+        let mut seed: u128 = 0x1234_5678_9abc_def0_0fed_cba9_8765_4321;
+        for address in 0..64 {
+            // A simple xorshift to cover arbitrary bit patterns,
+            // including words with bad parity.
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let word = seed & ((1u128 << 112) - 1);
+            ucode.c_mem.push(CWord::from_bits(address * 3, word));
+        }
+
+        for &radix in &[Radix::Octal, Radix::Decimal, Radix::Hex] {
+            let text = disassemble_in(&ucode, radix);
+            let asm = match assemble(&text) {
+                Ok(a) => a,
+                Err(e) => panic!("{}", e),
+            };
+
+            assert_eq!(asm.words.len(), ucode.c_mem.len());
+            for (a, b) in asm.words.iter().zip(ucode.c_mem.mem.iter()) {
+                assert_eq!(a.address, b.address);
+                assert_eq!(a.bits(), b.bits());
+            }
+        }
+    }
+}
@@ -0,0 +1,406 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use shell;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//
+// Terminal colors.
+//
+// Listings, warnings and the line being typed are colored with ANSI
+// escapes from the current theme. `set color on` always colors,
+// `set color off` never does, and `set color auto`, the default,
+// colors only output that ends up on a terminal, unless NO_COLOR is
+// set or the terminal is dumb. Coloring only adds escapes, so colored
+// text takes as many columns as the plain text.
+//
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Auto,
+    On,
+    Off,
+}
+
+impl Mode {
+    pub fn named(name: &str) -> Option<Mode> {
+        match name {
+            "auto" => Some(Mode::Auto),
+            "on" => Some(Mode::On),
+            "off" => Some(Mode::Off),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Auto => "auto",
+            Mode::On => "on",
+            Mode::Off => "off",
+        }
+    }
+}
+
+/// What a piece of text is, which decides its color.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Style {
+    Address,
+    Symbol,
+    Field,
+    Opcode,
+    Number,
+    Target,
+    Trap,
+    Halt,
+    Warning,
+    Comment,
+    Command,
+    Error,
+    Prompt,
+    Hint,
+}
+
+/// SGR parameters for each style; an empty string leaves text plain.
+pub struct Theme {
+    pub name: &'static str,
+    address: &'static str,
+    symbol: &'static str,
+    field: &'static str,
+    opcode: &'static str,
+    number: &'static str,
+    target: &'static str,
+    trap: &'static str,
+    halt: &'static str,
+    warning: &'static str,
+    comment: &'static str,
+    command: &'static str,
+    error: &'static str,
+    prompt: &'static str,
+    hint: &'static str,
+}
+
+pub static THEMES: &[Theme] = &[
+    Theme {
+        name: "dark",
+        address: "36",
+        symbol: "1;33",
+        field: "",
+        opcode: "32",
+        number: "35",
+        target: "1;36",
+        trap: "1;31",
+        halt: "1;37;41",
+        warning: "1;33",
+        comment: "90",
+        command: "1;34",
+        error: "31",
+        prompt: "1;32",
+        hint: "1",
+    },
+    Theme {
+        name: "light",
+        address: "34",
+        symbol: "1;35",
+        field: "",
+        opcode: "32",
+        number: "35",
+        target: "1;34",
+        trap: "1;31",
+        halt: "1;37;41",
+        warning: "1;31",
+        comment: "2",
+        command: "1;34",
+        error: "31",
+        prompt: "1;32",
+        hint: "2",
+    },
+];
+
+impl Theme {
+    pub fn named(name: &str) -> Option<&'static Theme> {
+        THEMES.iter().find(|t| t.name == name)
+    }
+
+    fn code(&self, style: Style) -> &'static str {
+        match style {
+            Style::Address => self.address,
+            Style::Symbol => self.symbol,
+            Style::Field => self.field,
+            Style::Opcode => self.opcode,
+            Style::Number => self.number,
+            Style::Target => self.target,
+            Style::Trap => self.trap,
+            Style::Halt => self.halt,
+            Style::Warning => self.warning,
+            Style::Comment => self.comment,
+            Style::Command => self.command,
+            Style::Error => self.error,
+            Style::Prompt => self.prompt,
+            Style::Hint => self.hint,
+        }
+    }
+
+    pub fn paint(&self, style: Style, text: &str) -> String {
+        let code = self.code(style);
+        if code.is_empty() || text.is_empty() {
+            text.to_string()
+        } else {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        }
+    }
+}
+
+static MODE: AtomicUsize = AtomicUsize::new(0);
+static THEME: AtomicUsize = AtomicUsize::new(0);
+
+const MODES: [Mode; 3] = [Mode::Auto, Mode::On, Mode::Off];
+
+pub fn mode() -> Mode {
+    MODES[MODE.load(Ordering::Relaxed)]
+}
+
+pub fn set_mode(mode: Mode) {
+    let index = MODES.iter().position(|&m| m == mode).unwrap();
+    MODE.store(index, Ordering::Relaxed);
+}
+
+pub fn theme() -> &'static Theme {
+    &THEMES[THEME.load(Ordering::Relaxed)]
+}
+
+pub fn set_theme(theme: &Theme) {
+    let index = THEMES.iter().position(|t| t.name == theme.name).unwrap();
+    THEME.store(index, Ordering::Relaxed);
+}
+
+fn auto(to_terminal: bool) -> bool {
+    to_terminal
+        && env::var_os("NO_COLOR").is_none()
+        && env::var("TERM").is_ok_and(|term| term != "dumb")
+}
+
+/// Whether command output should be colored.
+pub fn enabled() -> bool {
+    match mode() {
+        Mode::On => true,
+        Mode::Off => false,
+        Mode::Auto => auto(shell::to_terminal()),
+    }
+}
+
+/// Whether the prompt and the line being typed should be colored.
+pub fn input_enabled() -> bool {
+    match mode() {
+        Mode::On => true,
+        Mode::Off => false,
+        Mode::Auto => auto(io::stdout().is_terminal()),
+    }
+}
+
+/// Color command output text, if output is colored.
+pub fn paint(style: Style, text: &str) -> String {
+    if enabled() {
+        theme().paint(style, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Color a warning, if output is colored.
+pub fn warning(text: &str) -> String {
+    paint(Style::Warning, text)
+}
+
+/// Color listing lines, as printed by `show` and `disasm`, if output
+/// is colored.
+pub fn listing(text: &str) -> String {
+    if enabled() {
+        listing_in(theme(), text)
+    } else {
+        text.to_string()
+    }
+}
+
+//
+// Listings
+//
+
+/// Color listing lines with `theme`. Lines are a word, as in
+/// "00003: foo: seq=popj naf=00012 ; comment", maybe after a diff
+/// marker, or its targets, as in "-> jump 00012 (three), trap 00020".
+pub fn listing_in(theme: &Theme, text: &str) -> String {
+    let lines: Vec<String> = text.split('\n').map(|l| listing_line(theme, l)).collect();
+    lines.join("\n")
+}
+
+fn listing_line(theme: &Theme, line: &str) -> String {
+    let (code, comment) = match line.find(" ; ") {
+        Some(at) => (&line[..at], Some(&line[at..])),
+        None => (line, None),
+    };
+    let body = code.trim_start();
+    let mut out = code[..code.len() - body.len()].to_string();
+
+    if let Some(targets) = body.strip_prefix("-> ") {
+        out.push_str("-> ");
+        let targets: Vec<String> = targets.split(", ").map(|t| target(theme, t)).collect();
+        out.push_str(&targets.join(", "));
+    } else {
+        let mut body = body;
+        for marker in &["- ", "+ "] {
+            if let Some(rest) = body.strip_prefix(marker) {
+                out.push_str(marker);
+                body = rest;
+                break;
+            }
+        }
+        let words: Vec<String> = body
+            .split(' ')
+            .enumerate()
+            .map(|(i, w)| listing_word(theme, i, w))
+            .collect();
+        out.push_str(&words.join(" "));
+    }
+
+    if let Some(comment) = comment {
+        out.push_str(&theme.paint(Style::Comment, comment));
+    }
+    out
+}
+
+fn listing_word(theme: &Theme, index: usize, word: &str) -> String {
+    if word.ends_with(':') {
+        let style = if index == 0 {
+            Style::Address
+        } else {
+            Style::Symbol
+        };
+        theme.paint(style, word)
+    } else if let Some((field, value)) = word.split_once('=') {
+        format!(
+            "{}={}",
+            theme.paint(Style::Field, field),
+            theme.paint(value_style(field, value), value)
+        )
+    } else {
+        word.to_string()
+    }
+}
+
+fn value_style(field: &str, value: &str) -> Style {
+    if value.contains("halt") {
+        Style::Halt
+    } else if value.contains("trap") {
+        Style::Trap
+    } else if field == "naf" {
+        Style::Target
+    } else if value.starts_with(|c: char| c.is_ascii_digit() || c == '#') {
+        Style::Number
+    } else {
+        Style::Opcode
+    }
+}
+
+/// Color one target, as in "jump 00012 (three)".
+fn target(theme: &Theme, text: &str) -> String {
+    let mut parts = text.splitn(3, ' ');
+    let kind = parts.next().unwrap_or("");
+    let kind_style = if kind == "trap" {
+        Style::Trap
+    } else {
+        Style::Target
+    };
+    let mut out = theme.paint(kind_style, kind);
+    if let Some(address) = parts.next() {
+        out.push(' ');
+        out.push_str(&theme.paint(Style::Address, address));
+    }
+    if let Some(label) = parts.next() {
+        out.push(' ');
+        out.push_str(&theme.paint(Style::Symbol, label));
+    }
+    out
+}
+
+/// Remove color escapes from text.
+pub fn strip(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dark() -> &'static Theme {
+        Theme::named("dark").unwrap()
+    }
+
+    #[test]
+    fn colors_listing_words() {
+        let line = "00003: foo: seq=popj spec=halt cond-func=trap naf=00012 magic=14 ; a comment";
+        let colored = listing_in(dark(), line);
+        assert_eq!(strip(&colored), line);
+        assert!(colored.starts_with("\x1b[36m00003:\x1b[0m \x1b[1;33mfoo:\x1b[0m "));
+        assert!(colored.contains("seq=\x1b[32mpopj\x1b[0m"));
+        assert!(colored.contains("spec=\x1b[1;37;41mhalt\x1b[0m"));
+        assert!(colored.contains("cond-func=\x1b[1;31mtrap\x1b[0m"));
+        assert!(colored.contains("naf=\x1b[1;36m00012\x1b[0m"));
+        assert!(colored.contains("magic=\x1b[35m14\x1b[0m"));
+        assert!(colored.ends_with("\x1b[90m ; a comment\x1b[0m"));
+    }
+
+    #[test]
+    fn colors_targets_and_diffs() {
+        let text =
+            "- 00001: naf=00002\n+ 00001: naf=00003\n       -> jump 00012 (three), trap 00020";
+        let colored = listing_in(dark(), text);
+        assert_eq!(strip(&colored), text);
+        assert!(colored.starts_with("- \x1b[36m00001:\x1b[0m"));
+        assert!(
+            colored.contains("\x1b[1;36mjump\x1b[0m \x1b[36m00012\x1b[0m \x1b[1;33m(three)\x1b[0m")
+        );
+        assert!(colored.contains("\x1b[1;31mtrap\x1b[0m"));
+    }
+
+    #[test]
+    fn names_modes_and_themes() {
+        for &mode in &MODES {
+            assert_eq!(Mode::named(mode.name()), Some(mode));
+        }
+        assert_eq!(Mode::named("sometimes"), None);
+        assert!(Theme::named("light").is_some());
+        assert!(Theme::named("plaid").is_none());
+    }
+}
@@ -0,0 +1,646 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use color::{Style, Theme};
+use fields;
+use layout;
+use radix;
+use ucode::{control_address, Microcode};
+
+//
+// Command registry.
+//
+// Every REPL command is described once: its name and aliases, the
+// arguments it takes and its help text. Dispatch, help, completion
+// and hints are all driven from the descriptions, so a new command
+// only has to be added to the table in main.rs.
+//
+
+/// What kind of value an argument takes, which decides how it is
+/// completed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Arg {
+    /// A control memory address or label.
+    Address,
+    /// An address, or one of a fixed set of words.
+    AddressOr(&'static [&'static str]),
+    /// A `field=value` assignment.
+    Assignment,
+    /// A file name.
+    File,
+    /// The name of a command.
+    Command,
+    /// One of a fixed set of words.
+    Keyword(&'static [&'static str]),
+    /// Anything else.
+    Text,
+}
+
+pub struct Param {
+    pub name: &'static str,
+    pub arg: Arg,
+    pub optional: bool,
+    /// Whether the parameter takes any number of values. Only the last
+    /// parameter may repeat.
+    pub repeats: bool,
+}
+
+pub const fn required(name: &'static str, arg: Arg) -> Param {
+    Param {
+        name,
+        arg,
+        optional: false,
+        repeats: false,
+    }
+}
+
+pub const fn optional(name: &'static str, arg: Arg) -> Param {
+    Param {
+        name,
+        arg,
+        optional: true,
+        repeats: false,
+    }
+}
+
+pub const fn repeated(name: &'static str, arg: Arg) -> Param {
+    Param {
+        name,
+        arg,
+        optional: true,
+        repeats: true,
+    }
+}
+
+/// A command, with `H` being whatever the REPL runs it with.
+pub struct Command<H> {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub params: &'static [Param],
+    /// One line for the command list.
+    pub summary: &'static str,
+    /// More detail for `help <command>`, or empty.
+    pub help: &'static str,
+    /// Whether an empty line runs the command again.
+    pub repeatable: bool,
+    pub handler: H,
+}
+
+impl<H> Command<H> {
+    fn param_syntax(param: &Param) -> String {
+        let mut text = if param.optional {
+            format!("[{}]", param.name)
+        } else {
+            format!("<{}>", param.name)
+        };
+        if param.repeats {
+            text.push_str("...");
+        }
+        text
+    }
+
+    /// The syntax of the parameters from `first` on.
+    fn params_from(&self, first: usize) -> String {
+        self.params
+            .iter()
+            .skip(first)
+            .map(Command::<H>::param_syntax)
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// The command's syntax, as in "goto <address>".
+    pub fn usage(&self) -> String {
+        if self.params.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.params_from(0))
+        }
+    }
+
+    /// The parameter that the `n`th argument is given for.
+    fn param(&self, n: usize) -> Option<&Param> {
+        match self.params.get(n) {
+            Some(p) => Some(p),
+            None => self.params.last().filter(|p| p.repeats),
+        }
+    }
+}
+
+/// Look a command up by name or alias.
+pub fn find<'a, H>(commands: &'a [Command<H>], name: &str) -> Option<&'a Command<H>> {
+    commands
+        .iter()
+        .find(|c| c.name == name || c.aliases.contains(&name))
+}
+
+/// The list of commands shown by `help`.
+pub fn summary<H>(commands: &[Command<H>]) -> String {
+    let usages: Vec<String> = commands.iter().map(|c| c.usage()).collect();
+    let width = usages.iter().map(|u| u.len()).max().unwrap_or(0) + 2;
+    let mut text = String::new();
+    for (command, usage) in commands.iter().zip(usages) {
+        text.push_str(&format!("{:1$}{2}\n", usage, width, command.summary));
+    }
+    text
+}
+
+/// The full help for one command.
+pub fn detail<H>(command: &Command<H>) -> String {
+    let mut text = format!("usage: {}\n", command.usage());
+    if !command.aliases.is_empty() {
+        text.push_str(&format!("aliases: {}\n", command.aliases.join(", ")));
+    }
+    text.push('\n');
+    text.push_str(command.summary);
+    text.push('\n');
+    if !command.help.is_empty() {
+        text.push('\n');
+        text.push_str(command.help);
+        text.push('\n');
+    }
+    text
+}
+
+//
+// Completion and hints
+//
+
+/// What completion knows about the loaded image, refreshed after each
+/// command.
+#[derive(Default)]
+pub struct Context {
+    pub labels: Vec<String>,
+    pub addresses: Vec<u16>,
+}
+
+impl Context {
+    pub fn new(ucode: &Microcode) -> Context {
+        Context {
+            labels: ucode.annotations.labels.values().cloned().collect(),
+            addresses: ucode.c_mem.mem.iter().map(|w| w.address).collect(),
+        }
+    }
+}
+
+/// Address completions are only offered when there are few enough
+/// to be worth listing.
+const MAX_ADDRESS_COMPLETIONS: usize = 64;
+
+/// Split the last command on the line up to `pos` into the finished
+/// words and the start and text of the word being typed.
+fn split(line: &str, pos: usize) -> (Vec<&str>, usize, &str) {
+    let before = &line[..pos];
+    let command = before.rfind(';').map_or(0, |i| i + 1);
+    let start = before
+        .rfind(char::is_whitespace)
+        .map_or(0, |i| i + 1)
+        .max(command);
+    let words = before[command..start].split_whitespace().collect();
+    (words, start, &before[start..])
+}
+
+fn matching(candidates: &[&str], prefix: &str) -> Vec<String> {
+    candidates
+        .iter()
+        .filter(|c| c.starts_with(prefix))
+        .map(|c| c.to_string())
+        .collect()
+}
+
+/// Complete the word being typed at `pos`. Returns the start of the
+/// word and its completions, or None when the word is a file name and
+/// should be completed as one.
+pub fn complete<H>(
+    commands: &[Command<H>],
+    context: &Context,
+    line: &str,
+    pos: usize,
+) -> Option<(usize, Vec<String>)> {
+    let (words, start, prefix) = split(line, pos);
+
+    if words.is_empty() {
+        let names: Vec<&str> = commands.iter().map(|c| c.name).collect();
+        return Some((start, matching(&names, prefix)));
+    }
+    // Output goes to a file.
+    if words.last().is_some_and(|w| w.ends_with('>')) {
+        return None;
+    }
+
+    let param = match find(commands, words[0]).and_then(|c| c.param(words.len() - 1)) {
+        Some(p) => p,
+        None => return Some((start, Vec::new())),
+    };
+
+    let candidates = match param.arg {
+        Arg::File => return None,
+        Arg::Text => Vec::new(),
+        Arg::Command => {
+            let names: Vec<&str> = commands.iter().map(|c| c.name).collect();
+            matching(&names, prefix)
+        }
+        Arg::Keyword(words) => matching(words, prefix),
+        Arg::Address | Arg::AddressOr(_) => {
+            let mut candidates = match param.arg {
+                Arg::AddressOr(words) => matching(words, prefix),
+                _ => Vec::new(),
+            };
+            let labels: Vec<&str> = context.labels.iter().map(|l| &l[..]).collect();
+            candidates.extend(matching(&labels, prefix));
+            let radix = radix::current();
+            if !prefix.is_empty() && prefix.chars().all(|c| c.is_digit(radix.base())) {
+                let addresses: Vec<String> = context
+                    .addresses
+                    .iter()
+                    .map(|&a| radix.format(a as u64))
+                    .filter(|a| a.starts_with(prefix))
+                    .collect();
+                if addresses.len() <= MAX_ADDRESS_COMPLETIONS {
+                    candidates.extend(addresses);
+                }
+            }
+            candidates
+        }
+        Arg::Assignment => {
+            let layout = layout::current();
+            match prefix.find('=') {
+                Some(eq) => {
+                    let (name, value) = (&prefix[..eq], &prefix[eq + 1..]);
+                    let mut values: Vec<String> = match layout.field(name) {
+                        Some(field) => field
+                            .values
+                            .iter()
+                            .filter(|v| v.0.starts_with(value))
+                            .map(|v| format!("{}={}", name, v.0))
+                            .collect(),
+                        None => Vec::new(),
+                    };
+                    // The NAF and other address fields take labels too.
+                    if name == "naf" {
+                        values.extend(
+                            context
+                                .labels
+                                .iter()
+                                .filter(|l| l.starts_with(value))
+                                .map(|l| format!("{}={}", name, l)),
+                        );
+                    }
+                    values
+                }
+                None => layout
+                    .fields
+                    .iter()
+                    .filter(|f| f.name.starts_with(prefix))
+                    .map(|f| format!("{}=", f.name))
+                    .collect(),
+            }
+        }
+    };
+
+    Some((start, candidates))
+}
+
+/// The hint shown after what has been typed: the rest of a command
+/// name and the syntax of the arguments still to come.
+pub fn hint<H>(commands: &[Command<H>], line: &str, pos: usize) -> Option<String> {
+    if pos < line.len() {
+        return None;
+    }
+    let (words, _, prefix) = split(line, pos);
+
+    if words.is_empty() {
+        if prefix.is_empty() {
+            return None;
+        }
+        let found: Vec<&Command<H>> = commands
+            .iter()
+            .filter(|c| c.name.starts_with(prefix))
+            .collect();
+        return match found.len() {
+            1 => {
+                let rest = &found[0].name[prefix.len()..];
+                let params = found[0].params_from(0);
+                Some(if params.is_empty() {
+                    rest.to_string()
+                } else {
+                    format!("{} {}", rest, params)
+                })
+            }
+            _ => None,
+        };
+    }
+
+    if !prefix.is_empty() || words.iter().any(|w| w.contains('>') || w.contains('|')) {
+        return None;
+    }
+    let command = find(commands, words[0])?;
+    let given = words.len() - 1;
+    if given >= command.params.len() {
+        return None;
+    }
+    Some(command.params_from(given))
+}
+
+/// Color a command line as it is typed with `theme`: command names,
+/// addresses and field assignments, with unknown commands, addresses
+/// that are not in the image, and bad assignments shown as errors.
+pub fn highlight<H>(
+    commands: &[Command<H>],
+    context: &Context,
+    theme: &Theme,
+    line: &str,
+) -> String {
+    let is_separator = |c: char| c.is_whitespace() || c == ';' || c == '>' || c == '|';
+    let mut out = String::new();
+    let mut command = None;
+    let mut index = 0;
+    let mut redirected = false;
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        if is_separator(c) {
+            if c == ';' {
+                command = None;
+                index = 0;
+                redirected = false;
+            } else if c == '>' || c == '|' {
+                redirected = true;
+            }
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let end = rest.find(is_separator).unwrap_or(rest.len());
+        let word = &rest[..end];
+        rest = &rest[end..];
+
+        if redirected {
+            out.push_str(word);
+        } else if index == 0 {
+            command = find(commands, word);
+            let style = if command.is_some() {
+                Some(Style::Command)
+            } else if commands.iter().any(|c| c.name.starts_with(word)) {
+                None
+            } else {
+                Some(Style::Error)
+            };
+            out.push_str(&style.map_or(word.to_string(), |s| theme.paint(s, word)));
+        } else {
+            let arg = command.and_then(|c| c.param(index - 1)).map(|p| p.arg);
+            out.push_str(&highlight_arg(context, theme, arg, word));
+        }
+        index += 1;
+    }
+    out
+}
+
+fn highlight_arg(context: &Context, theme: &Theme, arg: Option<Arg>, word: &str) -> String {
+    if word.contains('=') {
+        return highlight_assignment(context, theme, word);
+    }
+    match arg {
+        Some(Arg::AddressOr(keywords)) if keywords.contains(&word) => {
+            theme.paint(Style::Opcode, word)
+        }
+        Some(Arg::Address) | Some(Arg::AddressOr(_)) => {
+            if is_address(context, word) {
+                theme.paint(Style::Address, word)
+            } else if is_expression(word) {
+                word.to_string()
+            } else {
+                theme.paint(Style::Error, word)
+            }
+        }
+        _ => word.to_string(),
+    }
+}
+
+fn is_address(context: &Context, word: &str) -> bool {
+    context.labels.iter().any(|l| l == word)
+        || radix::parse_number(word)
+            .and_then(control_address)
+            .is_some_and(|a| context.addresses.contains(&a))
+}
+
+/// Whether a word may be an address expression, which is left to the
+/// command to check.
+fn is_expression(word: &str) -> bool {
+    word == "ctos" || word.contains(|c| "+-().".contains(c))
+}
+
+fn highlight_assignment(context: &Context, theme: &Theme, word: &str) -> String {
+    let (name, value) = word.split_once('=').unwrap();
+    let layout = layout::current();
+    let field = match layout.field(name) {
+        Some(field) => field,
+        None => return theme.paint(Style::Error, word),
+    };
+    let style = if value.is_empty() {
+        None
+    } else if name == "naf" && context.labels.iter().any(|l| l == value) {
+        Some(Style::Symbol)
+    } else if field.value_named(value).is_some() || fields::code_named(name, value).is_some() {
+        Some(Style::Opcode)
+    } else if radix::parse_number(value).is_some_and(|v| field.fits(v)) {
+        Some(Style::Number)
+    } else if name == "naf" && is_expression(value) {
+        None
+    } else {
+        Some(Style::Error)
+    };
+    format!(
+        "{}={}",
+        theme.paint(Style::Field, name),
+        style.map_or(value.to_string(), |s| theme.paint(s, value))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color;
+
+    const ON_OFF: &[&str] = &["on", "off"];
+
+    static COMMANDS: &[Command<()>] = &[
+        Command {
+            name: "goto",
+            aliases: &["g"],
+            params: &[required("address", Arg::Address)],
+            summary: "Go somewhere.",
+            help: "",
+            repeatable: false,
+            handler: (),
+        },
+        Command {
+            name: "set",
+            aliases: &[],
+            params: &[
+                required("address", Arg::Address),
+                repeated("field=value", Arg::Assignment),
+            ],
+            summary: "Set fields.",
+            help: "Fields are set in order.",
+            repeatable: false,
+            handler: (),
+        },
+        Command {
+            name: "load",
+            aliases: &[],
+            params: &[required("file", Arg::File)],
+            summary: "Load a file.",
+            help: "",
+            repeatable: false,
+            handler: (),
+        },
+        Command {
+            name: "trace",
+            aliases: &[],
+            params: &[optional("state", Arg::Keyword(ON_OFF))],
+            summary: "Trace.",
+            help: "",
+            repeatable: false,
+            handler: (),
+        },
+    ];
+
+    fn context() -> Context {
+        Context {
+            labels: vec!["start".to_string(), "stop".to_string()],
+            addresses: vec![0o10, 0o11, 0o100, 0o2000],
+        }
+    }
+
+    fn completions(line: &str) -> Option<(usize, Vec<String>)> {
+        complete(COMMANDS, &context(), line, line.len())
+    }
+
+    #[test]
+    fn finds_commands_by_alias() {
+        assert_eq!(find(COMMANDS, "g").map(|c| c.name), Some("goto"));
+        assert!(find(COMMANDS, "bogus").is_none());
+    }
+
+    #[test]
+    fn generates_usage_and_help() {
+        assert_eq!(COMMANDS[1].usage(), "set <address> [field=value]...");
+        assert_eq!(COMMANDS[3].usage(), "trace [state]");
+        assert!(summary(COMMANDS).contains("goto <address>                  Go somewhere.\n"));
+        let detail = detail(&COMMANDS[0]);
+        assert!(detail.starts_with("usage: goto <address>\naliases: g\n"));
+    }
+
+    #[test]
+    fn completes_names_labels_and_addresses() {
+        assert_eq!(completions("go"), Some((0, vec!["goto".to_string()])));
+        assert_eq!(
+            completions("goto st"),
+            Some((5, vec!["start".to_string(), "stop".to_string()]))
+        );
+        assert_eq!(
+            completions("goto 1"),
+            Some((
+                5,
+                vec!["10".to_string(), "11".to_string(), "100".to_string()]
+            ))
+        );
+        assert_eq!(
+            completions("trace o"),
+            Some((6, vec!["on".to_string(), "off".to_string()]))
+        );
+        assert_eq!(completions("load fi"), None);
+        assert_eq!(
+            completions("goto 10; go"),
+            Some((9, vec!["goto".to_string()]))
+        );
+        assert_eq!(completions("goto 10 >> fi"), None);
+    }
+
+    #[test]
+    fn completes_fields_and_values() {
+        assert_eq!(
+            completions("set 10 amra-"),
+            Some((7, vec!["amra-sel=".to_string()]))
+        );
+        assert_eq!(
+            completions("set 10 naf=1 seq=p"),
+            Some((13, vec!["seq=pushj".to_string(), "seq=popj".to_string()]))
+        );
+        assert_eq!(
+            completions("set 10 naf=st"),
+            Some((7, vec!["naf=start".to_string(), "naf=stop".to_string()]))
+        );
+    }
+
+    #[test]
+    fn hints_argument_syntax() {
+        assert_eq!(hint(COMMANDS, "got", 3), Some("o <address>".to_string()));
+        assert_eq!(
+            hint(COMMANDS, "set ", 4),
+            Some("<address> [field=value]...".to_string())
+        );
+        assert_eq!(
+            hint(COMMANDS, "set 10 ", 7),
+            Some("[field=value]...".to_string())
+        );
+        assert_eq!(hint(COMMANDS, "goto 10 ", 8), None);
+        assert_eq!(hint(COMMANDS, "goto 1", 6), None);
+        assert_eq!(
+            hint(COMMANDS, "trace; goto ", 12),
+            Some("<address>".to_string())
+        );
+        assert_eq!(hint(COMMANDS, "trace > x ", 10), None);
+    }
+
+    #[test]
+    fn highlights_commands_and_addresses() {
+        let theme = Theme::named("dark").unwrap();
+        let paint = |line: &str| highlight(COMMANDS, &context(), theme, line);
+        let line = "goto start; got 12; gox 10; set 10 naf=stop bogus=1 seq=popj > 12";
+        let colored = paint(line);
+        assert_eq!(color::strip(&colored), line);
+
+        let start = theme.paint(Style::Address, "start");
+        assert!(colored.starts_with(&format!(
+            "{} {};",
+            theme.paint(Style::Command, "goto"),
+            start
+        )));
+        // A command name still being typed is left alone.
+        assert!(colored.contains(" got 12;"));
+        assert!(paint("goto 12").ends_with(&theme.paint(Style::Error, "12")));
+        assert!(paint("goto start+1").ends_with(" start+1"));
+        assert!(colored.contains(&theme.paint(Style::Error, "gox")));
+        assert!(colored.contains(&format!("naf={}", theme.paint(Style::Symbol, "stop"))));
+        assert!(colored.contains(&theme.paint(Style::Error, "bogus=1")));
+        assert!(colored.contains(&format!("seq={}", theme.paint(Style::Opcode, "popj"))));
+        assert!(colored.ends_with("> 12"));
+        assert_eq!(
+            paint("goto 11"),
+            format!(
+                "{} {}",
+                theme.paint(Style::Command, "goto"),
+                theme.paint(Style::Address, "11")
+            )
+        );
+    }
+}
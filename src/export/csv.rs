@@ -0,0 +1,189 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::io;
+use std::io::Write;
+
+use layout::{self, Field};
+use radix::{self, Radix};
+use ucode::Microcode;
+
+//
+// CSV and TSV export of control memory.
+//
+// One row per control memory word and one column per microword
+// field, in layout order unless columns are chosen. Values are
+// plain numbers in the chosen radix, without prefixes.
+//
+
+pub struct CsvOptions {
+    pub separator: char,
+    pub radix: Radix,
+    pub columns: Vec<Field>,
+}
+
+impl CsvOptions {
+    /// Parse options given as `radix=16` and `fields=amra,naf,...`.
+    pub fn parse(separator: char, args: &[&str]) -> Result<CsvOptions, String> {
+        let layout = layout::current();
+        let mut options = CsvOptions {
+            separator,
+            radix: radix::current(),
+            columns: layout.fields.clone(),
+        };
+
+        for arg in args {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next().unwrap_or(""), parts.next()) {
+                ("radix", Some(r)) => {
+                    options.radix = r
+                        .parse()
+                        .ok()
+                        .and_then(Radix::from_base)
+                        .ok_or_else(|| format!("bad radix '{}'", r))?;
+                }
+                ("fields", Some(names)) => {
+                    options.columns = names
+                        .split(',')
+                        .map(|n| {
+                            layout
+                                .field(n)
+                                .cloned()
+                                .ok_or_else(|| format!("unknown field '{}'", n))
+                        })
+                        .collect::<Result<Vec<Field>, String>>()?;
+                }
+                _ => return Err(format!("bad option '{}'", arg)),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Quote free text for CSV. TSV has no quoting, so tabs and line
+    /// breaks become spaces there.
+    fn quote(&self, text: &str) -> String {
+        if self.separator == '\t' {
+            text.replace(['\t', '\r', '\n'], " ")
+        } else if text.contains([self.separator, '"', '\r', '\n']) {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+pub fn write<W: Write>(ucode: &Microcode, options: &CsvOptions, mut writer: W) -> io::Result<()> {
+    let separator = options.separator.to_string();
+
+    let notes = &ucode.annotations;
+
+    let mut header = vec!["address".to_string()];
+    header.extend(options.columns.iter().map(|f| f.name.clone()));
+    header.push("label".to_string());
+    header.push("comment".to_string());
+    writeln!(writer, "{}", header.join(&separator))?;
+
+    for cword in &ucode.c_mem.mem {
+        let word = cword.bits();
+        let mut row = vec![options.radix.format(u64::from(cword.address))];
+        row.extend(
+            options
+                .columns
+                .iter()
+                .map(|f| options.radix.format(f.get(word))),
+        );
+        row.push(options.quote(notes.label(cword.address).unwrap_or("")));
+        row.push(options.quote(notes.comment(cword.address).unwrap_or("")));
+        writeln!(writer, "{}", row.join(&separator))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ucode::CWord;
+
+    fn export(separator: char, args: &[&str]) -> Vec<String> {
+        let mut ucode = Microcode::new();
+        ucode
+            .c_mem
+            .push(CWord::new(0o10, 0o31 << 48 | 0o777, 0o20 << 16));
+        ucode.c_mem.push(CWord::new(0o11, 0, 0));
+        assert!(ucode.annotations.set_label(0o10, Some("start")).is_ok());
+        ucode.annotations.set_comment(0o10, Some("first word"));
+        let options = match CsvOptions::parse(separator, args) {
+            Ok(o) => o,
+            Err(e) => panic!("{}", e),
+        };
+        let mut out = Vec::new();
+        assert!(write(&ucode, &options, &mut out).is_ok());
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn writes_every_field_by_default() {
+        let rows = export(',', &[]);
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].starts_with("address,amra,abus-src,r-base,"));
+        assert_eq!(
+            rows[0].split(',').count(),
+            layout::current().fields.len() + 3
+        );
+        assert!(rows[1].starts_with("10,777,7,0,"));
+    }
+
+    #[test]
+    fn selects_columns_and_radix() {
+        let rows = export('\t', &["fields=spec,naf", "radix=16"]);
+        assert_eq!(
+            rows,
+            vec![
+                "address\tspec\tnaf\tlabel\tcomment",
+                "8\t19\t10\tstart\tfirst word",
+                "9\t0\t0\t\t"
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_comments() {
+        let rows = export(',', &["fields=spec"]);
+        assert_eq!(rows[1], "10,31,start,first word");
+        let options = CsvOptions::parse(',', &[]).ok().unwrap();
+        assert_eq!(options.quote("a, \"b\""), "\"a, \"\"b\"\"\"");
+        assert_eq!(options.quote("a\nb"), "\"a\nb\"");
+        let options = CsvOptions::parse('\t', &[]).ok().unwrap();
+        assert_eq!(options.quote("a\tb\r\nc"), "a b  c");
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        assert!(CsvOptions::parse(',', &["fields=amra,bogus"]).is_err());
+        assert!(CsvOptions::parse(',', &["radix=7"]).is_err());
+        assert!(CsvOptions::parse(',', &["wat"]).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use asm;
+use flow::{self, Access, Flow};
+use lisp::LispWord;
+use project::Annotations;
+use radix;
+use ucode::{MicroInstruction, Microcode};
+
+//
+// HTML report.
+//
+// A static set of pages for browsing an image without the REPL: a
+// front page, one page per routine, and a page each for A-memory and
+// B-memory. Control memory addresses link to the word's anchor on
+// its routine's page, and every word lists what leads to it.
+//
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+td, th { padding: 2px 8px; text-align: left; vertical-align: top; }
+tr:nth-child(even) { background: #f4f4f4; }
+code, .word { font-family: monospace; }
+.missing { color: #a00; }
+nav a { margin-right: 1em; }
+";
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn routine_page(entry: u16) -> String {
+    format!("r{:05o}.html", entry)
+}
+
+/// An address, by label if it has one.
+fn address_name(notes: &Annotations, addr: u16) -> String {
+    match notes.label(addr) {
+        Some(label) => escape(label),
+        None => radix::address(addr),
+    }
+}
+
+/// A link to a control memory word, or the bare address if there is
+/// no word there.
+fn word_link(notes: &Annotations, flow: &Flow, addr: u16) -> String {
+    match flow.routine(addr) {
+        Some(r) => format!(
+            "<a href=\"{}#w{:05o}\">{}</a>",
+            routine_page(r.entry),
+            addr,
+            address_name(notes, addr)
+        ),
+        None => format!(
+            "<span class=\"missing\">{}</span>",
+            address_name(notes, addr)
+        ),
+    }
+}
+
+fn access_name(access: Access) -> &'static str {
+    match access {
+        Access::Read => "read",
+        Access::Write => "write",
+    }
+}
+
+fn page_start<W: Write>(writer: &mut W, title: &str) -> io::Result<()> {
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html><head><meta charset=\"utf-8\">")?;
+    writeln!(writer, "<title>{}</title>", escape(title))?;
+    writeln!(writer, "<link rel=\"stylesheet\" href=\"style.css\">")?;
+    writeln!(writer, "</head><body>")?;
+    writeln!(
+        writer,
+        "<nav><a href=\"index.html\">Index</a><a href=\"amem.html\">A-memory</a>\
+         <a href=\"bmem.html\">B-memory</a></nav>"
+    )?;
+    writeln!(writer, "<h1>{}</h1>", escape(title))
+}
+
+fn page_end<W: Write>(writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "</body></html>")
+}
+
+fn create(dir: &Path, name: &str) -> io::Result<io::BufWriter<File>> {
+    Ok(io::BufWriter::new(File::create(dir.join(name))?))
+}
+
+//
+// Pages
+//
+
+fn write_index<W: Write>(ucode: &Microcode, flow: &Flow, writer: &mut W) -> io::Result<()> {
+    page_start(writer, "Microcode image")?;
+
+    writeln!(writer, "<table>")?;
+    let path = ucode.path.clone().unwrap_or_default();
+    let rows = [
+        ("Loaded from", escape(&path)),
+        ("Version", ucode.version.to_string()),
+        ("Comment", escape(&ucode.comment)),
+        ("A-memory", format!("{} words", ucode.a_mem.len())),
+        ("B-memory", format!("{} words", ucode.b_mem.len())),
+        ("Control memory", format!("{} words", ucode.c_mem.len())),
+        ("Type map", format!("{} words", ucode.type_map.len())),
+        ("Pico store", format!("{} words", ucode.pico_store.len())),
+        ("Routines", flow.routines.len().to_string()),
+    ];
+    for &(name, ref value) in &rows {
+        writeln!(writer, "<tr><th>{}</th><td>{}</td></tr>", name, value)?;
+    }
+    writeln!(writer, "</table>")?;
+
+    writeln!(writer, "<h2>Routines</h2>")?;
+    writeln!(writer, "<table>")?;
+    writeln!(
+        writer,
+        "<tr><th>Entry</th><th>Label</th><th>Words</th><th>Entered from</th></tr>"
+    )?;
+    for routine in &flow.routines {
+        writeln!(
+            writer,
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            routine_page(routine.entry),
+            radix::address(routine.entry),
+            escape(ucode.annotations.label(routine.entry).unwrap_or("")),
+            routine.words.len(),
+            flow.predecessors(routine.entry).len()
+        )?;
+    }
+    writeln!(writer, "</table>")?;
+
+    page_end(writer)
+}
+
+fn write_routine<W: Write>(
+    ucode: &Microcode,
+    flow: &Flow,
+    index: usize,
+    writer: &mut W,
+) -> io::Result<()> {
+    let notes = &ucode.annotations;
+    let routine = &flow.routines[index];
+    let title = match notes.label(routine.entry) {
+        Some(label) => format!("Routine {} ({})", label, radix::address(routine.entry)),
+        None => format!("Routine {}", radix::address(routine.entry)),
+    };
+    page_start(writer, &title)?;
+
+    writeln!(writer, "<table>")?;
+    writeln!(
+        writer,
+        "<tr><th>Address</th><th>Label</th><th>Instruction</th><th>Comment</th>\
+         <th>Goes to</th><th>Memory</th><th>Reached from</th></tr>"
+    )?;
+
+    for addr in &routine.words {
+        let cword = match ucode.c_word(*addr) {
+            Some(cword) => cword,
+            None => continue,
+        };
+        let u = MicroInstruction::new(cword);
+
+        let text = asm::disassemble_word(cword);
+        let fields = text.split_once(':').map_or("", |x| x.1).trim();
+
+        let successors: Vec<String> = flow
+            .successors(*addr)
+            .iter()
+            .map(|e| format!("{} {}", e.kind.name(), word_link(notes, flow, e.to)))
+            .collect();
+
+        let mut memory = Vec::new();
+        for (a, access) in flow::a_refs(&u) {
+            memory.push(format!(
+                "{} <a href=\"amem.html#a{:04o}\">A {}</a>",
+                access_name(access),
+                a,
+                radix::padded(a as u64, 12)
+            ));
+        }
+        for (b, access) in flow::b_refs(&u) {
+            let mut text = format!(
+                "{} <a href=\"bmem.html#b{:03o}\">B {}</a>",
+                access_name(access),
+                b,
+                radix::padded(b as u64, 8)
+            );
+            if access == Access::Read {
+                if let Some(w) = ucode.b_mem.mem.iter().find(|w| w.address == b) {
+                    text.push_str(&format!(" = {}", LispWord::new(w.data)));
+                }
+            }
+            memory.push(text);
+        }
+
+        let predecessors: Vec<String> = flow
+            .predecessors(*addr)
+            .iter()
+            .map(|e| format!("{} {}", e.kind.name(), word_link(notes, flow, e.from)))
+            .collect();
+
+        writeln!(
+            writer,
+            "<tr id=\"w{:05o}\"><td class=\"word\">{}</td><td>{}</td>\
+             <td><code>{}</code></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            addr,
+            radix::address(*addr),
+            escape(notes.label(*addr).unwrap_or("")),
+            escape(fields),
+            escape(notes.comment(*addr).unwrap_or("")),
+            successors.join("<br>"),
+            memory.join("<br>"),
+            predecessors.join("<br>")
+        )?;
+    }
+
+    writeln!(writer, "</table>")?;
+    page_end(writer)
+}
+
+type Xrefs = fn(&Flow, u16) -> &[(u16, Access)];
+
+/// The A-memory page, or given prefix 'b', the B-memory page.
+fn write_memory<W: Write>(
+    ucode: &Microcode,
+    flow: &Flow,
+    prefix: char,
+    writer: &mut W,
+) -> io::Result<()> {
+    let (mem, name, xrefs): (_, _, Xrefs) = if prefix == 'a' {
+        (&ucode.a_mem, "A-memory", Flow::a_xrefs)
+    } else {
+        (&ucode.b_mem, "B-memory", Flow::b_xrefs)
+    };
+    page_start(writer, name)?;
+
+    let (digits, bits) = if prefix == 'a' { (4, 12) } else { (3, 8) };
+    let mut addresses: BTreeSet<u16> = mem.mem.iter().map(|w| w.address).collect();
+    let limit = if prefix == 'a' { 0o10000 } else { 0o400 };
+    for addr in 0..limit {
+        if !xrefs(flow, addr).is_empty() {
+            addresses.insert(addr);
+        }
+    }
+
+    writeln!(writer, "<table>")?;
+    writeln!(
+        writer,
+        "<tr><th>Address</th><th>Word</th><th>Decoded</th><th>Used by</th></tr>"
+    )?;
+    for addr in addresses {
+        let (word, decoded) = match mem.mem.iter().find(|w| w.address == addr) {
+            Some(w) => (radix::padded(w.data, 36), LispWord::new(w.data).to_string()),
+            None => (String::new(), String::new()),
+        };
+        let users: Vec<String> = xrefs(flow, addr)
+            .iter()
+            .map(|&(from, access)| {
+                format!(
+                    "{} {}",
+                    access_name(access),
+                    word_link(&ucode.annotations, flow, from)
+                )
+            })
+            .collect();
+        let id = format!("{}{:02$o}", prefix, addr, digits);
+        writeln!(
+            writer,
+            "<tr id=\"{}\"><td class=\"word\">{}</td><td class=\"word\">{}</td>\
+             <td>{}</td><td>{}</td></tr>",
+            id,
+            radix::padded(addr as u64, bits),
+            word,
+            decoded,
+            users.join("<br>")
+        )?;
+    }
+    writeln!(writer, "</table>")?;
+
+    page_end(writer)
+}
+
+/// Write the report into `dir`, creating it if need be. Returns the
+/// number of pages written.
+pub fn export(ucode: &Microcode, dir: &str) -> io::Result<usize> {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+    let flow = Flow::new(ucode);
+
+    create(dir, "style.css")?.write_all(STYLE.as_bytes())?;
+    write_index(ucode, &flow, &mut create(dir, "index.html")?)?;
+
+    write_memory(ucode, &flow, 'a', &mut create(dir, "amem.html")?)?;
+    write_memory(ucode, &flow, 'b', &mut create(dir, "bmem.html")?)?;
+
+    for (i, routine) in flow.routines.iter().enumerate() {
+        write_routine(
+            ucode,
+            &flow,
+            i,
+            &mut create(dir, &routine_page(routine.entry))?,
+        )?;
+    }
+
+    Ok(flow.routines.len() + 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ucode::ABWord;
+
+    fn image() -> Microcode {
+        let mut ucode = asm::image(
+            "        .org 100\n\
+             main:   npc-sel=next bmra=42 seq=pushj naf=sub\n\
+             sub:    npc-sel=next amra=7 cpc-sel=ctos seq=popj\n",
+        );
+        ucode.b_mem.push(ABWord {
+            address: 0o42,
+            data: 0o123,
+        });
+        ucode.comment = "<test>".to_string();
+        assert!(ucode.annotations.set_label(0o101, Some("sub")).is_ok());
+        ucode.annotations.set_comment(0o101, Some("a & b"));
+        ucode
+    }
+
+    fn page<F: Fn(&Microcode, &Flow, &mut Vec<u8>) -> io::Result<()>>(write: F) -> String {
+        let ucode = image();
+        let flow = Flow::new(&ucode);
+        let mut out = Vec::new();
+        assert!(write(&ucode, &flow, &mut out).is_ok());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn index_lists_header_and_routines() {
+        let html = page(write_index);
+        assert!(html.contains("<td>&lt;test&gt;</td>"));
+        assert!(html.contains("<a href=\"r00100.html\">00100</a>"));
+        assert!(html.contains("<a href=\"r00101.html\">00101</a>"));
+    }
+
+    #[test]
+    fn routine_pages_link_targets_and_callers() {
+        let html = page(|u, f, w| write_routine(u, f, 1, w));
+        assert!(html.contains("<h1>Routine sub (00101)</h1>"));
+        assert!(html.contains("<tr id=\"w00101\"><td class=\"word\">00101</td><td>sub</td>"));
+        assert!(html.contains("<td>a &amp; b</td>"));
+        assert!(html.contains("call <a href=\"r00100.html#w00100\">00100</a>"));
+        assert!(html.contains("read <a href=\"amem.html#a0007\">A 0007</a>"));
+        let html = page(|u, f, w| write_routine(u, f, 0, w));
+        assert!(html.contains("call <a href=\"r00101.html#w00101\">sub</a>"));
+        assert!(html.contains("read <a href=\"bmem.html#b042\">B 042</a> = cdr 0 fixnum 123"));
+    }
+
+    #[test]
+    fn memory_pages_decode_and_cross_reference() {
+        let html = page(|u, f, w| write_memory(u, f, 'b', w));
+        assert!(html.contains("<tr id=\"b042\">"));
+        assert!(html.contains("<td>cdr 0 fixnum 123</td>"));
+        assert!(html.contains("read <a href=\"r00100.html#w00100\">00100</a>"));
+    }
+}
@@ -0,0 +1,209 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+
+use serde_json;
+
+use layout;
+use lisp::LispWord;
+use ucode::{ABWord, Mem, Microcode, PicoStoreWord};
+
+//
+// JSON export.
+//
+// The whole image in one document. Control words carry their raw
+// bits both as the two numbers the image file holds and as an octal
+// string, and their fields keyed by the names the current layout
+// gives them.
+//
+
+/// Identifies the document; bumped when its shape changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+pub struct Image<'a> {
+    pub format: &'static str,
+    pub format_version: u32,
+    pub header: Header<'a>,
+    pub comment: &'a str,
+    pub a_mem: Vec<MemWord>,
+    pub b_mem: Vec<MemWord>,
+    pub c_mem: Vec<ControlWord>,
+    pub type_map: Vec<u8>,
+    pub pico_store: &'a [PicoStoreWord],
+    pub annotations: Vec<Annotation<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct Header<'a> {
+    pub path: Option<&'a str>,
+    pub version: u16,
+}
+
+/// An A or B memory word.
+#[derive(Serialize)]
+pub struct MemWord {
+    pub address: u16,
+    pub data: u64,
+    pub lisp: LispWord,
+}
+
+/// A control memory word.
+#[derive(Serialize)]
+pub struct ControlWord {
+    pub address: u16,
+    pub bits: String,
+    pub data_l: u64,
+    pub data_h: u64,
+    pub parity_ok: bool,
+    /// Every field and alias of the layout, by name.
+    pub fields: BTreeMap<String, u64>,
+}
+
+/// A label, comment or entry point from the project file.
+#[derive(Serialize)]
+pub struct Annotation<'a> {
+    pub address: u16,
+    pub label: Option<&'a str>,
+    pub comment: Option<&'a str>,
+    pub entry: bool,
+}
+
+fn mem_words(mem: &Mem<ABWord>) -> Vec<MemWord> {
+    mem.mem
+        .iter()
+        .map(|w| MemWord {
+            address: w.address,
+            data: w.data,
+            lisp: LispWord::new(w.data),
+        })
+        .collect()
+}
+
+impl<'a> Image<'a> {
+    pub fn new(ucode: &'a Microcode) -> Image<'a> {
+        let layout = layout::current();
+        let notes = &ucode.annotations;
+
+        Image {
+            format: "uc-explorer-image",
+            format_version: FORMAT_VERSION,
+            header: Header {
+                path: ucode.path.as_deref(),
+                version: ucode.version,
+            },
+            comment: &ucode.comment,
+            a_mem: mem_words(&ucode.a_mem),
+            b_mem: mem_words(&ucode.b_mem),
+            c_mem: ucode
+                .c_mem
+                .mem
+                .iter()
+                .map(|w| {
+                    let word = w.bits();
+                    ControlWord {
+                        address: w.address,
+                        bits: format!("{:038o}", word),
+                        data_l: w.data_l,
+                        data_h: w.data_h,
+                        parity_ok: layout.with_parity(word) == word,
+                        fields: layout
+                            .fields
+                            .iter()
+                            .map(|f| (f.name.clone(), f.get(word)))
+                            .collect(),
+                    }
+                })
+                .collect(),
+            type_map: ucode.type_map.mem.iter().map(|t| t.data).collect(),
+            pico_store: &ucode.pico_store.mem,
+            annotations: notes
+                .addresses()
+                .into_iter()
+                .map(|address| Annotation {
+                    address,
+                    label: notes.label(address),
+                    comment: notes.comment(address),
+                    entry: notes.entries.contains(&address),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Write the image as pretty-printed JSON.
+pub fn write<W: Write>(ucode: &Microcode, writer: W) -> io::Result<()> {
+    serde_json::to_writer_pretty(writer, &Image::new(ucode)).map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use ucode::CWord;
+
+    #[test]
+    fn exports_every_section() {
+        let mut ucode = Microcode::new();
+        ucode.version = 0o123;
+        ucode.comment = "TEST".to_string();
+        ucode.a_mem.push(ABWord {
+            address: 0o100,
+            data: (1 << 34) | 0xffff_ffff,
+        });
+        ucode.b_mem.push(ABWord {
+            address: 0o10,
+            data: (0o23 << 28) | 0o1234,
+        });
+        ucode.c_mem.push(CWord::new(0o1000, 0o31 << 48, 1 << 47));
+        ucode.annotations.set_comment(0o1000, Some("halts"));
+
+        let mut out = Vec::new();
+        assert!(write(&ucode, &mut out).is_ok());
+        let json: Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(json["format_version"], FORMAT_VERSION);
+        assert_eq!(json["header"]["version"], 0o123);
+        assert_eq!(json["comment"], "TEST");
+        assert_eq!(json["a_mem"][0]["lisp"]["cdr_code"], 1);
+        assert_eq!(json["a_mem"][0]["lisp"]["fixnum"], -1);
+        assert_eq!(json["b_mem"][0]["lisp"]["data_type"], 0o23);
+        assert_eq!(json["b_mem"][0]["lisp"]["address"], 0o1234);
+        assert_eq!(json["c_mem"][0]["address"], 0o1000);
+        assert_eq!(json["c_mem"][0]["fields"]["spec"], 0o31);
+        assert_eq!(json["c_mem"][0]["fields"]["lbus-dev"], 0);
+        assert_eq!(
+            json["c_mem"][0]["fields"].as_object().unwrap().len(),
+            layout::current().fields.len()
+        );
+        assert_eq!(json["c_mem"][0]["parity_ok"], false);
+        assert_eq!(
+            json["c_mem"][0]["bits"],
+            format!("{:038o}", (1u128 << 111) | (0o31 << 48))
+        );
+        assert!(json["type_map"].as_array().unwrap().is_empty());
+        assert_eq!(json["annotations"][0]["address"], 0o1000);
+        assert_eq!(json["annotations"][0]["comment"], "halts");
+        assert_eq!(json["annotations"][0]["label"], Value::Null);
+    }
+}
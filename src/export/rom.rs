@@ -0,0 +1,497 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+use serde_json;
+
+use layout;
+use radix;
+use ucode::Microcode;
+
+//
+// ROM and FPGA initialization exports.
+//
+// Each memory is written at its full depth, one word per address,
+// with unpopulated addresses set to a fill value. Words are cut or
+// zero-extended to the chosen width. Intel HEX follows the usual
+// memory-initialization convention of one record per word, with the
+// record address being the word address and the data big-endian.
+//
+// Every export is accompanied by a JSON manifest, written next to it
+// with ".manifest.json" appended, that says which bits hold what.
+//
+
+const C_MEM_DEPTH: usize = 16384;
+const A_MEM_DEPTH: usize = 4096;
+const B_MEM_DEPTH: usize = 256;
+const TYPE_MAP_DEPTH: usize = 4096;
+
+/// The memories that can be exported.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Memory {
+    C,
+    A,
+    B,
+    TypeMap,
+}
+
+/// The output formats.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    ReadMemH,
+    ReadMemB,
+    IntelHex,
+    BinaryLE,
+    BinaryBE,
+}
+
+impl Memory {
+    pub fn named(name: &str) -> Option<Memory> {
+        match name {
+            "cmem" => Some(Memory::C),
+            "amem" => Some(Memory::A),
+            "bmem" => Some(Memory::B),
+            "typemap" => Some(Memory::TypeMap),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Memory::C => "cmem",
+            Memory::A => "amem",
+            Memory::B => "bmem",
+            Memory::TypeMap => "typemap",
+        }
+    }
+
+    /// The width of a word as stored in the image.
+    pub fn natural_width(self) -> u32 {
+        match self {
+            Memory::C => layout::WORD_BITS,
+            Memory::A | Memory::B => 40,
+            Memory::TypeMap => 8,
+        }
+    }
+
+    fn depth(self) -> usize {
+        match self {
+            Memory::C => C_MEM_DEPTH,
+            Memory::A => A_MEM_DEPTH,
+            Memory::B => B_MEM_DEPTH,
+            Memory::TypeMap => TYPE_MAP_DEPTH,
+        }
+    }
+}
+
+impl Format {
+    pub fn named(name: &str) -> Option<Format> {
+        match name {
+            "readmemh" => Some(Format::ReadMemH),
+            "readmemb" => Some(Format::ReadMemB),
+            "ihex" => Some(Format::IntelHex),
+            "bin-le" => Some(Format::BinaryLE),
+            "bin-be" => Some(Format::BinaryBE),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::ReadMemH => "readmemh",
+            Format::ReadMemB => "readmemb",
+            Format::IntelHex => "ihex",
+            Format::BinaryLE => "bin-le",
+            Format::BinaryBE => "bin-be",
+        }
+    }
+}
+
+pub struct RomOptions {
+    pub memory: Memory,
+    pub format: Format,
+    pub width: u32,
+    pub fill: u128,
+}
+
+impl RomOptions {
+    /// Parse options given as `width=N` and `fill=V`. The width is
+    /// decimal, the fill value a number as typed at the prompt.
+    pub fn parse(memory: Memory, format: Format, args: &[&str]) -> Result<RomOptions, String> {
+        let mut options = RomOptions {
+            memory,
+            format,
+            width: memory.natural_width(),
+            fill: 0,
+        };
+
+        for arg in args {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next().unwrap_or(""), parts.next()) {
+                ("width", Some(w)) => {
+                    options.width = match w.parse::<u32>() {
+                        Ok(w) if (1..=128).contains(&w) => w,
+                        _ => return Err(format!("bad width '{}'", w)),
+                    }
+                }
+                ("fill", Some(v)) => {
+                    options.fill = match radix::parse_number(v) {
+                        Some(v) => v as u128,
+                        None => return Err(format!("bad fill value '{}'", v)),
+                    }
+                }
+                _ => return Err(format!("bad option '{}'", arg)),
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn mask(&self) -> u128 {
+        if self.width == 128 {
+            !0
+        } else {
+            (1u128 << self.width) - 1
+        }
+    }
+
+    fn bytes_per_word(&self) -> usize {
+        self.width.div_ceil(8) as usize
+    }
+}
+
+/// Every word of the memory, in address order, at its full depth.
+pub fn words(ucode: &Microcode, options: &RomOptions) -> Vec<u128> {
+    let mut words = vec![options.fill & options.mask(); options.memory.depth()];
+    let mut put = |address: usize, value: u128| {
+        if address < words.len() {
+            words[address] = value & options.mask();
+        }
+    };
+
+    match options.memory {
+        Memory::C => {
+            for w in &ucode.c_mem.mem {
+                put(w.address as usize, w.bits());
+            }
+        }
+        Memory::A | Memory::B => {
+            let mem = if options.memory == Memory::A {
+                &ucode.a_mem
+            } else {
+                &ucode.b_mem
+            };
+            for w in &mem.mem {
+                put(w.address as usize, w.data as u128);
+            }
+        }
+        Memory::TypeMap => {
+            for (i, t) in ucode.type_map.mem.iter().enumerate() {
+                put(i, t.data as u128);
+            }
+        }
+    }
+
+    words
+}
+
+/// Write the memory in the chosen format.
+pub fn write<W: Write>(ucode: &Microcode, options: &RomOptions, mut writer: W) -> io::Result<()> {
+    let words = words(ucode, options);
+    let bytes = options.bytes_per_word();
+
+    match options.format {
+        Format::ReadMemH | Format::ReadMemB => {
+            writeln!(
+                writer,
+                "// {} {} bits x {} words",
+                options.memory.name(),
+                options.width,
+                words.len()
+            )?;
+            for (address, word) in words.iter().enumerate() {
+                if options.format == Format::ReadMemH {
+                    write!(writer, "{:01$x}", word, options.width.div_ceil(4) as usize)?;
+                } else {
+                    write!(writer, "{:01$b}", word, options.width as usize)?;
+                }
+                let label = match options.memory {
+                    Memory::C => ucode.annotations.label(address as u16),
+                    _ => None,
+                };
+                match label {
+                    Some(label) => writeln!(writer, " // {}", label)?,
+                    None => writeln!(writer)?,
+                }
+            }
+        }
+        Format::IntelHex => {
+            let mut segment = 0;
+            for (address, word) in words.iter().enumerate() {
+                if address >> 16 != segment {
+                    segment = address >> 16;
+                    writeln!(
+                        writer,
+                        "{}",
+                        ihex_record(0, 4, &[(segment >> 8) as u8, segment as u8])
+                    )?;
+                }
+                let data = big_endian(*word, bytes);
+                writeln!(writer, "{}", ihex_record(address as u16, 0, &data))?;
+            }
+            writeln!(writer, "{}", ihex_record(0, 1, &[]))?;
+        }
+        Format::BinaryLE | Format::BinaryBE => {
+            for word in &words {
+                let mut data = big_endian(*word, bytes);
+                if options.format == Format::BinaryLE {
+                    data.reverse();
+                }
+                writer.write_all(&data)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn big_endian(word: u128, bytes: usize) -> Vec<u8> {
+    (0..bytes).rev().map(|i| (word >> (8 * i)) as u8).collect()
+}
+
+/// Format one Intel HEX record, checksum included.
+fn ihex_record(address: u16, kind: u8, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    record.push(sum.wrapping_neg());
+    let hex: Vec<String> = record.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}", hex.concat())
+}
+
+//
+// Manifest
+//
+
+#[derive(Serialize)]
+pub struct Manifest {
+    pub memory: &'static str,
+    pub format: &'static str,
+    pub width: u32,
+    pub depth: usize,
+    pub fill: String,
+    pub bytes_per_word: usize,
+    pub byte_order: &'static str,
+    pub addressing: &'static str,
+    pub fields: Vec<ManifestField>,
+}
+
+/// Where a field lies in an exported word. Bits at or above the
+/// export width are not present in the output.
+#[derive(Serialize)]
+pub struct ManifestField {
+    pub name: String,
+    pub title: String,
+    pub high: u32,
+    pub low: u32,
+    pub group: Option<String>,
+}
+
+fn manifest_field(
+    name: &str,
+    title: &str,
+    high: u32,
+    low: u32,
+    group: Option<&str>,
+) -> ManifestField {
+    ManifestField {
+        name: name.to_string(),
+        title: title.to_string(),
+        high,
+        low,
+        group: group.map(|g| g.to_string()),
+    }
+}
+
+impl Manifest {
+    pub fn new(options: &RomOptions) -> Manifest {
+        let fields = match options.memory {
+            Memory::C => layout::current()
+                .fields
+                .iter()
+                .map(|f| ManifestField {
+                    name: f.name.clone(),
+                    title: f.title.clone(),
+                    high: f.lo + f.width - 1,
+                    low: f.lo,
+                    group: f.group.clone(),
+                })
+                .collect(),
+            Memory::A | Memory::B => vec![
+                manifest_field("cdr-code", "CDR code", 35, 34, None),
+                manifest_field("data-type", "Data type", 33, 28, None),
+                manifest_field(
+                    "high-type",
+                    "High type (0 for fixnums)",
+                    33,
+                    32,
+                    Some("data-type"),
+                ),
+                manifest_field("address", "Address", 27, 0, None),
+                manifest_field("fixnum", "Fixnum value", 31, 0, Some("address")),
+            ],
+            Memory::TypeMap => vec![manifest_field("cond", "Type map condition", 0, 0, None)],
+        };
+
+        let addressing = match options.memory {
+            Memory::TypeMap => "type map select * 64 + data type",
+            _ => "word address",
+        };
+
+        let byte_order = match options.format {
+            Format::BinaryLE => "little-endian",
+            Format::BinaryBE | Format::IntelHex => "big-endian",
+            _ => "none",
+        };
+
+        Manifest {
+            memory: options.memory.name(),
+            format: options.format.name(),
+            width: options.width,
+            depth: options.memory.depth(),
+            fill: format!("{:o}", options.fill & options.mask()),
+            bytes_per_word: options.bytes_per_word(),
+            byte_order,
+            addressing,
+            fields,
+        }
+    }
+}
+
+/// Write the export and its manifest.
+pub fn export(ucode: &Microcode, options: &RomOptions, path: &str) -> io::Result<()> {
+    write(ucode, options, io::BufWriter::new(File::create(path)?))?;
+
+    let manifest = File::create(format!("{}.manifest.json", path))?;
+    serde_json::to_writer_pretty(manifest, &Manifest::new(options)).map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ucode::{ABWord, CWord, TypeWord};
+
+    fn image() -> Microcode {
+        let mut ucode = Microcode::new();
+        ucode
+            .c_mem
+            .push(CWord::new(1, 0x0123_4567_89ab_cdef, 0xfedc_ba98_7654));
+        ucode.a_mem.push(ABWord {
+            address: 2,
+            data: 0xa_bcde_f012,
+        });
+        ucode.type_map.push(TypeWord { data: 5 });
+        ucode
+    }
+
+    fn output(memory: Memory, format: Format, args: &[&str]) -> Vec<u8> {
+        let options = match RomOptions::parse(memory, format, args) {
+            Ok(o) => o,
+            Err(e) => panic!("{}", e),
+        };
+        let mut out = Vec::new();
+        assert!(write(&image(), &options, &mut out).is_ok());
+        out
+    }
+
+    fn lines(out: Vec<u8>) -> Vec<String> {
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn readmemh_fills_and_pads() {
+        let lines = lines(output(Memory::C, Format::ReadMemH, &["fill=7"]));
+        assert_eq!(lines.len(), C_MEM_DEPTH + 1);
+        assert!(lines[0].starts_with("//"));
+        assert_eq!(lines[1], "0000000000000000000000000007");
+        assert_eq!(lines[2], "fedcba9876540123456789abcdef");
+    }
+
+    #[test]
+    fn readmem_shows_labels() {
+        let mut ucode = image();
+        assert!(ucode.annotations.set_label(1, Some("start")).is_ok());
+        let options = RomOptions::parse(Memory::C, Format::ReadMemH, &[])
+            .ok()
+            .unwrap();
+        let mut out = Vec::new();
+        assert!(write(&ucode, &options, &mut out).is_ok());
+        assert_eq!(lines(out)[2], "fedcba9876540123456789abcdef // start");
+    }
+
+    #[test]
+    fn readmemb_cuts_to_width() {
+        let lines = lines(output(Memory::A, Format::ReadMemB, &["width=4"]));
+        assert_eq!(lines.len(), A_MEM_DEPTH + 1);
+        assert_eq!(lines[3], "0010");
+    }
+
+    #[test]
+    fn binary_in_both_byte_orders() {
+        let le = output(Memory::A, Format::BinaryLE, &[]);
+        let be = output(Memory::A, Format::BinaryBE, &[]);
+        assert_eq!(le.len(), A_MEM_DEPTH * 5);
+        assert_eq!(&le[10..15], &[0x12, 0xf0, 0xde, 0xbc, 0x0a]);
+        assert_eq!(&be[10..15], &[0x0a, 0xbc, 0xde, 0xf0, 0x12]);
+    }
+
+    #[test]
+    fn intel_hex_records_have_checksums() {
+        let lines = lines(output(Memory::TypeMap, Format::IntelHex, &[]));
+        assert_eq!(lines[0], ":0100000005FA");
+        assert_eq!(lines[1], ":0100010000FE");
+        assert_eq!(lines.last().unwrap(), ":00000001FF");
+    }
+
+    #[test]
+    fn manifest_lists_fields() {
+        let options = RomOptions::parse(Memory::C, Format::BinaryLE, &["width=128"])
+            .ok()
+            .unwrap();
+        let manifest = Manifest::new(&options);
+        assert_eq!(manifest.bytes_per_word, 16);
+        assert_eq!(manifest.fields.len(), layout::current().fields.len());
+        let naf = manifest.fields.iter().find(|f| f.name == "naf").unwrap();
+        assert_eq!((naf.high, naf.low), (93, 80));
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        assert!(RomOptions::parse(Memory::C, Format::IntelHex, &["width=0"]).is_err());
+        assert!(RomOptions::parse(Memory::C, Format::IntelHex, &["fill=xyz"]).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+
+use layout::{self, Field};
+use ucode::Microcode;
+
+//
+// Rust and C source export.
+//
+// Emits the image as constant tables an emulator can compile in,
+// along with accessors for every microword field and constants for
+// symbolic field values, all generated from the current layout.
+//
+// Control memory is a table of (address, word) entries in address
+// order. Rust gets the word as a u128; C gets it as a pair of 64-bit
+// halves, low holding bits 63-0 and high bits 111-64.
+//
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Lang {
+    Rust,
+    C,
+}
+
+impl Lang {
+    pub fn named(name: &str) -> Option<Lang> {
+        match name {
+            "rust" => Some(Lang::Rust),
+            "c" => Some(Lang::C),
+            _ => None,
+        }
+    }
+}
+
+/// Turn a field or value name into an identifier. Returns None for
+/// names that cannot be made into one.
+fn identifier(name: &str) -> Option<String> {
+    let mut id = name.replace('+', "-plus-");
+    id = id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let id = id
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("_");
+
+    match id.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => Some(id),
+        _ => None,
+    }
+}
+
+fn mask(width: u32) -> u64 {
+    if width >= 64 {
+        !0
+    } else {
+        (1 << width) - 1
+    }
+}
+
+/// The symbolic values of a field, as (identifier, value). Names that
+/// collide once made into identifiers are left out.
+fn value_constants(field: &Field) -> Vec<(String, u64)> {
+    let mut seen = BTreeSet::new();
+    let mut values = Vec::new();
+    for &(ref name, value) in &field.values {
+        if let Some(id) = identifier(name) {
+            if seen.insert(id.clone()) {
+                values.push((id, value));
+            }
+        }
+    }
+    values
+}
+
+/// A trailing comment with the label and comment of an address.
+fn annotation(ucode: &Microcode, addr: u16) -> String {
+    let notes = &ucode.annotations;
+    match (notes.label(addr), notes.comment(addr)) {
+        (Some(l), Some(c)) => format!(" // {}: {}", l, c),
+        (Some(l), None) => format!(" // {}:", l),
+        (None, Some(c)) => format!(" // {}", c),
+        (None, None) => String::new(),
+    }
+}
+
+fn header<W: Write>(ucode: &Microcode, comment: &str, writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "{} Generated by uc-explorer. Do not edit.", comment)?;
+    if let Some(ref path) = ucode.path {
+        writeln!(writer, "{} Image: {}", comment, path)?;
+    }
+    writeln!(writer, "{} Version: {}", comment, ucode.version)?;
+    for line in ucode.comment.lines() {
+        writeln!(writer, "{} {}", comment, line)?;
+    }
+    writeln!(writer)
+}
+
+//
+// Rust
+//
+
+fn write_rust<W: Write>(ucode: &Microcode, writer: &mut W) -> io::Result<()> {
+    header(ucode, "//", writer)?;
+
+    writeln!(writer, "// Microword field accessors.")?;
+    writeln!(writer)?;
+    for field in &layout::current().fields {
+        let id = match identifier(&field.name) {
+            Some(id) => id,
+            None => continue,
+        };
+        writeln!(
+            writer,
+            "/// {} <{}:{}>",
+            field.title,
+            field.lo + field.width - 1,
+            field.lo
+        )?;
+        writeln!(writer, "#[inline]")?;
+        writeln!(writer, "pub fn {}(word: u128) -> u64 {{", id)?;
+        writeln!(
+            writer,
+            "    ((word >> {}) as u64) & 0o{:o}",
+            field.lo,
+            mask(field.width)
+        )?;
+        writeln!(writer, "}}")?;
+        writeln!(writer)?;
+        for (value, n) in value_constants(field) {
+            writeln!(
+                writer,
+                "pub const {}_{}: u64 = 0o{:o};",
+                id.to_uppercase(),
+                value.to_uppercase(),
+                n
+            )?;
+        }
+        if !field.values.is_empty() {
+            writeln!(writer)?;
+        }
+    }
+
+    writeln!(writer, "/// Control memory as (address, word).")?;
+    writeln!(
+        writer,
+        "pub const C_MEM: [(u16, u128); {}] = [",
+        ucode.c_mem.mem.len()
+    )?;
+    for w in &ucode.c_mem.mem {
+        writeln!(
+            writer,
+            "    (0o{:05o}, 0o{:038o}),{}",
+            w.address,
+            w.bits(),
+            annotation(ucode, w.address)
+        )?;
+    }
+    writeln!(writer, "];")?;
+    writeln!(writer)?;
+
+    for (name, mem) in &[("A_MEM", &ucode.a_mem), ("B_MEM", &ucode.b_mem)] {
+        writeln!(writer, "/// {}-memory as (address, word).", &name[..1])?;
+        writeln!(
+            writer,
+            "pub const {}: [(u16, u64); {}] = [",
+            name,
+            mem.mem.len()
+        )?;
+        for w in &mem.mem {
+            writeln!(writer, "    (0o{:04o}, 0o{:014o}),", w.address, w.data)?;
+        }
+        writeln!(writer, "];")?;
+        writeln!(writer)?;
+    }
+
+    writeln!(writer, "/// The type map.")?;
+    writeln!(
+        writer,
+        "pub const TYPE_MAP: [u8; {}] = [",
+        ucode.type_map.mem.len()
+    )?;
+    for chunk in ucode.type_map.mem.chunks(16) {
+        let entries: Vec<String> = chunk.iter().map(|t| format!("0o{:o}", t.data)).collect();
+        writeln!(writer, "    {},", entries.join(", "))?;
+    }
+    writeln!(writer, "];")?;
+    writeln!(writer)?;
+
+    writeln!(writer, "/// The pico store as (address, word).")?;
+    writeln!(
+        writer,
+        "pub const PICO_STORE: [(u16, u32); {}] = [",
+        ucode.pico_store.mem.len()
+    )?;
+    for w in &ucode.pico_store.mem {
+        writeln!(writer, "    (0o{:06o}, 0o{:011o}),", w.address, w.data)?;
+    }
+    writeln!(writer, "];")
+}
+
+//
+// C
+//
+
+/// Close a C table. C has no empty initializers, so an empty table
+/// gets a placeholder entry; its count macro still says zero.
+fn end_c_table<W: Write>(writer: &mut W, name: &str, count: usize) -> io::Result<()> {
+    if count == 0 {
+        writeln!(writer, "    {{ 0 }},")?;
+    }
+    writeln!(writer, "}};")?;
+    writeln!(writer, "#define {}_COUNT {}", name.to_uppercase(), count)?;
+    writeln!(writer)
+}
+
+fn write_c<W: Write>(ucode: &Microcode, writer: &mut W) -> io::Result<()> {
+    header(ucode, "//", writer)?;
+
+    writeln!(writer, "#include <stdint.h>")?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "struct uc_cword {{ uint16_t address; uint64_t low, high; }};"
+    )?;
+    writeln!(
+        writer,
+        "struct uc_abword {{ uint16_t address; uint64_t data; }};"
+    )?;
+    writeln!(
+        writer,
+        "struct uc_picoword {{ uint16_t address; uint32_t data; }};"
+    )?;
+    writeln!(writer)?;
+
+    writeln!(writer, "// Microword field accessors.")?;
+    writeln!(writer)?;
+    for field in &layout::current().fields {
+        let id = match identifier(&field.name) {
+            Some(id) => id,
+            None => continue,
+        };
+        let hi = field.lo + field.width - 1;
+        let expr = if field.lo >= 64 {
+            format!("high >> {}", field.lo - 64)
+        } else if hi < 64 {
+            format!("low >> {}", field.lo)
+        } else {
+            format!("(low >> {}) | (high << {})", field.lo, 64 - field.lo)
+        };
+        writeln!(writer, "// {} <{}:{}>", field.title, hi, field.lo)?;
+        writeln!(
+            writer,
+            "static inline uint64_t uc_{}(uint64_t low, uint64_t high)",
+            id
+        )?;
+        writeln!(writer, "{{")?;
+        writeln!(writer, "    (void)low;")?;
+        writeln!(writer, "    (void)high;")?;
+        writeln!(
+            writer,
+            "    return ({}) & 0{:o}ULL;",
+            expr,
+            mask(field.width)
+        )?;
+        writeln!(writer, "}}")?;
+        writeln!(writer)?;
+        for (value, n) in value_constants(field) {
+            writeln!(
+                writer,
+                "#define UC_{}_{} 0{:o}ULL",
+                id.to_uppercase(),
+                value.to_uppercase(),
+                n
+            )?;
+        }
+        if !field.values.is_empty() {
+            writeln!(writer)?;
+        }
+    }
+
+    writeln!(writer, "static const struct uc_cword uc_c_mem[] = {{")?;
+    for w in &ucode.c_mem.mem {
+        let bits = w.bits();
+        writeln!(
+            writer,
+            "    {{ 0{:05o}, 0{:022o}ULL, 0{:016o}ULL }},{}",
+            w.address,
+            bits as u64,
+            (bits >> 64) as u64,
+            annotation(ucode, w.address)
+        )?;
+    }
+    end_c_table(writer, "uc_c_mem", ucode.c_mem.mem.len())?;
+
+    for (name, mem) in &[("uc_a_mem", &ucode.a_mem), ("uc_b_mem", &ucode.b_mem)] {
+        writeln!(writer, "static const struct uc_abword {}[] = {{", name)?;
+        for w in &mem.mem {
+            writeln!(writer, "    {{ 0{:04o}, 0{:014o}ULL }},", w.address, w.data)?;
+        }
+        end_c_table(writer, name, mem.mem.len())?;
+    }
+
+    writeln!(writer, "static const uint8_t uc_type_map[] = {{")?;
+    for chunk in ucode.type_map.mem.chunks(16) {
+        let entries: Vec<String> = chunk.iter().map(|t| format!("0{:o}", t.data)).collect();
+        writeln!(writer, "    {},", entries.join(", "))?;
+    }
+    end_c_table(writer, "uc_type_map", ucode.type_map.mem.len())?;
+
+    writeln!(
+        writer,
+        "static const struct uc_picoword uc_pico_store[] = {{"
+    )?;
+    for w in &ucode.pico_store.mem {
+        writeln!(writer, "    {{ 0{:06o}, 0{:011o} }},", w.address, w.data)?;
+    }
+    end_c_table(writer, "uc_pico_store", ucode.pico_store.mem.len())
+}
+
+/// Write the image as source in the given language.
+pub fn write<W: Write>(ucode: &Microcode, lang: Lang, mut writer: W) -> io::Result<()> {
+    match lang {
+        Lang::Rust => write_rust(ucode, &mut writer),
+        Lang::C => write_c(ucode, &mut writer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ucode::{ABWord, CWord};
+
+    fn source(lang: Lang) -> String {
+        let mut ucode = Microcode::new();
+        ucode.c_mem.push(CWord::new(0o17, 0o7, 0o1_0000));
+        ucode.annotations.set_comment(0o17, Some("only word"));
+        ucode.b_mem.push(ABWord {
+            address: 3,
+            data: 0o42,
+        });
+        let mut out = Vec::new();
+        assert!(write(&ucode, lang, &mut out).is_ok());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn names_become_identifiers() {
+        assert_eq!(identifier("cdr-not-0"), Some("cdr_not_0".to_string()));
+        assert_eq!(identifier("x+y+1"), Some("x_plus_y_plus_1".to_string()));
+        assert_eq!(identifier("x-1"), Some("x_1".to_string()));
+        assert_eq!(identifier("0bad"), None);
+    }
+
+    #[test]
+    fn rust_source_has_tables_and_accessors() {
+        let source = source(Lang::Rust);
+        assert!(source
+            .contains("pub fn naf(word: u128) -> u64 {\n    ((word >> 80) as u64) & 0o37777\n}"));
+        assert!(source.contains("pub const SEQ_PUSHJ: u64 = 0o1;"));
+        assert!(source.contains(&format!(
+            "    (0o00017, 0o{:038o}), // only word",
+            (1u128 << 76) | 0o7
+        )));
+        assert!(source
+            .contains("pub const B_MEM: [(u16, u64); 1] = [\n    (0o0003, 0o00000000000042),"));
+    }
+
+    #[test]
+    fn c_source_splits_words() {
+        let source = source(Lang::C);
+        assert!(source.contains("    return (high >> 16) & 037777ULL;"));
+        assert!(source.contains("    return (low >> 62) & 03ULL;"));
+        assert!(source.contains(&format!(
+            "    {{ 000017, 0{:022o}ULL, 0{:016o}ULL }}, // only word",
+            0o7, 0o1_0000
+        )));
+        assert!(source.contains("    { 0 },\n};\n#define UC_A_MEM_COUNT 0\n"));
+    }
+}
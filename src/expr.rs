@@ -0,0 +1,424 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use layout;
+use radix;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use ucode::{control_address, Microcode};
+
+//
+// Address expressions.
+//
+// Commands take their addresses and counts as expressions:
+//
+//   1000  #x200  512.    numbers, in the current radix or their own
+//   start  foo+3  bar-1  labels and arithmetic
+//   .                    the current address
+//   ctos                 the top of the control stack: where the
+//                        routine that was followed into returns
+//   naf(.)  seq(foo+1)   a field of the word at an address
+//   (foo+bar)-2          grouping
+//
+// A range is two expressions joined by "..", and takes in both ends.
+// Labels may contain '-' and '.', so a name is read as the longest
+// label it starts with: "foo-3" is three before foo, unless there is
+// a label "foo-3".
+//
+
+/// What names in an expression refer to.
+pub struct Env<'a> {
+    pub ucode: &'a Microcode,
+    pub current: Option<u16>,
+    pub ctos: Option<u16>,
+}
+
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ExprError {
+    /// The expression stopped where an operand was expected.
+    Incomplete,
+    /// Text that could not be read, from where it starts.
+    Unexpected(String),
+    BadNumber(String),
+    UnknownName(String),
+    UnknownField(String),
+    NoCurrent,
+    NoCtos,
+    NoWord(u16),
+    OutOfRange(i64),
+    Negative(i64),
+    Backwards(u16, u16),
+}
+
+fn signed(value: i64) -> String {
+    if value < 0 {
+        format!("-{}", radix::number(value.unsigned_abs()))
+    } else {
+        radix::number(value as u64)
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExprError::Incomplete => write!(f, "The expression is incomplete"),
+            ExprError::Unexpected(ref text) => write!(f, "Unexpected '{}'", text),
+            ExprError::BadNumber(ref text) => {
+                write!(
+                    f,
+                    "'{}' is not a number in radix {}",
+                    text,
+                    radix::current()
+                )
+            }
+            ExprError::UnknownName(ref name) => write!(f, "Unknown label '{}'", name),
+            ExprError::UnknownField(ref name) => write!(f, "Unknown field '{}'", name),
+            ExprError::NoCurrent => write!(f, "There is no current address for '.'"),
+            ExprError::NoCtos => write!(f, "The control stack is empty; follow a call first"),
+            ExprError::NoWord(addr) => {
+                write!(f, "No control memory word at {}", radix::address(addr))
+            }
+            ExprError::OutOfRange(value) => {
+                write!(f, "{} is not a control memory address", signed(value))
+            }
+            ExprError::Negative(value) => write!(f, "Count {} is negative", signed(value)),
+            ExprError::Backwards(from, to) => write!(
+                f,
+                "The range {}..{} runs backwards",
+                radix::address(from),
+                radix::address(to)
+            ),
+        }
+    }
+}
+
+impl error::Error for ExprError {}
+
+struct Parser<'a, 'b> {
+    env: &'b Env<'a>,
+    text: &'b str,
+    pos: usize,
+}
+
+/// Characters that can follow the first letter of a name.
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-_.$%*".contains(c)
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn rest(&self) -> &'b str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_space(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn at_range(&mut self) -> bool {
+        self.skip_space();
+        self.rest().starts_with("..")
+    }
+
+    fn finish(&mut self) -> Result<(), ExprError> {
+        self.skip_space();
+        match self.rest() {
+            "" => Ok(()),
+            rest => Err(ExprError::Unexpected(rest.to_string())),
+        }
+    }
+
+    fn expr(&mut self) -> Result<i64, ExprError> {
+        let mut value = if self.eat("-") {
+            let value = self.term()?;
+            value.checked_neg().ok_or(ExprError::OutOfRange(value))?
+        } else {
+            self.term()?
+        };
+        while !self.at_range() {
+            if self.eat("+") {
+                value = value.saturating_add(self.term()?);
+            } else if self.eat("-") {
+                value = value.saturating_sub(self.term()?);
+            } else {
+                break;
+            }
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<i64, ExprError> {
+        self.skip_space();
+        let c = match self.rest().chars().next() {
+            Some(c) => c,
+            None => return Err(ExprError::Incomplete),
+        };
+        if c == '(' {
+            self.pos += 1;
+            let value = self.expr()?;
+            if !self.eat(")") {
+                return match self.rest() {
+                    "" => Err(ExprError::Incomplete),
+                    rest => Err(ExprError::Unexpected(rest.to_string())),
+                };
+            }
+            Ok(value)
+        } else if c == '.' && !self.at_range() {
+            self.pos += 1;
+            self.env.current.map(i64::from).ok_or(ExprError::NoCurrent)
+        } else if c.is_ascii_digit() || c == '#' {
+            self.number()
+        } else if c.is_ascii_alphabetic() {
+            self.name()
+        } else {
+            Err(ExprError::Unexpected(self.rest().to_string()))
+        }
+    }
+
+    fn number(&mut self) -> Result<i64, ExprError> {
+        let rest = self.rest();
+        let mut len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '#')
+            .unwrap_or(rest.len());
+        // A trailing point makes a number decimal, unless it starts a
+        // range.
+        if rest[len..].starts_with('.') && !rest[len..].starts_with("..") {
+            len += 1;
+        }
+        let token = &rest[..len];
+        self.pos += len;
+        match radix::parse_number(token).map(i64::try_from) {
+            Some(Ok(value)) => Ok(value),
+            _ => Err(ExprError::BadNumber(token.to_string())),
+        }
+    }
+
+    fn name(&mut self) -> Result<i64, ExprError> {
+        let rest = self.rest();
+        let run = &rest[..rest.find(|c| !is_name_char(c)).unwrap_or(rest.len())];
+
+        // A field of a word, as in naf(.).
+        if rest[run.len()..].starts_with('(') {
+            let layout = layout::current();
+            let field = layout
+                .field(run)
+                .ok_or_else(|| ExprError::UnknownField(run.to_string()))?;
+            self.pos += run.len() + 1;
+            let addr = to_address(self.expr()?)?;
+            if !self.eat(")") {
+                return Err(ExprError::Incomplete);
+            }
+            return match self.env.ucode.c_word(addr) {
+                Some(cword) => Ok(field.get(cword.bits()) as i64),
+                None => Err(ExprError::NoWord(addr)),
+            };
+        }
+
+        // The longest label or keyword the run starts with, ending
+        // before an operator.
+        let ends = run
+            .char_indices()
+            .filter(|&(_, c)| c == '-' || c == '.')
+            .map(|(i, _)| i)
+            .chain(Some(run.len()));
+        let mut ends: Vec<usize> = ends.collect();
+        ends.reverse();
+        for end in ends {
+            let name = &run[..end];
+            let value = if name == "ctos" {
+                Some(self.env.ctos.map(i64::from).ok_or(ExprError::NoCtos))
+            } else {
+                self.env
+                    .ucode
+                    .annotations
+                    .address_of(name)
+                    .map(|a| Ok(i64::from(a)))
+            };
+            if let Some(value) = value {
+                self.pos += end;
+                return value;
+            }
+        }
+
+        let end = run
+            .find('-')
+            .or_else(|| run.find(".."))
+            .unwrap_or(run.len());
+        Err(ExprError::UnknownName(run[..end].to_string()))
+    }
+}
+
+fn to_address(value: i64) -> Result<u16, ExprError> {
+    u64::try_from(value)
+        .ok()
+        .and_then(control_address)
+        .ok_or(ExprError::OutOfRange(value))
+}
+
+fn parser<'a, 'b>(env: &'b Env<'a>, text: &'b str) -> Parser<'a, 'b> {
+    Parser { env, text, pos: 0 }
+}
+
+/// The value of an expression.
+pub fn value(env: &Env, text: &str) -> Result<i64, ExprError> {
+    let mut parser = parser(env, text);
+    let value = parser.expr()?;
+    parser.finish()?;
+    Ok(value)
+}
+
+/// A control memory address.
+pub fn address(env: &Env, text: &str) -> Result<u16, ExprError> {
+    to_address(value(env, text)?)
+}
+
+/// A count, which cannot be negative.
+pub fn count(env: &Env, text: &str) -> Result<usize, ExprError> {
+    match value(env, text)? {
+        n if n < 0 => Err(ExprError::Negative(n)),
+        n => Ok(n as usize),
+    }
+}
+
+/// A range of addresses, as "from..to", or one address as a range of
+/// one.
+pub fn range(env: &Env, text: &str) -> Result<(u16, u16), ExprError> {
+    let mut parser = parser(env, text);
+    let from = to_address(parser.expr()?)?;
+    let to = if parser.eat("..") {
+        to_address(parser.expr()?)?
+    } else {
+        from
+    };
+    parser.finish()?;
+    if to < from {
+        return Err(ExprError::Backwards(from, to));
+    }
+    Ok((from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+
+    fn image() -> Microcode {
+        let mut ucode = asm::image(
+            "        .org 10\n\
+             foo:    npc-sel=next naf=bar\n\
+             .org 20\n\
+             bar:    npc-sel=next naf=foo\n",
+        );
+        assert!(ucode.annotations.set_label(0o10, Some("foo")).is_ok());
+        assert!(ucode.annotations.set_label(0o20, Some("bar")).is_ok());
+        assert!(ucode.annotations.set_label(0o30, Some("a-b")).is_ok());
+        ucode
+    }
+
+    #[test]
+    fn evaluates_label_arithmetic() {
+        let ucode = image();
+        let env = Env {
+            ucode: &ucode,
+            current: Some(0o20),
+            ctos: None,
+        };
+        assert_eq!(address(&env, "foo+3"), Ok(0o13));
+        assert_eq!(address(&env, "bar-1"), Ok(0o17));
+        assert_eq!(address(&env, "a-b"), Ok(0o30));
+        assert_eq!(address(&env, "a-b - (bar - foo)"), Ok(0o20));
+        assert_eq!(address(&env, "#x10 + 10."), Ok(26));
+        assert_eq!(address(&env, ". + 1"), Ok(0o21));
+        assert_eq!(address(&env, "naf(.)"), Ok(0o10));
+        assert_eq!(address(&env, "naf(naf(foo))"), Ok(0o10));
+        assert_eq!(count(&env, "bar-foo"), Ok(8));
+    }
+
+    #[test]
+    fn evaluates_ranges() {
+        let ucode = image();
+        let env = Env {
+            ucode: &ucode,
+            current: None,
+            ctos: Some(0o11),
+        };
+        assert_eq!(range(&env, "1000..1200"), Ok((0o1000, 0o1200)));
+        assert_eq!(range(&env, "foo..bar"), Ok((0o10, 0o20)));
+        assert_eq!(range(&env, "#d8..ctos"), Ok((8, 0o11)));
+        assert_eq!(range(&env, "ctos"), Ok((0o11, 0o11)));
+        assert_eq!(
+            range(&env, "bar..foo"),
+            Err(ExprError::Backwards(0o20, 0o10))
+        );
+    }
+
+    #[test]
+    fn explains_errors() {
+        let ucode = image();
+        let env = Env {
+            ucode: &ucode,
+            current: None,
+            ctos: None,
+        };
+        assert_eq!(address(&env, ""), Err(ExprError::Incomplete));
+        assert_eq!(address(&env, "foo+"), Err(ExprError::Incomplete));
+        assert_eq!(
+            address(&env, "baz-2"),
+            Err(ExprError::UnknownName("baz".to_string()))
+        );
+        assert_eq!(
+            address(&env, "19"),
+            Err(ExprError::BadNumber("19".to_string()))
+        );
+        assert_eq!(
+            address(&env, "foo)"),
+            Err(ExprError::Unexpected(")".to_string()))
+        );
+        assert_eq!(address(&env, "."), Err(ExprError::NoCurrent));
+        assert_eq!(address(&env, "ctos"), Err(ExprError::NoCtos));
+        assert_eq!(address(&env, "naf(11)"), Err(ExprError::NoWord(0o11)));
+        assert_eq!(
+            address(&env, "nav(foo)"),
+            Err(ExprError::UnknownField("nav".to_string()))
+        );
+        assert_eq!(address(&env, "foo-11"), Err(ExprError::OutOfRange(-1)));
+        assert_eq!(
+            address(&env, "-#x8000000000000000"),
+            Err(ExprError::BadNumber("#x8000000000000000".to_string()))
+        );
+        assert_eq!(
+            address(&env, "-(0 - #x7fffffffffffffff - 1)"),
+            Err(ExprError::OutOfRange(i64::MIN))
+        );
+        assert_eq!(count(&env, "foo-bar"), Err(ExprError::Negative(-8)));
+    }
+}
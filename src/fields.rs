@@ -0,0 +1,461 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+//
+// Typed microinstruction field values.
+//
+// Each field with a fixed set of meanings gets an enum, with a
+// variant for every code the field can hold. Codes the documentation
+// leaves unassigned or reserves are variants too, named after their
+// octal code, so that every word decodes to something and checks can
+// name what they found. Display and FromStr use the symbolic names
+// of docs/microword.toml.
+//
+
+/// A field value that can be written into a microinstruction.
+pub trait FieldValue: Copy {
+    /// The layout name of the field.
+    const FIELD: &'static str;
+
+    /// The value's code in the field.
+    fn code(self) -> u8;
+}
+
+/// A name that is not one of a field's values.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ParseFieldError {
+    pub field: &'static str,
+    pub name: String,
+}
+
+impl fmt::Display for ParseFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' is not a value of {}.", self.name, self.field)
+    }
+}
+
+impl error::Error for ParseFieldError {}
+
+macro_rules! field_enum {
+    (
+        $(#[$doc:meta])*
+        $name:ident: $field:expr;
+        $($variant:ident = $code:expr => $text:expr,)*
+    ) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[repr(u8)]
+        pub enum $name {
+            $($variant = $code,)*
+        }
+
+        impl $name {
+            /// Every value, in code order.
+            pub const ALL: &'static [$name] = &[$($name::$variant,)*];
+
+            /// The value with the given code. Bits above the field
+            /// are ignored, as the hardware would.
+            pub fn from_code(code: u8) -> $name {
+                $name::ALL[code as usize % $name::ALL.len()]
+            }
+
+            pub fn code(self) -> u8 {
+                self as u8
+            }
+
+            /// The symbolic name of the value.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($name::$variant => $text,)*
+                }
+            }
+
+            /// Whether the code is reserved or unassigned.
+            pub fn is_reserved(self) -> bool {
+                self.name().starts_with("reserved-")
+            }
+        }
+
+        impl From<u8> for $name {
+            fn from(code: u8) -> $name {
+                $name::from_code(code)
+            }
+        }
+
+        impl FieldValue for $name {
+            const FIELD: &'static str = $field;
+
+            fn code(self) -> u8 {
+                self as u8
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseFieldError;
+
+            fn from_str(s: &str) -> Result<$name, ParseFieldError> {
+                $name::ALL
+                    .iter()
+                    .find(|value| value.name() == s)
+                    .cloned()
+                    .ok_or_else(|| ParseFieldError {
+                        field: $field,
+                        name: s.to_string(),
+                    })
+            }
+        }
+    };
+}
+
+field_enum! {
+    /// U AMRA SEL: how U AMRA addresses A memory.
+    AmraSel: "amra-sel";
+    Immediate = 0 => "imm",
+    Lbus = 1 => "lbus",
+    Base = 2 => "base",
+    Other = 3 => "other",
+}
+
+field_enum! {
+    /// U ABUS SRC: the A bus source on the MC board, when U AMRA SEL
+    /// is 3 and U R BASE is 3.
+    AbusSource: "abus-src";
+    Md = 0 => "md",
+    OtherMd = 1 => "other-md",
+    Vma = 2 => "vma",
+    Epc = 3 => "epc",
+    MdAdvance = 4 => "md-advance",
+    PhtaAsn = 5 => "phta-asn",
+    Map = 6 => "map",
+    MapSelected = 7 => "map-0",
+}
+
+field_enum! {
+    /// U XYBUS SEL: which of the A and B buses drive X and Y.
+    XybusSel: "xybus-sel";
+    Ab = 0 => "ab",
+    Ba = 1 => "ba",
+}
+
+field_enum! {
+    /// U AMWA SEL: how U AMWA addresses A memory.
+    AmwaSel: "amwa-sel";
+    Immediate = 0 => "imm",
+    Base = 1 => "base",
+    Same = 2 => "same",
+    Lbus = 3 => "lbus",
+}
+
+field_enum! {
+    /// U SEQ: the sequencer function.
+    Seq: "seq";
+    None = 0 => "none",
+    Pushj = 1 => "pushj",
+    Dismiss = 2 => "dismiss",
+    Popj = 3 => "popj",
+}
+
+field_enum! {
+    /// U BMEM FROM XBUS: where B memory write data comes from.
+    BmemSource: "bmem-from-xbus";
+    Obus = 0 => "obus",
+    Xbus = 1 => "xbus",
+}
+
+field_enum! {
+    /// U MEM: the memory control function.
+    MemOp: "mem";
+    None = 0 => "none",
+    Udev = 1 => "udev",
+    Read = 2 => "read",
+    Write = 3 => "write",
+    IncrementVma = 4 => "inc-vma",
+    LoadVma = 5 => "load-vma",
+    BlockRead = 6 => "block-read",
+    BlockWrite = 7 => "block-write",
+}
+
+field_enum! {
+    /// U SPEC: the special function.
+    SpecialFunction: "spec";
+    RReg = 0o0 => "r",
+    SReg = 0o1 => "s",
+    Sp = 0o2 => "sp",
+    Fp = 0o3 => "fp",
+    Eb = 0o4 => "eb",
+    DpControl = 0o5 => "dp-control",
+    WriteMaps = 0o6 => "write-maps",
+    ClearStackOffset = 0o7 => "clear-stack-offset",
+    ArithTrap = 0o10 => "arith-trap",
+    TrapTypeMap = 0o11 => "trap-type",
+    TrapTypeMapFixnum = 0o12 => "trap-type-fixnum",
+    MultiplyCheck = 0o13 => "multiply-check",
+    Crocks = 0o14 => "crocks",
+    AlubSign = 0o15 => "alub-sign",
+    CrocksYbus = 0o16 => "crocks-ybus",
+    Multiply = 0o17 => "multiply",
+    None = 0o20 => "none",
+    AddrFromAbus = 0o21 => "addr-from-abus",
+    InhibitTags = 0o22 => "inhibit-tags",
+    Dma = 0o23 => "dma",
+    Phta = 0o24 => "phta",
+    CheckWrite = 0o25 => "check-write",
+    Reserved26 = 0o26 => "reserved-26",
+    IfuControl = 0o27 => "ifu",
+    ArithTrapDispatch = 0o30 => "arith-trap-dispatch",
+    Halt = 0o31 => "halt",
+    NpcMagic = 0o32 => "npc-magic",
+    AwakenTask = 0o33 => "awaken-task",
+    WriteTask = 0o34 => "write-task",
+    DisableTasking = 0o35 => "disable-tasking",
+    Reserved36 = 0o36 => "reserved-36",
+    Reserved37 = 0o37 => "reserved-37",
+}
+
+field_enum! {
+    /// U COND SEL: the condition tested.
+    CondSel: "cond-sel";
+    CdrNot0 = 0o0 => "cdr-not-0",
+    CdrNot1 = 0o1 => "cdr-not-1",
+    CdrNot2 = 0o2 => "cdr-not-2",
+    CdrNot3 = 0o3 => "cdr-not-3",
+    TypeMap = 0o4 => "type-map",
+    NotFixnum = 0o5 => "not-fixnum",
+    Alub0 = 0o6 => "alub-0",
+    Ybus31 = 0o7 => "ybus-31",
+    GcTemp = 0o10 => "gc-temp",
+    GcThisStack = 0o11 => "gc-this-stack",
+    GcOtherStack = 0o12 => "gc-other-stack",
+    AluZero = 0o13 => "alu-zero-28",
+    AluNonzero = 0o14 => "alu-nonzero-32",
+    AluNonzero34 = 0o15 => "alu-nonzero-34",
+    NotCarry28 = 0o16 => "not-carry-28",
+    NotCarry32 = 0o17 => "not-carry-32",
+    Alu31 = 0o20 => "alu-31",
+    SequenceBreak = 0o21 => "sequence-break",
+    Trace1 = 0o22 => "trace-1",
+    Trace2 = 0o23 => "trace-2",
+    LbusDevCond = 0o24 => "lbus-dev-cond",
+    McCond = 0o25 => "mc-cond",
+    Reserved26 = 0o26 => "reserved-26",
+    Reserved27 = 0o27 => "reserved-27",
+    Reserved30 = 0o30 => "reserved-30",
+    Reserved31 = 0o31 => "reserved-31",
+    Reserved32 = 0o32 => "reserved-32",
+    Reserved33 = 0o33 => "reserved-33",
+    Reserved34 = 0o34 => "reserved-34",
+    Reserved35 = 0o35 => "reserved-35",
+    Reserved36 = 0o36 => "reserved-36",
+    Reserved37 = 0o37 => "reserved-37",
+}
+
+field_enum! {
+    /// U COND FUNC: what the condition does.
+    CondFunc: "cond-func";
+    Ignore = 0 => "ignore",
+    Skip = 1 => "skip",
+    Trap = 2 => "trap",
+    TrapFalse = 3 => "trap-false",
+}
+
+field_enum! {
+    /// U ALU: the ALU function. The arithmetic trap special functions
+    /// can select a second set of functions with the same codes.
+    AluFunction: "alu";
+    X = 0o0 => "x",
+    Alub = 0o1 => "alub",
+    XPlus1 = 0o2 => "x+1",
+    XMinus1 = 0o3 => "x-1",
+    XPlusY = 0o4 => "x+y",
+    XMinusY = 0o5 => "x-y",
+    XPlusYPlus1 = 0o6 => "x+y+1",
+    XMinusYMinus1 = 0o7 => "x-y-1",
+    And = 0o10 => "and",
+    Ior = 0o11 => "ior",
+    Xor = 0o12 => "xor",
+    Reserved13 = 0o13 => "reserved-13",
+    Reserved14 = 0o14 => "reserved-14",
+    Reserved15 = 0o15 => "reserved-15",
+    Reserved16 = 0o16 => "reserved-16",
+    Reserved17 = 0o17 => "reserved-17",
+}
+
+field_enum! {
+    /// U BYTE F: the byte function of the shifter and masher.
+    ByteFunction: "byte-f";
+    Pass = 0 => "pass",
+    Weird = 1 => "weird",
+    Ldb = 2 => "ldb",
+    General = 3 => "general",
+}
+
+field_enum! {
+    /// U OBUS CDR: where the CDR code of the O bus comes from.
+    ObusCdr: "obus-cdr";
+    A = 0 => "a",
+    B = 1 => "b",
+    BLow = 2 => "b-low",
+    Reserved3 = 3 => "reserved-3",
+    Const0 = 4 => "const-0",
+    Const1 = 5 => "const-1",
+    Const2 = 6 => "const-2",
+    Const3 = 7 => "const-3",
+}
+
+field_enum! {
+    /// U OBUS HTYPE: where the high type bits of the O bus come from.
+    ObusHtype: "obus-htype";
+    A = 0 => "a",
+    B = 1 => "b",
+    BLow = 2 => "b-low",
+    Reserved3 = 3 => "reserved-3",
+    Const0 = 4 => "const-0",
+    Const1 = 5 => "const-1",
+    Const2 = 6 => "const-2",
+    Const3 = 7 => "const-3",
+}
+
+field_enum! {
+    /// U OBUS LTYPE SEL: where the low type bits of the O bus come
+    /// from.
+    LtypeSel: "obus-ltype-sel";
+    Magic = 0 => "magic",
+    Alu = 1 => "alu",
+}
+
+field_enum! {
+    /// U CPC SEL: where the next address comes from.
+    CpcSel: "cpc-sel";
+    Naf = 0 => "naf",
+    Ctos = 1 => "ctos",
+    Npc = 2 => "npc",
+    Reserved3 = 3 => "reserved-3",
+}
+
+field_enum! {
+    /// U NPC SEL: where the address after the next comes from.
+    NpcSel: "npc-sel";
+    Dispatch = 0 => "dispatch",
+    Next = 1 => "next",
+}
+
+/// The code of a named value of the named field, for the fields
+/// above.
+pub fn code_named(field: &str, name: &str) -> Option<u8> {
+    macro_rules! lookup {
+        ($($name:ident),*) => {
+            $(
+                if field == <$name as FieldValue>::FIELD {
+                    return name.parse::<$name>().ok().map($name::code);
+                }
+            )*
+        };
+    }
+
+    lookup!(
+        AmraSel,
+        AbusSource,
+        XybusSel,
+        AmwaSel,
+        Seq,
+        BmemSource,
+        MemOp,
+        SpecialFunction,
+        CondSel,
+        CondFunc,
+        AluFunction,
+        ByteFunction,
+        ObusCdr,
+        ObusHtype,
+        LtypeSel,
+        CpcSel,
+        NpcSel
+    );
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use layout::Layout;
+
+    fn agrees<V: FieldValue + fmt::Display>(all: &[V]) {
+        let layout = Layout::builtin();
+        let field = layout.field(V::FIELD).unwrap();
+        assert_eq!(all.len() as u64, 1 << field.width, "{}", V::FIELD);
+        for (code, value) in all.iter().enumerate() {
+            assert_eq!(FieldValue::code(*value) as usize, code);
+            let name = value.to_string();
+            if !field.values.is_empty() && !name.starts_with("reserved-") {
+                assert_eq!(field.value_named(&name), Some(code as u64), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn enums_agree_with_the_layout() {
+        agrees(AmraSel::ALL);
+        agrees(AbusSource::ALL);
+        agrees(XybusSel::ALL);
+        agrees(AmwaSel::ALL);
+        agrees(Seq::ALL);
+        agrees(BmemSource::ALL);
+        agrees(MemOp::ALL);
+        agrees(SpecialFunction::ALL);
+        agrees(CondSel::ALL);
+        agrees(CondFunc::ALL);
+        agrees(AluFunction::ALL);
+        agrees(ByteFunction::ALL);
+        agrees(ObusCdr::ALL);
+        agrees(ObusHtype::ALL);
+        agrees(LtypeSel::ALL);
+        agrees(CpcSel::ALL);
+        agrees(NpcSel::ALL);
+    }
+
+    #[test]
+    fn parses_and_prints_names() {
+        assert_eq!("halt".parse::<SpecialFunction>(), Ok(SpecialFunction::Halt));
+        assert_eq!(
+            SpecialFunction::from_code(0o26),
+            SpecialFunction::Reserved26
+        );
+        assert!(SpecialFunction::Reserved26.is_reserved());
+        assert_eq!(MemOp::BlockRead.to_string(), "block-read");
+        assert_eq!(Seq::from_code(1 | 4), Seq::Pushj);
+        assert_eq!(
+            "jump".parse::<Seq>().unwrap_err().to_string(),
+            "'jump' is not a value of seq."
+        );
+        assert_eq!(code_named("cond-sel", "reserved-37"), Some(0o37));
+        assert_eq!(code_named("naf", "foo"), None);
+    }
+}
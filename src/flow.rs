@@ -0,0 +1,532 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use fields::{AmraSel, AmwaSel, CondFunc, CpcSel, NpcSel, Seq, SpecialFunction};
+use sim::{DISPATCH_MASK, SKIP_BIT};
+use ucode::{MicroInstruction, Microcode, ADDRESS_MASK, B_MEM_IMMEDIATES, B_MEM_SCRATCHPAD};
+
+//
+// Static control flow and cross references.
+//
+// The successors of a control memory word are worked out from its
+// fields alone, the way the sequencer in sim.rs would choose them.
+// Anything that depends on machine state is either enumerated (skips
+// and dispatches) or left out (returns through CTOS and jumps through
+// NPC, whose targets were chosen by an earlier instruction).
+//
+// Routines are found by starting at every call, trap and dispatch
+// target, every word nothing else leads to and every word marked as
+// an entry point, and following jumps and skips until another
+// routine's entry is reached.
+//
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// CPC from NAF.
+    Jump,
+    /// CPC from NAF with the skip bit set by a false condition.
+    Skip,
+    /// CPC from NAF with a Pushj.
+    Call,
+    /// NPC from NAF with dispatch bits.
+    Dispatch,
+    /// A trap to NAF, or with dispatch, to NAF with dispatch bits.
+    Trap,
+}
+
+impl EdgeKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Jump => "jump",
+            EdgeKind::Skip => "skip",
+            EdgeKind::Call => "call",
+            EdgeKind::Dispatch => "dispatch",
+            EdgeKind::Trap => "trap",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: u16,
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+/// Whether a word can trap, through U COND FUNC or a trap special
+/// function.
+pub fn can_trap(u: &MicroInstruction) -> bool {
+    match u.spec() {
+        SpecialFunction::ArithTrap
+        | SpecialFunction::TrapTypeMap
+        | SpecialFunction::TrapTypeMapFixnum
+        | SpecialFunction::ArithTrapDispatch => true,
+        _ => u.cond_func() == CondFunc::Trap || u.cond_func() == CondFunc::TrapFalse,
+    }
+}
+
+/// The statically known successors of the word at `from`.
+pub fn edges(from: u16, u: &MicroInstruction) -> Vec<Edge> {
+    let naf = u.naf() & ADDRESS_MASK;
+    let mut edges = Vec::new();
+    let mut edge = |to: u16, kind: EdgeKind| edges.push(Edge { from, to, kind });
+
+    if u.spec() == SpecialFunction::ArithTrapDispatch {
+        // The buses supply the dispatch bits of the trap address.
+        for d in 0..16 {
+            edge((naf & !DISPATCH_MASK) | (d << 8), EdgeKind::Trap);
+        }
+    } else if can_trap(u) {
+        edge(naf, EdgeKind::Trap);
+    }
+
+    // CPC SEL 3 is not used, and the simulator treats it as NAF.
+    if u.cpc_sel() == CpcSel::Naf || u.cpc_sel() == CpcSel::Reserved3 {
+        let kind = if u.seq() == Seq::Pushj {
+            EdgeKind::Call
+        } else {
+            EdgeKind::Jump
+        };
+        if u.cond_func() == CondFunc::Skip {
+            edge(naf & !SKIP_BIT, kind);
+            edge(naf | SKIP_BIT, EdgeKind::Skip);
+        } else {
+            edge(naf, kind);
+        }
+    }
+
+    if u.npc_sel() == NpcSel::Dispatch && u.spec() != SpecialFunction::NpcMagic {
+        for d in 0..16 {
+            edge((naf & !DISPATCH_MASK) | (d << 8), EdgeKind::Dispatch);
+        }
+    }
+
+    edges
+}
+
+/// The successors of a word in an image, in order. Dispatch and trap
+/// dispatch tables are sparse, so only their populated targets are
+/// kept.
+pub fn image_edges(ucode: &Microcode, addr: u16) -> Vec<Edge> {
+    match ucode.c_word(addr) {
+        Some(cword) => populated(edges(addr, &MicroInstruction::new(cword)), |a| {
+            ucode.c_word(a).is_some()
+        }),
+        None => Vec::new(),
+    }
+}
+
+fn populated<F: Fn(u16) -> bool>(mut edges: Vec<Edge>, is_word: F) -> Vec<Edge> {
+    let trap_dispatch = edges.iter().filter(|e| e.kind == EdgeKind::Trap).count() > 1;
+    edges.retain(|e| match e.kind {
+        EdgeKind::Dispatch => is_word(e.to),
+        EdgeKind::Trap if trap_dispatch => is_word(e.to),
+        _ => true,
+    });
+    edges.sort();
+    edges.dedup();
+    edges
+}
+
+//
+// Cycle by cycle.
+//
+// Edges say where control can get to. Where it goes in the very next
+// cycle also depends on NPC, which the word before loaded, so walking
+// the image a cycle at a time carries NPC along as the sequencer does.
+//
+
+/// What NPC holds, as far as can be told without running the image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Npc {
+    /// The address after the next instruction.
+    Next(u16),
+    /// A dispatch by the word at this address.
+    Dispatch(u16),
+    /// Loaded by NPC magic, or not known.
+    Unknown,
+}
+
+/// What NPC holds after `u`, at `addr`, runs and goes to `next`.
+pub fn npc_after(addr: u16, u: &MicroInstruction, next: u16) -> Npc {
+    if u.spec() == SpecialFunction::NpcMagic {
+        Npc::Unknown
+    } else if u.npc_sel() == NpcSel::Dispatch {
+        Npc::Dispatch(addr)
+    } else {
+        Npc::Next(next.wrapping_add(1) & ADDRESS_MASK)
+    }
+}
+
+/// The populated addresses the dispatch by the word at `addr` can
+/// reach.
+pub fn dispatch_targets(ucode: &Microcode, addr: u16) -> Vec<u16> {
+    let naf = ucode.c_word(addr).map_or(0, |w| w.decode().naf()) & ADDRESS_MASK;
+    (0..16)
+        .map(|d| (naf & !DISPATCH_MASK) | (d << 8))
+        .filter(|&a| ucode.c_word(a).is_some())
+        .collect()
+}
+
+/// Where `u`, at `addr`, can go in the next cycle given what NPC
+/// holds, with what it leaves in NPC. Returns through CTOS, jumps
+/// through an unknown NPC and traps are left out.
+pub fn next_cycles(
+    ucode: &Microcode,
+    addr: u16,
+    u: &MicroInstruction,
+    npc: Npc,
+) -> Vec<(u16, Npc)> {
+    let mut next = match u.cpc_sel() {
+        CpcSel::Naf | CpcSel::Reserved3 => vec![u.naf() & ADDRESS_MASK],
+        CpcSel::Npc => match npc {
+            Npc::Next(a) => vec![a],
+            Npc::Dispatch(from) => dispatch_targets(ucode, from),
+            Npc::Unknown => Vec::new(),
+        },
+        CpcSel::Ctos => Vec::new(),
+    };
+    if u.cond_func() == CondFunc::Skip {
+        next = next
+            .iter()
+            .flat_map(|&a| vec![a & !SKIP_BIT, a | SKIP_BIT])
+            .collect();
+    }
+    next.sort();
+    next.dedup();
+    next.into_iter()
+        .filter(|&a| ucode.c_word(a).is_some())
+        .map(|a| (a, npc_after(addr, u, a)))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// The A-memory and B-memory locations a word names directly, as
+/// (address, access). Base-relative and bus addressing are left out.
+pub fn a_refs(u: &MicroInstruction) -> Vec<(u16, Access)> {
+    let mut refs = Vec::new();
+    if u.amra_sel() == AmraSel::Immediate {
+        refs.push((u.amra() & 0xfff, Access::Read));
+    }
+    match u.amwa_sel() {
+        AmwaSel::Immediate => refs.push((u.amwa() & 0xfff, Access::Write)),
+        AmwaSel::Same if u.amra_sel() == AmraSel::Immediate => {
+            refs.push((u.amra() & 0xfff, Access::Write))
+        }
+        _ => {}
+    }
+    refs
+}
+
+pub fn b_refs(u: &MicroInstruction) -> Vec<(u16, Access)> {
+    let mut refs = Vec::new();
+    if u16::from(u.bmra()) >= B_MEM_IMMEDIATES {
+        refs.push((u16::from(u.bmra()), Access::Read));
+    }
+    // Every word writes the scratchpad at 360-377; only the extended
+    // writes name a location worth listing.
+    let write = u.b_write_address();
+    if write < B_MEM_SCRATCHPAD {
+        refs.push((write, Access::Write));
+    }
+    refs
+}
+
+pub struct Routine {
+    pub entry: u16,
+    /// The words of the routine, in address order.
+    pub words: Vec<u16>,
+}
+
+pub struct Flow {
+    pub routines: Vec<Routine>,
+    successors: BTreeMap<u16, Vec<Edge>>,
+    predecessors: BTreeMap<u16, Vec<Edge>>,
+    routine_of: BTreeMap<u16, usize>,
+    a_xrefs: BTreeMap<u16, Vec<(u16, Access)>>,
+    b_xrefs: BTreeMap<u16, Vec<(u16, Access)>>,
+}
+
+impl Flow {
+    pub fn new(ucode: &Microcode) -> Flow {
+        let words: BTreeSet<u16> = ucode.c_mem.mem.iter().map(|w| w.address).collect();
+        let mut successors: BTreeMap<u16, Vec<Edge>> = BTreeMap::new();
+        let mut predecessors: BTreeMap<u16, Vec<Edge>> = BTreeMap::new();
+        let mut a_xrefs: BTreeMap<u16, Vec<(u16, Access)>> = BTreeMap::new();
+        let mut b_xrefs: BTreeMap<u16, Vec<(u16, Access)>> = BTreeMap::new();
+
+        for cword in &ucode.c_mem.mem {
+            let u = MicroInstruction::new(cword);
+            let out = populated(edges(cword.address, &u), |a| words.contains(&a));
+            for e in &out {
+                predecessors.entry(e.to).or_default().push(*e);
+            }
+            successors.insert(cword.address, out);
+
+            for (addr, access) in a_refs(&u) {
+                a_xrefs
+                    .entry(addr)
+                    .or_default()
+                    .push((cword.address, access));
+            }
+            for (addr, access) in b_refs(&u) {
+                b_xrefs
+                    .entry(addr)
+                    .or_default()
+                    .push((cword.address, access));
+            }
+        }
+
+        let mut entries = BTreeSet::new();
+        for (to, edges) in &predecessors {
+            if words.contains(to) && edges.iter().any(|e| e.kind >= EdgeKind::Call) {
+                entries.insert(*to);
+            }
+        }
+        for addr in &words {
+            if !predecessors.contains_key(addr) || ucode.annotations.entries.contains(addr) {
+                entries.insert(*addr);
+            }
+        }
+
+        let mut flow = Flow {
+            routines: Vec::new(),
+            successors,
+            predecessors,
+            routine_of: BTreeMap::new(),
+            a_xrefs,
+            b_xrefs,
+        };
+
+        for entry in &entries {
+            flow.add_routine(*entry, &entries);
+        }
+        // Whatever is left only loops among itself.
+        while let Some(addr) = words.iter().find(|a| !flow.routine_of.contains_key(a)) {
+            flow.add_routine(*addr, &entries);
+        }
+
+        flow.routines.sort_by_key(|r| r.entry);
+        for (i, routine) in flow.routines.iter().enumerate() {
+            for addr in &routine.words {
+                flow.routine_of.insert(*addr, i);
+            }
+        }
+
+        flow
+    }
+
+    fn add_routine(&mut self, entry: u16, entries: &BTreeSet<u16>) {
+        let index = self.routines.len();
+        let mut words = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(entry);
+        self.routine_of.insert(entry, index);
+
+        while let Some(addr) = queue.pop_front() {
+            words.push(addr);
+            for e in self.successors(addr).to_vec() {
+                let within = e.kind == EdgeKind::Jump || e.kind == EdgeKind::Skip;
+                if within
+                    && self.successors.contains_key(&e.to)
+                    && !entries.contains(&e.to)
+                    && !self.routine_of.contains_key(&e.to)
+                {
+                    self.routine_of.insert(e.to, index);
+                    queue.push_back(e.to);
+                }
+            }
+        }
+
+        words.sort();
+        self.routines.push(Routine { entry, words });
+    }
+
+    /// The statically known successors of a word.
+    pub fn successors(&self, addr: u16) -> &[Edge] {
+        self.successors.get(&addr).map_or(&[], |v| &v[..])
+    }
+
+    /// The words that lead to a word, in address order.
+    pub fn predecessors(&self, addr: u16) -> &[Edge] {
+        self.predecessors.get(&addr).map_or(&[], |v| &v[..])
+    }
+
+    /// The words that can run just before `addr`, with what each
+    /// leaves in NPC. Words that reach `addr` only by way of NPC or the
+    /// control stack are not known statically, and are not found.
+    pub fn npc_before(&self, ucode: &Microcode, addr: u16) -> Vec<(u16, Npc)> {
+        self.predecessors(addr)
+            .iter()
+            .filter(|e| e.kind != EdgeKind::Trap && e.kind != EdgeKind::Dispatch)
+            .filter_map(|e| {
+                ucode
+                    .c_word(e.from)
+                    .map(|w| (e.from, npc_after(e.from, &w.decode(), addr)))
+            })
+            .collect()
+    }
+
+    /// The routine a word belongs to.
+    pub fn routine(&self, addr: u16) -> Option<&Routine> {
+        self.routine_of.get(&addr).map(|i| &self.routines[*i])
+    }
+
+    /// The words that name an A-memory location.
+    pub fn a_xrefs(&self, addr: u16) -> &[(u16, Access)] {
+        self.a_xrefs.get(&addr).map_or(&[], |v| &v[..])
+    }
+
+    /// The words that name a B-memory location.
+    pub fn b_xrefs(&self, addr: u16) -> &[(u16, Access)] {
+        self.b_xrefs.get(&addr).map_or(&[], |v| &v[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::image;
+
+    fn targets(flow: &Flow, addr: u16) -> Vec<(u16, EdgeKind)> {
+        flow.successors(addr)
+            .iter()
+            .map(|e| (e.to, e.kind))
+            .collect()
+    }
+
+    #[test]
+    fn follows_jumps_skips_calls_and_traps() {
+        let flow = Flow::new(&image(
+            "        .org 10\n\
+             start:  npc-sel=next naf=skip\n\
+             skip:   npc-sel=next cond-func=skip naf=sub\n\
+             sub:    npc-sel=next seq=pushj naf=trap\n\
+             trap:   npc-sel=next cond-func=trap cpc-sel=ctos naf=start\n",
+        ));
+        assert_eq!(targets(&flow, 0o10), vec![(0o11, EdgeKind::Jump)]);
+        assert_eq!(
+            targets(&flow, 0o11),
+            vec![(0o12, EdgeKind::Jump), (0o10012, EdgeKind::Skip)]
+        );
+        assert_eq!(targets(&flow, 0o12), vec![(0o13, EdgeKind::Call)]);
+        assert_eq!(targets(&flow, 0o13), vec![(0o10, EdgeKind::Trap)]);
+        assert_eq!(flow.predecessors(0o13)[0].from, 0o12);
+    }
+
+    #[test]
+    fn keeps_populated_dispatch_targets() {
+        let flow = Flow::new(&image(
+            "        .org 100\n\
+             disp:   npc-sel=dispatch naf=1100\n\
+             .org 1100\n\
+             d0:     npc-sel=next naf=1100\n\
+             .org 3100\n\
+             d6:     npc-sel=next naf=3100\n",
+        ));
+        let dispatches: Vec<u16> = flow
+            .successors(0o100)
+            .iter()
+            .filter(|e| e.kind == EdgeKind::Dispatch)
+            .map(|e| e.to)
+            .collect();
+        assert_eq!(dispatches, vec![0o100, 0o1100, 0o3100]);
+    }
+
+    #[test]
+    fn arith_trap_dispatch_traps_through_a_table() {
+        let u = image("spec=arith-trap-dispatch npc-sel=next naf=300\n")
+            .c_word(0)
+            .unwrap()
+            .decode();
+        let traps: Vec<u16> = edges(0, &u)
+            .iter()
+            .filter(|e| e.kind == EdgeKind::Trap)
+            .map(|e| e.to)
+            .collect();
+        assert_eq!(traps.len(), 16);
+        assert_eq!((traps[0], traps[15]), (0o300, 0o7700));
+
+        let flow = Flow::new(&image(
+            "        .org 20\n\
+             arith:  spec=arith-trap-dispatch npc-sel=next naf=1100\n\
+             .org 1100\n\
+             t0:     npc-sel=next naf=1100\n\
+             .org 5100\n\
+             t10:    npc-sel=next naf=5100\n",
+        ));
+        assert_eq!(
+            targets(&flow, 0o20),
+            vec![
+                (0o1100, EdgeKind::Jump),
+                (0o1100, EdgeKind::Trap),
+                (0o5100, EdgeKind::Trap)
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_words_into_routines() {
+        let flow = Flow::new(&image(
+            "        .org 20\n\
+             main:   npc-sel=next seq=pushj naf=sub\n\
+             back:   npc-sel=next naf=back\n\
+             sub:    npc-sel=next naf=sub2\n\
+             sub2:   npc-sel=next cpc-sel=ctos seq=popj\n",
+        ));
+        let entries: Vec<u16> = flow.routines.iter().map(|r| r.entry).collect();
+        assert_eq!(entries, vec![0o20, 0o21, 0o22]);
+        assert_eq!(flow.routine(0o23).map(|r| r.entry), Some(0o22));
+        assert_eq!(flow.routine(0o22).unwrap().words, vec![0o22, 0o23]);
+    }
+
+    #[test]
+    fn marked_entries_start_routines() {
+        let mut ucode = image(
+            "        .org 20\n\
+             one:    npc-sel=next naf=two\n\
+             two:    npc-sel=next naf=three\n\
+             three:  npc-sel=next cpc-sel=ctos seq=popj\n",
+        );
+        assert_eq!(Flow::new(&ucode).routines.len(), 1);
+        ucode.annotations.set_entry(0o21, true);
+        let flow = Flow::new(&ucode);
+        assert_eq!(flow.routines.len(), 2);
+        assert_eq!(flow.routine(0o22).map(|r| r.entry), Some(0o21));
+    }
+
+    #[test]
+    fn cross_references_memories() {
+        let flow = Flow::new(&image(
+            "amra-sel=imm amra=123 amwa-sel=imm amwa=456 bmra=42 npc-sel=next\n",
+        ));
+        assert_eq!(flow.a_xrefs(0o123), &[(0, Access::Read)]);
+        assert_eq!(flow.a_xrefs(0o456), &[(0, Access::Write)]);
+        assert_eq!(flow.b_xrefs(0o42), &[(0, Access::Read)]);
+        assert!(flow.b_xrefs(0o3).is_empty());
+    }
+}
@@ -0,0 +1,769 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::HashSet;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use sim::{MainMemory, SimError, Simulator, StepResult, A_MEM_SIZE, B_MEM_SIZE};
+use ucode::{control_address, ADDRESS_MASK};
+
+//
+// GDB Remote Serial Protocol stub.
+//
+// The program counter is CPC, so breakpoints are set on control
+// memory addresses. A-memory, B-memory and main memory are mapped
+// into the debugger's address space as 8-byte little-endian words:
+//
+//   A-memory     0x01000000 + 8 * address
+//   B-memory     0x02000000 + 8 * address
+//   Main memory  0x100000000 + 8 * address
+//
+
+pub const A_MEM_BASE: u64 = 0x0100_0000;
+pub const B_MEM_BASE: u64 = 0x0200_0000;
+pub const MAIN_MEM_BASE: u64 = 0x1_0000_0000;
+const MAIN_MEM_WORDS: u64 = 0x1000_0000;
+
+/// The largest packet the stub takes. A memory read replies with two
+/// hex digits a byte, so it reads at most half this many bytes.
+const PACKET_SIZE: u64 = 0x4000;
+
+/// How many instructions to run between checks for an interrupt.
+const INTERRUPT_POLL: u64 = 4096;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Registers in the order GDB sees them.
+const REGISTERS: [&str; 16] = [
+    "cpc",
+    "npc",
+    "csp",
+    "ctos",
+    "sp",
+    "fp",
+    "eb",
+    "stack_base",
+    "vma",
+    "md",
+    "other_md",
+    "pc",
+    "inst",
+    "r",
+    "s",
+    "cycles",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+struct Watchpoint {
+    kind: WatchKind,
+    start: u16,
+    end: u16,
+}
+
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+}
+
+/// Breakpoint and watchpoint state for one debugging session.
+pub struct GdbStub {
+    breakpoints: HashSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub {
+            breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// Handle one packet, without the framing.
+    fn handle<M: MainMemory>(&mut self, sim: &mut Simulator<M>, packet: &str) -> Action {
+        let (command, args) = packet.split_at(if packet.is_empty() { 0 } else { 1 });
+
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => REGISTERS
+                .iter()
+                .enumerate()
+                .map(|(n, _)| hex_word(read_register(sim, n)))
+                .collect(),
+            "G" => {
+                for n in 0..REGISTERS.len() {
+                    if let Some(value) = args.get(n * 16..n * 16 + 16).and_then(parse_word) {
+                        write_register(sim, n, value);
+                    }
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTERS.len() => hex_word(read_register(sim, n)),
+                _ => "E01".to_string(),
+            },
+            "P" => match parse_assignment(args) {
+                Some((n, value)) if n < REGISTERS.len() => {
+                    write_register(sim, n, value);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "m" => match parse_range(args) {
+                Some((addr, len)) => {
+                    let mut data = String::new();
+                    for i in 0..len.min(PACKET_SIZE / 2) {
+                        match addr.checked_add(i).and_then(|a| read_byte(sim, a)) {
+                            Some(b) => data.push_str(&format!("{:02x}", b)),
+                            None if i == 0 => return Action::Reply("E01".to_string()),
+                            None => break,
+                        }
+                    }
+                    data
+                }
+                None => "E01".to_string(),
+            },
+            "M" => match args.find(':') {
+                Some(colon) => match (parse_range(&args[..colon]), parse_bytes(&args[colon + 1..]))
+                {
+                    (Some((addr, _)), Some(bytes)) => {
+                        // Write nothing unless every byte has somewhere
+                        // to go.
+                        let addrs: Option<Vec<u64>> = (0..bytes.len() as u64)
+                            .map(|i| addr.checked_add(i).filter(|&a| locate(a).is_some()))
+                            .collect();
+                        match addrs {
+                            Some(addrs) => {
+                                for (&a, &b) in addrs.iter().zip(&bytes) {
+                                    write_byte(sim, a, b);
+                                }
+                                "OK".to_string()
+                            }
+                            None => "E01".to_string(),
+                        }
+                    }
+                    _ => "E01".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            "s" | "c" => {
+                if let Ok(addr) = u64::from_str_radix(args, 16) {
+                    match control_address(addr) {
+                        Some(addr) => sim.jump(addr),
+                        None => return Action::Reply("E01".to_string()),
+                    }
+                }
+                return Action::Resume {
+                    step: command == "s",
+                };
+            }
+            "Z" | "z" => self.set_point(command == "Z", args),
+            "D" => return Action::Detach,
+            "k" => return Action::Detach,
+            "H" | "T" => "OK".to_string(),
+            "q" => self.query(args),
+            _ => String::new(),
+        };
+
+        Action::Reply(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            )
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if args == "C" {
+            "QC1".to_string()
+        } else if args == "fThreadInfo" {
+            "m1".to_string()
+        } else if args == "sThreadInfo" {
+            "l".to_string()
+        } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_range(range) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let marker = if end == xml.len() { "l" } else { "m" };
+                    format!("{}{}", marker, &xml[start..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    /// Insert or remove a breakpoint or watchpoint.
+    fn set_point(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+        let len = parts
+            .next()
+            .and_then(|l| u64::from_str_radix(l, 16).ok())
+            .unwrap_or(1);
+
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return "E01".to_string(),
+        };
+
+        let watch = match kind {
+            Some("0") | Some("1") => {
+                let addr = match control_address(addr) {
+                    Some(addr) => addr,
+                    None => return "E01".to_string(),
+                };
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".to_string();
+            }
+            Some("2") => WatchKind::Write,
+            Some("3") => WatchKind::Read,
+            Some("4") => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        // Only A-memory can be watched.
+        let a_end = A_MEM_BASE + 8 * A_MEM_SIZE as u64;
+        if !(A_MEM_BASE..a_end).contains(&addr) {
+            return "E01".to_string();
+        }
+        let start = ((addr - A_MEM_BASE) / 8) as u16;
+        let end = (addr.saturating_add(len.max(1) - 1).min(a_end - 1) - A_MEM_BASE) as u16 / 8;
+
+        if insert {
+            self.watchpoints.push(Watchpoint {
+                kind: watch,
+                start,
+                end,
+            });
+        } else {
+            self.watchpoints
+                .retain(|w| !(w.kind == watch && w.start == start && w.end == end));
+        }
+
+        "OK".to_string()
+    }
+
+    /// The watchpoint hit by the last instruction, if any, as a stop
+    /// reply fragment.
+    fn watch_hit<M: MainMemory>(&self, sim: &Simulator<M>) -> Option<String> {
+        let hit = |addr: Option<u16>, w: &Watchpoint| match addr {
+            Some(a) => a >= w.start && a <= w.end,
+            None => false,
+        };
+
+        for w in &self.watchpoints {
+            let (name, addr) = match w.kind {
+                WatchKind::Write if hit(sim.last_a_write, w) => ("watch", sim.last_a_write),
+                WatchKind::Read if hit(sim.last_a_read, w) => ("rwatch", sim.last_a_read),
+                WatchKind::Access if hit(sim.last_a_write, w) => ("awatch", sim.last_a_write),
+                WatchKind::Access if hit(sim.last_a_read, w) => ("awatch", sim.last_a_read),
+                _ => continue,
+            };
+            let addr = A_MEM_BASE + 8 * addr.unwrap_or(0) as u64;
+            return Some(format!("{}:{:x};", name, addr));
+        }
+
+        None
+    }
+
+    /// Run the machine until something stops it, and return the stop
+    /// reply. `interrupted` is polled now and then while running.
+    fn resume<M: MainMemory, F: FnMut() -> bool>(
+        &self,
+        sim: &mut Simulator<M>,
+        step: bool,
+        mut interrupted: F,
+    ) -> String {
+        let mut count: u64 = 0;
+
+        loop {
+            match sim.step() {
+                Ok(StepResult::Halted) => return format!("S{:02x}", SIGTRAP),
+                Ok(_) => {}
+                Err(SimError::NoInstruction(_)) => return format!("S{:02x}", SIGSEGV),
+            }
+
+            if let Some(watch) = self.watch_hit(sim) {
+                return format!("T{:02x}{}", SIGTRAP, watch);
+            }
+
+            if step {
+                return format!("S{:02x}", SIGTRAP);
+            }
+
+            if self.breakpoints.contains(&sim.cpc) {
+                return format!("T{:02x}swbreak:;", SIGTRAP);
+            }
+
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_POLL) && interrupted() {
+                return format!("S{:02x}", SIGINT);
+            }
+        }
+    }
+}
+
+impl Default for GdbStub {
+    fn default() -> GdbStub {
+        GdbStub::new()
+    }
+}
+
+/// How a debugging session is going, as `serve` reports it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    /// Waiting for a debugger at this address.
+    Listening(SocketAddr),
+    /// A debugger connected from this address.
+    Connected(SocketAddr),
+    /// The debugger detached or closed the connection.
+    Detached,
+}
+
+/// Wait for a debugger on a local TCP port and serve it until it
+/// detaches. `status` is told when the stub is listening and when
+/// the debugger comes and goes.
+pub fn serve<M: MainMemory, F: FnMut(Status)>(
+    sim: &mut Simulator<M>,
+    port: u16,
+    mut status: F,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    status(Status::Listening(listener.local_addr()?));
+
+    let (mut stream, peer) = listener.accept()?;
+    status(Status::Connected(peer));
+
+    let mut stub = GdbStub::new();
+
+    while let Some(packet) = read_packet(&mut stream)? {
+        let reply = match stub.handle(sim, &packet) {
+            Action::Reply(reply) => reply,
+            Action::Resume { step } => {
+                let poll = stream.try_clone()?;
+                stub.resume(sim, step, || poll_interrupt(&poll))
+            }
+            Action::Detach => {
+                write_packet(&mut stream, "OK")?;
+                break;
+            }
+        };
+        write_packet(&mut stream, &reply)?;
+    }
+
+    status(Status::Detached);
+    Ok(())
+}
+
+//
+// Packet framing
+//
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+/// Read the next packet, acknowledging it. Returns None when the
+/// connection closes.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut byte = [0; 1];
+
+    loop {
+        // Skip acknowledgements and anything else before the start.
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => break,
+                0x03 => return Ok(Some("?".to_string())),
+                _ => {}
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+
+        let data = String::from_utf8_lossy(&data).to_string();
+        let expected = u8::from_str_radix(&String::from_utf8_lossy(&sum), 16).ok();
+
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
+
+/// Check for a ^C from the debugger without blocking.
+fn poll_interrupt(stream: &TcpStream) -> bool {
+    let mut byte = [0; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let result = (&*stream).read(&mut byte);
+    let _ = stream.set_nonblocking(false);
+    match result {
+        Ok(1) => byte[0] == 0x03,
+        _ => false,
+    }
+}
+
+//
+// Registers and memory
+//
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\">\
+         <feature name=\"org.symbolics.3600.sequencer\">",
+    );
+    for (n, name) in REGISTERS.iter().enumerate() {
+        let kind = if n == 0 { " type=\"code_ptr\"" } else { "" };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"64\" regnum=\"{}\"{}/>",
+            name, n, kind
+        ));
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+fn read_register<M: MainMemory>(sim: &Simulator<M>, n: usize) -> u64 {
+    match n {
+        0 => sim.cpc as u64,
+        1 => sim.npc as u64,
+        2 => sim.csp as u64,
+        3 => sim.ctos() as u64,
+        4 => sim.sp as u64,
+        5 => sim.fp as u64,
+        6 => sim.eb as u64,
+        7 => sim.stack_base as u64,
+        8 => sim.vma as u64,
+        9 => sim.md,
+        10 => sim.other_md,
+        11 => sim.pc as u64,
+        12 => sim.inst as u64,
+        13 => sim.r_reg as u64,
+        14 => sim.s_reg as u64,
+        _ => sim.cycles,
+    }
+}
+
+fn write_register<M: MainMemory>(sim: &mut Simulator<M>, n: usize, value: u64) {
+    match n {
+        0 => sim.cpc = value as u16 & ADDRESS_MASK,
+        1 => sim.npc = value as u16 & ADDRESS_MASK,
+        2 => sim.csp = value as usize % sim.control_stack.len(),
+        3 => sim.control_stack[sim.csp] = value as u16 & ADDRESS_MASK,
+        4 => sim.sp = value as u32,
+        5 => sim.fp = value as u32,
+        6 => sim.eb = value as u16,
+        7 => sim.stack_base = value as u8 & 0x3,
+        8 => sim.vma = value as u32,
+        9 => sim.md = value,
+        10 => sim.other_md = value,
+        11 => sim.pc = value as u32,
+        12 => sim.inst = value as u8,
+        13 => sim.r_reg = value as u8,
+        14 => sim.s_reg = value as u8,
+        _ => sim.cycles = value,
+    }
+}
+
+/// Which word a debugger address falls in: (region, word index, byte).
+enum Location {
+    AMem(usize),
+    BMem(usize),
+    Main(u32),
+}
+
+fn locate(addr: u64) -> Option<(Location, u32)> {
+    let byte = (addr % 8) as u32;
+    if (A_MEM_BASE..A_MEM_BASE + 8 * A_MEM_SIZE as u64).contains(&addr) {
+        Some((Location::AMem(((addr - A_MEM_BASE) / 8) as usize), byte))
+    } else if (B_MEM_BASE..B_MEM_BASE + 8 * B_MEM_SIZE as u64).contains(&addr) {
+        Some((Location::BMem(((addr - B_MEM_BASE) / 8) as usize), byte))
+    } else if (MAIN_MEM_BASE..MAIN_MEM_BASE + 8 * MAIN_MEM_WORDS).contains(&addr) {
+        Some((Location::Main(((addr - MAIN_MEM_BASE) / 8) as u32), byte))
+    } else {
+        None
+    }
+}
+
+fn read_byte<M: MainMemory>(sim: &mut Simulator<M>, addr: u64) -> Option<u8> {
+    let (location, byte) = locate(addr)?;
+    let word = match location {
+        Location::AMem(a) => sim.a_mem[a],
+        Location::BMem(b) => sim.b_mem[b],
+        Location::Main(m) => sim.memory.read(m),
+    };
+    Some((word >> (8 * byte)) as u8)
+}
+
+fn write_byte<M: MainMemory>(sim: &mut Simulator<M>, addr: u64, value: u8) -> bool {
+    let (location, byte) = match locate(addr) {
+        Some(l) => l,
+        None => return false,
+    };
+    let merge = |word: u64| (word & !(0xff << (8 * byte))) | ((value as u64) << (8 * byte));
+    match location {
+        Location::AMem(a) => sim.a_mem[a] = merge(sim.a_mem[a]),
+        Location::BMem(b) => sim.b_mem[b] = merge(sim.b_mem[b]),
+        Location::Main(m) => {
+            let word = sim.memory.read(m);
+            sim.memory.write(m, merge(word));
+        }
+    }
+    true
+}
+
+//
+// Packet arguments
+//
+
+fn hex_word(value: u64) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_word(hex: &str) -> Option<u64> {
+    let bytes = parse_bytes(hex)?;
+    let mut word = [0; 8];
+    for (i, b) in bytes.iter().take(8).enumerate() {
+        word[i] = *b;
+    }
+    Some(u64::from_le_bytes(word))
+}
+
+/// Parse "addr,len".
+fn parse_range(args: &str) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, ',');
+    let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let len = u64::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+/// Parse "n=value" for the P packet.
+fn parse_assignment(args: &str) -> Option<(usize, u64)> {
+    let mut parts = args.splitn(2, '=');
+    let n = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let value = parse_word(parts.next()?)?;
+    Some((n, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sim::SparseMemory;
+    use ucode::{CWord, Microcode};
+
+    /// Two instructions that copy A[100] to A[200] and loop.
+    fn machine() -> Simulator<SparseMemory> {
+        let mut ucode = Microcode::new();
+        // SPEC=20, AMRA=100, AMWA=200, LTYPE SEL=1, CPC SEL=NAF
+        let low = 0o100 | (0o200 << 16) | (0o20 << 48);
+        ucode.c_mem.push(CWord::new(0, low, (1 << 12) | (1 << 16)));
+        ucode.c_mem.push(CWord::new(1, low, 1 << 12));
+        Simulator::new(&ucode, SparseMemory::new())
+    }
+
+    fn reply(stub: &mut GdbStub, sim: &mut Simulator<SparseMemory>, packet: &str) -> String {
+        match stub.handle(sim, packet) {
+            Action::Reply(r) => r,
+            Action::Resume { step } => stub.resume(sim, step, || true),
+            Action::Detach => "detach".to_string(),
+        }
+    }
+
+    #[test]
+    fn checksums_packets() {
+        assert_eq!(checksum("OK"), 0x9a);
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let mut sim = machine();
+        let mut stub = GdbStub::new();
+        sim.fp = 0x1234;
+        assert_eq!(reply(&mut stub, &mut sim, "p5"), "3412000000000000");
+        assert_eq!(reply(&mut stub, &mut sim, "P4=0100000000000000"), "OK");
+        assert_eq!(sim.sp, 1);
+        assert_eq!(reply(&mut stub, &mut sim, "g").len(), 16 * REGISTERS.len());
+    }
+
+    #[test]
+    fn reads_and_writes_a_memory() {
+        let mut sim = machine();
+        let mut stub = GdbStub::new();
+        sim.a_mem[2] = 0x0102;
+        assert_eq!(reply(&mut stub, &mut sim, "m1000010,2"), "0201");
+        assert_eq!(reply(&mut stub, &mut sim, "M1000018,1:ff"), "OK");
+        assert_eq!(sim.a_mem[3], 0xff);
+        assert_eq!(reply(&mut stub, &mut sim, "m0,1"), "E01");
+    }
+
+    #[test]
+    fn keeps_memory_packets_in_bounds() {
+        let mut sim = machine();
+        let mut stub = GdbStub::new();
+        let read = reply(&mut stub, &mut sim, "m1000000,ffffffffffffffff");
+        assert_eq!(read.len() as u64, PACKET_SIZE);
+        assert_eq!(reply(&mut stub, &mut sim, "mffffffffffffffff,2"), "E01");
+
+        // A write that runs off the end of A-memory writes nothing.
+        let last = A_MEM_BASE + 8 * A_MEM_SIZE as u64 - 1;
+        let packet = format!("M{:x},2:abcd", last);
+        assert_eq!(reply(&mut stub, &mut sim, &packet), "E01");
+        assert_eq!(sim.a_mem[A_MEM_SIZE - 1], 0);
+
+        assert_eq!(
+            reply(&mut stub, &mut sim, "Z2,1007ff8,ffffffffffffffff"),
+            "OK"
+        );
+    }
+
+    #[test]
+    fn single_steps() {
+        let mut sim = machine();
+        let mut stub = GdbStub::new();
+        assert_eq!(reply(&mut stub, &mut sim, "s"), "S05");
+        assert_eq!(sim.cpc, 1);
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut sim = machine();
+        let mut stub = GdbStub::new();
+        assert_eq!(reply(&mut stub, &mut sim, "Z0,1,1"), "OK");
+        assert_eq!(reply(&mut stub, &mut sim, "c"), "T05swbreak:;");
+        assert_eq!(sim.cpc, 1);
+        assert_eq!(reply(&mut stub, &mut sim, "z0,1,1"), "OK");
+        assert_eq!(reply(&mut stub, &mut sim, "c"), "S02");
+    }
+
+    #[test]
+    fn rejects_addresses_outside_control_memory() {
+        let mut sim = machine();
+        let mut stub = GdbStub::new();
+        assert_eq!(reply(&mut stub, &mut sim, "c4000"), "E01");
+        assert_eq!(reply(&mut stub, &mut sim, "sffff"), "E01");
+        assert_eq!(reply(&mut stub, &mut sim, "Z0,4001,1"), "E01");
+        assert_eq!(sim.cpc, 0);
+    }
+
+    #[test]
+    fn stops_at_a_memory_watchpoints() {
+        let mut sim = machine();
+        let mut stub = GdbStub::new();
+        let addr = A_MEM_BASE + 8 * 0o200;
+        assert_eq!(
+            reply(&mut stub, &mut sim, &format!("Z2,{:x},8", addr)),
+            "OK"
+        );
+        assert_eq!(
+            reply(&mut stub, &mut sim, "c"),
+            format!("T05watch:{:x};", addr)
+        );
+    }
+
+    #[test]
+    fn reports_status_to_the_caller() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        let (tx, rx) = mpsc::channel();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(rx.recv().unwrap()).unwrap();
+            stream.write_all(b"$D#44").unwrap();
+            let mut reply = [0; 6];
+            stream.read_exact(&mut reply).unwrap();
+        });
+
+        let mut sim = machine();
+        let mut seen = Vec::new();
+        serve(&mut sim, 0, |status| {
+            if let Status::Listening(addr) = status {
+                tx.send(addr).unwrap();
+            }
+            seen.push(status);
+        })
+        .unwrap();
+        client.join().unwrap();
+
+        assert_eq!(seen.len(), 3);
+        assert!(matches!(seen[1], Status::Connected(_)));
+        assert_eq!(seen[2], Status::Detached);
+    }
+}
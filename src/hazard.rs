@@ -0,0 +1,413 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde_json;
+
+use fields::{AbusSource, AmraSel, CpcSel, MemOp, Seq, SpecialFunction};
+use flow::{self, Flow, Npc};
+use radix;
+use ucode::{MicroInstruction, Microcode, ADDRESS_MASK};
+
+//
+// Pipeline hazards.
+//
+// Some effects of a microinstruction only land in later cycles: the
+// data of a memory read arrives in MD after the read has started, the
+// address an instruction loads into NPC is used by the instruction
+// after next, and a task keeps running for one or two instructions
+// after it dismisses itself. None of this shows in a single word, so
+// each hazard is found by walking every path from the word that starts
+// the effect through the cycles it takes.
+//
+// The walk carries NPC along, as the sequencer does, so a word that
+// jumps to NPC goes where the word before it on that path said. A
+// path ends where the next address is not known statically: a return
+// through the control stack, or a jump through an NPC loaded by NPC
+// magic. Traps are left out, since a trap abandons the cycle.
+//
+// The documentation does not give the memory latency. It is taken
+// here to be two cycles: data read by a start-read in one instruction
+// may be used by the instruction after next.
+//
+
+/// The cycle, counting the start-read as cycle 0, from which MD holds
+/// the data read.
+pub const MD_READY: usize = 2;
+
+/// How many instructions a task runs after a Dismiss: two for tasks
+/// 1-7, one for the others, as the simulator counts them.
+pub const DISMISS_SHADOW: usize = 2;
+
+/// One cycle of the sequence that leads to a hazard.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Step {
+    pub cycle: usize,
+    pub address: u16,
+    pub note: String,
+}
+
+/// A hazard at an address, with the cycles that lead to it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Hazard {
+    pub address: u16,
+    pub kind: &'static str,
+    pub message: String,
+    pub steps: Vec<Step>,
+}
+
+impl fmt::Display for Hazard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: {}",
+            radix::address(self.address),
+            self.kind,
+            self.message
+        )
+    }
+}
+
+/// Find the hazards at the words from `from` to `to`.
+pub fn analyse(ucode: &Microcode, from: u16, to: u16) -> Vec<Hazard> {
+    let flow = Flow::new(ucode);
+    let mut hazards = Vec::new();
+
+    for (address, inst) in ucode.instructions() {
+        if starts_read(&inst) {
+            md_too_soon(ucode, &flow, address, &mut hazards);
+        }
+        if inst.seq() == Seq::Dismiss {
+            dismiss_shadow(ucode, &flow, address, &mut hazards);
+        }
+        if inst.cpc_sel() == CpcSel::Npc || inst.seq() == Seq::Pushj {
+            npc_prefetch(ucode, &flow, address, &inst, &mut hazards);
+        }
+    }
+
+    hazards.retain(|h| h.address >= from && h.address <= to);
+    hazards.sort_by_key(|h| h.address);
+    let mut seen = BTreeSet::new();
+    hazards.retain(|h| seen.insert((h.address, h.kind, h.message.clone())));
+    hazards
+}
+
+/// Hazards as a JSON array, for other tools.
+pub fn to_json(hazards: &[Hazard]) -> String {
+    serde_json::to_string_pretty(hazards).unwrap_or_default()
+}
+
+fn inst_at(ucode: &Microcode, addr: u16) -> Option<MicroInstruction> {
+    ucode.c_word(addr).map(|w| w.decode())
+}
+
+/// Every path of up to `cycles` further instructions from `start`,
+/// for each thing NPC can hold as `start` runs.
+fn paths(ucode: &Microcode, flow: &Flow, start: u16, cycles: usize) -> Vec<Vec<u16>> {
+    let mut npcs: Vec<Npc> = flow
+        .npc_before(ucode, start)
+        .iter()
+        .map(|&(_, n)| n)
+        .collect();
+    npcs.sort();
+    npcs.dedup();
+    if npcs.is_empty() {
+        npcs.push(Npc::Unknown);
+    }
+
+    let mut done = Vec::new();
+    let mut open: Vec<(Vec<u16>, Npc)> = npcs.into_iter().map(|n| (vec![start], n)).collect();
+    while let Some((path, npc)) = open.pop() {
+        let last = path[path.len() - 1];
+        let next = match inst_at(ucode, last) {
+            Some(u) if path.len() <= cycles => flow::next_cycles(ucode, last, &u, npc),
+            _ => Vec::new(),
+        };
+        if next.is_empty() {
+            done.push(path);
+            continue;
+        }
+        for (to, npc) in next {
+            let mut longer = path.clone();
+            longer.push(to);
+            open.push((longer, npc));
+        }
+    }
+    done.sort();
+    done.dedup();
+    done
+}
+
+fn step(cycle: usize, address: u16, note: &str) -> Step {
+    Step {
+        cycle,
+        address,
+        note: note.to_string(),
+    }
+}
+
+fn starts_read(u: &MicroInstruction) -> bool {
+    u.mem() == MemOp::Read || u.mem() == MemOp::BlockRead
+}
+
+/// Whether a word uses the data in MD: as its A bus source, through
+/// the Lbus, or to write the IFU decode memories.
+fn reads_md(u: &MicroInstruction) -> bool {
+    let abus_md = u.amra_sel() == AmraSel::Other
+        && u.r_base() == 3
+        && (u.abus_src() == AbusSource::Md || u.abus_src() == AbusSource::MdAdvance);
+    let decode_write =
+        u.spec() == SpecialFunction::IfuControl && (u.magic() == 5 || u.magic() == 6);
+    abus_md || u.amra_sel() == AmraSel::Lbus || decode_write
+}
+
+fn count(n: usize, what: &str) -> String {
+    match n {
+        1 => format!("one {}", what),
+        2 => format!("two {}s", what),
+        n => format!("{} {}s", n, what),
+    }
+}
+
+fn md_too_soon(ucode: &Microcode, flow: &Flow, start: u16, hazards: &mut Vec<Hazard>) {
+    for path in paths(ucode, flow, start, MD_READY - 1) {
+        for (cycle, &addr) in path.iter().enumerate().skip(1) {
+            if !inst_at(ucode, addr).is_some_and(|u| reads_md(&u)) {
+                continue;
+            }
+            let mut steps = vec![step(0, start, "starts a memory read")];
+            for (c, &a) in path.iter().enumerate().take(cycle).skip(1) {
+                steps.push(step(c, a, ""));
+            }
+            steps.push(step(
+                cycle,
+                addr,
+                &format!("reads MD, which holds the data from cycle {}", MD_READY),
+            ));
+            hazards.push(Hazard {
+                address: addr,
+                kind: "md-too-soon",
+                message: format!(
+                    "reads MD {} after the read started at {}",
+                    count(cycle, "cycle"),
+                    radix::address(start)
+                ),
+                steps,
+            });
+            break;
+        }
+    }
+}
+
+fn dismiss_shadow(ucode: &Microcode, flow: &Flow, start: u16, hazards: &mut Vec<Hazard>) {
+    for path in paths(ucode, flow, start, DISMISS_SHADOW) {
+        for (cycle, &addr) in path.iter().enumerate().skip(1) {
+            let u = match inst_at(ucode, addr) {
+                Some(u) => u,
+                None => continue,
+            };
+            let tasks = if cycle == 1 {
+                "every task"
+            } else {
+                "tasks 1-7"
+            };
+            let problem = if u.seq() == Seq::Dismiss {
+                "dismisses again".to_string()
+            } else if u.spec() == SpecialFunction::DisableTasking {
+                "disables tasking while the task is being switched out".to_string()
+            } else if starts_read(&u) && cycle + MD_READY > DISMISS_SHADOW {
+                format!(
+                    "starts a memory read whose data arrives after {} {} switched out",
+                    tasks,
+                    if cycle == 1 { "is" } else { "are" }
+                )
+            } else {
+                continue;
+            };
+
+            let mut steps = vec![step(0, start, "dismisses the task")];
+            for (c, &a) in path.iter().enumerate().take(cycle + 1).skip(1) {
+                let runs = if c == 1 {
+                    "runs for every task"
+                } else {
+                    "runs for tasks 1-7 only"
+                };
+                let note = if c == cycle {
+                    format!("{}; {}", runs, problem)
+                } else {
+                    runs.to_string()
+                };
+                steps.push(step(c, a, &note));
+            }
+            hazards.push(Hazard {
+                address: addr,
+                kind: "dismiss-shadow",
+                message: format!(
+                    "{} {} after the Dismiss at {}, run by {}",
+                    problem,
+                    count(cycle, "instruction"),
+                    radix::address(start),
+                    tasks
+                ),
+                steps,
+            });
+        }
+    }
+}
+
+fn npc_prefetch(
+    ucode: &Microcode,
+    flow: &Flow,
+    addr: u16,
+    u: &MicroInstruction,
+    hazards: &mut Vec<Hazard>,
+) {
+    let before = flow.npc_before(ucode, addr);
+
+    // A call pushes NPC as the return address, which is the word after
+    // the call unless the word before loaded NPC with a dispatch.
+    if u.seq() == Seq::Pushj {
+        for &(from, npc) in &before {
+            if let Npc::Dispatch(_) = npc {
+                hazards.push(Hazard {
+                    address: addr,
+                    kind: "npc-prefetch",
+                    message: format!(
+                        "calls with NPC loaded by the dispatch at {}, so it returns to the \
+                         dispatch target rather than to {}",
+                        radix::address(from),
+                        radix::address(addr.wrapping_add(1) & ADDRESS_MASK)
+                    ),
+                    steps: vec![
+                        step(0, from, "loads NPC with its dispatch"),
+                        step(1, addr, "pushes NPC as the return address"),
+                    ],
+                });
+            }
+        }
+    }
+
+    // A jump through NPC goes where the word before said; when the
+    // words that lead here disagree, where it goes depends on the path.
+    if u.cpc_sel() == CpcSel::Npc {
+        let dispatch = before.iter().any(|&(_, n)| matches!(n, Npc::Dispatch(_)));
+        let next = before.iter().any(|&(_, n)| matches!(n, Npc::Next(_)));
+        if dispatch && next {
+            let mut steps = Vec::new();
+            for &(from, npc) in &before {
+                let note = match npc {
+                    Npc::Dispatch(_) => "loads NPC with its dispatch".to_string(),
+                    Npc::Next(a) => format!("loads NPC with {}", radix::address(a)),
+                    Npc::Unknown => "loads NPC through NPC magic".to_string(),
+                };
+                steps.push(step(0, from, &note));
+            }
+            steps.push(step(1, addr, "jumps to NPC"));
+            hazards.push(Hazard {
+                address: addr,
+                kind: "npc-prefetch",
+                message: "jumps to NPC, which holds a dispatch on some paths here and the \
+                          next address on others"
+                    .to_string(),
+                steps,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::image;
+
+    fn found(ucode: &Microcode) -> Vec<(u16, &'static str)> {
+        analyse(ucode, 0, 0o37777)
+            .iter()
+            .map(|h| (h.address, h.kind))
+            .collect()
+    }
+
+    #[test]
+    fn md_is_read_too_soon() {
+        let ucode = image(
+            "        .org 10\n\
+             npc-sel=next naf=11 mem=read\n\
+             npc-sel=next naf=12 amra-sel=other r-base=3 abus-src=md\n\
+             npc-sel=next naf=13 mem=read\n\
+             npc-sel=next naf=14\n\
+             npc-sel=next naf=14 amra-sel=other r-base=3 abus-src=md\n",
+        );
+        let hazards = analyse(&ucode, 0, 0o37777);
+        assert_eq!(hazards.len(), 1);
+        assert_eq!(hazards[0].address, 0o11);
+        assert_eq!(hazards[0].kind, "md-too-soon");
+        let cycles: Vec<(usize, u16)> = hazards[0]
+            .steps
+            .iter()
+            .map(|s| (s.cycle, s.address))
+            .collect();
+        assert_eq!(cycles, vec![(0, 0o10), (1, 0o11)]);
+    }
+
+    #[test]
+    fn follows_npc() {
+        let ucode = image(
+            "        .org 50\n\
+             npc-sel=next naf=51\n\
+             npc-sel=next cpc-sel=npc mem=read\n\
+             npc-sel=next naf=52 amra-sel=other r-base=3 abus-src=md\n",
+        );
+        assert_eq!(found(&ucode), vec![(0o52, "md-too-soon")]);
+    }
+
+    #[test]
+    fn words_after_a_dismiss() {
+        let ucode = image(
+            "        .org 20\n\
+             npc-sel=next naf=21 seq=dismiss\n\
+             npc-sel=next naf=22\n\
+             npc-sel=next naf=23 mem=read\n\
+             npc-sel=next naf=23\n",
+        );
+        assert_eq!(found(&ucode), vec![(0o22, "dismiss-shadow")]);
+        let hazards = analyse(&ucode, 0o22, 0o22);
+        assert!(hazards[0].message.contains("tasks 1-7"));
+        assert_eq!(hazards[0].steps.len(), 3);
+    }
+
+    #[test]
+    fn npc_from_a_dispatch() {
+        let ucode = image(
+            "        .org 30\n\
+             a:      npc-sel=dispatch naf=call\n\
+             call:   npc-sel=next seq=pushj naf=40\n\
+             b:      npc-sel=next naf=jump\n\
+             jump:   npc-sel=next cpc-sel=npc\n\
+             .org 40\n\
+             npc-sel=dispatch naf=jump\n",
+        );
+        assert_eq!(
+            found(&ucode),
+            vec![(0o31, "npc-prefetch"), (0o33, "npc-prefetch")]
+        );
+    }
+}
@@ -0,0 +1,367 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::BTreeSet;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use shellexpand;
+
+use asm;
+use fields::{CpcSel, Seq, SpecialFunction};
+use flow::{EdgeKind, Flow};
+use project::{Opcode, OPCODE_MAX};
+use ucode::{control_address, Microcode};
+
+//
+// Macroinstruction entry points.
+//
+// The IFU decodes each macroinstruction through two decode memories,
+// written by IFU control 5 (Write Decode LH) and 6 (Write Decode RH)
+// from memory data, and the microcode gets to the handler by taking
+// its next address from CTOS without popping the control stack, which
+// the IFU answers with the dispatch address. The decode memories are
+// loaded from Lisp memory when the machine starts, so which handler
+// serves which opcode is not in the image itself. What the image does
+// show is where the dispatches and decode writes are, and which words
+// nothing else leads to; the opcode table itself comes from imported
+// opcode lists and from handlers given by hand.
+//
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OpcodeError {
+    Io(io::Error),
+    /// A line of an opcode list, and what is wrong with it.
+    Invalid(usize, String),
+}
+
+impl From<io::Error> for OpcodeError {
+    fn from(err: io::Error) -> OpcodeError {
+        OpcodeError::Io(err)
+    }
+}
+
+impl fmt::Display for OpcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OpcodeError::Io(ref err) => err.fmt(f),
+            OpcodeError::Invalid(line, ref msg) => write!(f, "line {}: {}", line, msg),
+        }
+    }
+}
+
+impl error::Error for OpcodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            OpcodeError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The half of the IFU decode memory a word writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Half {
+    Left,
+    Right,
+}
+
+impl Half {
+    pub fn name(self) -> &'static str {
+        match self {
+            Half::Left => "LH",
+            Half::Right => "RH",
+        }
+    }
+}
+
+/// The words that take the IFU dispatch address: those that take
+/// their next address from CTOS without a Popj.
+pub fn dispatch_sites(ucode: &Microcode) -> Vec<u16> {
+    ucode
+        .instructions()
+        .filter(|(_, u)| u.cpc_sel() == CpcSel::Ctos && u.seq() != Seq::Popj)
+        .map(|(addr, _)| addr)
+        .collect()
+}
+
+/// The words that write the IFU decode memories.
+pub fn decode_writes(ucode: &Microcode) -> Vec<(u16, Half)> {
+    ucode
+        .instructions()
+        .filter(|(_, u)| u.spec() == SpecialFunction::IfuControl)
+        .filter_map(|(addr, u)| match u.magic() {
+            5 => Some((addr, Half::Left)),
+            6 => Some((addr, Half::Right)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Words nothing else in the image leads to, which only a dispatch
+/// can reach, and that are not yet the handler of an opcode. Words
+/// just after a call, which its return reaches, are left out.
+pub fn candidates(ucode: &Microcode, flow: &Flow) -> Vec<u16> {
+    let handlers: BTreeSet<u16> = ucode
+        .annotations
+        .opcodes
+        .values()
+        .filter_map(|op| op.handler)
+        .collect();
+    ucode
+        .c_mem
+        .mem
+        .iter()
+        .map(|w| w.address)
+        .filter(|a| flow.predecessors(*a).is_empty() && !handlers.contains(a))
+        .filter(|a| !calls(flow, a.wrapping_sub(1)))
+        .collect()
+}
+
+fn calls(flow: &Flow, addr: u16) -> bool {
+    flow.successors(addr)
+        .iter()
+        .any(|e| e.kind == EdgeKind::Call)
+}
+
+/// The words of the routine at `entry`, with those of the routines
+/// its calls return to.
+fn body(flow: &Flow, entry: u16) -> Vec<u16> {
+    let mut entries = vec![entry];
+    let mut words = Vec::new();
+    while let Some(e) = entries.pop() {
+        for &addr in flow.routine(e).map_or(&[][..], |r| &r.words[..]) {
+            words.push(addr);
+            let back = addr.wrapping_add(1);
+            let returns_here =
+                calls(flow, addr) && flow.routine(back).is_some_and(|r| r.entry == back);
+            if returns_here && !entries.contains(&back) && !words.contains(&back) {
+                entries.push(back);
+            }
+        }
+    }
+    words.sort();
+    words.dedup();
+    words
+}
+
+/// The opcode a word names, by name or number, or None if it names
+/// none or the number is too big for an opcode.
+pub fn opcode(ucode: &Microcode, word: &str) -> Option<u16> {
+    ucode.annotations.opcode_named(word).or_else(|| {
+        asm::parse_number(word)
+            .filter(|n| *n <= u64::from(OPCODE_MAX))
+            .map(|n| n as u16)
+    })
+}
+
+/// Parse an opcode list. Each line gives an octal opcode, then its
+/// name, then the address or label of its handler, and either of the
+/// last two may be left out or given as "-". Anything after a "#" is a
+/// comment.
+pub fn parse_list(ucode: &Microcode, text: &str) -> Result<Vec<(u16, Opcode)>, OpcodeError> {
+    let mut opcodes = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let invalid = |msg: String| OpcodeError::Invalid(i + 1, msg);
+        let words: Vec<&str> = line
+            .split('#')
+            .next()
+            .unwrap_or("")
+            .split_whitespace()
+            .collect();
+        if words.is_empty() {
+            continue;
+        }
+        if words.len() > 3 {
+            return Err(invalid("too many fields".to_string()));
+        }
+        let code = match asm::parse_number(words[0]) {
+            Some(n) if n <= u64::from(OPCODE_MAX) => n as u16,
+            _ => return Err(invalid(format!("bad opcode '{}'", words[0]))),
+        };
+        let name = words.get(1).filter(|w| **w != "-").map(|w| w.to_string());
+        let handler = match words.get(2) {
+            Some(&"-") | None => None,
+            Some(w) => match ucode
+                .annotations
+                .address_of(w)
+                .or_else(|| asm::parse_number(w).and_then(control_address))
+            {
+                Some(addr) => Some(addr),
+                None => return Err(invalid(format!("bad handler '{}'", w))),
+            },
+        };
+        opcodes.push((code, Opcode { name, handler }));
+    }
+    Ok(opcodes)
+}
+
+/// Read an opcode list into the opcode table, keeping what the table
+/// already has where the list leaves something out. Returns how many
+/// opcodes the list gave.
+pub fn import(ucode: &mut Microcode, path: &str) -> Result<usize, OpcodeError> {
+    let mut text = String::new();
+    File::open(&*shellexpand::tilde(path))?.read_to_string(&mut text)?;
+    let opcodes = parse_list(ucode, &text)?;
+    for (code, op) in &opcodes {
+        let old = ucode
+            .annotations
+            .opcodes
+            .get(code)
+            .cloned()
+            .unwrap_or_default();
+        let merged = Opcode {
+            name: op.name.clone().or(old.name),
+            handler: op.handler.or(old.handler),
+        };
+        ucode.annotations.set_opcode(*code, Some(merged));
+    }
+    Ok(opcodes.len())
+}
+
+/// A routine reached from a handler, one line of its call tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Branch {
+    pub depth: usize,
+    /// Where the routine is entered.
+    pub entry: u16,
+    /// How it is reached: by a call, or a jump out of the routine above.
+    pub kind: EdgeKind,
+    /// The routine has already been shown, and is not shown again.
+    pub repeated: bool,
+}
+
+/// How deep a call tree goes.
+const MAX_DEPTH: usize = 16;
+
+/// The routines called, or jumped to, by the routine at `entry`, and
+/// theirs in turn, depth first. A routine carries on after each call
+/// at the word its return reaches.
+pub fn call_tree(flow: &Flow, entry: u16) -> Vec<Branch> {
+    let mut tree = Vec::new();
+    let mut seen = BTreeSet::new();
+    seen.insert(entry);
+    grow(flow, entry, 1, &mut seen, &mut tree);
+    tree
+}
+
+fn grow(flow: &Flow, entry: u16, depth: usize, seen: &mut BTreeSet<u16>, tree: &mut Vec<Branch>) {
+    let words = body(flow, entry);
+    let mut out: Vec<(u16, EdgeKind)> = Vec::new();
+    for addr in &words {
+        for e in flow.successors(*addr) {
+            let leaves =
+                e.kind == EdgeKind::Call || (e.kind != EdgeKind::Trap && !words.contains(&e.to));
+            if leaves && !out.iter().any(|&(a, _)| a == e.to) {
+                out.push((e.to, e.kind));
+            }
+        }
+    }
+
+    for (to, kind) in out {
+        let repeated = !seen.insert(to);
+        tree.push(Branch {
+            depth,
+            entry: to,
+            kind,
+            repeated,
+        });
+        if !repeated && depth < MAX_DEPTH {
+            grow(flow, to, depth + 1, seen, tree);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::image;
+
+    const SOURCE: &str = "        .org 10\n\
+         next:   npc-sel=next cpc-sel=ctos\n\
+         load:   npc-sel=next naf=12 spec=ifu magic=5\n\
+         npc-sel=next naf=next spec=ifu magic=6\n\
+         car:    npc-sel=next naf=20 seq=pushj\n\
+         npc-sel=next naf=next\n\
+         .org 20\n\
+         sub:    npc-sel=next naf=30 seq=pushj\n\
+         npc-sel=next cpc-sel=ctos seq=popj\n\
+         .org 30\n\
+         npc-sel=next cpc-sel=ctos seq=popj\n";
+
+    #[test]
+    fn finds_dispatches_and_decode_writes() {
+        let ucode = image(SOURCE);
+        assert_eq!(dispatch_sites(&ucode), vec![0o10]);
+        assert_eq!(
+            decode_writes(&ucode),
+            vec![(0o11, Half::Left), (0o12, Half::Right)]
+        );
+        let flow = Flow::new(&ucode);
+        assert_eq!(candidates(&ucode, &flow), vec![0o11, 0o13]);
+    }
+
+    #[test]
+    fn reads_opcode_lists() {
+        let mut ucode = image(SOURCE);
+        ucode.annotations.set_label(0o13, Some("car")).unwrap();
+        let list = "# opcode name handler\n\
+                    42 car car\n\
+                    43 cdr   # not found yet\n\
+                    44 - 14\n";
+        let opcodes = parse_list(&ucode, list).unwrap();
+        assert_eq!(opcodes.len(), 3);
+        assert_eq!(opcodes[0].1.handler, Some(0o13));
+        assert_eq!(opcodes[1].1.name, Some("cdr".to_string()));
+        assert_eq!(opcodes[1].1.handler, None);
+        assert_eq!(opcodes[2].1.name, None);
+        assert!(parse_list(&ucode, "42 car nowhere\n").is_err());
+
+        for (code, op) in opcodes {
+            ucode.annotations.set_opcode(code, Some(op));
+        }
+        assert_eq!(opcode(&ucode, "cdr"), Some(0o43));
+        assert_eq!(opcode(&ucode, "44"), Some(0o44));
+        assert_eq!(opcode(&ucode, "177777"), Some(0xffff));
+        assert_eq!(opcode(&ucode, "200000"), None);
+    }
+
+    #[test]
+    fn grows_call_trees() {
+        let ucode = image(SOURCE);
+        let flow = Flow::new(&ucode);
+        let tree: Vec<(usize, u16, EdgeKind)> = call_tree(&flow, 0o13)
+            .iter()
+            .map(|b| (b.depth, b.entry, b.kind))
+            .collect();
+        assert_eq!(
+            tree,
+            vec![
+                (1, 0o20, EdgeKind::Call),
+                (2, 0o30, EdgeKind::Call),
+                (1, 0o10, EdgeKind::Jump),
+            ]
+        );
+    }
+}
@@ -0,0 +1,504 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+
+use radix;
+use toml;
+
+//
+// Microword layout.
+//
+// The position, printing and symbolic values of every microword
+// field come from a field specification, normally the built-in copy
+// of docs/microword.toml. Another specification can be loaded at run
+// time to try a corrected or alternative layout.
+//
+
+/// Width of a microword in bits.
+pub const WORD_BITS: u32 = 112;
+
+const BUILTIN: &str = include_str!("../docs/microword.toml");
+
+/// The fields the typed accessors of `MicroInstruction`, and the
+/// analyses built on them, depend on. Every layout must define them;
+/// any other field may be renamed, split or left out.
+const REQUIRED: &[&str] = &[
+    "amra",
+    "r-base",
+    "abus-src",
+    "amra-sel",
+    "xybus-sel",
+    "stkp-count",
+    "amwa",
+    "lbus-dev",
+    "w-base",
+    "stkp-dir",
+    "amwa-sel",
+    "seq",
+    "bmra",
+    "bmwa",
+    "bmem-from-xbus",
+    "mem",
+    "spec",
+    "magic",
+    "cond-sel",
+    "cond-func",
+    "alu",
+    "byte-f",
+    "obus-cdr",
+    "obus-htype",
+    "obus-ltype-sel",
+    "cpc-sel",
+    "npc-sel",
+    "naf",
+    "speed",
+    "type-map-sel",
+];
+
+/// A field of the microword.
+#[derive(Clone)]
+pub struct Field {
+    pub name: String,
+    pub title: String,
+    pub lo: u32,
+    pub width: u32,
+    /// The radix values are always shown in, or None to follow the
+    /// radix chosen with `set radix`.
+    pub radix: Option<u32>,
+    /// The field whose bits this one is another view of.
+    pub group: Option<String>,
+    pub default: u64,
+    pub values: Vec<(String, u64)>,
+}
+
+impl Field {
+    pub fn get(&self, word: u128) -> u64 {
+        ((word >> self.lo) & self.mask()) as u64
+    }
+
+    pub fn set(&self, word: u128, value: u64) -> u128 {
+        (word & !(self.mask() << self.lo)) | (((value as u128) & self.mask()) << self.lo)
+    }
+
+    fn mask(&self) -> u128 {
+        (1u128 << self.width) - 1
+    }
+
+    /// Whether a value is small enough for the field.
+    pub fn fits(&self, value: u64) -> bool {
+        (value as u128) >> self.width == 0
+    }
+
+    pub fn is_alias(&self) -> bool {
+        self.group.is_some()
+    }
+
+    pub fn value_name(&self, value: u64) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|v| v.1 == value)
+            .map(|v| v.0.as_str())
+    }
+
+    pub fn value_named(&self, name: &str) -> Option<u64> {
+        self.values.iter().find(|v| v.0 == name).map(|v| v.1)
+    }
+
+    /// Format a value in the field's radix, padded to the field width.
+    pub fn format(&self, value: u64) -> String {
+        let radix = match self.radix {
+            Some(radix) => radix,
+            None => return radix::padded(value, self.width),
+        };
+        let digits = match radix {
+            2 => self.width,
+            8 => self.width.div_ceil(3),
+            16 => self.width.div_ceil(4),
+            _ => 0,
+        } as usize;
+        match radix {
+            2 => format!("{:01$b}", value, digits),
+            16 => format!("{:01$x}", value, digits),
+            10 => format!("{}", value),
+            _ => format!("{:01$o}", value, digits),
+        }
+    }
+}
+
+//
+// Error handling
+//
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LayoutError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl From<io::Error> for LayoutError {
+    fn from(err: io::Error) -> LayoutError {
+        LayoutError::Io(err)
+    }
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LayoutError::Io(ref err) => err.fmt(f),
+            LayoutError::Parse(ref msg) => write!(f, "{}", msg),
+            LayoutError::Invalid(ref msg) => write!(f, "Invalid layout: {}", msg),
+        }
+    }
+}
+
+impl error::Error for LayoutError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            LayoutError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+//
+// Specification file
+//
+
+#[derive(Deserialize)]
+struct SpecFile {
+    field: Vec<FieldSpec>,
+}
+
+#[derive(Deserialize)]
+struct FieldSpec {
+    name: String,
+    title: Option<String>,
+    bits: String,
+    radix: Option<u32>,
+    group: Option<String>,
+    default: Option<u64>,
+    values: Option<BTreeMap<String, u64>>,
+}
+
+/// Parse a bit range, "high-low" or a single bit.
+fn parse_bits(bits: &str) -> Option<(u32, u32)> {
+    let mut parts = bits.splitn(2, '-');
+    let hi = parts.next()?.trim().parse::<u32>().ok()?;
+    let lo = match parts.next() {
+        Some(lo) => lo.trim().parse::<u32>().ok()?,
+        None => hi,
+    };
+    Some((hi, lo))
+}
+
+/// A complete microword layout.
+pub struct Layout {
+    pub fields: Vec<Field>,
+    index: HashMap<String, usize>,
+}
+
+impl Layout {
+    /// The layout built into the program.
+    pub fn builtin() -> Layout {
+        match Layout::parse(BUILTIN) {
+            Ok(layout) => layout,
+            Err(e) => panic!("built-in layout: {}", e),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Layout, LayoutError> {
+        let mut text = String::new();
+        File::open(shellexpand::tilde(path).to_string())?.read_to_string(&mut text)?;
+        Layout::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Layout, LayoutError> {
+        let spec: SpecFile =
+            toml::from_str(text).map_err(|e| LayoutError::Parse(format!("{}", e)))?;
+        let invalid = |msg: String| LayoutError::Invalid(msg);
+
+        let mut fields = Vec::new();
+        let mut index = HashMap::new();
+
+        for entry in spec.field {
+            let (hi, lo) = parse_bits(&entry.bits).ok_or_else(|| {
+                invalid(format!("{}: bad bit range '{}'", entry.name, entry.bits))
+            })?;
+            if lo > hi || hi >= WORD_BITS || hi - lo >= 64 {
+                return Err(invalid(format!(
+                    "{}: bad bit range '{}'",
+                    entry.name, entry.bits
+                )));
+            }
+            if let Some(radix) = entry.radix {
+                if ![2, 8, 10, 16].contains(&radix) {
+                    return Err(invalid(format!("{}: bad radix {}", entry.name, radix)));
+                }
+            }
+            let mut values: Vec<(String, u64)> =
+                entry.values.unwrap_or_default().into_iter().collect();
+            values.sort_by_key(|v| v.1);
+
+            if index.insert(entry.name.clone(), fields.len()).is_some() {
+                return Err(invalid(format!("{}: defined twice", entry.name)));
+            }
+            let name = entry.name;
+            let title = entry.title.unwrap_or_else(|| name.to_uppercase());
+            fields.push(Field {
+                name,
+                title,
+                lo,
+                width: hi - lo + 1,
+                radix: entry.radix,
+                group: entry.group,
+                default: entry.default.unwrap_or(0),
+                values,
+            });
+        }
+
+        let layout = Layout { fields, index };
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    /// Check that the fields outside alias groups cover every bit
+    /// exactly once, that each alias lies within its group, and that
+    /// the fields the program depends on are there.
+    fn validate(&self) -> Result<(), LayoutError> {
+        if let Some(name) = REQUIRED.iter().find(|name| self.field(name).is_none()) {
+            return Err(LayoutError::Invalid(format!(
+                "there must be a field named '{}'",
+                name
+            )));
+        }
+
+        let mut covered: u128 = 0;
+        for field in &self.fields {
+            let bits = field.mask() << field.lo;
+            match field.group {
+                Some(ref group) => match self.field(group) {
+                    Some(g) if !g.is_alias() && (g.mask() << g.lo) & bits == bits => {}
+                    _ => {
+                        return Err(LayoutError::Invalid(format!(
+                            "{}: not within group '{}'",
+                            field.name, group
+                        )))
+                    }
+                },
+                None => {
+                    if covered & bits != 0 {
+                        return Err(LayoutError::Invalid(format!(
+                            "{}: overlaps another field",
+                            field.name
+                        )));
+                    }
+                    covered |= bits;
+                }
+            }
+            if !field.fits(field.default) || field.values.iter().any(|v| !field.fits(v.1)) {
+                return Err(LayoutError::Invalid(format!(
+                    "{}: value does not fit",
+                    field.name
+                )));
+            }
+        }
+
+        if covered != (1u128 << WORD_BITS) - 1 {
+            let missing = (0..WORD_BITS)
+                .find(|b| covered & (1u128 << b) == 0)
+                .unwrap_or(0);
+            return Err(LayoutError::Invalid(format!(
+                "bit {} is not covered",
+                missing
+            )));
+        }
+
+        match self.field("parity") {
+            Some(f) if f.width == 1 => Ok(()),
+            _ => Err(LayoutError::Invalid(
+                "there must be a one-bit field named 'parity'".to_string(),
+            )),
+        }
+    }
+
+    /// Look up a field or alias by name.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.index.get(name).map(|i| &self.fields[*i])
+    }
+
+    /// The value of a named field, or 0 if the layout has no such field.
+    pub fn value(&self, name: &str, word: u128) -> u64 {
+        self.field(name).map_or(0, |f| f.get(word))
+    }
+
+    /// The fields that are not aliases, which together hold every bit.
+    pub fn primary(&self) -> Vec<&Field> {
+        self.fields.iter().filter(|f| !f.is_alias()).collect()
+    }
+
+    /// The word with every field at its default, before parity.
+    pub fn blank_word(&self) -> u128 {
+        self.primary()
+            .iter()
+            .fold(0, |word, field| field.set(word, field.default))
+    }
+
+    /// The parity bit that gives the word odd parity.
+    pub fn parity(&self, word: u128) -> u64 {
+        let parity = self.field("parity").unwrap();
+        let without = parity.set(word, 0);
+        if without.count_ones().is_multiple_of(2) {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// The word with its parity bit set for odd parity.
+    pub fn with_parity(&self, word: u128) -> u128 {
+        self.field("parity").unwrap().set(word, self.parity(word))
+    }
+
+    /// Print every field of a word, one per line, with aliases
+    /// marked with '>'.
+    pub fn describe(&self, word: u128) -> String {
+        let mut text = String::new();
+        for field in &self.fields {
+            let marker = if field.is_alias() { "> " } else { "" };
+            text.push_str(&format!(
+                "{:<18}{}\n",
+                format!("{}{}:", marker, field.title),
+                field.format(field.get(word))
+            ));
+        }
+        text
+    }
+}
+
+//
+// The layout in use
+//
+
+static CURRENT: RwLock<Option<Arc<Layout>>> = RwLock::new(None);
+
+/// The layout in use, the built-in one unless another was loaded.
+pub fn current() -> Arc<Layout> {
+    if let Some(ref layout) = *CURRENT.read().unwrap() {
+        return layout.clone();
+    }
+    let layout = Arc::new(Layout::builtin());
+    *CURRENT.write().unwrap() = Some(layout.clone());
+    layout
+}
+
+/// Use a different layout from now on.
+pub fn set_current(layout: Layout) {
+    *CURRENT.write().unwrap() = Some(Arc::new(layout));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The built-in layout with two more aliases.
+    fn extended() -> String {
+        format!(
+            "{}{}",
+            BUILTIN,
+            "
+            [[field]]
+            name = \"nibble\"
+            bits = \"99-96\"
+            group = \"type-map-sel\"
+            radix = 16
+            [[field]]
+            name = \"low-op\"
+            bits = \"105-102\"
+            group = \"au-op\"
+            "
+        )
+    }
+
+    fn error(text: &str) -> String {
+        match Layout::parse(text) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => format!("{}", e),
+        }
+    }
+
+    #[test]
+    fn builtin_layout_is_valid() {
+        let layout = Layout::builtin();
+        assert_eq!(layout.primary().len(), 28);
+        let bmra = layout.field("bmra").unwrap();
+        assert_eq!((bmra.lo, bmra.width), (32, 8));
+        assert_eq!(layout.field("spec").unwrap().default, 0o20);
+        assert_eq!(layout.field("alu").unwrap().value_named("x-1"), Some(3));
+    }
+
+    #[test]
+    fn parses_an_extended_layout() {
+        let layout = match Layout::parse(&extended()) {
+            Ok(l) => l,
+            Err(e) => panic!("{}", e),
+        };
+        let nibble = layout.field("nibble").unwrap();
+        assert!(nibble.is_alias());
+        assert_eq!(nibble.format(0xa), "a");
+        assert_eq!(layout.field("low-op").unwrap().title, "LOW-OP");
+        assert_eq!(layout.value("spec", layout.blank_word()), 0o20);
+        assert_eq!(layout.value("missing", !0), 0);
+        assert_eq!(layout.with_parity(0).count_ones(), 1);
+    }
+
+    #[test]
+    fn rejects_bad_layouts() {
+        let extended = extended();
+        assert!(error(&extended.replace("109-102", "108-102")).contains("bit 109"));
+        assert!(error(&extended.replace("109-102", "109-101")).contains("overlaps"));
+        assert!(error(&extended.replace("\"99-96\"", "\"103-96\"")).contains("group"));
+        assert!(error(&extended.replace("\"parity\"", "\"p\"")).contains("parity"));
+        assert!(error(&extended.replace("\"x-1\" = 0o3", "\"x-1\" = 0o77")).contains("fit"));
+        assert!(error(&extended.replace("\"naf\"", "\"next\"")).contains("'naf'"));
+        assert!(error(&extended.replace("\"w-base\"", "\"w\"")).contains("'w-base'"));
+        assert!(Layout::parse(&extended.replace("\"au-op\"", "\"fpa-op\"")).is_ok());
+        assert!(!error("[[field]]\nname = 3\n").is_empty());
+    }
+
+    #[test]
+    fn formats_in_the_field_radix() {
+        let mut field = Layout::builtin().field("amra").unwrap().clone();
+        field.radix = Some(8);
+        assert_eq!(field.format(0o12), "0012");
+        field.radix = Some(2);
+        assert_eq!(field.format(5), "000000000101");
+        field.radix = Some(10);
+        assert_eq!(field.format(0o12), "10");
+    }
+}
@@ -0,0 +1,93 @@
+//
+// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+//
+// This file is part of the Symbolics Microcode Explorer.
+//
+// The Symbolics Microcode Explorer is free software: you can
+// redistribute it and/or modify it under the terms of the GNU
+// General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// The Symbolics Microcode Explorer is distributed in the hope that it
+// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with The Symbolics Microde Explorer.  If not, see
+// <https://www.gnu.org/licenses/>.
+//
+
+//! Reading, decoding, analysing and editing Symbolics 3600 microcode.
+//!
+//! A [`Microcode`](ucode/struct.Microcode.html) image is loaded from a
+//! `.mic` file. Its control memory words decode into
+//! [`MicroInstruction`](ucode/struct.MicroInstruction.html)s, whose
+//! fields are read with typed accessors:
+//!
+//! ```no_run
+//! use uc_explorer::fields::Seq;
+//! use uc_explorer::ucode::Microcode;
+//!
+//! let mut ucode = Microcode::new();
+//! ucode.load("3600.mic").expect("cannot load the image");
+//! for (address, inst) in ucode.instructions() {
+//!     if inst.seq() == Seq::Pushj {
+//!         println!("{:o} calls {:o}", address, inst.naf());
+//!     }
+//! }
+//! ```
+//!
+//! Fields with symbolic values, such as U SEQ and U SPEC, decode to
+//! the enums of [`fields`](fields/index.html), which print and parse
+//! the names the assembler uses.
+//!
+//! The layout of a microword comes from `docs/microword.toml`, or a
+//! file read with [`Layout::load`](layout/struct.Layout.html#method.load). Beyond the
+//! image itself there are an assembler and disassembler (`asm`),
+//! static control flow (`flow`), a simulator (`sim`) with a GDB stub
+//! (`gdb`), patches (`patch`), project annotations (`project`) and
+//! exports to other formats (`export`).
+//!
+//! The interactive explorer is built with the `repl` feature, which is
+//! on by default. Its command line support lives in `command`, `shell`
+//! and `color`.
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate shellexpand;
+extern crate toml;
+
+pub mod asm;
+pub mod export;
+pub mod expr;
+pub mod fields;
+pub mod flow;
+pub mod gdb;
+pub mod hazard;
+pub mod ifu;
+pub mod layout;
+pub mod lint;
+pub mod lisp;
+pub mod nav;
+pub mod patch;
+pub mod project;
+pub mod radix;
+pub mod sim;
+pub mod timing;
+pub mod ucode;
+
+#[cfg(feature = "repl")]
+pub mod color;
+#[cfg(feature = "repl")]
+pub mod command;
+#[cfg(feature = "repl")]
+pub mod shell;
+
+pub use ucode::{
+    ABWord, CWord, Instructions, MicroInstruction, Microcode, MicrocodeError, PicoStoreWord,
+    TypeWord,
+};
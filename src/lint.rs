@@ -0,0 +1,382 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde_json;
+
+use fields::{AmraSel, CondFunc, CpcSel, MemOp, ObusCdr, ObusHtype, SpecialFunction};
+use flow::{EdgeKind, Flow};
+use radix;
+use ucode::{MicroInstruction, Microcode, B_MEM_IMMEDIATES, B_MEM_SCRATCHPAD};
+
+//
+// Static checks of control memory words.
+//
+// The documentation of the microword gives rules that the field
+// decoder cannot see: codes that are illegal, fields that must be
+// used together, and special functions that only work in pairs. Each
+// rule here checks one of them. Rules can be turned off for an image,
+// and allowed at single addresses, through the project annotations.
+//
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// What a rule is given to check one word.
+pub struct Word<'a> {
+    pub address: u16,
+    pub inst: MicroInstruction,
+    ucode: &'a Microcode,
+    flow: &'a Flow,
+    extended_writes: &'a BTreeSet<u16>,
+}
+
+pub struct Rule {
+    pub name: &'static str,
+    pub severity: Severity,
+    pub summary: &'static str,
+    check: fn(&Word) -> Vec<String>,
+}
+
+/// A rule broken at an address.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Finding {
+    pub address: u16,
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: {} [{}]",
+            radix::address(self.address),
+            self.severity.name(),
+            self.message,
+            self.rule
+        )
+    }
+}
+
+pub static RULES: &[Rule] = &[
+    Rule {
+        name: "obus-illegal",
+        severity: Severity::Error,
+        summary: "U OBUS CDR and U OBUS HTYPE must not be 3.",
+        check: obus_illegal,
+    },
+    Rule {
+        name: "disable-tasking",
+        severity: Severity::Error,
+        summary: "Disable tasking only works given in two words in a row.",
+        check: disable_tasking,
+    },
+    Rule {
+        name: "ifu-load-pc",
+        severity: Severity::Error,
+        summary: "IFU Load PC must be given with a load of VMA.",
+        check: ifu_load_pc,
+    },
+    Rule {
+        name: "amra-sel-lbus",
+        severity: Severity::Warning,
+        summary: "AMRA SEL 1 wants U AMRA<8:6> = 0 and U R BASE = 3.",
+        check: amra_sel_lbus,
+    },
+    Rule {
+        name: "bmem-constant",
+        severity: Severity::Warning,
+        summary: "B-memory constants 10-357 are only set by the image or an extended write.",
+        check: bmem_constant,
+    },
+    Rule {
+        name: "reserved-code",
+        severity: Severity::Warning,
+        summary: "Fields in use should not hold reserved or unassigned codes.",
+        check: reserved_code,
+    },
+];
+
+/// The rule with the given name.
+pub fn rule(name: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|r| r.name == name)
+}
+
+/// Whether a rule is turned on for an image.
+pub fn enabled(ucode: &Microcode, rule: &Rule) -> bool {
+    !ucode.annotations.disabled_rules.contains(rule.name)
+}
+
+/// Check the words from `from` to `to` with every rule that is turned
+/// on, leaving out the findings allowed at their address.
+pub fn check(ucode: &Microcode, from: u16, to: u16) -> Vec<Finding> {
+    let flow = Flow::new(ucode);
+    let extended_writes = ucode
+        .instructions()
+        .filter_map(|(_, u)| extended_write(&u))
+        .collect();
+    let rules: Vec<&Rule> = RULES.iter().filter(|r| enabled(ucode, r)).collect();
+
+    let mut findings = Vec::new();
+    for (address, inst) in ucode.instructions() {
+        if address < from || address > to {
+            continue;
+        }
+        let word = Word {
+            address,
+            inst,
+            ucode,
+            flow: &flow,
+            extended_writes: &extended_writes,
+        };
+        for rule in &rules {
+            if ucode.annotations.is_allowed(address, rule.name) {
+                continue;
+            }
+            for message in (rule.check)(&word) {
+                findings.push(Finding {
+                    address,
+                    rule: rule.name,
+                    severity: rule.severity,
+                    message,
+                });
+            }
+        }
+    }
+
+    findings.sort_by_key(|f| f.address);
+    findings
+}
+
+/// Findings as a JSON array, for other tools.
+pub fn to_json(findings: &[Finding]) -> String {
+    serde_json::to_string_pretty(findings).unwrap_or_default()
+}
+
+/// The B-memory address an extended write goes to.
+fn extended_write(u: &MicroInstruction) -> Option<u16> {
+    Some(u.b_write_address()).filter(|&addr| addr < B_MEM_SCRATCHPAD)
+}
+
+fn is_disable_tasking(ucode: &Microcode, addr: u16) -> bool {
+    ucode
+        .c_word(addr)
+        .is_some_and(|w| w.decode().spec() == SpecialFunction::DisableTasking)
+}
+
+//
+// Rules
+//
+
+fn obus_illegal(word: &Word) -> Vec<String> {
+    let mut messages = Vec::new();
+    if word.inst.obus_cdr() == ObusCdr::Reserved3 {
+        messages.push("U OBUS CDR is 3, which is illegal".to_string());
+    }
+    if word.inst.obus_htype() == ObusHtype::Reserved3 {
+        messages.push("U OBUS HTYPE is 3, which is illegal".to_string());
+    }
+    messages
+}
+
+/// A Disable Tasking must come straight after another, or be followed
+/// by one wherever the word goes next. Traps are not the next word.
+fn disable_tasking(word: &Word) -> Vec<String> {
+    if word.inst.spec() != SpecialFunction::DisableTasking {
+        return Vec::new();
+    }
+
+    let in_a_row = |kind: EdgeKind| kind != EdgeKind::Trap;
+    let after = word
+        .flow
+        .predecessors(word.address)
+        .iter()
+        .any(|e| in_a_row(e.kind) && is_disable_tasking(word.ucode, e.from));
+    let next: Vec<u16> = word
+        .flow
+        .successors(word.address)
+        .iter()
+        .filter(|e| in_a_row(e.kind))
+        .map(|e| e.to)
+        .collect();
+    let before = !next.is_empty() && next.iter().all(|a| is_disable_tasking(word.ucode, *a));
+
+    if after || before {
+        Vec::new()
+    } else {
+        vec!["disable tasking is not given in the word before or after".to_string()]
+    }
+}
+
+fn ifu_load_pc(word: &Word) -> Vec<String> {
+    let u = &word.inst;
+    if u.spec() == SpecialFunction::IfuControl && u.magic() <= 1 && u.mem() != MemOp::LoadVma {
+        vec![format!(
+            "IFU Load PC is given with U MEM {}, not load-vma",
+            u.mem()
+        )]
+    } else {
+        Vec::new()
+    }
+}
+
+fn amra_sel_lbus(word: &Word) -> Vec<String> {
+    let u = &word.inst;
+    if u.amra_sel() == AmraSel::Lbus && (u.abus_src().code() != 0 || u.r_base() != 3) {
+        vec![format!(
+            "AMRA SEL 1 with U AMRA<8:6> = {} and U R BASE = {}",
+            u.abus_src().code(),
+            u.r_base()
+        )]
+    } else {
+        Vec::new()
+    }
+}
+
+fn bmem_constant(word: &Word) -> Vec<String> {
+    let u = &word.inst;
+    let mut messages = Vec::new();
+
+    let read = u.bmra() as u16;
+    if (B_MEM_IMMEDIATES..B_MEM_SCRATCHPAD).contains(&read)
+        && !word.ucode.b_mem.iter().any(|w| w.address() == read)
+        && !word.extended_writes.contains(&read)
+    {
+        messages.push(format!(
+            "reads B-memory constant {}, which the image does not set and no word writes",
+            radix::padded(u64::from(read), 8)
+        ));
+    }
+
+    if let Some(write) = extended_write(u) {
+        if write < B_MEM_IMMEDIATES {
+            messages.push(format!(
+                "writes B-memory {}, which reads as the macroinstruction immediate",
+                radix::padded(u64::from(write), 8)
+            ));
+        }
+    }
+
+    messages
+}
+
+/// Reserved codes in fields the word uses. U COND SEL is also the byte
+/// size of some byte functions, so it only counts when tested.
+fn reserved_code(word: &Word) -> Vec<String> {
+    let u = &word.inst;
+    let mut messages = Vec::new();
+    let mut reserved = |title: &str, value: &dyn fmt::Display| {
+        messages.push(format!("{} is {}, which is not assigned", title, value));
+    };
+
+    if u.spec().is_reserved() {
+        reserved("U SPEC", &u.spec());
+    }
+    if u.cpc_sel() == CpcSel::Reserved3 {
+        reserved("U CPC SEL", &u.cpc_sel());
+    }
+    if u.cond_func() != CondFunc::Ignore && u.cond_sel().is_reserved() {
+        reserved("U COND SEL", &u.cond_sel());
+    }
+    let weird = (u.spec() == SpecialFunction::ArithTrap
+        || u.spec() == SpecialFunction::ArithTrapDispatch)
+        && u.magic() & 0x4 != 0;
+    if !weird && u.alu().is_reserved() {
+        reserved("U ALU", &u.alu());
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::image;
+
+    fn found(ucode: &Microcode) -> Vec<(u16, &'static str)> {
+        check(ucode, 0, 0o37777)
+            .iter()
+            .map(|f| (f.address, f.rule))
+            .collect()
+    }
+
+    #[test]
+    fn checks_single_words() {
+        let ucode = image(
+            "        .org 10\n\
+             npc-sel=next naf=11 obus-cdr=3\n\
+             npc-sel=next naf=12 spec=ifu magic=1 mem=read\n\
+             npc-sel=next naf=13 spec=ifu magic=0 mem=load-vma\n\
+             npc-sel=next naf=14 amra-sel=lbus r-base=3 abus-src=2\n\
+             npc-sel=next naf=15 spec=reserved-26 bmra=42\n",
+        );
+        assert_eq!(
+            found(&ucode),
+            vec![
+                (0o10, "obus-illegal"),
+                (0o11, "ifu-load-pc"),
+                (0o13, "amra-sel-lbus"),
+                (0o14, "bmem-constant"),
+                (0o14, "reserved-code"),
+            ]
+        );
+    }
+
+    #[test]
+    fn disable_tasking_needs_a_pair() {
+        let ucode = image(
+            "        .org 20\n\
+             npc-sel=next naf=21 spec=disable-tasking\n\
+             npc-sel=next naf=22 spec=disable-tasking\n\
+             npc-sel=next naf=23\n\
+             npc-sel=next naf=24 spec=disable-tasking\n\
+             npc-sel=next naf=24\n",
+        );
+        assert_eq!(found(&ucode), vec![(0o23, "disable-tasking")]);
+    }
+
+    #[test]
+    fn rules_can_be_allowed_and_turned_off() {
+        let mut ucode = image("npc-sel=next obus-cdr=3 obus-htype=3 spec=reserved-36\n");
+        assert_eq!(check(&ucode, 0, 0).len(), 3);
+        ucode.annotations.set_allowed(0, "obus-illegal", true);
+        assert_eq!(found(&ucode), vec![(0, "reserved-code")]);
+        ucode.annotations.set_rule_enabled("reserved-code", false);
+        assert!(found(&ucode).is_empty());
+        assert!(check(&image("npc-sel=next obus-cdr=3\n"), 1, 0o37777).is_empty());
+    }
+}
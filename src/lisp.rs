@@ -0,0 +1,103 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::fmt;
+
+use radix;
+
+//
+// Lisp words.
+//
+// A 36-bit 3600 word is a two bit CDR code <35:34> and either a
+// fixnum, when the high type bits <33:32> are zero, or a six bit data
+// type <33:28> and a 28-bit address.
+//
+
+const ADDRESS_MASK: u64 = 0x0fff_ffff;
+
+pub fn cdr_code(word: u64) -> u64 {
+    (word >> 34) & 0x3
+}
+
+pub fn high_type(word: u64) -> u64 {
+    (word >> 32) & 0x3
+}
+
+pub fn data_type(word: u64) -> u64 {
+    (word >> 28) & 0x3f
+}
+
+/// Fixnums carry a high type field of zero and a 32-bit immediate.
+pub fn is_fixnum(word: u64) -> bool {
+    high_type(word) == 0
+}
+
+/// The fields of a tagged word.
+#[derive(Clone, Copy, Serialize)]
+pub struct LispWord {
+    pub cdr_code: u8,
+    pub data_type: u8,
+    /// The immediate value, for fixnums.
+    pub fixnum: Option<i32>,
+    /// The address, for everything else.
+    pub address: Option<u32>,
+}
+
+impl LispWord {
+    pub fn new(word: u64) -> LispWord {
+        let fixnum = is_fixnum(word);
+        LispWord {
+            cdr_code: cdr_code(word) as u8,
+            data_type: data_type(word) as u8,
+            fixnum: if fixnum {
+                Some(word as u32 as i32)
+            } else {
+                None
+            },
+            address: if fixnum {
+                None
+            } else {
+                Some((word & ADDRESS_MASK) as u32)
+            },
+        }
+    }
+}
+
+impl fmt::Display for LispWord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.fixnum, self.address) {
+            (Some(n), _) => write!(
+                f,
+                "cdr {} fixnum {}{}",
+                radix::number(self.cdr_code as u64),
+                if n < 0 { "-" } else { "" },
+                radix::number((n as i64).unsigned_abs())
+            ),
+            (_, Some(a)) => write!(
+                f,
+                "cdr {} type {} address {}",
+                radix::number(self.cdr_code as u64),
+                radix::padded(self.data_type as u64, 6),
+                radix::padded(a as u64, 28)
+            ),
+            _ => Ok(()),
+        }
+    }
+}
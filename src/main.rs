@@ -20,175 +20,2140 @@
 ///
 extern crate clap;
 extern crate rustyline;
+extern crate uc_explorer;
 
-pub mod ucode;
+use clap::{App, Arg, ArgMatches, SubCommand};
 
-use clap::{App, Arg};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::{Cmd, CompletionType, Config, EditMode, Editor, Helper, KeyPress};
+
+use std::borrow::Cow::{self, Borrowed, Owned};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::io::Write;
+use std::process;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use uc_explorer::{
+    asm, color, command, export, expr, flow, gdb, hazard, ifu, layout, lint, nav, patch, project,
+    radix, shell, sim, timing, ucode,
+};
+
+use color::Style;
+use command::{optional, repeated, required, Command, Context};
+use flow::{EdgeKind, Flow};
+use layout::{Field, Layout};
+use nav::Cursor;
+use radix::Radix;
+use shell::{Output, Statement, SyntaxError};
+use sim::{Simulator, SparseMemory};
+use ucode::{MicroInstruction, Microcode, ADDRESS_MASK};
+
+// Commands print with these instead of print! and println!, so that
+// their output can be redirected or paged.
+macro_rules! out {
+    ($($arg:tt)*) => (shell::output(format_args!($($arg)*)));
+}
+
+macro_rules! outln {
+    () => (shell::output(format_args!("\n")));
+    ($($arg:tt)*) => (shell::output(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+static PROMPT: &str = "uc-explorer> ";
+const DEFAULT_GDB_PORT: u16 = 1234;
+
+pub enum HandlerError {
+    Io(io::Error),
+    ParseError,
+    Syntax(SyntaxError),
+    /// The command could not do what was asked, for the reason given.
+    Failed(String),
+}
+
+pub enum HandlerResult {
+    Handled,
+    /// The command ran but found nothing, as `grep` finds no lines,
+    /// or found problems, as `diff` finds differences.
+    NotFound,
+    Quit,
+}
+
+impl From<io::Error> for HandlerError {
+    fn from(err: io::Error) -> HandlerError {
+        HandlerError::Io(err)
+    }
+}
+
+impl From<SyntaxError> for HandlerError {
+    fn from(err: SyntaxError) -> HandlerError {
+        HandlerError::Syntax(err)
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandlerError::Io(ref err) => err.fmt(f),
+            HandlerError::ParseError => write!(f, "Parse Error"),
+            HandlerError::Syntax(ref err) => err.fmt(f),
+            HandlerError::Failed(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+fn do_dump(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    if words.len() == 2 {
+        outln!("Dumping to file {}...", words[1]);
+
+        let mut file = File::create(words[1])?;
+
+        // TODO: There must be a better way to write this.
+        match write!(file, "{}", ucode) {
+            Ok(_) => {}
+            Err(e) => return Err(HandlerError::Io(e)),
+        }
+    } else {
+        return Err(HandlerError::Failed("usage: dump [filename]".to_string()));
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_show(ucode: &mut Microcode, _words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    if ucode.path.is_some() {
+        outln!("Loaded From:     {}", ucode.path());
+        outln!("Version:         {}", radix::number(ucode.version as u64));
+        outln!("Commend:         {}", ucode.comment);
+        outln!("A-Mem Size:      {} words", ucode.a_mem.len());
+        outln!("B-Mem Size:      {} words", ucode.b_mem.len());
+        outln!("C-Mem Size:      {} words", ucode.c_mem.len());
+        outln!("Type Map Size:   {} words", ucode.type_map.len());
+        outln!("Pico Store Size: {} words", ucode.pico_store.len());
+    } else {
+        outln!("No microcode is loaded.");
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_load(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    cursor.clear();
+    if words.len() == 2 {
+        match ucode.load(words[1]) {
+            Ok(()) => {
+                outln!("Loaded file {}", ucode.path());
+                load_project(ucode);
+            }
+            Err(e) => return Err(HandlerError::Failed(format!("Cannot load file. {}", e))),
+        }
+    } else {
+        return Err(HandlerError::Failed("usage: load [filename]".to_string()));
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_asm(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    if words.len() == 2 {
+        match asm::assemble_file(words[1]) {
+            Ok(assembly) => {
+                let count = assembly.words.len();
+                for cword in assembly.words {
+                    // Words already there are edited, so they can be
+                    // undone and still know what they were.
+                    if !ucode.edit_c_word(cword) {
+                        ucode.store_c_word(cword);
+                    }
+                }
+                let mut labels: Vec<(&String, &u16)> = assembly.labels.iter().collect();
+                labels.sort_by_key(|&(_, addr)| *addr);
+                for (name, &addr) in labels {
+                    if let Err(e) = ucode.annotations.set_label(addr, Some(name)) {
+                        outln!("Label not kept: {}", e);
+                    }
+                }
+                outln!("Assembled {} words from {}", count, words[1]);
+            }
+            Err(e) => return Err(HandlerError::Failed(format!("Cannot assemble file. {}", e))),
+        }
+    } else {
+        return Err(HandlerError::Failed("usage: asm [filename]".to_string()));
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_disasm(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    if words.len() == 2 {
+        outln!("Disassembling to file {}...", words[1]);
+
+        let mut file = File::create(words[1])?;
+        write!(file, "{}", asm::disassemble(ucode))?;
+    } else {
+        return Err(HandlerError::Failed("usage: disasm [filename]".to_string()));
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_set(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    match words.get(1) {
+        Some(&"radix") => return do_set_radix(words),
+        Some(&"color") => return do_set_color(words),
+        Some(&"theme") => return do_set_theme(words),
+        _ => {}
+    }
+
+    if words.len() < 3 {
+        return Err(HandlerError::Failed(
+            "usage: set [address] [field=value ...]".to_string(),
+        ));
+    }
+
+    let address = parse_address(ucode, cursor, words[1])?;
+
+    let layout = layout::current();
+    let mut assignments = Vec::new();
+    {
+        let env = expr_env(ucode, cursor);
+        for assignment in words[2..].iter().filter(|w| !w.is_empty()) {
+            assignments.push(parse_assignment(&layout, &env, assignment)?);
+        }
+    }
+
+    let mut inst = match ucode.c_word(address) {
+        Some(cword) => MicroInstruction::new(cword),
+        None => {
+            return Err(HandlerError::Failed(format!(
+                "No control memory word at {}",
+                radix::address(address)
+            )));
+        }
+    };
+
+    let mut explicit_parity = false;
+    for (field, value) in assignments {
+        inst.set_field(field, value);
+        explicit_parity |= field.name == "parity";
+    }
+
+    if !explicit_parity {
+        inst.fix_parity();
+    }
+
+    let before = asm::disassemble_word(ucode.c_word(address).unwrap());
+    let cword = inst.encode(address);
+    ucode.edit_c_word(cword);
+    outln!("{}", color::listing(&format!("- {}", before)));
+    outln!(
+        "{}",
+        color::listing(&format!("+ {}", asm::disassemble_word(&cword)))
+    );
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_set_radix(words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    match words.len() {
+        2 => outln!("Radix is {}.", radix::current()),
+        3 => match words[2].parse::<u32>().ok().and_then(Radix::from_base) {
+            Some(r) => {
+                radix::set_current(r);
+                outln!("Radix is now {}.", r);
+            }
+            None => {
+                return Err(HandlerError::Failed(
+                    "usage: set radix [8|10|16]".to_string(),
+                ))
+            }
+        },
+        _ => {
+            return Err(HandlerError::Failed(
+                "usage: set radix [8|10|16]".to_string(),
+            ))
+        }
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_set_color(words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    match words.len() {
+        2 => outln!("Color is {}.", color::mode().name()),
+        3 => match color::Mode::named(words[2]) {
+            Some(mode) => {
+                color::set_mode(mode);
+                outln!("Color is now {}.", mode.name());
+            }
+            None => {
+                return Err(HandlerError::Failed(
+                    "usage: set color [on|off|auto]".to_string(),
+                ))
+            }
+        },
+        _ => {
+            return Err(HandlerError::Failed(
+                "usage: set color [on|off|auto]".to_string(),
+            ))
+        }
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_set_theme(words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    let names: Vec<&str> = color::THEMES.iter().map(|t| t.name).collect();
+    let usage = || HandlerError::Failed(format!("usage: set theme [{}]", names.join("|")));
+    match words.len() {
+        2 => outln!("Theme is {}.", color::theme().name),
+        3 => match color::Theme::named(words[2]) {
+            Some(theme) => {
+                color::set_theme(theme);
+                outln!("Theme is now {}.", theme.name);
+            }
+            None => return Err(usage()),
+        },
+        _ => return Err(usage()),
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+/// Parse a `field=value` argument against the layout. The NAF is an
+/// address, and can be given as an address expression.
+fn parse_assignment<'a>(
+    layout: &'a Layout,
+    env: &expr::Env,
+    assignment: &str,
+) -> Result<(&'a Field, u64), HandlerError> {
+    let mut parts = assignment.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let field = match layout.field(name) {
+        Some(f) => f,
+        None => return Err(HandlerError::Failed(format!("Unknown field '{}'", name))),
+    };
+    let text = parts.next().unwrap_or("");
+    let value = match asm::parse_value(field, text, radix::current()) {
+        Some(v) => Some(v),
+        None if name == "naf" => match expr::address(env, text) {
+            Ok(addr) => Some(addr as u64),
+            Err(e) => {
+                return Err(HandlerError::Failed(format!(
+                    "Bad value in '{}'. {}",
+                    assignment, e
+                )))
+            }
+        },
+        None => None,
+    };
+    match value {
+        Some(v) if field.fits(v) => Ok((field, v)),
+        _ => Err(HandlerError::Failed(format!(
+            "Bad value in '{}'",
+            assignment
+        ))),
+    }
+}
+
+/// List the control memory words whose fields have all the given
+/// values, optionally only in a range of addresses.
+fn do_find(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    let (range, assignments) = match words.get(1) {
+        Some(w) if !w.contains('=') => (parse_range(ucode, cursor, w)?, &words[2..]),
+        _ => ((0, ADDRESS_MASK), &words[1..]),
+    };
+    if assignments.is_empty() {
+        return Err(HandlerError::Failed(
+            "usage: find [range] [field=value ...]".to_string(),
+        ));
+    }
+
+    let layout = layout::current();
+    let env = expr_env(ucode, cursor);
+    let mut wanted = Vec::new();
+    for assignment in assignments.iter().filter(|w| !w.is_empty()) {
+        wanted.push(parse_assignment(&layout, &env, assignment)?);
+    }
+
+    let mut found = 0;
+    let in_range = |a: u16| a >= range.0 && a <= range.1;
+    for cword in ucode.c_mem.mem.iter().filter(|w| in_range(w.address())) {
+        let word = cword.bits();
+        if wanted
+            .iter()
+            .all(|&(field, value)| field.get(word) == value)
+        {
+            outln!(
+                "{}",
+                color::listing(&asm::disassemble_annotated(ucode, cword))
+            );
+            found += 1;
+        }
+    }
+
+    if found == 0 {
+        outln!("No words match.");
+        return Ok(HandlerResult::NotFound);
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_undo(ucode: &mut Microcode, _words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    match ucode.undo() {
+        Some(edit) => outln!("{}", color::listing(&asm::disassemble_word(&edit.before))),
+        None => outln!("Nothing to undo."),
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_redo(ucode: &mut Microcode, _words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    match ucode.redo() {
+        Some(edit) => outln!("{}", color::listing(&asm::disassemble_word(&edit.after))),
+        None => outln!("Nothing to redo."),
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_modified(ucode: &mut Microcode, _words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    let modified = ucode.modified();
+    if modified.is_empty() {
+        outln!("No words have been modified.");
+    }
+    for (original, current) in modified {
+        outln!(
+            "{}",
+            color::listing(&format!("- {}", asm::disassemble_word(original)))
+        );
+        outln!(
+            "{}",
+            color::listing(&format!("+ {}", asm::disassemble_word(current)))
+        );
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_save(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    if words.len() == 2 {
+        match ucode.save(words[1]) {
+            Ok(()) => {
+                outln!("Saved to file {}", words[1]);
+                // Keep the annotations with the image they describe.
+                if !ucode.annotations.is_empty() {
+                    if let Err(e) = project::save_for(ucode, words[1]) {
+                        outln!("Cannot save project file. {}", e);
+                    }
+                }
+            }
+            Err(e) => return Err(HandlerError::Failed(format!("Cannot save file. {}", e))),
+        }
+    } else {
+        return Err(HandlerError::Failed("usage: save [filename]".to_string()));
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_apply(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    if words.len() != 2 && words.len() != 3 {
+        return Err(HandlerError::Failed(
+            "usage: apply [patch] [output]".to_string(),
+        ));
+    }
+
+    let result = patch::parse_file(words[1]).and_then(|p| p.apply(ucode));
+    match result {
+        Ok(count) => {
+            outln!("Applied {}, {} locations changed", words[1], count);
+            if words.len() == 3 {
+                do_save(ucode, vec!["save", words[2]])?;
+            }
+        }
+        Err(e) => return Err(HandlerError::Failed(format!("Cannot apply patch. {}", e))),
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_mkpatch(_ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    if words.len() != 4 {
+        return Err(HandlerError::Failed(
+            "usage: mkpatch [old] [new] [patch]".to_string(),
+        ));
+    }
+
+    let mut old = Microcode::new();
+    let mut new = Microcode::new();
+    if let Err(e) = old.load(words[1]).and_then(|_| new.load(words[2])) {
+        return Err(HandlerError::Failed(format!("Cannot load file. {}", e)));
+    }
+
+    outln!("Writing patch to file {}...", words[3]);
+    let mut file = File::create(words[3])?;
+    write!(file, "{}", patch::diff(&old, &new))?;
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_layout(_ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    match words.len() {
+        1 => out!("{}", layout_summary()),
+        2 => match Layout::load(words[1]) {
+            Ok(l) => {
+                layout::set_current(l);
+                outln!("Loaded layout {}", words[1]);
+            }
+            Err(e) => return Err(HandlerError::Failed(format!("Cannot load layout. {}", e))),
+        },
+        _ => return Err(HandlerError::Failed("usage: layout [file]".to_string())),
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn layout_summary() -> String {
+    let mut text = String::new();
+    for field in &layout::current().fields {
+        let bits = if field.width == 1 {
+            format!("{}", field.lo)
+        } else {
+            format!("{}-{}", field.lo + field.width - 1, field.lo)
+        };
+        let group = match field.group {
+            Some(ref g) => format!(" (in {})", g),
+            None => String::new(),
+        };
+        text.push_str(&format!(
+            "{:<16}{:<10}{}{}\n",
+            field.name, bits, field.title, group
+        ));
+    }
+    text
+}
+
+fn export_usage() -> HandlerError {
+    HandlerError::Failed(
+        "usage: export json [file]\n       \
+         export csv|tsv [file] [fields=f1,f2,...] [radix=8|10|16]\n       \
+         export readmemh|readmemb|ihex|bin-le|bin-be cmem|amem|bmem|typemap [file]\n              \
+         [width=bits] [fill=value]\n       \
+         export source [file] --lang rust|c\n       \
+         export html [directory]"
+            .to_string(),
+    )
+}
+
+fn do_export(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    if words.len() < 3 {
+        return Err(export_usage());
+    }
+
+    match (words[1], words.len()) {
+        ("json", 3) => {
+            outln!("Exporting JSON to file {}...", words[2]);
+            let file = File::create(words[2])?;
+            export::json::write(ucode, io::BufWriter::new(file))?;
+        }
+        ("csv", _) | ("tsv", _) => {
+            let separator = if words[1] == "csv" { ',' } else { '\t' };
+            match export::csv::CsvOptions::parse(separator, &words[3..]) {
+                Ok(options) => {
+                    outln!(
+                        "Exporting {} to file {}...",
+                        words[1].to_uppercase(),
+                        words[2]
+                    );
+                    let file = File::create(words[2])?;
+                    export::csv::write(ucode, &options, io::BufWriter::new(file))?;
+                }
+                Err(e) => return Err(HandlerError::Failed(format!("Cannot export. {}", e))),
+            }
+        }
+        ("html", 3) => {
+            outln!("Exporting HTML report to directory {}...", words[2]);
+            let pages = export::html::export(ucode, words[2])?;
+            outln!("Wrote {} pages.", pages);
+        }
+        ("source", 5) if words[3] == "--lang" => match export::source::Lang::named(words[4]) {
+            Some(lang) => {
+                outln!("Exporting {} source to file {}...", words[4], words[2]);
+                let file = File::create(words[2])?;
+                export::source::write(ucode, lang, io::BufWriter::new(file))?;
+            }
+            None => return Err(export_usage()),
+        },
+        (format, _) if export::rom::Format::named(format).is_some() => {
+            let format = export::rom::Format::named(format).unwrap();
+            let memory = match export::rom::Memory::named(words[2]) {
+                Some(memory) => memory,
+                None => {
+                    return Err(export_usage());
+                }
+            };
+            if words.len() < 4 {
+                return Err(export_usage());
+            }
+            match export::rom::RomOptions::parse(memory, format, &words[4..]) {
+                Ok(options) => {
+                    outln!("Exporting {} to file {}...", words[2], words[3]);
+                    export::rom::export(ucode, &options, words[3])?;
+                }
+                Err(e) => return Err(HandlerError::Failed(format!("Cannot export. {}", e))),
+            }
+        }
+        _ => return Err(export_usage()),
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+/// What the names in address expressions refer to.
+fn expr_env<'a>(ucode: &'a Microcode, cursor: &Cursor) -> expr::Env<'a> {
+    expr::Env {
+        ucode,
+        current: cursor.current(),
+        ctos: cursor.ctos(),
+    }
+}
+
+/// A control memory address, given as an expression.
+fn parse_address(ucode: &Microcode, cursor: &Cursor, token: &str) -> Result<u16, HandlerError> {
+    expr::address(&expr_env(ucode, cursor), token)
+        .map_err(|e| HandlerError::Failed(format!("Bad address '{}'. {}", token, e)))
+}
+
+/// A range of control memory addresses, as "from..to" or one address.
+fn parse_range(
+    ucode: &Microcode,
+    cursor: &Cursor,
+    token: &str,
+) -> Result<(u16, u16), HandlerError> {
+    expr::range(&expr_env(ucode, cursor), token)
+        .map_err(|e| HandlerError::Failed(format!("Bad range '{}'. {}", token, e)))
+}
+
+/// A count, given as an expression.
+fn parse_count(ucode: &Microcode, cursor: &Cursor, token: &str) -> Result<usize, HandlerError> {
+    expr::count(&expr_env(ucode, cursor), token)
+        .map_err(|e| HandlerError::Failed(format!("Bad count '{}'. {}", token, e)))
+}
+
+/// Read the project file of a newly loaded image, if it has one.
+fn load_project(ucode: &mut Microcode) {
+    match project::load(ucode) {
+        Ok(Some(report)) => {
+            outln!(
+                "Loaded {} annotations from {}",
+                report.annotations,
+                report.path
+            );
+            for (from, to) in &report.relocated {
+                outln!(
+                    "  {} has moved to {}",
+                    radix::address(*from),
+                    radix::address(*to)
+                );
+            }
+            for addr in &report.unmatched {
+                outln!(
+                    "  {} has changed and was not found elsewhere",
+                    radix::address(*addr)
+                );
+            }
+        }
+        Ok(None) => {}
+        Err(e) => outln!("Cannot load project file. {}", e),
+    }
+}
+
+fn save_project(ucode: &Microcode) {
+    if let Err(e) = project::save(ucode) {
+        outln!("Cannot save project file. {}", e);
+    }
+}
+
+/// Parse the address argument of the annotation commands, which need
+/// a loaded image to keep the project file next to.
+fn annotation_address(
+    ucode: &Microcode,
+    cursor: &Cursor,
+    token: &str,
+) -> Result<u16, HandlerError> {
+    if ucode.path.is_none() {
+        return Err(HandlerError::Failed("No microcode is loaded.".to_string()));
+    }
+    parse_address(ucode, cursor, token)
+}
+
+fn do_label(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    match words.len() {
+        1 => {
+            for (addr, label) in &ucode.annotations.labels {
+                outln!("{}  {}", radix::address(*addr), label);
+            }
+        }
+        2 | 3 => {
+            let addr = annotation_address(ucode, cursor, words[1])?;
+            match ucode.annotations.set_label(addr, words.get(2).cloned()) {
+                Ok(()) => save_project(ucode),
+                Err(e) => {
+                    return Err(HandlerError::Failed(format!(
+                        "Cannot label {}. {}",
+                        radix::address(addr),
+                        e
+                    )))
+                }
+            }
+        }
+        _ => {
+            return Err(HandlerError::Failed(
+                "usage: label [address] [name]".to_string(),
+            ))
+        }
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_comment(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    if words.len() == 1 {
+        for (addr, comment) in &ucode.annotations.comments {
+            outln!("{}  {}", radix::address(*addr), comment);
+        }
+    } else {
+        let addr = annotation_address(ucode, cursor, words[1])?;
+        let text = words[2..].join(" ");
+        let text = text.trim();
+        ucode
+            .annotations
+            .set_comment(addr, if text.is_empty() { None } else { Some(text) });
+        save_project(ucode);
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+fn do_entry(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    match (words.len(), words.get(2).cloned()) {
+        (1, _) => {
+            for addr in &ucode.annotations.entries {
+                match ucode.annotations.label(*addr) {
+                    Some(label) => outln!("{}  {}", radix::address(*addr), label),
+                    None => outln!("{}", radix::address(*addr)),
+                }
+            }
+        }
+        (2, _) | (3, Some("off")) => {
+            let addr = annotation_address(ucode, cursor, words[1])?;
+            ucode.annotations.set_entry(addr, words.len() == 2);
+            save_project(ucode);
+        }
+        _ => {
+            return Err(HandlerError::Failed(
+                "usage: entry [address] [off]".to_string(),
+            ))
+        }
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+//
+// Lint
+//
+
+fn lint_usage() -> HandlerError {
+    HandlerError::Failed(
+        "usage: lint [range] [format=text|json]\n       \
+         lint rules\n       \
+         lint on|off <rule>...\n       \
+         lint allow [address rule [off]]"
+            .to_string(),
+    )
+}
+
+fn do_lint(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    match words.get(1) {
+        Some(&"rules") if words.len() == 2 => {
+            for rule in lint::RULES {
+                let state = if lint::enabled(ucode, rule) {
+                    "on"
+                } else {
+                    "off"
+                };
+                outln!(
+                    "{:<16} {:<8} {:<4} {}",
+                    rule.name,
+                    rule.severity.name(),
+                    state,
+                    rule.summary
+                );
+            }
+            return Ok(HandlerResult::Handled);
+        }
+        Some(&"on") | Some(&"off") if words.len() > 2 => {
+            for name in &words[2..] {
+                if lint::rule(name).is_none() {
+                    return Err(HandlerError::Failed(format!("No lint rule '{}'.", name)));
+                }
+            }
+            for name in &words[2..] {
+                ucode.annotations.set_rule_enabled(name, words[1] == "on");
+            }
+            if ucode.path.is_some() {
+                save_project(ucode);
+            }
+            return Ok(HandlerResult::Handled);
+        }
+        Some(&"allow") => return do_lint_allow(ucode, cursor, words),
+        _ => {}
+    }
+
+    let mut range = (0, ADDRESS_MASK);
+    let mut json = false;
+    for word in &words[1..] {
+        match *word {
+            "format=text" => json = false,
+            "format=json" => json = true,
+            w if !w.contains('=') => range = parse_range(ucode, cursor, w)?,
+            _ => return Err(lint_usage()),
+        }
+    }
+
+    let findings = lint::check(ucode, range.0, range.1);
+    if json {
+        outln!("{}", lint::to_json(&findings));
+    } else if findings.is_empty() {
+        outln!("No problems found.");
+    } else {
+        for finding in &findings {
+            let style = match finding.severity {
+                lint::Severity::Error => Style::Error,
+                lint::Severity::Warning => Style::Warning,
+            };
+            outln!(
+                "{}: {}: {} [{}]",
+                color::paint(Style::Address, &radix::address(finding.address)),
+                color::paint(style, finding.severity.name()),
+                finding.message,
+                finding.rule
+            );
+        }
+    }
+
+    if findings.is_empty() {
+        Ok(HandlerResult::Handled)
+    } else {
+        Ok(HandlerResult::NotFound)
+    }
+}
+
+/// List the rules allowed at addresses, or allow one at an address.
+fn do_lint_allow(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    match (words.len(), words.get(4).cloned()) {
+        (2, _) => {
+            for (addr, rules) in &ucode.annotations.allowed {
+                let rules: Vec<&str> = rules.iter().map(|r| &r[..]).collect();
+                outln!("{}  {}", radix::address(*addr), rules.join(" "));
+            }
+        }
+        (4, _) | (5, Some("off")) => {
+            let addr = annotation_address(ucode, cursor, words[2])?;
+            if lint::rule(words[3]).is_none() {
+                return Err(HandlerError::Failed(format!(
+                    "No lint rule '{}'.",
+                    words[3]
+                )));
+            }
+            ucode
+                .annotations
+                .set_allowed(addr, words[3], words.len() == 4);
+            save_project(ucode);
+        }
+        _ => return Err(lint_usage()),
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+//
+// Hazards
+//
+
+fn do_hazards(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    let mut range = (0, ADDRESS_MASK);
+    let mut json = false;
+    for word in &words[1..] {
+        match *word {
+            "format=text" => json = false,
+            "format=json" => json = true,
+            w if !w.contains('=') => range = parse_range(ucode, cursor, w)?,
+            _ => {
+                return Err(HandlerError::Failed(
+                    "usage: hazards [range] [format=text|json]".to_string(),
+                ))
+            }
+        }
+    }
+
+    let hazards = hazard::analyse(ucode, range.0, range.1);
+    if json {
+        outln!("{}", hazard::to_json(&hazards));
+    } else if hazards.is_empty() {
+        outln!("No hazards found.");
+    } else {
+        for h in &hazards {
+            outln!(
+                "{}: {}: {}",
+                color::paint(Style::Address, &radix::address(h.address)),
+                color::paint(Style::Warning, h.kind),
+                h.message
+            );
+            for step in &h.steps {
+                let line = format!(
+                    "    cycle {}  {}  {}",
+                    step.cycle,
+                    nav::address_name(ucode, step.address),
+                    step.note
+                );
+                outln!("{}", line.trim_end());
+            }
+        }
+    }
+
+    if hazards.is_empty() {
+        Ok(HandlerResult::Handled)
+    } else {
+        Ok(HandlerResult::NotFound)
+    }
+}
+
+//
+// Timing
+//
+
+fn plural(n: u32, what: &str) -> String {
+    if n == 1 {
+        format!("{} {}", n, what)
+    } else {
+        format!("{} {}s", n, what)
+    }
+}
+
+/// Some words of a path, as addresses.
+fn path_text(path: &[u16]) -> String {
+    const SHOWN: usize = 12;
+    let mut text: Vec<String> = path
+        .iter()
+        .take(SHOWN)
+        .map(|a| radix::address(*a))
+        .collect();
+    if path.len() > SHOWN {
+        text.push(format!("... ({} words)", path.len()));
+    }
+    text.join(" ")
+}
+
+fn show_bounds(bounds: &timing::Bounds) -> HandlerResult {
+    let (best, worst) = match (bounds.best, bounds.worst) {
+        (Some(b), Some(w)) => (b, w),
+        _ => {
+            outln!("  No path found.");
+            return HandlerResult::NotFound;
+        }
+    };
+    outln!(
+        "  best   {:>8}  {}",
+        format!("{} ns", best.ns),
+        plural(best.cycles, "cycle")
+    );
+    outln!(
+        "  worst  {:>8}  {}",
+        format!("{} ns", worst.ns),
+        plural(worst.cycles, "cycle")
+    );
+    outln!("  best path   {}", path_text(&bounds.best_path));
+    outln!("  worst path  {}", path_text(&bounds.worst_path));
+    if !bounds.unbounded.is_empty() {
+        let words: Vec<String> = bounds
+            .unbounded
+            .iter()
+            .map(|a| radix::address(*a))
+            .collect();
+        outln!("  Loops with no bound, taken once, at {}.", words.join(" "));
+    }
+    if bounds.incomplete {
+        outln!("  Not every path could be followed; the times are for those that were.");
+    }
+    HandlerResult::Handled
+}
 
-use rustyline::{Cmd, CompletionType, Config, EditMode, Editor, Helper, KeyPress};
-use rustyline::completion::{Completer, FilenameCompleter, Pair};
-use rustyline::error::ReadlineError;
-use rustyline::highlight::Highlighter;
-use rustyline::hint::Hinter;
+fn do_timing(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    let flow = Flow::new(ucode);
+    let mut timer = timing::Timer::new(ucode, &flow);
 
-use std::fmt;
-use std::fs::File;
-use std::io;
-use std::io::Write;
-use std::vec::Vec;
-use std::borrow::Cow::{self, Borrowed, Owned};
+    if words.len() == 3 {
+        let from = parse_address(ucode, cursor, words[1])?;
+        let to = parse_address(ucode, cursor, words[2])?;
+        outln!(
+            "{} to {}:",
+            nav::address_name(ucode, from),
+            nav::address_name(ucode, to)
+        );
+        return Ok(show_bounds(&timer.between(from, Some(to))));
+    }
+
+    let addr = match words.get(1) {
+        Some(word) if words.len() == 2 => parse_address(ucode, cursor, word)?,
+        None => match cursor.current() {
+            Some(addr) => addr,
+            None => return Err(HandlerError::Failed("No current address.".to_string())),
+        },
+        _ => {
+            return Err(HandlerError::Failed(
+                "usage: timing [address [to]]".to_string(),
+            ))
+        }
+    };
+    let routine = match flow.routine(addr) {
+        Some(r) => r,
+        None => {
+            return Err(HandlerError::Failed(format!(
+                "No word at {}.",
+                radix::address(addr)
+            )))
+        }
+    };
+
+    outln!(
+        "Routine {}, {}:",
+        nav::address_name(ucode, routine.entry),
+        plural(routine.words.len() as u32, "word")
+    );
+    for block in timing::blocks(ucode, &flow, routine) {
+        let first = block.words[0];
+        let last = block.words[block.words.len() - 1];
+        let span = if first == last {
+            radix::address(first)
+        } else {
+            format!("{}-{}", radix::address(first), radix::address(last))
+        };
+        outln!(
+            "  {:<12} {:>8}  {}",
+            span,
+            format!("{} ns", block.cost.ns),
+            plural(block.cost.cycles, "cycle")
+        );
+    }
+    outln!("Entry to exit:");
+    Ok(show_bounds(&timer.between(routine.entry, None)))
+}
+
+/// List the loop bounds, or set or remove one.
+fn do_loop(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    match words.len() {
+        1 => {
+            for (addr, bound) in &ucode.annotations.loop_bounds {
+                outln!("{}  {}", nav::address_name(ucode, *addr), bound);
+            }
+        }
+        3 => {
+            let addr = annotation_address(ucode, cursor, words[1])?;
+            let bound = match words[2] {
+                "off" => None,
+                word => match parse_count(ucode, cursor, word)? {
+                    0 => {
+                        return Err(HandlerError::Failed(
+                            "A loop runs at least once.".to_string(),
+                        ))
+                    }
+                    n => Some(n as u32),
+                },
+            };
+            ucode.annotations.set_loop_bound(addr, bound);
+            save_project(ucode);
+        }
+        _ => {
+            return Err(HandlerError::Failed(
+                "usage: loop [address count|off]".to_string(),
+            ))
+        }
+    }
+
+    Ok(HandlerResult::Handled)
+}
 
-use ucode::Microcode;
+//
+// Macroinstructions
+//
 
-static PROMPT: &'static str = "uc-explorer> ";
-static COLORED_PROMPT: &'static str = "\x1b[1;32muc-explorer>\x1b[0m ";
+fn opcodes_usage() -> HandlerError {
+    HandlerError::Failed(
+        "usage: opcodes [entries]\n       \
+         opcodes import <file>\n       \
+         opcodes set <opcode> <address> [name]\n       \
+         opcodes off <opcode>"
+            .to_string(),
+    )
+}
 
-struct Hints {}
+fn parse_opcode(ucode: &Microcode, word: &str) -> Result<u16, HandlerError> {
+    ifu::opcode(ucode, word).ok_or_else(|| HandlerError::Failed(format!("No opcode '{}'.", word)))
+}
 
-impl Hinter for Hints {
-    fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
-        None // Hints are not yet implemented
+fn show_addresses(ucode: &Microcode, heading: &str, addresses: &[u16]) {
+    outln!("{}", heading);
+    if addresses.is_empty() {
+        outln!("  none");
+    }
+    for addr in addresses {
+        outln!("  {}", nav::address_name(ucode, *addr));
     }
 }
 
-pub enum HandlerError {
-    Io(io::Error),
-    ParseError,
+fn do_opcodes(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    match (words.get(1).cloned(), words.len()) {
+        (None, _) => {
+            if ucode.annotations.opcodes.is_empty() {
+                outln!("No opcodes. Import a list with \"opcodes import <file>\".");
+                return Ok(HandlerResult::NotFound);
+            }
+            for (code, op) in &ucode.annotations.opcodes {
+                let handler = op.handler.map(|a| nav::address_name(ucode, a));
+                outln!(
+                    "{:>6}  {:<24} {}",
+                    radix::number(*code as u64),
+                    op.name.as_ref().map_or("-", |n| &n[..]),
+                    handler.as_ref().map_or("-", |h| &h[..])
+                );
+            }
+        }
+        (Some("entries"), 2) => {
+            let flow = Flow::new(ucode);
+            show_addresses(ucode, "IFU dispatches:", &ifu::dispatch_sites(ucode));
+            outln!("Decode memory writes:");
+            for (addr, half) in ifu::decode_writes(ucode) {
+                outln!("  {}  {}", nav::address_name(ucode, addr), half.name());
+            }
+            show_addresses(
+                ucode,
+                "Words only a dispatch reaches, not yet handlers:",
+                &ifu::candidates(ucode, &flow),
+            );
+        }
+        (Some("import"), 3) => {
+            if ucode.path.is_none() {
+                return Err(HandlerError::Failed("No microcode is loaded.".to_string()));
+            }
+            let count = ifu::import(ucode, words[2]).map_err(|e| {
+                HandlerError::Failed(format!("Unable to import {}: {}", words[2], e))
+            })?;
+            outln!("Imported {} opcodes.", count);
+            save_project(ucode);
+        }
+        (Some("set"), 4) | (Some("set"), 5) => {
+            let code = parse_opcode(ucode, words[2])?;
+            let handler = annotation_address(ucode, cursor, words[3])?;
+            let old = ucode
+                .annotations
+                .opcodes
+                .get(&code)
+                .cloned()
+                .unwrap_or_default();
+            let name = words.get(4).map(|n| n.to_string()).or(old.name);
+            let op = project::Opcode {
+                name,
+                handler: Some(handler),
+            };
+            ucode.annotations.set_opcode(code, Some(op));
+            save_project(ucode);
+        }
+        (Some("off"), 3) => {
+            let code = parse_opcode(ucode, words[2])?;
+            ucode.annotations.set_opcode(code, None);
+            save_project(ucode);
+        }
+        _ => return Err(opcodes_usage()),
+    }
+
+    Ok(HandlerResult::Handled)
 }
 
-pub enum HandlerResult {
-    Handled,
-    Quit,
+/// Go to the handler of a macroinstruction and show what it calls.
+fn do_macro(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    if words.len() != 2 {
+        return Err(HandlerError::Failed("usage: macro <opcode>".to_string()));
+    }
+
+    let code = parse_opcode(ucode, words[1])?;
+    let op = ucode
+        .annotations
+        .opcodes
+        .get(&code)
+        .cloned()
+        .unwrap_or_default();
+    let name = op.name.unwrap_or_else(|| radix::number(code as u64));
+    let handler = match op.handler {
+        Some(addr) => addr,
+        None => {
+            return Err(HandlerError::Failed(format!(
+                "No handler is known for {}. Give one with \"opcodes set\".",
+                name
+            )))
+        }
+    };
+
+    cursor.goto(handler);
+    show_current(ucode, cursor);
+
+    let flow = Flow::new(ucode);
+    let tree = ifu::call_tree(&flow, handler);
+    outln!("{} ({}):", name, radix::number(code as u64));
+    if tree.is_empty() {
+        outln!("  calls nothing");
+    }
+    for branch in &tree {
+        outln!(
+            "{}{} {}{}",
+            "  ".repeat(branch.depth),
+            branch.kind.name(),
+            nav::address_name(ucode, branch.entry),
+            if branch.repeated { " ..." } else { "" }
+        );
+    }
+
+    Ok(HandlerResult::Handled)
 }
 
-impl From<io::Error> for HandlerError {
-    fn from(err: io::Error) -> HandlerError {
-        HandlerError::Io(err)
+//
+// Navigation
+//
+
+fn show_current(ucode: &Microcode, cursor: &Cursor) {
+    match cursor.current() {
+        Some(addr) => outln!("{}", color::listing(&nav::describe(ucode, addr))),
+        None => outln!("No current address."),
     }
 }
 
-impl fmt::Display for HandlerError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            HandlerError::Io(ref err) => err.fmt(f),
-            HandlerError::ParseError => write!(f, "Parse Error"),
+fn do_goto(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    if words.len() != 2 {
+        return Err(HandlerError::Failed("usage: goto [address]".to_string()));
+    }
+
+    let addr = parse_address(ucode, cursor, words[1])?;
+    cursor.goto(addr);
+    show_current(ucode, cursor);
+
+    Ok(HandlerResult::Handled)
+}
+
+/// Show the words in a range of addresses.
+fn do_list(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    if words.len() != 2 {
+        return Err(HandlerError::Failed("usage: list [range]".to_string()));
+    }
+
+    let (from, to) = parse_range(ucode, cursor, words[1])?;
+    let mut found = 0;
+    for cword in ucode
+        .c_mem
+        .mem
+        .iter()
+        .filter(|w| w.address() >= from && w.address() <= to)
+    {
+        outln!(
+            "{}",
+            color::listing(&asm::disassemble_annotated(ucode, cword))
+        );
+        found += 1;
+    }
+
+    if found == 0 {
+        outln!(
+            "No words from {} to {}.",
+            radix::address(from),
+            radix::address(to)
+        );
+        return Ok(HandlerResult::NotFound);
+    }
+
+    Ok(HandlerResult::Handled)
+}
+
+/// Step to the next populated word, or with `forward` false, the
+/// previous one, an optional number of times.
+fn do_step(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+    forward: bool,
+) -> Result<HandlerResult, HandlerError> {
+    let count = match words.get(1) {
+        None => 1,
+        Some(n) => parse_count(ucode, cursor, n)?,
+    };
+    if count == 0 || words.len() > 2 {
+        return Err(HandlerError::Failed(format!("usage: {} [count]", words[0])));
+    }
+
+    let mut addr = cursor.current();
+    for _ in 0..count {
+        let next = match addr {
+            Some(a) => nav::neighbour(ucode, a, forward),
+            None if forward => ucode.c_mem.mem.first().map(|w| w.address()),
+            None => ucode.c_mem.mem.last().map(|w| w.address()),
+        };
+        match next {
+            Some(a) => addr = Some(a),
+            None => break,
+        }
+    }
+
+    match addr {
+        Some(a) if Some(a) != cursor.current() => {
+            cursor.step_to(a);
+            show_current(ucode, cursor);
         }
+        _ if forward => outln!("No next word."),
+        _ => outln!("No previous word."),
     }
+
+    Ok(HandlerResult::Handled)
 }
 
-fn do_dump(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
-    if words.len() == 2 {
-        println!("Dumping to file {}...", words[1]);
+fn do_follow(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    let addr = match cursor.current() {
+        Some(addr) => addr,
+        None => {
+            return Err(HandlerError::Failed("No current address.".to_string()));
+        }
+    };
 
-        let mut file = File::create(&words[1])?;
+    let targets = nav::targets(ucode, addr);
+    // A return goes to the top of the control stack, once following a
+    // call has put something there.
+    if targets.is_empty() && words.len() == 1 && nav::returns(ucode, addr) && cursor.ret().is_some()
+    {
+        show_current(ucode, cursor);
+        return Ok(HandlerResult::Handled);
+    }
 
-        // TODO: There must be a better way to write this.
-        match write!(file, "{}", ucode) {
-            Ok(_) => {}
-            Err(e) => return Err(HandlerError::Io(e)),
+    let chosen = match (words.get(1), targets.len()) {
+        (None, 0) => {
+            outln!("No known target from {}.", radix::address(addr));
+            None
         }
-    } else {
-        println!("usage: dump [filename]");
+        (None, 1) => Some(targets[0]),
+        (None, _) => {
+            for (i, e) in targets.iter().enumerate() {
+                outln!(
+                    "{:>3}  {:<8} {}",
+                    i + 1,
+                    e.kind.name(),
+                    nav::address_name(ucode, e.to)
+                );
+            }
+            outln!("Use follow [n] or follow [kind] to choose.");
+            None
+        }
+        (Some(choice), _) => {
+            let by_kind = targets.iter().find(|e| e.kind.name() == *choice);
+            let by_number = || {
+                expr::count(&expr_env(ucode, cursor), choice)
+                    .ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|i| targets.get(i))
+            };
+            match by_kind.or_else(by_number) {
+                Some(e) => Some(*e),
+                None => {
+                    outln!("No target '{}' from {}.", choice, radix::address(addr));
+                    None
+                }
+            }
+        }
+    };
+
+    if let Some(e) = chosen {
+        if e.kind == EdgeKind::Call {
+            cursor.call(e.to);
+        } else {
+            cursor.goto(e.to);
+        }
+        show_current(ucode, cursor);
     }
 
     Ok(HandlerResult::Handled)
 }
 
-fn do_show(ucode: &mut Microcode, _words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
-    if ucode.path.is_some() {
-        println!("Loaded From:     {}", ucode.path());
-        println!("Version:         {}", ucode.version);
-        println!("Commend:         {}", ucode.comment);
-        println!("A-Mem Size:      {} words", ucode.a_mem.len());
-        println!("B-Mem Size:      {} words", ucode.b_mem.len());
-        println!("C-Mem Size:      {} words", ucode.c_mem.len());
-        println!("Type Map Size:   {} words", ucode.type_map.len());
-        println!("Pico Store Size: {} words", ucode.pico_store.len());
+/// Go back through the jump history, or with `back` false, forward.
+fn do_history(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    back: bool,
+) -> Result<HandlerResult, HandlerError> {
+    let moved = if back {
+        cursor.back()
     } else {
-        println!("No microcode is loaded.");
+        cursor.forward()
+    };
+
+    match moved {
+        Some(_) => show_current(ucode, cursor),
+        None if back => outln!("Nothing to go back to."),
+        None => outln!("Nothing to go forward to."),
     }
 
     Ok(HandlerResult::Handled)
 }
 
-fn do_load(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
-    if words.len() == 2 {
-        match ucode.load(words[1]) {
-            Ok(()) => {
-                println!("Loaded file {}", ucode.path());
-            }
-            Err(e) => {
-                println!("Cannot load file. {}", e);
+fn do_gdb(ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    let port = match words.len() {
+        1 => DEFAULT_GDB_PORT,
+        2 => match words[1].parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                return Err(HandlerError::Failed("usage: gdb [port]".to_string()));
             }
+        },
+        _ => {
+            return Err(HandlerError::Failed("usage: gdb [port]".to_string()));
         }
-    } else {
-        println!("usage: load [filename]");
+    };
+
+    if ucode.path.is_none() {
+        return Err(HandlerError::Failed("No microcode is loaded.".to_string()));
     }
 
+    let mut sim = Simulator::new(ucode, SparseMemory::new());
+    gdb::serve(&mut sim, port, |status| match status {
+        gdb::Status::Listening(addr) => outln!("Waiting for GDB on {}...", addr),
+        gdb::Status::Connected(peer) => outln!("Debugger connected from {}", peer),
+        gdb::Status::Detached => outln!("Debugger detached."),
+    })?;
+
     Ok(HandlerResult::Handled)
 }
 
-// TODO: Automatically generate help from command list.
-fn do_help() -> Result<HandlerResult, HandlerError> {
-    println!("help                Show this help.");
-    println!("load [file]         Load a Microcode file.");
-    println!("dump [file]         Disassemble to file.");
-    println!("show                Show microcode overview.");
-    println!("q,quit              Leave the shell.");
+fn do_next(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    do_step(ucode, cursor, words, true)
+}
+
+fn do_prev(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    do_step(ucode, cursor, words, false)
+}
+
+fn do_back(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    _words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    do_history(ucode, cursor, true)
+}
+
+fn do_forward(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    _words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    do_history(ucode, cursor, false)
+}
+
+fn do_help(_ucode: &mut Microcode, words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    match words.get(1) {
+        None => {
+            out!("{}", command::summary(COMMANDS));
+            outln!("An empty line repeats the last command, or shows the current word.");
+        }
+        Some(name) => match command::find(COMMANDS, name) {
+            Some(cmd) => out!("{}", command::detail(cmd)),
+            None => {
+                return Err(HandlerError::Failed(format!(
+                    "No command '{}'. Type help for a list.",
+                    name
+                )))
+            }
+        },
+    }
 
     Ok(HandlerResult::Handled)
 }
 
-fn handle_command(ucode: &mut Microcode, input: &str) -> Result<HandlerResult, HandlerError> {
-    let words = input.split(" ").collect::<Vec<&str>>();
+fn do_quit(_ucode: &mut Microcode, _words: Vec<&str>) -> Result<HandlerResult, HandlerError> {
+    Ok(HandlerResult::Quit)
+}
 
-    if words.len() == 0 {
-        Err(HandlerError::ParseError)
-    } else {
-        match words[0] {
-            "quit" => Ok(HandlerResult::Quit),
-            "q" => Ok(HandlerResult::Quit),
-            "help" => do_help(),
-            "dump" => do_dump(ucode, words),
-            "show" => do_show(ucode, words),
-            "load" => do_load(ucode, words),
-            "" => Ok(HandlerResult::Handled),
-            _ => Err(HandlerError::ParseError),
+//
+// Command table
+//
+
+type ImageHandler = fn(&mut Microcode, Vec<&str>) -> Result<HandlerResult, HandlerError>;
+type CursorHandler =
+    fn(&mut Microcode, &mut Cursor, Vec<&str>) -> Result<HandlerResult, HandlerError>;
+
+/// How a command is run: most only need the image, navigation
+/// commands also need the cursor.
+enum Handler {
+    Image(ImageHandler),
+    Cursor(CursorHandler),
+}
+
+const EXPORT_FORMATS: &[&str] = &[
+    "json", "csv", "tsv", "readmemh", "readmemb", "ihex", "bin-le", "bin-be", "source", "html",
+];
+
+/// The settings `set` takes in place of an address.
+const SETTINGS: &[&str] = &["radix", "color", "theme"];
+
+const FOLLOW_KINDS: &[&str] = &["jump", "skip", "call", "dispatch", "trap"];
+
+/// The words `hazards` takes in place of a range.
+const FORMATS: &[&str] = &["format=text", "format=json"];
+
+const OPCODE_ACTIONS: &[&str] = &["entries", "import", "set", "off"];
+
+/// The words `lint` takes in place of a range.
+const LINT_ACTIONS: &[&str] = &["rules", "on", "off", "allow", "format=text", "format=json"];
+
+static COMMANDS: &[Command<Handler>] = &[
+    Command {
+        name: "help",
+        aliases: &["?"],
+        params: &[optional("command", command::Arg::Command)],
+        summary: "Show this help, or more about one command.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Image(do_help),
+    },
+    Command {
+        name: "load",
+        aliases: &[],
+        params: &[required("file", command::Arg::File)],
+        summary: "Load a Microcode file.",
+        help: "Annotations are read from the project file kept beside the image.",
+        repeatable: false,
+        handler: Handler::Cursor(do_load),
+    },
+    Command {
+        name: "dump",
+        aliases: &[],
+        params: &[required("file", command::Arg::File)],
+        summary: "Disassemble to file.",
+        help: "",
+        repeatable: false,
+        handler: Handler::Image(do_dump),
+    },
+    Command {
+        name: "show",
+        aliases: &[],
+        params: &[],
+        summary: "Show microcode overview.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Image(do_show),
+    },
+    Command {
+        name: "asm",
+        aliases: &[],
+        params: &[required("file", command::Arg::File)],
+        summary: "Assemble a file into control memory.",
+        help: "",
+        repeatable: false,
+        handler: Handler::Image(do_asm),
+    },
+    Command {
+        name: "disasm",
+        aliases: &[],
+        params: &[required("file", command::Arg::File)],
+        summary: "Disassemble to file in assembler syntax.",
+        help: "",
+        repeatable: false,
+        handler: Handler::Image(do_disasm),
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        params: &[
+            required("address", command::Arg::AddressOr(SETTINGS)),
+            repeated("field=value", command::Arg::Assignment),
+        ],
+        summary: "Set fields of a control memory word, or a setting.",
+        help: "Values are numbers or the symbolic names from the layout. Parity is\n\
+               recomputed unless it is set explicitly.\n\
+               \n\
+               \"set radix\" shows the radix numbers are shown and read in, and\n\
+               \"set radix 8\", 10 or 16 chooses it. Any number can give its own\n\
+               radix, as #o17, #d15 or #xf, or be decimal with a point, as 15.\n\
+               \n\
+               \"set color on\", off or auto chooses whether listings and the line\n\
+               being typed are colored; auto, the default, colors only output to\n\
+               a terminal. \"set theme dark\" or light chooses the colors.",
+        repeatable: false,
+        handler: Handler::Cursor(do_set),
+    },
+    Command {
+        name: "find",
+        aliases: &[],
+        params: &[
+            optional("range", command::Arg::Address),
+            repeated("field=value", command::Arg::Assignment),
+        ],
+        summary: "List the words with the given field values.",
+        help: "Values are numbers or the symbolic names from the layout. A range,\n\
+               as 1000..1200 or foo..bar, limits the search to those addresses.",
+        repeatable: true,
+        handler: Handler::Cursor(do_find),
+    },
+    Command {
+        name: "list",
+        aliases: &[],
+        params: &[required("range", command::Arg::Address)],
+        summary: "Show the words in a range of addresses.",
+        help: "The range is two addresses joined by .., as 1000..1200 or foo..bar,\n\
+               and takes in both ends. A single address lists just that word.",
+        repeatable: false,
+        handler: Handler::Cursor(do_list),
+    },
+    Command {
+        name: "undo",
+        aliases: &[],
+        params: &[],
+        summary: "Undo the last change to control memory.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Image(do_undo),
+    },
+    Command {
+        name: "redo",
+        aliases: &[],
+        params: &[],
+        summary: "Redo the last undone change.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Image(do_redo),
+    },
+    Command {
+        name: "modified",
+        aliases: &[],
+        params: &[],
+        summary: "List modified control memory words.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Image(do_modified),
+    },
+    Command {
+        name: "save",
+        aliases: &[],
+        params: &[required("file", command::Arg::File)],
+        summary: "Write the image to a microcode file.",
+        help: "Annotations are saved in a project file beside the new image.",
+        repeatable: false,
+        handler: Handler::Image(do_save),
+    },
+    Command {
+        name: "apply",
+        aliases: &[],
+        params: &[
+            required("patch", command::Arg::File),
+            optional("output", command::Arg::File),
+        ],
+        summary: "Apply a patch file, optionally saving.",
+        help: "",
+        repeatable: false,
+        handler: Handler::Image(do_apply),
+    },
+    Command {
+        name: "mkpatch",
+        aliases: &[],
+        params: &[
+            required("old", command::Arg::File),
+            required("new", command::Arg::File),
+            required("patch", command::Arg::File),
+        ],
+        summary: "Write a patch turning one image into another.",
+        help: "",
+        repeatable: false,
+        handler: Handler::Image(do_mkpatch),
+    },
+    Command {
+        name: "export",
+        aliases: &[],
+        params: &[
+            required("format", command::Arg::Keyword(EXPORT_FORMATS)),
+            repeated("argument", command::Arg::File),
+        ],
+        summary: "Export the image in another format.",
+        help: "export json <file>\n\
+               export csv|tsv <file> [fields=f1,f2,...] [radix=8|10|16]\n\
+               export readmemh|readmemb|ihex|bin-le|bin-be cmem|amem|bmem|typemap <file>\n       \
+               [width=bits] [fill=value]\n\
+               export source <file> --lang rust|c\n\
+               export html <directory>",
+        repeatable: false,
+        handler: Handler::Image(do_export),
+    },
+    Command {
+        name: "label",
+        aliases: &[],
+        params: &[
+            optional("address", command::Arg::Address),
+            optional("name", command::Arg::Text),
+        ],
+        summary: "Label an address, or list labels.",
+        help: "Without a name, the address's label is removed.",
+        repeatable: false,
+        handler: Handler::Cursor(do_label),
+    },
+    Command {
+        name: "comment",
+        aliases: &[],
+        params: &[
+            optional("address", command::Arg::Address),
+            repeated("text", command::Arg::Text),
+        ],
+        summary: "Comment an address, or list comments.",
+        help: "Without text, the address's comment is removed.",
+        repeatable: false,
+        handler: Handler::Cursor(do_comment),
+    },
+    Command {
+        name: "entry",
+        aliases: &[],
+        params: &[
+            optional("address", command::Arg::Address),
+            optional("off", command::Arg::Keyword(&["off"])),
+        ],
+        summary: "Mark a routine entry point, or list them.",
+        help: "",
+        repeatable: false,
+        handler: Handler::Cursor(do_entry),
+    },
+    Command {
+        name: "goto",
+        aliases: &[],
+        params: &[required("address", command::Arg::Address)],
+        summary: "Make an address or label the current word.",
+        help: "Addresses, here and in every other command, are expressions:\n\
+               \n  \
+               1000  #x200  512.    numbers, in the radix or their own\n  \
+               start  foo+3  bar-1  labels and arithmetic\n  \
+               .                    the current address\n  \
+               ctos                 where the routine followed into returns\n  \
+               naf(.)  seq(foo+1)   a field of the word at an address\n  \
+               (foo+bar)-2          grouping\n\
+               \n\
+               Counts are expressions too, as in \"next bar-foo\".",
+        repeatable: true,
+        handler: Handler::Cursor(do_goto),
+    },
+    Command {
+        name: "next",
+        aliases: &[],
+        params: &[optional("count", command::Arg::Address)],
+        summary: "Step to the next word.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Cursor(do_next),
+    },
+    Command {
+        name: "prev",
+        aliases: &[],
+        params: &[optional("count", command::Arg::Address)],
+        summary: "Step to the previous word.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Cursor(do_prev),
+    },
+    Command {
+        name: "follow",
+        aliases: &[],
+        params: &[optional("n|kind", command::Arg::Keyword(FOLLOW_KINDS))],
+        summary: "Go where the current word jumps, skips or traps.",
+        help: "When there is more than one place to go, they are listed and one is\n\
+               chosen by number or by kind.\n\
+               \n\
+               Following a call pushes the word after it onto a control stack, as a\n\
+               Pushj does, and following a return from a word that takes its next\n\
+               address from CTOS goes back there. The top of the stack is ctos in\n\
+               address expressions.",
+        repeatable: true,
+        handler: Handler::Cursor(do_follow),
+    },
+    Command {
+        name: "back",
+        aliases: &[],
+        params: &[],
+        summary: "Retrace goto and follow.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Cursor(do_back),
+    },
+    Command {
+        name: "forward",
+        aliases: &[],
+        params: &[],
+        summary: "Undo a step back.",
+        help: "",
+        repeatable: true,
+        handler: Handler::Cursor(do_forward),
+    },
+    Command {
+        name: "hazards",
+        aliases: &[],
+        params: &[
+            optional("range", command::Arg::AddressOr(FORMATS)),
+            optional("format", command::Arg::Keyword(FORMATS)),
+        ],
+        summary: "Find pipeline hazards along the control flow.",
+        help: "hazards [range] [format=text|json]\n\
+               \n\
+               Follows every path from each word that starts a memory read, loads\n\
+               NPC or dismisses its task, and reports the words that use MD before\n\
+               the data arrives, return or jump through an NPC that is not the one\n\
+               expected, or do something that cannot finish in the instructions a\n\
+               task runs after a Dismiss. Each hazard is followed by the cycles\n\
+               that lead to it, counted from the word that started it.",
+        repeatable: false,
+        handler: Handler::Cursor(do_hazards),
+    },
+    Command {
+        name: "lint",
+        aliases: &[],
+        params: &[
+            optional("range", command::Arg::AddressOr(LINT_ACTIONS)),
+            repeated("argument", command::Arg::Text),
+        ],
+        summary: "Check control memory words against the microword rules.",
+        help: "lint [range] [format=text|json]\n\
+               lint rules\n\
+               lint on|off <rule>...\n\
+               lint allow [address rule [off]]\n\
+               \n\
+               Each finding is one line, \"address: severity: message [rule]\", or\n\
+               with format=json an array of objects with those members. \"lint rules\"\n\
+               lists the rules and whether they are on. A rule turned off, or\n\
+               allowed at an address, is not reported; both are kept in the\n\
+               project file.",
+        repeatable: false,
+        handler: Handler::Cursor(do_lint),
+    },
+    Command {
+        name: "timing",
+        aliases: &[],
+        params: &[
+            optional("address", command::Arg::Address),
+            optional("to", command::Arg::Address),
+        ],
+        summary: "Show how long a routine, or the paths between two words, take.",
+        help: "timing [address]\n\
+               timing <from> <to>\n\
+               \n\
+               With one address, or none for the current word, shows the basic blocks\n\
+               of its routine and the shortest and longest times from the entry to an\n\
+               exit. With two, shows the shortest and longest times along the paths\n\
+               from the first word to the second, counting both. U SPEED 0-3 gives\n\
+               cycles of 180, 210, 225 and 255 ns, and a call counts the time its\n\
+               routine takes. Loops run as often as \"loop\" allows.",
+        repeatable: false,
+        handler: Handler::Cursor(do_timing),
+    },
+    Command {
+        name: "loop",
+        aliases: &[],
+        params: &[
+            optional("address", command::Arg::Address),
+            optional("count", command::Arg::AddressOr(&["off"])),
+        ],
+        summary: "Bound how often a loop runs, for timing, or list the bounds.",
+        help: "loop [address count|off]\n\
+               \n\
+               The word at the address runs at most count times along one path, and\n\
+               the rest of its loop as often as it does. A loop with no bound is taken\n\
+               once. Bounds are kept in the project file.",
+        repeatable: false,
+        handler: Handler::Cursor(do_loop),
+    },
+    Command {
+        name: "opcodes",
+        aliases: &[],
+        params: &[
+            optional("action", command::Arg::Keyword(OPCODE_ACTIONS)),
+            repeated("argument", command::Arg::Text),
+        ],
+        summary: "Show the macroinstruction opcode table, or change it.",
+        help: "opcodes [entries]\n\
+               opcodes import <file>\n\
+               opcodes set <opcode> <address> [name]\n\
+               opcodes off <opcode>\n\
+               \n\
+               Lists each opcode with its name and handler. \"opcodes entries\" shows\n\
+               the words that take the IFU dispatch, the words that write the decode\n\
+               memories and the words only a dispatch can reach. An opcode list has a\n\
+               line for each opcode, giving the octal opcode, its name and the address\n\
+               or label of its handler, with \"-\" for either one not known. The table\n\
+               is kept in the project file.",
+        repeatable: false,
+        handler: Handler::Cursor(do_opcodes),
+    },
+    Command {
+        name: "macro",
+        aliases: &[],
+        params: &[required("opcode", command::Arg::Text)],
+        summary: "Go to the handler of a macroinstruction and show its call tree.",
+        help: "macro <opcode>\n\
+               \n\
+               The opcode is given by name or number. The tree shows the routines the\n\
+               handler calls, and those it jumps into, and theirs in turn. One already\n\
+               shown is marked \"...\" and not shown again.",
+        repeatable: false,
+        handler: Handler::Cursor(do_macro),
+    },
+    Command {
+        name: "layout",
+        aliases: &[],
+        params: &[optional("file", command::Arg::File)],
+        summary: "Show the microword layout, or load one.",
+        help: "",
+        repeatable: false,
+        handler: Handler::Image(do_layout),
+    },
+    Command {
+        name: "gdb",
+        aliases: &[],
+        params: &[optional("port", command::Arg::Text)],
+        summary: "Serve a GDB remote stub.",
+        help: "The stub listens on port 1234 unless another is given.",
+        repeatable: false,
+        handler: Handler::Image(do_gdb),
+    },
+    Command {
+        name: "quit",
+        aliases: &["q"],
+        params: &[],
+        summary: "Leave the shell.",
+        help: "",
+        repeatable: false,
+        handler: Handler::Image(do_quit),
+    },
+];
+
+fn handle_command(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    words: Vec<&str>,
+) -> Result<HandlerResult, HandlerError> {
+    if words.is_empty() {
+        return Ok(HandlerResult::Handled);
+    }
+
+    match command::find(COMMANDS, words[0]) {
+        Some(cmd) => match cmd.handler {
+            Handler::Image(handler) => handler(ucode, words),
+            Handler::Cursor(handler) => handler(ucode, cursor, words),
+        },
+        None => Err(HandlerError::ParseError),
+    }
+}
+
+/// Run one command, sending its output where the command line says.
+fn handle_statement(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    statement: &Statement,
+) -> Result<HandlerResult, HandlerError> {
+    let words: Vec<&str> = statement.words.iter().map(|w| &w[..]).collect();
+    match statement.output {
+        Output::Terminal => handle_command(ucode, cursor, words),
+        ref output => {
+            let paged = *output == Output::Pager;
+            let (result, text) = shell::capture(paged, || handle_command(ucode, cursor, words));
+            shell::deliver(output, &text)?;
+            result
+        }
+    }
+}
+
+/// Run the commands on a line in turn.
+fn handle_line(
+    ucode: &mut Microcode,
+    cursor: &mut Cursor,
+    line: &str,
+) -> Result<HandlerResult, HandlerError> {
+    let mut found_nothing = false;
+
+    for statement in shell::parse(line)? {
+        match handle_statement(ucode, cursor, &statement)? {
+            HandlerResult::Handled => {}
+            HandlerResult::NotFound => found_nothing = true,
+            HandlerResult::Quit => return Ok(HandlerResult::Quit),
         }
     }
+
+    if found_nothing {
+        Ok(HandlerResult::NotFound)
+    } else {
+        Ok(HandlerResult::Handled)
+    }
+}
+
+/// The command an empty line after this line repeats: the last one
+/// on it, if it can be repeated.
+fn repeated_command(line: &str) -> Option<Statement> {
+    let statement = shell::parse(line).ok()?.pop()?;
+    match command::find(COMMANDS, &statement.words[0]) {
+        Some(cmd) if cmd.repeatable => Some(statement),
+        _ => None,
+    }
 }
 
-struct MyHelper(FilenameCompleter);
+struct MyHelper {
+    files: FilenameCompleter,
+    context: Rc<RefCell<Context>>,
+}
 
 impl Completer for MyHelper {
     type Candidate = Pair;
 
     fn complete(&self, line: &str, pos: usize) -> Result<(usize, Vec<Pair>), ReadlineError> {
-        self.0.complete(line, pos)
+        match command::complete(COMMANDS, &self.context.borrow(), line, pos) {
+            Some((start, candidates)) => {
+                let pairs = candidates
+                    .into_iter()
+                    .map(|c| Pair {
+                        display: c.clone(),
+                        replacement: c,
+                    })
+                    .collect();
+                Ok((start, pairs))
+            }
+            None => self.files.complete(line, pos),
+        }
     }
 }
 
 impl Hinter for MyHelper {
-    fn hint(&self, line: &str, _pos: usize) -> Option<String> {
-        if line == "hello" {
-            Some(" World".to_owned())
-        } else {
-            None
-        }
+    fn hint(&self, line: &str, pos: usize) -> Option<String> {
+        command::hint(COMMANDS, line, pos)
     }
 }
 
 impl Highlighter for MyHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if color::input_enabled() {
+            Owned(command::highlight(
+                COMMANDS,
+                &self.context.borrow(),
+                color::theme(),
+                line,
+            ))
+        } else {
+            Borrowed(line)
+        }
+    }
+
     fn highlight_prompt<'p>(&self, prompt: &'p str) -> Cow<'p, str> {
-        if prompt == PROMPT {
-            Borrowed(COLORED_PROMPT)
+        if color::input_enabled() {
+            let name = prompt.trim_end();
+            Owned(color::theme().paint(Style::Prompt, name) + &prompt[name.len()..])
         } else {
             Borrowed(prompt)
         }
     }
 
     fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
-        Owned("\x1b[1m".to_owned() + hint + "\x1b[m")
+        if color::input_enabled() {
+            Owned(color::theme().paint(Style::Hint, hint))
+        } else {
+            Borrowed(hint)
+        }
+    }
+
+    // Every character can change how the line is colored.
+    fn highlight_char(&self, _grapheme: &str) -> bool {
+        color::input_enabled()
     }
 }
 
@@ -202,19 +2167,43 @@ fn process_loop(ucode: &mut Microcode) {
         .edit_mode(EditMode::Emacs)
         .build();
 
-    let h = MyHelper(FilenameCompleter::new());
+    let context = Rc::new(RefCell::new(Context::new(ucode)));
+    let h = MyHelper {
+        files: FilenameCompleter::new(),
+        context: context.clone(),
+    };
     let mut rl = Editor::with_config(config);
     rl.set_helper(Some(h));
     rl.bind_sequence(KeyPress::Meta('N'), Cmd::HistorySearchForward);
     rl.bind_sequence(KeyPress::Meta('P'), Cmd::HistorySearchBackward);
 
+    let mut cursor = Cursor::new();
+    let mut last_command: Option<Statement> = None;
+
     loop {
         let readline = rl.readline(PROMPT);
         match readline {
             Ok(line) => {
-                rl.add_history_entry(line.as_ref());
-                match handle_command(ucode, line.as_ref()) {
-                    Ok(HandlerResult::Handled) => {
+                // An empty line repeats the last command, as in gdb, or
+                // shows the current word when there is none to repeat.
+                let result = if line.trim().is_empty() {
+                    match last_command {
+                        Some(ref statement) => handle_statement(ucode, &mut cursor, statement),
+                        None => {
+                            if cursor.current().is_some() {
+                                show_current(ucode, &cursor);
+                            }
+                            continue;
+                        }
+                    }
+                } else {
+                    rl.add_history_entry(line.as_str());
+                    last_command = repeated_command(&line);
+                    handle_line(ucode, &mut cursor, &line)
+                };
+                *context.borrow_mut() = Context::new(ucode);
+                match result {
+                    Ok(HandlerResult::Handled) | Ok(HandlerResult::NotFound) => {
                         // Normal result. Continue looping.
                     }
                     Ok(HandlerResult::Quit) => {
@@ -229,6 +2218,14 @@ fn process_loop(ucode: &mut Microcode) {
                         // Parse error. Display failure, keep looping.
                         println!("?");
                     }
+                    Err(HandlerError::Failed(reason)) => {
+                        // The command explained itself. Keep looping.
+                        println!("{}", color::paint(Style::Error, &reason));
+                    }
+                    Err(HandlerError::Syntax(e)) => {
+                        // Bad command line. Display it, keep looping.
+                        println!("{}", e);
+                    }
                 }
             }
             Err(e) => {
@@ -239,7 +2236,135 @@ fn process_loop(ucode: &mut Microcode) {
     }
 }
 
+//
+// Batch mode
+//
+
+/// Exit statuses follow diff(1) and grep(1): 1 means the commands ran
+/// but found differences or nothing, 2 that something failed.
+const EXIT_OK: i32 = 0;
+const EXIT_NOT_FOUND: i32 = 1;
+const EXIT_FAILED: i32 = 2;
+
+/// The exit status for the result of a command, reporting failures.
+fn exit_status(input: &str, result: Result<HandlerResult, HandlerError>) -> i32 {
+    match result {
+        Ok(HandlerResult::NotFound) => EXIT_NOT_FOUND,
+        Ok(_) => EXIT_OK,
+        Err(HandlerError::ParseError) => {
+            eprintln!("Unknown command: {}", input.trim());
+            EXIT_FAILED
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            EXIT_FAILED
+        }
+    }
+}
+
+/// Run command lines in order, stopping at the first one that fails.
+fn run_lines(ucode: &mut Microcode, lines: &[&str]) -> i32 {
+    let mut cursor = Cursor::new();
+    let mut status = EXIT_OK;
+
+    for line in lines {
+        let result = handle_line(ucode, &mut cursor, line);
+        if let Ok(HandlerResult::Quit) = result {
+            break;
+        }
+        match exit_status(line, result) {
+            EXIT_FAILED => return EXIT_FAILED,
+            s => status = status.max(s),
+        }
+    }
+
+    status
+}
+
+/// The command lines of a script. Lines starting with '#' are
+/// comments.
+fn script_lines(script: &str) -> Vec<&str> {
+    script
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect()
+}
+
+/// Load an image for a batch command. Problems with the project file
+/// are reported but do not stop the command.
+fn load_image(path: &str) -> Result<Microcode, i32> {
+    let mut ucode = Microcode::new();
+    if let Err(e) = ucode.load(path) {
+        eprintln!("Unable to parse microcode: {}", e);
+        return Err(EXIT_FAILED);
+    }
+    if let Err(e) = project::load(&mut ucode) {
+        eprintln!("Cannot load project file. {}", e);
+    }
+    Ok(ucode)
+}
+
+/// Write text to a file, or to standard output when no file is given.
+fn write_output(text: &str, path: Option<&str>) -> i32 {
+    let result = match path {
+        Some(path) => File::create(path).and_then(|mut f| f.write_all(text.as_bytes())),
+        None => io::stdout().write_all(text.as_bytes()),
+    };
+    match result {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            eprintln!("Cannot write output. {}", e);
+            EXIT_FAILED
+        }
+    }
+}
+
+/// Run one of the one-shot subcommands.
+fn run_subcommand(name: &str, args: &ArgMatches) -> i32 {
+    let file = args.value_of("file").unwrap_or("");
+
+    if name == "diff" {
+        let new = args.value_of("new").unwrap_or("");
+        let (old, new) = match (load_image(file), load_image(new)) {
+            (Ok(old), Ok(new)) => (old, new),
+            _ => return EXIT_FAILED,
+        };
+        let patch = patch::diff(&old, &new);
+        print!("{}", patch);
+        return if patch.is_empty() {
+            EXIT_OK
+        } else {
+            EXIT_NOT_FOUND
+        };
+    }
+
+    let mut ucode = match load_image(file) {
+        Ok(ucode) => ucode,
+        Err(status) => return status,
+    };
+    let rest: Vec<&str> = args.values_of("args").map_or(Vec::new(), |v| v.collect());
+
+    match name {
+        "info" => run_lines(&mut ucode, &["show"]),
+        "dump" => {
+            let text = format!("{}", ucode);
+            write_output(&text, args.value_of("output"))
+        }
+        "disasm" => write_output(&asm::disassemble(&ucode), args.value_of("output")),
+        "export" | "find" | "hazards" | "lint" => {
+            let mut words = vec![name];
+            words.extend(rest);
+            let result = handle_command(&mut ucode, &mut Cursor::new(), words);
+            exit_status(name, result)
+        }
+        _ => EXIT_FAILED,
+    }
+}
+
 fn main() {
+    let file_arg = || Arg::with_name("file").help("Microcode file").required(true);
+    let output_arg = || Arg::with_name("output").help("Output file (default standard output)");
+
     let app = App::new("Symbolics Microcode Explorer")
         .version("1.0")
         .author("Seth Morabito <web@loomcom.com>")
@@ -250,19 +2375,118 @@ fn main() {
                 .help("Input file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("layout")
+                .short("l")
+                .help("Microword layout file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("commands")
+                .short("c")
+                .help("Run a command line and exit")
+                .takes_value(true)
+                .conflicts_with("script"),
+        )
+        .arg(
+            Arg::with_name("script")
+                .short("s")
+                .help("Run the commands in a script file and exit")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Show an overview of an image")
+                .arg(file_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Dump an image")
+                .arg(file_arg())
+                .arg(output_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Disassemble an image in assembler syntax")
+                .arg(file_arg())
+                .arg(output_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Write a patch turning one image into another (exit 1 if they differ)")
+                .arg(file_arg())
+                .arg(
+                    Arg::with_name("new")
+                        .help("New microcode file")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export an image, as the export command does")
+                .arg(file_arg())
+                .arg(Arg::with_name("args").required(true).multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("hazards")
+                .about("Find pipeline hazards along the control flow (exit 1 if any)")
+                .arg(file_arg())
+                .arg(Arg::with_name("args").multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Check the image against the microword rules (exit 1 if any fail)")
+                .arg(file_arg())
+                .arg(Arg::with_name("args").multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("find")
+                .about("List the words with the given field values (exit 1 if none)")
+                .arg(file_arg())
+                .arg(Arg::with_name("args").required(true).multiple(true)),
+        )
         .get_matches();
 
-    let mut state = Microcode::new();
+    if let Some(path) = app.value_of("layout") {
+        match Layout::load(path) {
+            Ok(l) => layout::set_current(l),
+            Err(reason) => {
+                eprintln!("Unable to load layout: {}", reason);
+                process::exit(EXIT_FAILED);
+            }
+        }
+    }
+
+    if let (name, Some(args)) = app.subcommand() {
+        process::exit(run_subcommand(name, args));
+    }
 
-    let file = app.value_of("file");
+    let mut state = Microcode::new();
 
-    match file {
-        Some(f) => match state.load(f) {
-            Ok(()) => process_loop(&mut state),
-            Err(reason) => println!("Unable to parse microcode: {}", reason),
-        },
-        None => {
-            process_loop(&mut state);
+    if let Some(f) = app.value_of("file") {
+        match state.load(f) {
+            Ok(()) => load_project(&mut state),
+            Err(reason) => {
+                eprintln!("Unable to parse microcode: {}", reason);
+                process::exit(EXIT_FAILED);
+            }
         }
     }
+
+    if let Some(commands) = app.value_of("commands") {
+        process::exit(run_lines(&mut state, &[commands]));
+    }
+
+    if let Some(path) = app.value_of("script") {
+        let script = match fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(e) => {
+                eprintln!("Cannot read script. {}", e);
+                process::exit(EXIT_FAILED);
+            }
+        };
+        process::exit(run_lines(&mut state, &script_lines(&script)));
+    }
+
+    process_loop(&mut state);
 }
@@ -0,0 +1,257 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use asm;
+use fields::CpcSel;
+use flow::{self, Edge};
+use radix;
+use ucode::{MicroInstruction, Microcode, ADDRESS_MASK};
+
+//
+// Cursor navigation.
+//
+// The REPL keeps a current control memory address. Going to an
+// address by name or by following a word remembers where we came
+// from, the way a browser does: back returns there, and forward
+// retraces a back until the next jump. Stepping to the next or
+// previous word does not count as a jump.
+//
+// Following a call also pushes where it returns to onto a shadow
+// control stack, which following a return pops. A Pushj saves NPC,
+// which for a word reached in the usual way is the address after it,
+// so that is taken to be the return address.
+//
+
+#[derive(Default)]
+pub struct Cursor {
+    current: Option<u16>,
+    back: Vec<u16>,
+    forward: Vec<u16>,
+    calls: Vec<u16>,
+}
+
+impl Cursor {
+    pub fn new() -> Cursor {
+        Cursor::default()
+    }
+
+    pub fn current(&self) -> Option<u16> {
+        self.current
+    }
+
+    /// Forget the current address and the history, as when a new
+    /// image is loaded.
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.back.clear();
+        self.forward.clear();
+        self.calls.clear();
+    }
+
+    /// Jump to an address, remembering the current one.
+    pub fn goto(&mut self, addr: u16) {
+        match self.current {
+            Some(current) if current == addr => return,
+            Some(current) => self.back.push(current),
+            None => {}
+        }
+        self.forward.clear();
+        self.current = Some(addr);
+    }
+
+    /// Jump into a routine called from the current address.
+    pub fn call(&mut self, addr: u16) {
+        if let Some(current) = self.current {
+            self.calls.push((current + 1) & ADDRESS_MASK);
+        }
+        self.goto(addr);
+    }
+
+    /// Jump to the top of the control stack, popping it.
+    pub fn ret(&mut self) -> Option<u16> {
+        let addr = self.calls.pop()?;
+        self.goto(addr);
+        Some(addr)
+    }
+
+    /// The top of the control stack.
+    pub fn ctos(&self) -> Option<u16> {
+        self.calls.last().cloned()
+    }
+
+    /// Move to an address without recording a jump.
+    pub fn step_to(&mut self, addr: u16) {
+        self.current = Some(addr);
+    }
+
+    pub fn back(&mut self) -> Option<u16> {
+        let addr = self.back.pop()?;
+        if let Some(current) = self.current {
+            self.forward.push(current);
+        }
+        self.current = Some(addr);
+        Some(addr)
+    }
+
+    pub fn forward(&mut self) -> Option<u16> {
+        let addr = self.forward.pop()?;
+        if let Some(current) = self.current {
+            self.back.push(current);
+        }
+        self.current = Some(addr);
+        Some(addr)
+    }
+}
+
+/// Whether a word returns, taking its next address from the top of
+/// the control stack.
+pub fn returns(ucode: &Microcode, addr: u16) -> bool {
+    ucode
+        .c_word(addr)
+        .is_some_and(|cword| MicroInstruction::new(cword).cpc_sel() == CpcSel::Ctos)
+}
+
+/// The first populated address after `addr`, or with `forward` false,
+/// the last one before it.
+pub fn neighbour(ucode: &Microcode, addr: u16, forward: bool) -> Option<u16> {
+    let addresses = ucode.c_mem.mem.iter().map(|w| w.address);
+    if forward {
+        addresses.filter(|a| *a > addr).min()
+    } else {
+        addresses.filter(|a| *a < addr).max()
+    }
+}
+
+/// The places the word at `addr` can go next, one per distinct
+/// address, in the order the sequencer considers them.
+pub fn targets(ucode: &Microcode, addr: u16) -> Vec<Edge> {
+    let mut targets: Vec<Edge> = Vec::new();
+    for edge in flow::image_edges(ucode, addr) {
+        if !targets.iter().any(|t| t.to == edge.to) {
+            targets.push(edge);
+        }
+    }
+    targets
+}
+
+/// An address with its label, if it has one.
+pub fn address_name(ucode: &Microcode, addr: u16) -> String {
+    match ucode.annotations.label(addr) {
+        Some(label) => format!("{} ({})", radix::address(addr), label),
+        None => radix::address(addr),
+    }
+}
+
+/// The word at `addr` as the REPL shows it: its disassembly, label
+/// and comment, and where it goes next.
+pub fn describe(ucode: &Microcode, addr: u16) -> String {
+    let cword = match ucode.c_word(addr) {
+        Some(cword) => cword,
+        None => return format!("No control memory word at {}", radix::address(addr)),
+    };
+
+    let mut text = asm::disassemble_annotated(ucode, cword);
+    let targets: Vec<String> = targets(ucode, addr)
+        .iter()
+        .map(|e| format!("{} {}", e.kind.name(), address_name(ucode, e.to)))
+        .collect();
+    if !targets.is_empty() {
+        text.push_str(&format!("\n       -> {}", targets.join(", ")));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flow::EdgeKind;
+
+    fn image() -> Microcode {
+        asm::image(
+            "        .org 10\n\
+             one:    npc-sel=next cond-func=skip naf=three\n\
+             .org 12\n\
+             three:  npc-sel=next cond-func=trap naf=three\n",
+        )
+    }
+
+    #[test]
+    fn remembers_jumps_but_not_steps() {
+        let mut cursor = Cursor::new();
+        cursor.goto(0o10);
+        cursor.goto(0o20);
+        cursor.step_to(0o21);
+        cursor.goto(0o30);
+        assert_eq!(cursor.back(), Some(0o21));
+        assert_eq!(cursor.back(), Some(0o10));
+        assert_eq!(cursor.back(), None);
+        assert_eq!(cursor.forward(), Some(0o21));
+        cursor.goto(0o40);
+        assert_eq!(cursor.forward(), None);
+        assert_eq!(cursor.current(), Some(0o40));
+    }
+
+    #[test]
+    fn keeps_a_control_stack() {
+        let mut cursor = Cursor::new();
+        cursor.goto(0o10);
+        cursor.call(0o100);
+        cursor.call(0o200);
+        assert_eq!(cursor.ctos(), Some(0o101));
+        assert_eq!(cursor.ret(), Some(0o101));
+        assert_eq!(cursor.ret(), Some(0o11));
+        assert_eq!(cursor.ret(), None);
+        assert_eq!(cursor.back(), Some(0o101));
+    }
+
+    #[test]
+    fn steps_over_gaps() {
+        let ucode = image();
+        assert_eq!(neighbour(&ucode, 0o10, true), Some(0o12));
+        assert_eq!(neighbour(&ucode, 0o12, true), None);
+        assert_eq!(neighbour(&ucode, 0o12, false), Some(0o10));
+        assert_eq!(neighbour(&ucode, 0o5, true), Some(0o10));
+    }
+
+    #[test]
+    fn lists_distinct_targets() {
+        let ucode = image();
+        let kinds: Vec<(u16, EdgeKind)> = targets(&ucode, 0o10)
+            .iter()
+            .map(|e| (e.to, e.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![(0o12, EdgeKind::Jump), (0o10012, EdgeKind::Skip)]
+        );
+        // A trap to the word's own jump target is one place to go.
+        assert_eq!(targets(&ucode, 0o12).len(), 1);
+    }
+
+    #[test]
+    fn describes_words() {
+        let mut ucode = image();
+        assert!(ucode.annotations.set_label(0o12, Some("three")).is_ok());
+        let text = describe(&ucode, 0o10);
+        assert!(text.starts_with("00010: "));
+        assert!(text.ends_with("-> jump 00012 (three), skip 10012"));
+        assert_eq!(describe(&ucode, 0o11), "No control memory word at 00011");
+    }
+}
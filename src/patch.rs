@@ -0,0 +1,622 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use asm;
+use layout::{self, Field};
+use radix::Radix;
+use ucode::{control_address, ABWord, CWord, Mem, Microcode};
+
+//
+// Patch files.
+//
+// A patch is a list of statements, one per line:
+//
+//     ; Fix the trap vector
+//     symbol  trap-entry 1234
+//     at      trap-entry
+//             expect spec=none alu=x+1
+//             set    alu=x-1 naf=2000
+//     at      2000
+//             replace spec=halt
+//     a-mem   100 expect 1234
+//     a-mem   100 set 1235
+//
+// "at" selects a control memory word. "expect" checks fields of it,
+// "set" changes fields and "replace" gives the whole word, in the
+// assembler's syntax. The pseudo-field "word" is all 112 bits at
+// once, in octal. Parity is recomputed unless it is set explicitly.
+//
+// Every expectation is checked against the image as it was before
+// the patch, and nothing is changed unless all of them hold.
+//
+
+const WORD_BITS: u32 = 112;
+
+/// Where a statement applies.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Target {
+    C(u16),
+    A(u16),
+    B(u16),
+}
+
+/// A value given to a field, or to the whole control word.
+enum Assignment {
+    Field(Field, u64),
+    Word(u128),
+}
+
+enum Action {
+    Expect(Vec<Assignment>),
+    Set(Vec<Assignment>),
+    Replace(Vec<Assignment>),
+    ExpectData(u64),
+    SetData(u64),
+}
+
+struct Statement {
+    line: usize,
+    target: Target,
+    action: Action,
+}
+
+pub struct Patch {
+    statements: Vec<Statement>,
+}
+
+//
+// Error handling
+//
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PatchError {
+    Io(io::Error),
+    Syntax(usize, String),
+    Mismatch(Vec<String>),
+}
+
+impl From<io::Error> for PatchError {
+    fn from(err: io::Error) -> PatchError {
+        PatchError::Io(err)
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::Io(ref err) => err.fmt(f),
+            PatchError::Syntax(line, ref msg) => write!(f, "line {}: {}", line, msg),
+            PatchError::Mismatch(ref failures) => write!(
+                f,
+                "{} expectation(s) failed, nothing changed:\n  {}",
+                failures.len(),
+                failures.join("\n  ")
+            ),
+        }
+    }
+}
+
+impl error::Error for PatchError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PatchError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+//
+// Parsing
+//
+
+pub fn parse_file(path: &str) -> Result<Patch, PatchError> {
+    let mut source = String::new();
+    File::open(shellexpand::tilde(path).to_string())?.read_to_string(&mut source)?;
+    parse(&source)
+}
+
+pub fn parse(source: &str) -> Result<Patch, PatchError> {
+    let mut symbols: HashMap<String, u16> = HashMap::new();
+    let mut statements = Vec::new();
+    let mut current: Option<u16> = None;
+
+    for (n, text) in source.lines().enumerate() {
+        let line = n + 1;
+        let syntax = |msg: String| PatchError::Syntax(line, msg);
+
+        let text = match text.find(';') {
+            Some(i) => &text[..i],
+            None => text,
+        };
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+
+        let address = |token: &str| -> Result<u16, PatchError> {
+            match symbols.get(token) {
+                Some(a) => Ok(*a),
+                None => match asm::parse_number(token).and_then(control_address) {
+                    Some(a) => Ok(a),
+                    None => Err(syntax(format!("bad address '{}'", token))),
+                },
+            }
+        };
+
+        match tokens[0] {
+            "symbol" if tokens.len() == 3 => {
+                let value = address(tokens[2])?;
+                symbols.insert(tokens[1].to_string(), value);
+            }
+            "at" if tokens.len() == 2 => {
+                current = Some(address(tokens[1])?);
+            }
+            "expect" | "set" | "replace" => {
+                let target = match current {
+                    Some(a) => Target::C(a),
+                    None => return Err(syntax(format!("'{}' before 'at'", tokens[0]))),
+                };
+                let assignments = parse_assignments(&tokens[1..]).map_err(&syntax)?;
+                let action = match tokens[0] {
+                    "expect" => Action::Expect(assignments),
+                    "set" => Action::Set(assignments),
+                    _ => Action::Replace(assignments),
+                };
+                statements.push(Statement {
+                    line,
+                    target,
+                    action,
+                });
+            }
+            "a-mem" | "b-mem" if tokens.len() == 4 => {
+                let location = address(tokens[1])?;
+                let target = if tokens[0] == "a-mem" {
+                    Target::A(location)
+                } else {
+                    Target::B(location)
+                };
+                let value = match asm::parse_number(tokens[3]) {
+                    Some(v) if v >> 40 == 0 => v,
+                    _ => return Err(syntax(format!("bad value '{}'", tokens[3]))),
+                };
+                let action = match tokens[2] {
+                    "expect" => Action::ExpectData(value),
+                    "set" => Action::SetData(value),
+                    _ => {
+                        return Err(syntax(format!(
+                            "expected expect or set, got '{}'",
+                            tokens[2]
+                        )))
+                    }
+                };
+                statements.push(Statement {
+                    line,
+                    target,
+                    action,
+                });
+            }
+            _ => return Err(syntax(format!("bad statement '{}'", text.trim()))),
+        }
+    }
+
+    Ok(Patch { statements })
+}
+
+fn parse_assignments(tokens: &[&str]) -> Result<Vec<Assignment>, String> {
+    let mut assignments = Vec::new();
+    for token in tokens {
+        let mut parts = token.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = match parts.next() {
+            Some(v) if !v.is_empty() => v,
+            _ => return Err(format!("expected field=value, got '{}'", token)),
+        };
+        if name == "word" {
+            match u128::from_str_radix(value, 8) {
+                Ok(w) if w >> WORD_BITS == 0 => assignments.push(Assignment::Word(w)),
+                _ => return Err(format!("bad word '{}'", value)),
+            }
+            continue;
+        }
+        let layout = layout::current();
+        let field = layout
+            .field(name)
+            .ok_or_else(|| format!("unknown field '{}'", name))?;
+        match asm::parse_value(field, value, Radix::Octal) {
+            Some(v) if field.fits(v) => assignments.push(Assignment::Field(field.clone(), v)),
+            _ => return Err(format!("bad value '{}' for {}", value, name)),
+        }
+    }
+    Ok(assignments)
+}
+
+//
+// Applying
+//
+
+fn ab_word(mem: &Mem<ABWord>, address: u16) -> Option<u64> {
+    mem.mem
+        .iter()
+        .find(|w| w.address == address)
+        .map(|w| w.data)
+}
+
+fn describe(target: Target) -> String {
+    match target {
+        Target::C(a) => format!("{:05o}", a),
+        Target::A(a) => format!("a-mem {:04o}", a),
+        Target::B(a) => format!("b-mem {:03o}", a),
+    }
+}
+
+impl Patch {
+    /// Check every expectation against the image, returning a
+    /// description of each one that fails.
+    pub fn check(&self, ucode: &Microcode) -> Vec<String> {
+        let mut failures = Vec::new();
+
+        for statement in &self.statements {
+            let fail = |msg: String| {
+                format!(
+                    "line {}: {} {}",
+                    statement.line,
+                    describe(statement.target),
+                    msg
+                )
+            };
+            match (statement.target, &statement.action) {
+                (Target::C(address), action) => {
+                    let word = ucode.c_word(address).map(CWord::bits);
+                    match (word, action) {
+                        (None, &Action::Replace(_)) => {}
+                        (None, _) => failures.push(fail("is not in the image".to_string())),
+                        (Some(word), Action::Expect(assignments)) => {
+                            for assignment in assignments {
+                                match *assignment {
+                                    Assignment::Field(ref field, value)
+                                        if field.get(word) != value =>
+                                    {
+                                        failures.push(fail(format!(
+                                            "{} is {:o}, expected {:o}",
+                                            field.name,
+                                            field.get(word),
+                                            value
+                                        )))
+                                    }
+                                    Assignment::Word(value) if word != value => {
+                                        failures.push(fail(format!(
+                                            "word is {:038o}, expected {:038o}",
+                                            word, value
+                                        )))
+                                    }
+                                    _ => {}
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                (Target::A(address), action) | (Target::B(address), action) => {
+                    let mem = match statement.target {
+                        Target::A(_) => &ucode.a_mem,
+                        _ => &ucode.b_mem,
+                    };
+                    match (ab_word(mem, address), action) {
+                        (None, _) => failures.push(fail("is not in the image".to_string())),
+                        (Some(data), &Action::ExpectData(value)) if data != value => {
+                            failures.push(fail(format!("is {:o}, expected {:o}", data, value)))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        failures
+    }
+
+    /// Apply the patch if every expectation holds. Control memory
+    /// changes are recorded so they can be undone. Returns the
+    /// number of locations changed.
+    pub fn apply(&self, ucode: &mut Microcode) -> Result<usize, PatchError> {
+        let failures = self.check(ucode);
+        if !failures.is_empty() {
+            return Err(PatchError::Mismatch(failures));
+        }
+
+        let layout = layout::current();
+
+        // The new contents of each location, with a flag for words
+        // whose parity was given explicitly.
+        let mut words: BTreeMap<u16, (u128, bool)> = BTreeMap::new();
+        let mut data: BTreeMap<Target, u64> = BTreeMap::new();
+
+        for statement in &self.statements {
+            match (statement.target, &statement.action) {
+                (Target::C(address), Action::Set(assignments)) => {
+                    let entry = words
+                        .entry(address)
+                        .or_insert_with(|| (ucode.c_word(address).unwrap().bits(), false));
+                    assign(entry, assignments);
+                }
+                (Target::C(address), Action::Replace(assignments)) => {
+                    let mut entry = (layout.blank_word(), false);
+                    assign(&mut entry, assignments);
+                    words.insert(address, entry);
+                }
+                (target, &Action::SetData(value)) => {
+                    data.insert(target, value);
+                }
+                _ => {}
+            }
+        }
+
+        let mut changed = 0;
+        for (&address, &(word, explicit_parity)) in &words {
+            let word = if explicit_parity {
+                word
+            } else {
+                layout.with_parity(word)
+            };
+            let cword = CWord::from_bits(address, word);
+            match ucode.c_word(address).map(CWord::bits) {
+                Some(old) if old == word => continue,
+                Some(_) => {
+                    ucode.edit_c_word(cword);
+                }
+                None => ucode.store_c_word(cword),
+            }
+            changed += 1;
+        }
+
+        for (&target, &value) in &data {
+            let (mem, address) = match target {
+                Target::A(a) => (&mut ucode.a_mem, a),
+                Target::B(a) => (&mut ucode.b_mem, a),
+                Target::C(_) => continue,
+            };
+            if let Some(w) = mem.mem.iter_mut().find(|w| w.address == address) {
+                if w.data != value {
+                    w.data = value;
+                    changed += 1;
+                }
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+fn assign(entry: &mut (u128, bool), assignments: &[Assignment]) {
+    for assignment in assignments {
+        match *assignment {
+            Assignment::Field(ref field, value) => {
+                entry.0 = field.set(entry.0, value);
+                entry.1 |= field.name == "parity";
+            }
+            Assignment::Word(value) => {
+                entry.0 = value;
+                entry.1 = true;
+            }
+        }
+    }
+}
+
+//
+// Generating
+//
+
+/// Write a patch that turns the old image into the new one.
+pub fn diff(old: &Microcode, new: &Microcode) -> String {
+    let layout = layout::current();
+    let mut text = String::new();
+
+    for cword in &new.c_mem.mem {
+        let word = cword.bits();
+        let original = match old.c_word(cword.address) {
+            Some(o) => o.bits(),
+            None => {
+                text.push_str(&format!(
+                    "at {:05o}\n    replace word={:038o}\n",
+                    cword.address, word
+                ));
+                continue;
+            }
+        };
+        if original == word {
+            continue;
+        }
+
+        let mut changes = String::new();
+        for field in layout.primary() {
+            let value = field.get(word);
+            // Parity is recomputed when applied, so it only needs
+            // to be given when the new word's parity is wrong.
+            let wanted = if field.name == "parity" {
+                value != layout.parity(word)
+            } else {
+                value != field.get(original)
+            };
+            if !wanted {
+                continue;
+            }
+            match field.values.iter().find(|v| v.1 == value) {
+                Some(v) => changes.push_str(&format!(" {}={}", field.name, v.0)),
+                None => changes.push_str(&format!(" {}={:o}", field.name, value)),
+            }
+        }
+        text.push_str(&format!(
+            "at {:05o}\n    expect word={:038o}\n    set{}\n",
+            cword.address, original, changes
+        ));
+    }
+
+    for cword in &old.c_mem.mem {
+        if new.c_word(cword.address).is_none() {
+            text.push_str(&format!(
+                "; {:05o} is not in the new image and cannot be removed\n",
+                cword.address
+            ));
+        }
+    }
+
+    diff_ab("a-mem", &old.a_mem, &new.a_mem, &mut text);
+    diff_ab("b-mem", &old.b_mem, &new.b_mem, &mut text);
+
+    text
+}
+
+fn diff_ab(name: &str, old: &Mem<ABWord>, new: &Mem<ABWord>, text: &mut String) {
+    for word in &new.mem {
+        match ab_word(old, word.address) {
+            Some(data) if data == word.data => {}
+            Some(data) => {
+                text.push_str(&format!("{} {:o} expect {:o}\n", name, word.address, data));
+                text.push_str(&format!(
+                    "{} {:o} set {:o}\n",
+                    name, word.address, word.data
+                ));
+            }
+            None => text.push_str(&format!(
+                "; {} {:o} is not in the old image and cannot be added\n",
+                name, word.address
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Microcode {
+        let mut ucode = Microcode::new();
+        let layout = layout::current();
+        for address in 0..4 {
+            let word = layout.with_parity(layout.blank_word());
+            ucode.c_mem.push(CWord::from_bits(address, word));
+        }
+        ucode.a_mem.push(ABWord {
+            address: 0o100,
+            data: 0o1234,
+        });
+        ucode
+    }
+
+    fn parse_ok(source: &str) -> Patch {
+        match parse(source) {
+            Ok(p) => p,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn field(ucode: &Microcode, address: u16, name: &str) -> u64 {
+        let word = ucode.c_word(address).unwrap().bits();
+        layout::current().field(name).unwrap().get(word)
+    }
+
+    #[test]
+    fn applies_sets_with_symbols_and_parity() {
+        let mut ucode = image();
+        let patch = parse_ok(
+            "symbol entry 2\n\
+             at entry\n\
+             expect spec=none alu=x\n\
+             set alu=x-1 naf=17\n\
+             a-mem 100 expect 1234\n\
+             a-mem 100 set 4321\n",
+        );
+        assert_eq!(patch.apply(&mut ucode).ok(), Some(2));
+        assert_eq!(field(&ucode, 2, "alu"), 3);
+        assert_eq!(field(&ucode, 2, "naf"), 0o17);
+        assert_eq!(ucode.c_word(2).unwrap().bits().count_ones() % 2, 1);
+        assert_eq!(ucode.a_mem.mem[0].data, 0o4321);
+        assert_eq!(ucode.modified().len(), 1);
+    }
+
+    #[test]
+    fn refuses_on_any_mismatch() {
+        let mut ucode = image();
+        let patch = parse_ok(
+            "at 1\n\
+             set alu=x+1\n\
+             at 2\n\
+             expect alu=xor\n\
+             a-mem 100 expect 7\n",
+        );
+        match patch.apply(&mut ucode) {
+            Err(PatchError::Mismatch(failures)) => assert_eq!(failures.len(), 2),
+            _ => panic!("expected a mismatch"),
+        }
+        assert_eq!(field(&ucode, 1, "alu"), 0);
+        assert!(ucode.modified().is_empty());
+    }
+
+    #[test]
+    fn replace_adds_new_words() {
+        let mut ucode = image();
+        let patch = parse_ok("at 100\nreplace spec=halt\n");
+        assert_eq!(patch.apply(&mut ucode).ok(), Some(1));
+        assert_eq!(field(&ucode, 0o100, "spec"), 0o31);
+        assert_eq!(field(&ucode, 0o100, "alu"), 0);
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        match parse("expect alu=x\n") {
+            Err(PatchError::Syntax(1, _)) => {}
+            _ => panic!("expected an error for a missing 'at'"),
+        }
+        match parse("at 1\nset alu=bogus\n") {
+            Err(PatchError::Syntax(2, _)) => {}
+            _ => panic!("expected an error for a bad value"),
+        }
+    }
+
+    #[test]
+    fn diff_produces_an_applicable_patch() {
+        let old = image();
+        let mut new = image();
+        let blank = layout::current().blank_word();
+        new.store_c_word(CWord::from_bits(1, blank | 0o7));
+        new.store_c_word(CWord::new(3, 0o777, 0));
+        new.store_c_word(CWord::from_bits(0o200, blank));
+        new.a_mem.mem[0].data = 0o55;
+
+        let text = diff(&old, &new);
+        let mut patched = image();
+        assert_eq!(parse_ok(&text).apply(&mut patched).ok(), Some(4));
+
+        for cword in &new.c_mem.mem {
+            let got = patched.c_word(cword.address).map(CWord::bits);
+            assert_eq!(got, Some(cword.bits()), "{:o}", cword.address);
+        }
+        assert_eq!(patched.a_mem.mem[0].data, 0o55);
+        assert!(diff(&new, &patched).is_empty());
+    }
+}
@@ -0,0 +1,667 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::{BTreeMap, BTreeSet};
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use toml;
+
+use asm;
+use radix;
+use ucode::{control_address, CWord, Microcode};
+
+//
+// Project files.
+//
+// Labels, comments, routine entry points, lint settings, loop bounds
+// and the macroinstruction opcode table are kept in a TOML file next
+// to the image, named after it with ".project.toml" appended. The
+// file records a fingerprint of the image it was written for and the
+// bits of every annotated word. When the image has changed since, an
+// annotation whose word has moved follows it to its new address, as
+// long as the word is found exactly once.
+//
+
+const SUFFIX: &str = ".project.toml";
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ProjectError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(String),
+    NoImage,
+}
+
+impl From<io::Error> for ProjectError {
+    fn from(err: io::Error) -> ProjectError {
+        ProjectError::Io(err)
+    }
+}
+
+impl fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProjectError::Io(ref err) => err.fmt(f),
+            ProjectError::Parse(ref msg) => write!(f, "{}", msg),
+            ProjectError::Invalid(ref msg) => write!(f, "{}", msg),
+            ProjectError::NoImage => write!(f, "No microcode is loaded"),
+        }
+    }
+}
+
+impl error::Error for ProjectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ProjectError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+//
+// Annotations
+//
+
+/// The highest macroinstruction opcode.
+pub const OPCODE_MAX: u16 = 0xffff;
+
+/// A macroinstruction opcode, by name and the word that handles it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Opcode {
+    pub name: Option<String>,
+    pub handler: Option<u16>,
+}
+
+/// User annotations of control memory addresses.
+#[derive(Clone, Default)]
+pub struct Annotations {
+    pub labels: BTreeMap<u16, String>,
+    pub comments: BTreeMap<u16, String>,
+    /// Addresses to treat as routine entry points.
+    pub entries: BTreeSet<u16>,
+    /// Lint rules not to report at an address.
+    pub allowed: BTreeMap<u16, BTreeSet<String>>,
+    /// Lint rules turned off for the whole image.
+    pub disabled_rules: BTreeSet<String>,
+    /// The most times a word runs along one path, for timing loops.
+    pub loop_bounds: BTreeMap<u16, u32>,
+    /// Macroinstruction opcodes and their handlers.
+    pub opcodes: BTreeMap<u16, Opcode>,
+}
+
+/// Labels start with a letter and are made of the characters the
+/// assembler accepts in a label, so that listings assemble.
+fn valid_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || "-_.$%*".contains(c))
+}
+
+impl Annotations {
+    pub fn clear(&mut self) {
+        self.labels.clear();
+        self.comments.clear();
+        self.entries.clear();
+        self.allowed.clear();
+        self.disabled_rules.clear();
+        self.loop_bounds.clear();
+        self.opcodes.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+            && self.comments.is_empty()
+            && self.entries.is_empty()
+            && self.allowed.is_empty()
+            && self.disabled_rules.is_empty()
+            && self.loop_bounds.is_empty()
+            && self.opcodes.is_empty()
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(|s| &s[..])
+    }
+
+    pub fn comment(&self, addr: u16) -> Option<&str> {
+        self.comments.get(&addr).map(|s| &s[..])
+    }
+
+    /// The address a label names.
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|&(_, l)| l == name)
+            .map(|(a, _)| *a)
+    }
+
+    /// Set or, given None, remove the label of an address.
+    pub fn set_label(&mut self, addr: u16, name: Option<&str>) -> Result<(), ProjectError> {
+        match name {
+            Some(name) => {
+                if !valid_label(name) {
+                    return Err(ProjectError::Invalid(format!("bad label '{}'", name)));
+                }
+                match self.address_of(name) {
+                    Some(other) if other != addr => {
+                        return Err(ProjectError::Invalid(format!(
+                            "'{}' already labels {}",
+                            name,
+                            radix::address(other)
+                        )))
+                    }
+                    _ => {}
+                }
+                self.labels.insert(addr, name.to_string());
+            }
+            None => {
+                self.labels.remove(&addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set or, given None, remove the comment on an address.
+    pub fn set_comment(&mut self, addr: u16, text: Option<&str>) {
+        match text {
+            Some(text) => self.comments.insert(addr, text.to_string()),
+            None => self.comments.remove(&addr),
+        };
+    }
+
+    pub fn set_entry(&mut self, addr: u16, entry: bool) {
+        if entry {
+            self.entries.insert(addr);
+        } else {
+            self.entries.remove(&addr);
+        }
+    }
+
+    /// Whether a lint rule is not to be reported at an address.
+    pub fn is_allowed(&self, addr: u16, rule: &str) -> bool {
+        self.allowed
+            .get(&addr)
+            .is_some_and(|rules| rules.contains(rule))
+    }
+
+    /// Allow a lint rule at an address, or stop allowing it.
+    pub fn set_allowed(&mut self, addr: u16, rule: &str, allow: bool) {
+        if allow {
+            self.allowed
+                .entry(addr)
+                .or_default()
+                .insert(rule.to_string());
+        } else if let Some(rules) = self.allowed.get_mut(&addr) {
+            rules.remove(rule);
+            if rules.is_empty() {
+                self.allowed.remove(&addr);
+            }
+        }
+    }
+
+    /// Turn a lint rule off for the whole image, or back on.
+    pub fn set_rule_enabled(&mut self, rule: &str, enabled: bool) {
+        if enabled {
+            self.disabled_rules.remove(rule);
+        } else {
+            self.disabled_rules.insert(rule.to_string());
+        }
+    }
+
+    /// Set or, given None, remove the loop bound of an address.
+    pub fn set_loop_bound(&mut self, addr: u16, bound: Option<u32>) {
+        match bound {
+            Some(n) => self.loop_bounds.insert(addr, n),
+            None => self.loop_bounds.remove(&addr),
+        };
+    }
+
+    /// The opcode with a name.
+    pub fn opcode_named(&self, name: &str) -> Option<u16> {
+        self.opcodes
+            .iter()
+            .find(|&(_, op)| op.name.as_ref().is_some_and(|n| n == name))
+            .map(|(code, _)| *code)
+    }
+
+    /// Set or, given None, remove an opcode.
+    pub fn set_opcode(&mut self, code: u16, opcode: Option<Opcode>) {
+        match opcode {
+            Some(op) => self.opcodes.insert(code, op),
+            None => self.opcodes.remove(&code),
+        };
+    }
+
+    /// Every annotated address.
+    pub fn addresses(&self) -> BTreeSet<u16> {
+        let mut addresses: BTreeSet<u16> = self.labels.keys().cloned().collect();
+        addresses.extend(self.comments.keys());
+        addresses.extend(&self.entries);
+        addresses.extend(self.allowed.keys());
+        addresses.extend(self.loop_bounds.keys());
+        // Handlers are kept with their words, so that they can follow them.
+        addresses.extend(self.opcodes.values().filter_map(|op| op.handler));
+        addresses
+    }
+}
+
+//
+// Fingerprints
+//
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// A 64-bit FNV-1a hash of the image's contents, as hex.
+pub fn fingerprint(ucode: &Microcode) -> String {
+    let mut hash = fnv(FNV_OFFSET, &ucode.version.to_le_bytes());
+    for w in &ucode.c_mem.mem {
+        hash = fnv(hash, &w.address.to_le_bytes());
+        hash = fnv(hash, &w.bits().to_le_bytes());
+    }
+    for mem in &[&ucode.a_mem, &ucode.b_mem] {
+        for w in &mem.mem {
+            hash = fnv(hash, &w.address.to_le_bytes());
+            hash = fnv(hash, &w.data.to_le_bytes());
+        }
+    }
+    for t in &ucode.type_map.mem {
+        hash = fnv(hash, &[t.data]);
+    }
+    format!("{:016x}", hash)
+}
+
+//
+// Project file
+//
+
+#[derive(Serialize, Deserialize)]
+struct ProjectFile {
+    fingerprint: String,
+    /// Lint rules turned off.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    disabled_rules: Vec<String>,
+    #[serde(default)]
+    annotation: Vec<Entry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    opcode: Vec<OpcodeEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Octal control memory address.
+    address: String,
+    /// The octal bits of the word at the address, if there was one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    word: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    entry: bool,
+    /// Lint rules not reported here.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allow: Vec<String>,
+    /// The most times the word runs along one path.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loop_bound: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpcodeEntry {
+    /// The octal opcode.
+    code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The octal control memory address of the handler.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handler: Option<String>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// What loading a project file found.
+pub struct LoadReport {
+    pub path: String,
+    pub annotations: usize,
+    /// (from, to) for every annotation that followed its word.
+    pub relocated: Vec<(u16, u16)>,
+    /// Annotations whose word could not be found after a change.
+    pub unmatched: Vec<u16>,
+}
+
+/// The project file for the loaded image.
+pub fn path(ucode: &Microcode) -> Result<String, ProjectError> {
+    match ucode.path {
+        Some(ref p) => Ok(format!("{}{}", p, SUFFIX)),
+        None => Err(ProjectError::NoImage),
+    }
+}
+
+fn word_at(ucode: &Microcode, addr: u16) -> Option<u128> {
+    ucode.c_word(addr).map(CWord::bits)
+}
+
+/// Write the annotations of the loaded image to its project file.
+pub fn save(ucode: &Microcode) -> Result<String, ProjectError> {
+    let image = ucode.path.clone().ok_or(ProjectError::NoImage)?;
+    save_for(ucode, &image)
+}
+
+/// Write the annotations to the project file of the image at
+/// `image`, as when the image has been saved under a new name.
+pub fn save_for(ucode: &Microcode, image: &str) -> Result<String, ProjectError> {
+    let path = format!("{}{}", shellexpand::tilde(image), SUFFIX);
+    let notes = &ucode.annotations;
+
+    let file = ProjectFile {
+        fingerprint: fingerprint(ucode),
+        disabled_rules: notes.disabled_rules.iter().cloned().collect(),
+        annotation: notes
+            .addresses()
+            .into_iter()
+            .map(|addr| Entry {
+                address: format!("{:05o}", addr),
+                word: word_at(ucode, addr).map(|w| format!("{:038o}", w)),
+                label: notes.label(addr).map(|s| s.to_string()),
+                comment: notes.comment(addr).map(|s| s.to_string()),
+                entry: notes.entries.contains(&addr),
+                allow: notes
+                    .allowed
+                    .get(&addr)
+                    .map_or(Vec::new(), |rules| rules.iter().cloned().collect()),
+                loop_bound: notes.loop_bounds.get(&addr).cloned(),
+            })
+            .collect(),
+        opcode: notes
+            .opcodes
+            .iter()
+            .map(|(code, op)| OpcodeEntry {
+                code: format!("{:o}", code),
+                name: op.name.clone(),
+                handler: op.handler.map(|a| format!("{:05o}", a)),
+            })
+            .collect(),
+    };
+
+    let text = toml::to_string(&file).map_err(|e| ProjectError::Parse(format!("{}", e)))?;
+    File::create(&path)?.write_all(text.as_bytes())?;
+    Ok(path)
+}
+
+/// Load the project file of the loaded image into its annotations,
+/// if there is one.
+pub fn load(ucode: &mut Microcode) -> Result<Option<LoadReport>, ProjectError> {
+    let path = path(ucode)?;
+    ucode.annotations.clear();
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+
+    let mut text = String::new();
+    File::open(&path)?.read_to_string(&mut text)?;
+    let file: ProjectFile =
+        toml::from_str(&text).map_err(|e| ProjectError::Parse(format!("{}", e)))?;
+    let changed = file.fingerprint != fingerprint(ucode);
+
+    let mut report = LoadReport {
+        path,
+        annotations: file.annotation.len(),
+        relocated: Vec::new(),
+        unmatched: Vec::new(),
+    };
+
+    // Work out where each annotation now belongs. Those whose word was
+    // found go first, so that one left behind at an address that has
+    // since been taken by a moved word does not overwrite it.
+    let mut placed = Vec::new();
+    for entry in file.annotation {
+        let addr = match asm::parse_number(&entry.address).and_then(control_address) {
+            Some(a) => a,
+            None => {
+                return Err(ProjectError::Invalid(format!(
+                    "bad address '{}'",
+                    entry.address
+                )))
+            }
+        };
+
+        let word = entry
+            .word
+            .as_ref()
+            .and_then(|w| u128::from_str_radix(w, 8).ok());
+        match word {
+            Some(word) if changed && word_at(ucode, addr) != Some(word) => {
+                let found: Vec<u16> = ucode
+                    .c_mem
+                    .mem
+                    .iter()
+                    .filter(|w| w.bits() == word)
+                    .map(|w| w.address)
+                    .collect();
+                if found.len() == 1 {
+                    report.relocated.push((addr, found[0]));
+                    placed.push((true, found[0], entry));
+                } else {
+                    report.unmatched.push(addr);
+                    placed.push((false, addr, entry));
+                }
+            }
+            _ => placed.push((true, addr, entry)),
+        }
+    }
+    placed.sort_by_key(|&(matched, _, _)| !matched);
+
+    let mut notes = Annotations::default();
+    for rule in &file.disabled_rules {
+        notes.set_rule_enabled(rule, false);
+    }
+    for (matched, addr, entry) in placed {
+        if let Some(ref label) = entry.label {
+            if matched || notes.label(addr).is_none() {
+                notes.set_label(addr, Some(label))?;
+            }
+        }
+        if let Some(ref comment) = entry.comment {
+            if matched || notes.comment(addr).is_none() {
+                notes.set_comment(addr, Some(comment));
+            }
+        }
+        if entry.entry {
+            notes.set_entry(addr, true);
+        }
+        for rule in &entry.allow {
+            notes.set_allowed(addr, rule, true);
+        }
+        if entry.loop_bound.is_some() && (matched || !notes.loop_bounds.contains_key(&addr)) {
+            notes.set_loop_bound(addr, entry.loop_bound);
+        }
+    }
+
+    // Handlers follow their words too.
+    for entry in &file.opcode {
+        let bad = |text: &str| ProjectError::Invalid(format!("bad opcode entry '{}'", text));
+        let code = match asm::parse_number(&entry.code) {
+            Some(n) if n <= u64::from(OPCODE_MAX) => n as u16,
+            _ => return Err(bad(&entry.code)),
+        };
+        let handler = match entry.handler {
+            Some(ref h) => {
+                let addr = asm::parse_number(h)
+                    .and_then(control_address)
+                    .ok_or_else(|| bad(h))?;
+                Some(
+                    report
+                        .relocated
+                        .iter()
+                        .find(|&&(from, _)| from == addr)
+                        .map_or(addr, |&(_, to)| to),
+                )
+            }
+            None => None,
+        };
+        notes.set_opcode(
+            code,
+            Some(Opcode {
+                name: entry.name.clone(),
+                handler,
+            }),
+        );
+    }
+
+    ucode.annotations = notes;
+    Ok(Some(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(name: &str, words: &[(u16, u128)]) -> Microcode {
+        let mut ucode = Microcode::new();
+        let path = ::std::env::temp_dir().join(format!(
+            "uc-explorer-{}-{}.mic",
+            ::std::process::id(),
+            name
+        ));
+        ucode.path = Some(path.to_str().unwrap().to_string());
+        for &(addr, word) in words {
+            ucode.c_mem.push(CWord::from_bits(addr, word));
+        }
+        ucode
+    }
+
+    #[test]
+    fn labels_are_checked() {
+        let mut notes = Annotations::default();
+        assert!(notes.set_label(0o10, Some("start")).is_ok());
+        assert!(notes.set_label(0o11, Some("start")).is_err());
+        assert!(notes.set_label(0o11, Some("1st")).is_err());
+        assert!(notes.set_label(0o11, Some("a;b")).is_err());
+        assert_eq!(notes.address_of("start"), Some(0o10));
+        assert!(notes.set_label(0o10, None).is_ok());
+        assert!(notes.is_empty());
+    }
+
+    #[test]
+    fn saves_and_reloads_annotations() {
+        let mut ucode = image("same", &[(0o10, 1), (0o11, 2)]);
+        assert!(ucode.annotations.set_label(0o10, Some("start")).is_ok());
+        ucode.annotations.set_comment(0o11, Some("the second word"));
+        ucode.annotations.set_entry(0o11, true);
+        ucode.annotations.set_allowed(0o10, "obus-illegal", true);
+        ucode.annotations.set_rule_enabled("bmem-constant", false);
+        ucode.annotations.set_loop_bound(0o11, Some(8));
+        let car = Opcode {
+            name: Some("car".to_string()),
+            handler: Some(0o10),
+        };
+        ucode.annotations.set_opcode(0o42, Some(car.clone()));
+        let path = save(&ucode).ok().unwrap();
+
+        ucode.annotations.clear();
+        let report = load(&mut ucode).ok().unwrap().unwrap();
+        ::std::fs::remove_file(path).unwrap();
+
+        assert_eq!(report.annotations, 2);
+        assert!(report.relocated.is_empty());
+        assert_eq!(ucode.annotations.label(0o10), Some("start"));
+        assert_eq!(ucode.annotations.comment(0o11), Some("the second word"));
+        assert!(ucode.annotations.entries.contains(&0o11));
+        assert!(ucode.annotations.is_allowed(0o10, "obus-illegal"));
+        assert!(!ucode.annotations.is_allowed(0o11, "obus-illegal"));
+        assert!(ucode.annotations.disabled_rules.contains("bmem-constant"));
+        assert_eq!(ucode.annotations.loop_bounds.get(&0o11), Some(&8));
+        assert_eq!(ucode.annotations.opcodes.get(&0o42), Some(&car));
+        assert_eq!(ucode.annotations.opcode_named("car"), Some(0o42));
+    }
+
+    #[test]
+    fn saves_and_reloads_opcodes_past_control_memory() {
+        let mut ucode = image("opcodes", &[(0o10, 1)]);
+        let last = Opcode {
+            name: Some("last".to_string()),
+            handler: Some(0o10),
+        };
+        ucode.annotations.set_opcode(0o177777, Some(last.clone()));
+        let path = save(&ucode).ok().unwrap();
+
+        ucode.annotations.clear();
+        let loaded = load(&mut ucode);
+        ::std::fs::remove_file(path).unwrap();
+
+        assert!(loaded.is_ok());
+        assert_eq!(ucode.annotations.opcodes.get(&0o177777), Some(&last));
+    }
+
+    #[test]
+    fn annotations_follow_moved_words() {
+        let mut ucode = image("moved", &[(0o10, 1), (0o11, 2), (0o12, 3)]);
+        assert!(ucode.annotations.set_label(0o11, Some("two")).is_ok());
+        assert!(ucode.annotations.set_label(0o12, Some("three")).is_ok());
+        for &(code, handler) in &[(1, 0o10), (2, 0o11)] {
+            let op = Opcode {
+                name: None,
+                handler: Some(handler),
+            };
+            ucode.annotations.set_opcode(code, Some(op));
+        }
+        let path = save(&ucode).ok().unwrap();
+
+        // Insert a word, moving the others up, and lose word 3.
+        let path_before = ucode.path.clone();
+        let mut ucode = image("moved", &[(0o10, 1), (0o11, 9), (0o12, 2)]);
+        ucode.path = path_before;
+        let report = load(&mut ucode).ok().unwrap().unwrap();
+        ::std::fs::remove_file(path).unwrap();
+
+        assert_eq!(report.relocated, vec![(0o11, 0o12)]);
+        assert_eq!(report.unmatched, vec![0o12]);
+        assert_eq!(ucode.annotations.label(0o12), Some("two"));
+        assert_eq!(ucode.annotations.address_of("three"), None);
+        assert_eq!(ucode.annotations.opcodes[&1].handler, Some(0o10));
+        assert_eq!(ucode.annotations.opcodes[&2].handler, Some(0o12));
+    }
+
+    #[test]
+    fn fingerprint_follows_contents() {
+        let a = image("fp", &[(0o10, 1)]);
+        let b = image("fp", &[(0o10, 2)]);
+        assert_eq!(fingerprint(&a), fingerprint(&image("fp", &[(0o10, 1)])));
+        assert_ne!(fingerprint(&a), fingerprint(&b));
+    }
+}
@@ -0,0 +1,209 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use ucode::ADDRESS_BITS;
+
+//
+// Number radix.
+//
+// Numbers are shown and read in the radix chosen with `set radix`,
+// octal unless it is changed. A number can always give its own radix
+// with a prefix, as in #o17, #d15 or #xf, or be written in decimal
+// with a trailing point, as in 15. In hex, a number without a prefix
+// must start with a digit, so that it is not taken for a name; hex
+// numbers that would start with a letter are printed with a 0.
+//
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Radix {
+    Octal,
+    Decimal,
+    Hex,
+}
+
+static CURRENT: AtomicU32 = AtomicU32::new(8);
+
+impl Radix {
+    pub fn from_base(base: u32) -> Option<Radix> {
+        match base {
+            8 => Some(Radix::Octal),
+            10 => Some(Radix::Decimal),
+            16 => Some(Radix::Hex),
+            _ => None,
+        }
+    }
+
+    pub fn base(self) -> u32 {
+        match self {
+            Radix::Octal => 8,
+            Radix::Decimal => 10,
+            Radix::Hex => 16,
+        }
+    }
+
+    /// The prefix that gives a number this radix.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Radix::Octal => "#o",
+            Radix::Decimal => "#d",
+            Radix::Hex => "#x",
+        }
+    }
+
+    /// How many digits the largest value of `bits` bits takes.
+    pub fn digits(self, bits: u32) -> usize {
+        match self {
+            Radix::Octal => bits.div_ceil(3) as usize,
+            Radix::Hex => bits.div_ceil(4) as usize,
+            Radix::Decimal => (((1u128 << bits) - 1) as f64).log10().floor() as usize + 1,
+        }
+    }
+
+    pub fn format(self, value: u64) -> String {
+        match self {
+            Radix::Octal => format!("{:o}", value),
+            Radix::Decimal => format!("{}", value),
+            Radix::Hex => {
+                let text = format!("{:x}", value);
+                if text.starts_with(|c: char| c.is_ascii_digit()) {
+                    text
+                } else {
+                    format!("0{}", text)
+                }
+            }
+        }
+    }
+
+    /// Format a value padded with zeros to the width of a `bits`-bit
+    /// value.
+    pub fn format_width(self, value: u64, bits: u32) -> String {
+        let digits = self.digits(bits);
+        match self {
+            Radix::Octal => format!("{:01$o}", value, digits),
+            Radix::Decimal => format!("{:01$}", value, digits),
+            Radix::Hex => {
+                // Keep room for the 0 that a leading letter needs.
+                let text = format!("{:01$x}", value, digits);
+                if text.starts_with(|c: char| c.is_ascii_digit()) {
+                    text
+                } else {
+                    format!("0{}", text)
+                }
+            }
+        }
+    }
+
+    /// Parse a number in this radix, unless it gives another.
+    pub fn parse(self, token: &str) -> Option<u64> {
+        let (base, digits) = if let Some(d) = token.strip_prefix("#o") {
+            (8, d)
+        } else if let Some(d) = token.strip_prefix("#d") {
+            (10, d)
+        } else if let Some(d) = token.strip_prefix("#x") {
+            (16, d)
+        } else if let Some(d) = token.strip_suffix('.') {
+            (10, d)
+        } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+            (self.base(), token)
+        } else {
+            return None;
+        };
+        // from_str_radix would take a sign.
+        if !digits.starts_with(|c: char| c.is_digit(base)) {
+            return None;
+        }
+        u64::from_str_radix(digits, base).ok()
+    }
+}
+
+impl fmt::Display for Radix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Radix::Octal => "octal",
+            Radix::Decimal => "decimal",
+            Radix::Hex => "hex",
+        };
+        write!(f, "{} ({})", self.base(), name)
+    }
+}
+
+pub fn current() -> Radix {
+    Radix::from_base(CURRENT.load(Ordering::Relaxed)).unwrap_or(Radix::Octal)
+}
+
+pub fn set_current(radix: Radix) {
+    CURRENT.store(radix.base(), Ordering::Relaxed);
+}
+
+/// Parse a number typed at the prompt.
+pub fn parse_number(token: &str) -> Option<u64> {
+    current().parse(token)
+}
+
+/// Format a number for display.
+pub fn number(value: u64) -> String {
+    current().format(value)
+}
+
+/// Format a number for display, padded to the width of `bits` bits.
+pub fn padded(value: u64, bits: u32) -> String {
+    current().format_width(value, bits)
+}
+
+/// Format a control memory address for display.
+pub fn address(addr: u16) -> String {
+    padded(addr as u64, ADDRESS_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_in_each_radix() {
+        assert_eq!(Radix::Octal.format_width(0o12, ADDRESS_BITS), "00012");
+        assert_eq!(Radix::Decimal.format_width(10, ADDRESS_BITS), "00010");
+        assert_eq!(Radix::Hex.format_width(10, ADDRESS_BITS), "000a");
+        assert_eq!(Radix::Hex.format_width(0xabc, 12), "0abc");
+        assert_eq!(Radix::Hex.format(0xff), "0ff");
+        assert_eq!(Radix::Hex.format(0x1f), "1f");
+        assert_eq!(Radix::Decimal.digits(8), 3);
+        assert_eq!(Radix::Decimal.digits(64), 20);
+    }
+
+    #[test]
+    fn parses_prefixes_and_points() {
+        assert_eq!(Radix::Octal.parse("17"), Some(0o17));
+        assert_eq!(Radix::Hex.parse("17"), Some(0x17));
+        assert_eq!(Radix::Decimal.parse("17"), Some(17));
+        assert_eq!(Radix::Hex.parse("#o17"), Some(0o17));
+        assert_eq!(Radix::Octal.parse("#xff"), Some(0xff));
+        assert_eq!(Radix::Octal.parse("#d19"), Some(19));
+        assert_eq!(Radix::Hex.parse("19."), Some(19));
+        assert_eq!(Radix::Hex.parse("0ff"), Some(0xff));
+        assert_eq!(Radix::Hex.parse("ff"), None);
+        assert_eq!(Radix::Octal.parse("8"), None);
+        assert_eq!(Radix::Octal.parse("#x+1"), None);
+        assert_eq!(Radix::Octal.parse("#x"), None);
+    }
+}
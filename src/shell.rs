@@ -0,0 +1,402 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use shellexpand;
+
+//
+// Command line grammar.
+//
+// A line holds one or more commands separated by ';'. Words are
+// separated by whitespace and may be quoted with '...' or "...", and
+// outside single quotes a backslash takes the next character as it
+// is. A command's output can be written to a file with '> file',
+// appended to one with '>> file', or shown a screenful at a time with
+// '| pager'.
+//
+
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum SyntaxError {
+    UnterminatedQuote,
+    MissingFile,
+    UnknownPipe(String),
+    TwoOutputs,
+    NoCommand,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyntaxError::UnterminatedQuote => write!(f, "Unterminated quote"),
+            SyntaxError::MissingFile => write!(f, "Missing file name after '>'"),
+            SyntaxError::UnknownPipe(ref name) => {
+                write!(f, "Cannot pipe to '{}', only to the pager", name)
+            }
+            SyntaxError::TwoOutputs => write!(f, "A command can only have one output"),
+            SyntaxError::NoCommand => write!(f, "Missing command"),
+        }
+    }
+}
+
+impl error::Error for SyntaxError {}
+
+/// Where a command's output goes.
+#[derive(Debug, PartialEq)]
+pub enum Output {
+    Terminal,
+    Write(String),
+    Append(String),
+    Pager,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Statement {
+    pub words: Vec<String>,
+    pub output: Output,
+}
+
+/// The names that `|` accepts, all meaning the internal pager.
+const PAGERS: &[&str] = &["pager", "more", "less"];
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Separator,
+    Write,
+    Append,
+    Pipe,
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, SyntaxError> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(SyntaxError::UnterminatedQuote),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => word.push(c),
+                            None => return Err(SyntaxError::UnterminatedQuote),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(SyntaxError::UnterminatedQuote),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                if let Some(c) = chars.next() {
+                    word.push(c);
+                }
+            }
+            ';' | '>' | '|' => {
+                if in_word {
+                    tokens.push(Token::Word(word.split_off(0)));
+                    in_word = false;
+                }
+                tokens.push(match c {
+                    ';' => Token::Separator,
+                    '|' => Token::Pipe,
+                    _ if chars.peek() == Some(&'>') => {
+                        chars.next();
+                        Token::Append
+                    }
+                    _ => Token::Write,
+                });
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    tokens.push(Token::Word(word.split_off(0)));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        tokens.push(Token::Word(word));
+    }
+
+    Ok(tokens)
+}
+
+/// Parse a line into the commands on it. Empty commands, as in a
+/// blank line or a trailing ';', are left out.
+pub fn parse(line: &str) -> Result<Vec<Statement>, SyntaxError> {
+    let mut statements = Vec::new();
+    let mut words = Vec::new();
+    let mut output = Output::Terminal;
+    let mut tokens = tokenize(line)?.into_iter();
+
+    loop {
+        let token = tokens.next();
+        let redirect = match token {
+            Some(Token::Word(word)) => {
+                words.push(word);
+                continue;
+            }
+            Some(Token::Write) | Some(Token::Append) => match tokens.next() {
+                Some(Token::Word(file)) if token == Some(Token::Write) => Output::Write(file),
+                Some(Token::Word(file)) => Output::Append(file),
+                _ => return Err(SyntaxError::MissingFile),
+            },
+            Some(Token::Pipe) => match tokens.next() {
+                Some(Token::Word(ref name)) if PAGERS.contains(&&name[..]) => Output::Pager,
+                Some(Token::Word(name)) => return Err(SyntaxError::UnknownPipe(name)),
+                _ => return Err(SyntaxError::UnknownPipe(String::new())),
+            },
+            Some(Token::Separator) | None => {
+                if words.is_empty() && output != Output::Terminal {
+                    return Err(SyntaxError::NoCommand);
+                }
+                if !words.is_empty() {
+                    statements.push(Statement {
+                        words: words.split_off(0),
+                        output,
+                    });
+                }
+                output = Output::Terminal;
+                if token.is_none() {
+                    return Ok(statements);
+                }
+                continue;
+            }
+        };
+        if output != Output::Terminal {
+            return Err(SyntaxError::TwoOutputs);
+        }
+        output = redirect;
+    }
+}
+
+//
+// Output
+//
+// Commands print through `output`, so that what they print can be
+// captured while they run and then sent where the command line said.
+//
+
+struct Capture {
+    buffer: Vec<u8>,
+    paged: bool,
+}
+
+thread_local! {
+    static CAPTURE: RefCell<Option<Capture>> = const { RefCell::new(None) };
+}
+
+/// Print command output to the terminal, or to the capture buffer
+/// while a command's output is being captured.
+pub fn output(args: fmt::Arguments) {
+    CAPTURE.with(|capture| match *capture.borrow_mut() {
+        Some(ref mut capture) => {
+            let _ = capture.buffer.write_fmt(args);
+        }
+        None => print!("{}", args),
+    })
+}
+
+/// Run `f`, capturing what it prints with `output`. `paged` says
+/// whether the captured text will be shown on the terminal by a pager.
+pub fn capture<R, F: FnOnce() -> R>(paged: bool, f: F) -> (R, Vec<u8>) {
+    CAPTURE.with(|capture| {
+        *capture.borrow_mut() = Some(Capture {
+            buffer: Vec::new(),
+            paged,
+        })
+    });
+    let result = f();
+    let capture = CAPTURE.with(|capture| capture.borrow_mut().take());
+    (result, capture.map(|c| c.buffer).unwrap_or_default())
+}
+
+/// Whether command output will end up on a terminal: it is not
+/// redirected to a file and standard output is a terminal.
+pub fn to_terminal() -> bool {
+    let paged = CAPTURE.with(|capture| capture.borrow().as_ref().is_none_or(|c| c.paged));
+    paged && io::stdout().is_terminal()
+}
+
+/// Send captured output where it was meant to go.
+pub fn deliver(output: &Output, text: &[u8]) -> io::Result<()> {
+    match *output {
+        Output::Terminal => io::stdout().write_all(text),
+        Output::Write(ref path) => OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(shellexpand::tilde(path).to_string())?
+            .write_all(text),
+        Output::Append(ref path) => OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(shellexpand::tilde(path).to_string())?
+            .write_all(text),
+        Output::Pager => {
+            let stdout = io::stdout();
+            if !stdout.is_terminal() {
+                return stdout.lock().write_all(text);
+            }
+            let stdin = io::stdin();
+            page(
+                &String::from_utf8_lossy(text),
+                screen_height(),
+                &mut stdin.lock(),
+                &mut stdout.lock(),
+            )
+        }
+    }
+}
+
+fn screen_height() -> usize {
+    ::std::env::var("LINES")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(24)
+}
+
+/// Show text a screenful at a time. Return shows the next screenful
+/// and 'q' stops.
+pub fn page<R: BufRead, W: Write>(
+    text: &str,
+    height: usize,
+    input: &mut R,
+    out: &mut W,
+) -> io::Result<()> {
+    let lines: Vec<&str> = text.lines().collect();
+    // Leave a line for the prompt.
+    let screenful = height.saturating_sub(1).max(1);
+
+    let mut shown = 0;
+    while shown < lines.len() {
+        for line in lines.iter().skip(shown).take(screenful) {
+            writeln!(out, "{}", line)?;
+        }
+        shown += screenful;
+        if shown >= lines.len() {
+            break;
+        }
+        write!(out, "--More-- ({}%) ", shown * 100 / lines.len())?;
+        out.flush()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 || answer.trim() == "q" {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(statement: &Statement) -> Vec<&str> {
+        statement.words.iter().map(|w| &w[..]).collect()
+    }
+
+    #[test]
+    fn splits_words_and_quotes() {
+        let parsed = parse("  load  'my file.mic' ").unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(words(&parsed[0]), vec!["load", "my file.mic"]);
+
+        let parsed = parse(r#"comment 10 "say \"hi\"" a\ b ''"#).unwrap();
+        assert_eq!(
+            words(&parsed[0]),
+            vec!["comment", "10", "say \"hi\"", "a b", ""]
+        );
+
+        assert_eq!(parse("load 'x").err(), Some(SyntaxError::UnterminatedQuote));
+        assert!(parse("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn separates_commands_and_outputs() {
+        let parsed =
+            parse("goto 10; dump>out.txt ;; find seq=popj >> 'a b' ; help | pager;").unwrap();
+        assert_eq!(parsed.len(), 4);
+        assert_eq!(words(&parsed[0]), vec!["goto", "10"]);
+        assert_eq!(parsed[0].output, Output::Terminal);
+        assert_eq!(parsed[1].output, Output::Write("out.txt".to_string()));
+        assert_eq!(words(&parsed[2]), vec!["find", "seq=popj"]);
+        assert_eq!(parsed[2].output, Output::Append("a b".to_string()));
+        assert_eq!(parsed[3].output, Output::Pager);
+
+        assert_eq!(parse("dump 'a;b>c'").unwrap()[0].words[1], "a;b>c");
+        assert_eq!(parse("dump >").err(), Some(SyntaxError::MissingFile));
+        assert_eq!(parse("dump > a > b").err(), Some(SyntaxError::TwoOutputs));
+        assert_eq!(parse("> a").err(), Some(SyntaxError::NoCommand));
+        assert_eq!(
+            parse("help | grep x").err(),
+            Some(SyntaxError::UnknownPipe("grep".to_string()))
+        );
+    }
+
+    #[test]
+    fn captures_output() {
+        let (result, text) = capture(false, || {
+            output(format_args!("one {}\n", 1));
+            2
+        });
+        assert_eq!(result, 2);
+        assert_eq!(text, b"one 1\n");
+    }
+
+    #[test]
+    fn pages_until_quit() {
+        let text = "1\n2\n3\n4\n5\n6\n7\n";
+        let mut out = Vec::new();
+        page(text, 3, &mut &b"\nq\n"[..], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "1\n2\n--More-- (28%) 3\n4\n--More-- (57%) "
+        );
+
+        let mut out = Vec::new();
+        page("1\n2\n", 24, &mut &b""[..], &mut out).unwrap();
+        assert_eq!(out, b"1\n2\n");
+    }
+}
@@ -0,0 +1,1374 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use fields::{
+    AbusSource, AluFunction, AmraSel, AmwaSel, BmemSource, ByteFunction, CondFunc, CondSel, CpcSel,
+    LtypeSel, MemOp, NpcSel, ObusCdr, ObusHtype, Seq, SpecialFunction, XybusSel,
+};
+use lisp::{cdr_code, data_type, high_type, is_fixnum};
+use radix;
+use ucode::{MicroInstruction, Microcode, ADDRESS_MASK};
+
+//
+// Datapath simulator.
+//
+// This executes control memory words one cycle at a time, following
+// the field descriptions in docs/microinstruction.bits.txt. Where the
+// documentation is silent or ambiguous the choice made here is noted
+// next to the code that makes it. Multiply, the GC map, the crocks
+// to Ybus and the FPA are not modeled.
+//
+
+pub const A_MEM_SIZE: usize = 4096;
+pub const B_MEM_SIZE: usize = 256;
+pub const C_MEM_SIZE: usize = 16384;
+pub const CONTROL_STACK_SIZE: usize = 16;
+
+const WORD_MASK: u64 = 0xf_ffff_ffff;
+const POINTER_MASK: u32 = 0x0fff_ffff;
+
+/// The skip bit of the next microinstruction address.
+pub(crate) const SKIP_BIT: u16 = 0x1000;
+
+/// The dispatch bits of the NAF replaced when U NPC SEL is 0, and
+/// when an arithmetic trap with dispatch is taken.
+pub(crate) const DISPATCH_MASK: u16 = 0x0f00;
+
+/// The bit of a type map entry used as the "Cond" bit.
+const TYPE_MAP_COND_BIT: u8 = 0x1;
+
+//
+// Main memory
+//
+
+/// The main memory seen through VMA and MD.
+pub trait MainMemory {
+    fn read(&mut self, address: u32) -> u64;
+    fn write(&mut self, address: u32, data: u64);
+}
+
+/// A main memory that only stores the words that have been written.
+/// Unwritten words read as zero.
+pub struct SparseMemory {
+    pub words: HashMap<u32, u64>,
+}
+
+impl SparseMemory {
+    pub fn new() -> SparseMemory {
+        SparseMemory {
+            words: HashMap::new(),
+        }
+    }
+}
+
+impl Default for SparseMemory {
+    fn default() -> SparseMemory {
+        SparseMemory::new()
+    }
+}
+
+impl MainMemory for SparseMemory {
+    fn read(&mut self, address: u32) -> u64 {
+        *self.words.get(&address).unwrap_or(&0)
+    }
+
+    fn write(&mut self, address: u32, data: u64) {
+        self.words.insert(address, data & WORD_MASK);
+    }
+}
+
+//
+// Error handling
+//
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SimError {
+    NoInstruction(u16),
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SimError::NoInstruction(addr) => {
+                write!(f, "No microinstruction at address {}", radix::address(addr))
+            }
+        }
+    }
+}
+
+impl error::Error for SimError {}
+
+/// The outcome of executing one microinstruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepResult {
+    Executed,
+    /// The instruction at `from` trapped to `to`. Its writes were suppressed.
+    Trapped {
+        from: u16,
+        to: u16,
+    },
+    /// The instruction executed and then stopped the machine.
+    Halted,
+    /// The current task finished the instructions that follow a Dismiss.
+    Dismissed,
+}
+
+/// The bus values computed during one cycle.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cycle {
+    pub abus: u64,
+    pub bbus: u64,
+    pub xbus: u64,
+    pub ybus: u64,
+    pub alub: u32,
+    pub alu: u32,
+    pub carry_28: bool,
+    pub carry_32: bool,
+    pub obus: u64,
+    pub condition: bool,
+    pub dispatch: u16,
+    /// The A-memory address read, if the Abus came from A-memory.
+    pub a_read_address: Option<u16>,
+}
+
+/// The datapath and sequencer state of a running machine.
+pub struct Simulator<M: MainMemory> {
+    pub memory: M,
+    pub a_mem: Vec<u64>,
+    pub b_mem: Vec<u64>,
+    pub type_map: Vec<u8>,
+
+    // Sequencer
+    pub cpc: u16,
+    pub npc: u16,
+    pub csp: usize,
+    pub control_stack: [u16; CONTROL_STACK_SIZE],
+
+    // Base registers and data path control
+    pub sp: u32,
+    pub fp: u32,
+    pub eb: u16,
+    pub stack_base: u8,
+    pub sequence_break: bool,
+    pub trace_1: bool,
+    pub trace_2: bool,
+
+    // Byte field registers
+    pub r_reg: u8,
+    pub s_reg: u8,
+
+    // Memory interface
+    pub vma: u32,
+    pub md: u64,
+    pub other_md: u64,
+
+    // Macroinstruction state
+    pub pc: u32,
+    pub inst: u8,
+
+    // Tasking
+    pub task: u8,
+    pub task_state: [u64; 16],
+    pub task_wakeups: u16,
+    pub tasking_disabled: bool,
+
+    pub cycles: u64,
+    pub halted: bool,
+
+    /// The A-memory addresses read and written by the last instruction.
+    pub last_a_read: Option<u16>,
+    pub last_a_write: Option<u16>,
+
+    control: Vec<Option<MicroInstruction>>,
+    dismiss_countdown: Option<u8>,
+    disable_tasking_count: u8,
+}
+
+impl<M: MainMemory> Simulator<M> {
+    /// Build a simulator whose A-memory, B-memory, type map and
+    /// control memory are seeded from the loaded microcode.
+    pub fn new(ucode: &Microcode, memory: M) -> Simulator<M> {
+        let mut a_mem = vec![0; A_MEM_SIZE];
+        for word in &ucode.a_mem.mem {
+            a_mem[word.address as usize % A_MEM_SIZE] = word.data & WORD_MASK;
+        }
+
+        let mut b_mem = vec![0; B_MEM_SIZE];
+        for word in &ucode.b_mem.mem {
+            b_mem[word.address as usize % B_MEM_SIZE] = word.data & WORD_MASK;
+        }
+
+        let mut control = vec![None; C_MEM_SIZE];
+        for word in &ucode.c_mem.mem {
+            control[word.address as usize % C_MEM_SIZE] = Some(MicroInstruction::new(word));
+        }
+
+        Simulator {
+            memory,
+            a_mem,
+            b_mem,
+            type_map: ucode.type_map.mem.iter().map(|t| t.data).collect(),
+            cpc: 0,
+            npc: 1,
+            csp: 0,
+            control_stack: [0; CONTROL_STACK_SIZE],
+            sp: 0,
+            fp: 0,
+            eb: 0,
+            stack_base: 0,
+            sequence_break: false,
+            trace_1: false,
+            trace_2: false,
+            r_reg: 0,
+            s_reg: 0,
+            vma: 0,
+            md: 0,
+            other_md: 0,
+            pc: 0,
+            inst: 0,
+            task: 0,
+            task_state: [0; 16],
+            task_wakeups: 0,
+            tasking_disabled: false,
+            cycles: 0,
+            halted: false,
+            last_a_read: None,
+            last_a_write: None,
+            control,
+            dismiss_countdown: None,
+            disable_tasking_count: 0,
+        }
+    }
+
+    /// Start execution at `addr`, with NPC pointing at the word after it.
+    pub fn jump(&mut self, addr: u16) {
+        self.cpc = addr & ADDRESS_MASK;
+        self.npc = (addr & ADDRESS_MASK).wrapping_add(1) & ADDRESS_MASK;
+        self.halted = false;
+    }
+
+    /// The microinstruction at a control memory address, if any.
+    pub fn instruction(&self, addr: u16) -> Option<&MicroInstruction> {
+        self.control[(addr & ADDRESS_MASK) as usize].as_ref()
+    }
+
+    /// The top of the control stack.
+    pub fn ctos(&self) -> u16 {
+        self.control_stack[self.csp]
+    }
+
+    /// Execute instructions until the machine halts, an error occurs,
+    /// or `limit` instructions have run.
+    pub fn run(&mut self, limit: u64) -> Result<StepResult, SimError> {
+        let mut result = StepResult::Executed;
+        for _ in 0..limit {
+            result = self.step()?;
+            if result == StepResult::Halted {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Execute the microinstruction at CPC.
+    pub fn step(&mut self) -> Result<StepResult, SimError> {
+        if self.halted {
+            return Ok(StepResult::Halted);
+        }
+
+        let cpc = self.cpc;
+        let u = match self.instruction(cpc) {
+            Some(u) => *u,
+            None => return Err(SimError::NoInstruction(cpc)),
+        };
+
+        let cycle = self.compute(&u);
+        self.cycles += 1;
+        self.last_a_read = cycle.a_read_address;
+        self.last_a_write = None;
+
+        if self.traps(&u, &cycle) {
+            // A trapping instruction is NOPed. NPC remembers where
+            // the trap came from so the handler can return to it.
+            let mut to = u.naf() & ADDRESS_MASK;
+            if u.spec() == SpecialFunction::ArithTrapDispatch {
+                // Trap address <11:10> from Abus<33:32> and <9:8>
+                // from Bbus<33:32>.
+                let bits = ((cycle.abus >> 32) & 0x3) << 2 | ((cycle.bbus >> 32) & 0x3);
+                to = (to & !DISPATCH_MASK) | ((bits as u16) << 8);
+            }
+            self.cpc = to;
+            self.npc = cpc;
+            return Ok(StepResult::Trapped { from: cpc, to });
+        }
+
+        self.write_a_mem(&u, &cycle);
+        self.write_b_mem(&u, &cycle);
+        self.count_stack_pointer(&u);
+        self.special_function(&u, &cycle);
+        self.memory_function(&u, &cycle);
+        self.sequence(&u, &cycle);
+
+        if u.spec() == SpecialFunction::Halt {
+            self.halted = true;
+            return Ok(StepResult::Halted);
+        }
+
+        Ok(self.count_dismiss(&u))
+    }
+
+    //
+    // Bus computation
+    //
+
+    /// Compute the bus values for one instruction without changing
+    /// any state.
+    pub fn compute(&self, u: &MicroInstruction) -> Cycle {
+        let mut cycle = Cycle::default();
+
+        let (abus, a_read_address) = self.read_abus(u);
+        cycle.abus = abus;
+        cycle.a_read_address = a_read_address;
+        cycle.bbus = self.read_bbus(u);
+
+        if u.xybus_sel() == XybusSel::Ab {
+            cycle.xbus = cycle.abus;
+            cycle.ybus = cycle.bbus;
+        } else {
+            cycle.xbus = cycle.bbus;
+            cycle.ybus = cycle.abus;
+        }
+
+        cycle.alub = self.shift_and_mask(u, cycle.xbus as u32, cycle.ybus as u32);
+
+        let (alu, carry_28, carry_32) =
+            alu(u.alu(), self.weird_alu(u), cycle.xbus as u32, cycle.alub);
+        cycle.alu = alu;
+        cycle.carry_28 = carry_28;
+        cycle.carry_32 = carry_32;
+
+        cycle.obus = obus(u, &cycle);
+        cycle.condition = self.condition(u, &cycle);
+        cycle.dispatch = dispatch(u, &cycle);
+
+        cycle
+    }
+
+    fn weird_alu(&self, u: &MicroInstruction) -> bool {
+        (u.spec() == SpecialFunction::ArithTrap || u.spec() == SpecialFunction::ArithTrapDispatch)
+            && u.magic() & 0x4 != 0
+    }
+
+    /// Compute a base register plus offset address in the 1K bank
+    /// selected by STACK BASE. Bit 8 of the field selects the offset
+    /// from INST instead of the field itself.
+    fn base_offset(&self, base_sel: u8, field: u16) -> u16 {
+        let offset = if field & 0x100 != 0 {
+            self.inst as u32
+        } else {
+            (field & 0xff) as u32
+        };
+
+        // INST<7> picks the stack pointer, and adds one so that a
+        // (sign extended) offset of -1 addresses the top of the stack.
+        let (base, offset) = match base_sel {
+            0 => (self.sp, offset),
+            1 => (self.fp, offset),
+            2 => (self.eb as u32, offset),
+            _ if self.inst & 0x80 != 0 => (self.sp.wrapping_add(1), offset as u8 as i8 as u32),
+            _ => (self.fp, offset),
+        };
+
+        ((self.stack_base as u16) << 10) | (base.wrapping_add(offset) & 0x3ff) as u16
+    }
+
+    fn read_abus(&self, u: &MicroInstruction) -> (u64, Option<u16>) {
+        match u.amra_sel() {
+            AmraSel::Immediate => {
+                let addr = u.amra() & 0xfff;
+                (self.a_mem[addr as usize], Some(addr))
+            }
+            // LBUS ADDR is taken to lie outside A-memory, so the data
+            // comes from main memory through MD.
+            AmraSel::Lbus => (self.md, None),
+            AmraSel::Base => {
+                let addr = self.base_offset(u.r_base(), u.amra());
+                (self.a_mem[addr as usize], Some(addr))
+            }
+            AmraSel::Other => {
+                let value = match u.r_base() {
+                    0 => self.sp as u64,
+                    1 => self.fp as u64,
+                    2 => self.eb as u64,
+                    _ => match u.abus_src() {
+                        AbusSource::Md | AbusSource::MdAdvance => self.md,
+                        AbusSource::OtherMd => self.other_md,
+                        AbusSource::Vma => self.vma as u64,
+                        AbusSource::Epc => self.pc as u64,
+                        // PHTA-ASN/IIR and the maps are not modeled.
+                        _ => 0,
+                    },
+                };
+                (value, None)
+            }
+        }
+    }
+
+    fn read_bbus(&self, u: &MicroInstruction) -> u64 {
+        match u.bmra() {
+            0..=3 => self.inst as u64,
+            4..=7 => (self.inst as i8 as i32 as u32) as u64,
+            addr => self.b_mem[addr as usize],
+        }
+    }
+
+    /// The shifter and masher. Returns ALUB, the B input of the ALU.
+    fn shift_and_mask(&self, u: &MicroInstruction, xbus: u32, ybus: u32) -> u32 {
+        // (rotation, size - 1, rotate mask, merge)
+        let (r, s, rotate_mask, merge) = match u.byte_f() {
+            ByteFunction::Pass => (0, 0o37, false, false),
+            ByteFunction::Weird => {
+                if u.magic() & 0x4 == 0 {
+                    let r = match u.magic() & 0xb {
+                        0o0 => 0o17,
+                        0o1 => 0o16,
+                        0o2 => 0o1,
+                        0o3 => 0o0,
+                        0o10 => 0o37,
+                        0o11 => 0o36,
+                        0o12 => 0o21,
+                        _ => 0o20,
+                    };
+                    if u.magic() & 0xb == 0o13 {
+                        (r, 0o17, true, false)
+                    } else {
+                        (r, 0o37, false, false)
+                    }
+                } else {
+                    (0o20, 0o17, u.magic() & 0x8 != 0, false)
+                }
+            }
+            ByteFunction::Ldb => (0, u.cond_sel().code() as u32, false, false),
+            ByteFunction::General => {
+                let (r, s) = match u.magic() & 0x3 {
+                    0 => ((u.amwa() & 0x1f) as u32, ((u.amwa() >> 5) & 0x1f) as u32),
+                    1 => (self.r_reg as u32, u.cond_sel().code() as u32),
+                    2 => (self.r_reg as u32, self.s_reg as u32),
+                    // The split of INST between R and S is a guess.
+                    _ => (
+                        (self.inst & 0x1f) as u32,
+                        (u.cond_sel().code() as u32 & 0x18) | (self.inst >> 5) as u32,
+                    ),
+                };
+                (r, s, u.magic() & 0x8 != 0, u.magic() & 0x4 != 0)
+            }
+        };
+
+        let rotated = ybus.rotate_left(r);
+        let mut mask = if s >= 31 {
+            0xffff_ffff
+        } else {
+            (1u32 << (s + 1)) - 1
+        };
+        if rotate_mask {
+            mask = mask.rotate_left(r);
+        }
+
+        let mut result = rotated & mask;
+        if merge {
+            result |= xbus & !mask;
+        }
+
+        if u.spec() == SpecialFunction::AlubSign {
+            // ALUB Sign Hack: Ybus<31> is wherever the rotate put it.
+            result ^= 0x8000_0000u32.rotate_left(r) & mask;
+        }
+
+        result
+    }
+
+    fn condition(&self, u: &MicroInstruction, cycle: &Cycle) -> bool {
+        match u.cond_sel() {
+            CondSel::CdrNot0 | CondSel::CdrNot1 | CondSel::CdrNot2 | CondSel::CdrNot3 => {
+                cdr_code(cycle.abus) != u.cond_sel().code() as u64
+            }
+            CondSel::TypeMap => self.type_map_cond(u, cycle),
+            CondSel::NotFixnum => !is_fixnum(cycle.bbus),
+            CondSel::Alub0 => cycle.alub & 0x1 != 0,
+            CondSel::Ybus31 => cycle.ybus & 0x8000_0000 != 0,
+            CondSel::AluZero => cycle.alu & POINTER_MASK == 0,
+            CondSel::AluNonzero => cycle.alu != 0,
+            CondSel::AluNonzero34 => cycle.obus & 0x3_ffff_ffff != 0,
+            CondSel::NotCarry28 => !cycle.carry_28,
+            CondSel::NotCarry32 => !cycle.carry_32,
+            CondSel::Alu31 => cycle.alu & 0x8000_0000 != 0,
+            CondSel::SequenceBreak => self.sequence_break,
+            CondSel::Trace1 => self.trace_1,
+            CondSel::Trace2 => self.trace_2,
+            // GC, microdevice and MC conditions are not modeled.
+            _ => false,
+        }
+    }
+
+    fn type_map_cond(&self, u: &MicroInstruction, cycle: &Cycle) -> bool {
+        let index = (u.type_map_sel() as usize) * 64 + data_type(cycle.abus) as usize;
+        match self.type_map.get(index) {
+            Some(entry) => entry & TYPE_MAP_COND_BIT != 0,
+            None => false,
+        }
+    }
+
+    /// Whether this instruction traps, either through U COND FUNC or
+    /// through one of the arithmetic trap special functions.
+    fn traps(&self, u: &MicroInstruction, cycle: &Cycle) -> bool {
+        let cond_trap = match u.cond_func() {
+            CondFunc::Trap => cycle.condition,
+            CondFunc::TrapFalse => !cycle.condition,
+            CondFunc::Ignore | CondFunc::Skip => false,
+        };
+
+        let type_trap = match u.spec() {
+            SpecialFunction::ArithTrap | SpecialFunction::ArithTrapDispatch => {
+                (u.magic() & 0x1 != 0 && self.type_map_cond(u, cycle))
+                    || (u.magic() & 0x2 != 0 && !is_fixnum(cycle.bbus))
+            }
+            SpecialFunction::TrapTypeMap => self.type_map_cond(u, cycle),
+            SpecialFunction::TrapTypeMapFixnum => {
+                self.type_map_cond(u, cycle) || !is_fixnum(cycle.bbus)
+            }
+            _ => false,
+        };
+
+        cond_trap || type_trap
+    }
+
+    //
+    // State updates
+    //
+
+    fn write_a_mem(&mut self, u: &MicroInstruction, cycle: &Cycle) {
+        let addr = match u.amwa_sel() {
+            AmwaSel::Immediate => u.amwa() & 0xfff,
+            AmwaSel::Base => self.base_offset(u.w_base(), u.amwa()),
+            AmwaSel::Same => match cycle.a_read_address {
+                Some(addr) => addr,
+                None => u.amra() & 0xfff,
+            },
+            // Bus writes go to main memory or a microdevice, and the
+            // bus address is taken to lie outside A-memory.
+            AmwaSel::Lbus => return,
+        };
+
+        self.a_mem[addr as usize] = cycle.obus;
+        self.last_a_write = Some(addr);
+    }
+
+    fn write_b_mem(&mut self, u: &MicroInstruction, cycle: &Cycle) {
+        let addr = u.b_write_address();
+        let data = if u.bmem_from_xbus() == BmemSource::Obus {
+            cycle.obus
+        } else {
+            (cycle.abus & 0xf_0000_0000) | (cycle.xbus & 0xffff_ffff)
+        };
+
+        self.b_mem[addr as usize] = data;
+    }
+
+    fn count_stack_pointer(&mut self, u: &MicroInstruction) {
+        if u.stkp_count() != 0 {
+            self.sp = if u.stkp_count_dir() != 0 {
+                self.sp.wrapping_add(1)
+            } else {
+                self.sp.wrapping_sub(1)
+            } & POINTER_MASK;
+        }
+    }
+
+    fn special_function(&mut self, u: &MicroInstruction, cycle: &Cycle) {
+        if u.spec() != SpecialFunction::DisableTasking {
+            self.disable_tasking_count = 0;
+        }
+
+        let obus = cycle.obus;
+
+        match u.spec() {
+            SpecialFunction::RReg => self.r_reg = (obus & 0x1f) as u8,
+            SpecialFunction::SReg => self.s_reg = (obus & 0x1f) as u8,
+            SpecialFunction::Sp => self.sp = obus as u32 & POINTER_MASK,
+            SpecialFunction::Fp => self.fp = obus as u32 & POINTER_MASK,
+            SpecialFunction::Eb => self.eb = (obus & 0x3ff) as u16,
+            SpecialFunction::DpControl => {
+                self.stack_base = (obus & 0x3) as u8;
+                self.sequence_break = obus & 0x4 != 0;
+                self.trace_1 = obus & 0x8 != 0;
+                self.trace_2 = obus & 0x10 != 0;
+            }
+            SpecialFunction::WriteMaps if u.magic() & 0x1 == 0 => {
+                let index = (u.type_map_sel() as usize) * 64 + data_type(cycle.abus) as usize;
+                if index >= self.type_map.len() {
+                    self.type_map.resize(index + 1, 0);
+                }
+                self.type_map[index] = (cycle.bbus & 0xf) as u8;
+            }
+            SpecialFunction::IfuControl => match u.magic() {
+                0 => self.pc = (obus as u32 & POINTER_MASK) << 1,
+                1 => self.pc = ((obus as u32 & POINTER_MASK) << 1) | 1,
+                2 | 3 => self.pc = self.pc.wrapping_add(1),
+                _ => {}
+            },
+            SpecialFunction::AwakenTask => {
+                let task = [1, 2, 5, 6][(u.magic() & 0x3) as usize];
+                self.task_wakeups |= 1 << task;
+            }
+            SpecialFunction::WriteTask => {
+                let task = ((obus >> 32) & 0xf) as usize;
+                self.task_state[task] = obus & 0xffff_ffff;
+            }
+            SpecialFunction::DisableTasking => {
+                self.disable_tasking_count += 1;
+                if self.disable_tasking_count >= 2 {
+                    self.tasking_disabled = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn memory_function(&mut self, u: &MicroInstruction, cycle: &Cycle) {
+        // Physical references take their address from the Abus.
+        let address = if let SpecialFunction::AddrFromAbus
+        | SpecialFunction::InhibitTags
+        | SpecialFunction::Dma = u.spec()
+        {
+            cycle.abus as u32 & POINTER_MASK
+        } else {
+            self.vma
+        };
+
+        // Memory-data-advance reads MD, increments VMA and swaps MDs.
+        if u.amra_sel() == AmraSel::Other
+            && u.r_base() == 3
+            && u.abus_src() == AbusSource::MdAdvance
+        {
+            self.vma = self.vma.wrapping_add(1) & POINTER_MASK;
+            ::std::mem::swap(&mut self.md, &mut self.other_md);
+        }
+
+        match u.mem() {
+            MemOp::Read => self.md = self.memory.read(address),
+            MemOp::Write => self.memory.write(address, cycle.obus),
+            MemOp::IncrementVma => self.vma = self.vma.wrapping_add(1) & POINTER_MASK,
+            MemOp::LoadVma => self.vma = cycle.obus as u32 & POINTER_MASK,
+            MemOp::BlockRead => {
+                self.md = self.memory.read(address);
+                self.vma = self.vma.wrapping_add(1) & POINTER_MASK;
+            }
+            MemOp::BlockWrite => {
+                self.memory.write(address, cycle.obus);
+                self.vma = self.vma.wrapping_add(1) & POINTER_MASK;
+            }
+            // Microdevice operations are not modeled.
+            MemOp::None | MemOp::Udev => {}
+        }
+    }
+
+    fn sequence(&mut self, u: &MicroInstruction, cycle: &Cycle) {
+        let naf = u.naf() & ADDRESS_MASK;
+        let ctos = self.ctos();
+
+        let mut next = match u.cpc_sel() {
+            CpcSel::Ctos => ctos,
+            CpcSel::Npc => self.npc,
+            // 3 is not used; treat it as NAF.
+            CpcSel::Naf | CpcSel::Reserved3 => naf,
+        };
+
+        if u.cond_func() == CondFunc::Skip {
+            // Skip: bit 12 of the next address comes from -COND.
+            if cycle.condition {
+                next &= !SKIP_BIT;
+            } else {
+                next |= SKIP_BIT;
+            }
+        }
+
+        let npc = if u.spec() == SpecialFunction::NpcMagic {
+            match (u.magic() & 0x3, u.npc_sel()) {
+                (2, _) => cycle.obus as u16,
+                (_, NpcSel::Dispatch) => self.cpc,
+                _ => ctos,
+            }
+        } else if u.npc_sel() == NpcSel::Dispatch {
+            (naf & !DISPATCH_MASK) | (cycle.dispatch << 8)
+        } else {
+            next.wrapping_add(1)
+        };
+
+        // The control stack holds NPC values: a Pushj saves the
+        // address that would have run after this instruction.
+        match u.seq() {
+            Seq::Pushj => {
+                self.csp = (self.csp + 1) % CONTROL_STACK_SIZE;
+                self.control_stack[self.csp] = self.npc;
+            }
+            Seq::Popj => {
+                self.csp = (self.csp + CONTROL_STACK_SIZE - 1) % CONTROL_STACK_SIZE;
+            }
+            _ => {}
+        }
+
+        self.cpc = next & ADDRESS_MASK;
+        self.npc = npc & ADDRESS_MASK;
+    }
+
+    /// Tasks 1-7 run two more instructions after a Dismiss, tasks
+    /// 8-15 run one more. The documentation says nothing of task 0,
+    /// which is taken to run one more, like tasks 8-15.
+    fn count_dismiss(&mut self, u: &MicroInstruction) -> StepResult {
+        if let Some(n) = self.dismiss_countdown {
+            if n <= 1 {
+                self.dismiss_countdown = None;
+                return StepResult::Dismissed;
+            }
+            self.dismiss_countdown = Some(n - 1);
+        } else if u.seq() == Seq::Dismiss {
+            self.dismiss_countdown = Some(if (1..8).contains(&self.task) { 2 } else { 1 });
+        }
+
+        StepResult::Executed
+    }
+}
+
+/// The ALU. Returns the 32-bit result and the carries out of bits 27
+/// and 31. `y` is ALUB, the output of the shifter.
+fn alu(function: AluFunction, weird: bool, x: u32, y: u32) -> (u32, bool, bool) {
+    fn add(x: u32, y: u32, carry_in: u32) -> (u32, bool, bool) {
+        let sum = x as u64 + y as u64 + carry_in as u64;
+        let low = (x & POINTER_MASK) as u64 + (y & POINTER_MASK) as u64 + carry_in as u64;
+        (sum as u32, low >> 28 != 0, sum >> 32 != 0)
+    }
+
+    // The weird functions give the same codes other meanings.
+    if weird {
+        match function.code() {
+            0o0 => add(x, 0, 1),
+            0o1 => add(x, 0xffff_ffff, 0),
+            0o2 => add(x, y, 0),
+            0o3 | 0o4 => add(x, !y, 1),
+            0o5 => add(x, !y, 0),
+            0o6 => (!(x & y), false, false),
+            0o7 => (x & !y, false, false),
+            _ => (0, false, false),
+        }
+    } else {
+        match function {
+            AluFunction::X => (x, false, false),
+            AluFunction::Alub => (y, false, false),
+            AluFunction::XPlus1 => add(x, 0, 1),
+            AluFunction::XMinus1 => add(x, 0xffff_ffff, 0),
+            AluFunction::XPlusY => add(x, y, 0),
+            AluFunction::XMinusY => add(x, !y, 1),
+            AluFunction::XPlusYPlus1 => add(x, y, 1),
+            AluFunction::XMinusYMinus1 => add(x, !y, 0),
+            AluFunction::And => (x & y, false, false),
+            AluFunction::Ior => (x | y, false, false),
+            AluFunction::Xor => (x ^ y, false, false),
+            _ => (0, false, false),
+        }
+    }
+}
+
+/// Compose the Obus from the CDR and type selects and the ALU output.
+fn obus(u: &MicroInstruction, cycle: &Cycle) -> u64 {
+    let cdr = match u.obus_cdr() {
+        ObusCdr::A => cdr_code(cycle.abus),
+        ObusCdr::B => cdr_code(cycle.bbus),
+        ObusCdr::BLow => (cycle.bbus >> 6) & 0x3,
+        ObusCdr::Reserved3 => 0, // Illegal
+        n => (n.code() & 0x3) as u64,
+    };
+
+    let htype = match u.obus_htype() {
+        ObusHtype::A => high_type(cycle.abus),
+        ObusHtype::B => high_type(cycle.bbus),
+        ObusHtype::BLow => (cycle.bbus >> 4) & 0x3,
+        ObusHtype::Reserved3 => 0, // Illegal
+        n => (n.code() & 0x3) as u64,
+    };
+
+    let low = if u.obus_ltype_sel() == LtypeSel::Magic {
+        ((u.magic() as u64 & 0xf) << 28) | (cycle.alu & POINTER_MASK) as u64
+    } else {
+        cycle.alu as u64
+    };
+
+    (cdr << 34) | (htype << 32) | low
+}
+
+/// The dispatch selected by the bottom three bits of the magic number.
+fn dispatch(u: &MicroInstruction, cycle: &Cycle) -> u16 {
+    let a = cycle.abus;
+    (match u.magic() & 0x7 {
+        0 => (cycle.alub & 0xf) as u64,
+        1 => cdr_code(a),
+        2 => (a >> 28) & 0xf,
+        3 => (a >> 22) & 0xf,
+        4 => (a >> 18) & 0xf,
+        5 => a & 0x7,
+        6 => (((cycle.bbus >> 30) & 0x3) << 2) | ((a >> 30) & 0x3),
+        _ => 0,
+    }) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use layout;
+    use ucode::{ABWord, CWord};
+
+    struct Word(u128);
+
+    impl Word {
+        /// A word that passes Xbus through the ALU into scratch A and
+        /// B locations, then continues at NPC.
+        fn new() -> Word {
+            Word(0)
+                .f("spec", 0o20)
+                .f("amwa", 0o7777)
+                .f("obus-ltype-sel", 1)
+                .f("cpc-sel", 2)
+                .f("npc-sel", 1)
+        }
+
+        /// The word with a field of the current layout set.
+        fn f(self, name: &str, value: u64) -> Word {
+            Word(layout::current().field(name).unwrap().set(self.0, value))
+        }
+    }
+
+    fn machine(words: Vec<(u16, Word)>) -> Simulator<SparseMemory> {
+        let mut ucode = Microcode::new();
+        for (addr, word) in words {
+            ucode.c_mem.push(CWord::from_bits(addr, word.0));
+        }
+        Simulator::new(&ucode, SparseMemory::new())
+    }
+
+    fn run_one(word: Word) -> Simulator<SparseMemory> {
+        let mut sim = machine(vec![(0, word)]);
+        assert_eq!(sim.step().ok(), Some(StepResult::Executed));
+        sim
+    }
+
+    #[test]
+    fn seeds_memories_from_image() {
+        let mut ucode = Microcode::new();
+        ucode.a_mem.push(ABWord {
+            address: 0o100,
+            data: 0o123,
+        });
+        ucode.b_mem.push(ABWord {
+            address: 0o360,
+            data: 0o456,
+        });
+        let sim = Simulator::new(&ucode, SparseMemory::new());
+        assert_eq!(sim.a_mem[0o100], 0o123);
+        assert_eq!(sim.b_mem[0o360], 0o456);
+    }
+
+    #[test]
+    fn missing_instruction_is_an_error() {
+        let mut sim = machine(vec![]);
+        match sim.step() {
+            Err(SimError::NoInstruction(0)) => {}
+            _ => panic!("expected NoInstruction"),
+        }
+    }
+
+    #[test]
+    fn amra_sel_0_reads_immediate_address() {
+        let mut sim = machine(vec![(0, Word::new().f("amra", 0o100).f("amwa", 0o200))]);
+        sim.a_mem[0o100] = 0o1234;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o200], 0o1234);
+    }
+
+    #[test]
+    fn amra_sel_2_reads_base_plus_offset() {
+        // Frame pointer base (U R BASE = 1) plus offset 3
+        let word = Word::new()
+            .f("amra-sel", 2)
+            .f("amra", (1 << 9) | 3)
+            .f("amwa", 0o200);
+        let mut sim = machine(vec![(0, word)]);
+        sim.fp = 0o40;
+        sim.stack_base = 1;
+        sim.a_mem[0o2043] = 77;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o200], 77);
+    }
+
+    #[test]
+    fn amra_sel_3_reads_base_registers() {
+        let word = Word::new().f("amra-sel", 3).f("amra", 0).f("amwa", 0o200);
+        let mut sim = machine(vec![(0, word)]);
+        sim.sp = 0o5555;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o200], 0o5555);
+
+        let word = Word::new()
+            .f("amra-sel", 3)
+            .f("amra", 3 << 9 | 2 << 6)
+            .f("amwa", 0o200);
+        let mut sim = machine(vec![(0, word)]);
+        sim.vma = 0o7070;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o200], 0o7070);
+    }
+
+    #[test]
+    fn bmra_reads_b_memory_and_immediates() {
+        let mut sim = machine(vec![
+            (
+                0,
+                Word::new()
+                    .f("xybus-sel", 1)
+                    .f("bmra", 0o370)
+                    .f("amwa", 0o200),
+            ),
+            (
+                1,
+                Word::new()
+                    .f("xybus-sel", 1)
+                    .f("bmra", 0o4)
+                    .f("amwa", 0o201),
+            ),
+        ]);
+        sim.b_mem[0o370] = 42;
+        sim.inst = 0xff;
+        sim.run(2).ok();
+        assert_eq!(sim.a_mem[0o200], 42);
+        assert_eq!(sim.a_mem[0o201], 0xffff_ffff);
+    }
+
+    #[test]
+    fn xybus_sel_swaps_buses() {
+        // ALU function 1 passes ALUB, which comes from the Ybus.
+        let word = Word::new()
+            .f("alu", 1)
+            .f("amra", 0o100)
+            .f("bmra", 0o360)
+            .f("amwa", 0o200);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 1;
+        sim.b_mem[0o360] = 2;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o200], 2);
+
+        let word = Word::new()
+            .f("xybus-sel", 1)
+            .f("alu", 1)
+            .f("amra", 0o100)
+            .f("bmra", 0o360)
+            .f("amwa", 0o200);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 1;
+        sim.b_mem[0o360] = 2;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o200], 1);
+    }
+
+    #[test]
+    fn alu_function_table() {
+        assert_eq!(alu(AluFunction::from(0o0), false, 5, 3).0, 5);
+        assert_eq!(alu(AluFunction::from(0o1), false, 5, 3).0, 3);
+        assert_eq!(alu(AluFunction::from(0o2), false, 5, 3).0, 6);
+        assert_eq!(alu(AluFunction::from(0o3), false, 5, 3).0, 4);
+        assert_eq!(alu(AluFunction::from(0o4), false, 5, 3).0, 8);
+        assert_eq!(alu(AluFunction::from(0o5), false, 5, 3).0, 2);
+        assert_eq!(alu(AluFunction::from(0o6), false, 5, 3).0, 9);
+        assert_eq!(alu(AluFunction::from(0o7), false, 5, 3).0, 1);
+        assert_eq!(alu(AluFunction::from(0o10), false, 6, 3).0, 2);
+        assert_eq!(alu(AluFunction::from(0o11), false, 6, 3).0, 7);
+        assert_eq!(alu(AluFunction::from(0o12), false, 6, 3).0, 5);
+        assert_eq!(alu(AluFunction::from(0o6), true, 6, 3).0, !2);
+        assert_eq!(alu(AluFunction::from(0o7), true, 6, 3).0, 4);
+    }
+
+    #[test]
+    fn alu_carries() {
+        let (result, carry_28, carry_32) = alu(AluFunction::from(0o4), false, 0xffff_ffff, 1);
+        assert_eq!(result, 0);
+        assert!(carry_28);
+        assert!(carry_32);
+
+        let (_, carry_28, carry_32) = alu(AluFunction::from(0o4), false, 0x0fff_ffff, 1);
+        assert!(carry_28);
+        assert!(!carry_32);
+    }
+
+    #[test]
+    fn byte_f_2_extracts_right_adjusted_byte() {
+        let word = Word::new()
+            .f("xybus-sel", 1)
+            .f("amra", 0o100)
+            .f("alu", 1)
+            .f("byte-f", 2)
+            .f("cond-sel", 3)
+            .f("amwa", 0o200);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 0o1234;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o200], 0o14);
+    }
+
+    #[test]
+    fn byte_f_3_loads_and_deposits_bytes() {
+        // LDB: rotate left 28 (right 4), 4 bits, from U AMWA<9:0>
+        let cycle_of = |magic, x: u32, y: u32| {
+            let sim = machine(vec![]);
+            let word = Word::new()
+                .f("byte-f", 3)
+                .f("magic", magic)
+                .f("amwa", (3 << 5) | 28);
+            let cw = CWord::from_bits(0, word.0);
+            sim.shift_and_mask(&MicroInstruction::new(&cw), x, y)
+        };
+        assert_eq!(cycle_of(0, 0, 0xabcd), 0xc);
+        // DPB: rotate mask and merge with the Xbus
+        assert_eq!(cycle_of(0xc, 0xffff_ffff, 0x0000_0005), 0x5fff_ffff);
+    }
+
+    #[test]
+    fn alub_sign_hack_complements_the_sign() {
+        // LDB of the sign bit alone: rotate left 1, 1 bit
+        let sign_of = |y: u32| {
+            let sim = machine(vec![]);
+            let word = Word::new().f("spec", 0o15).f("byte-f", 3).f("amwa", 1);
+            let cw = CWord::from_bits(0, word.0);
+            sim.shift_and_mask(&MicroInstruction::new(&cw), 0, y)
+        };
+        assert_eq!(sign_of(0x8000_0000), 0);
+        assert_eq!(sign_of(0x7fff_ffff), 1);
+    }
+
+    #[test]
+    fn obus_composes_tags() {
+        let word = Word::new()
+            .f("amra", 0o100)
+            .f("obus-cdr", 6)
+            .f("obus-htype", 1)
+            .f("obus-ltype-sel", 0)
+            .f("magic", 0o5)
+            .f("bmra", 0o360)
+            .f("amwa", 0o200);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 0xfff_ffff;
+        sim.b_mem[0o360] = 3 << 32;
+        sim.step().ok();
+        assert_eq!(
+            sim.a_mem[0o200],
+            (2 << 34) | (3 << 32) | (5 << 28) | 0xfff_ffff
+        );
+    }
+
+    #[test]
+    fn amwa_sel_1_writes_base_plus_offset() {
+        let word = Word::new().f("amra", 0o100).f("amwa-sel", 1).f("amwa", 5);
+        let mut sim = machine(vec![(0, word)]);
+        sim.sp = 0o10;
+        sim.a_mem[0o100] = 9;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o15], 9);
+    }
+
+    #[test]
+    fn amwa_sel_3_suppresses_a_memory_write() {
+        let word = Word::new()
+            .f("amra", 0o100)
+            .f("amwa-sel", 3)
+            .f("amwa", 0o200);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 9;
+        sim.step().ok();
+        assert_eq!(sim.a_mem[0o200], 0);
+    }
+
+    #[test]
+    fn bmwa_writes_scratchpad_and_extended_locations() {
+        let sim = run_one(Word::new().f("bmwa", 5).f("alu", 2));
+        assert_eq!(sim.b_mem[0o365], 1);
+
+        let word = Word::new()
+            .f("bmwa", 5)
+            .f("alu", 2)
+            .f("spec", 0o14)
+            .f("magic", 0o10)
+            .f("amwa", 0o120);
+        let sim = run_one(word);
+        assert_eq!(sim.b_mem[0o125], 1);
+    }
+
+    #[test]
+    fn bmem_from_xbus_takes_abus_high_bits() {
+        let word = Word::new()
+            .f("amra", 0o100)
+            .f("bmem-from-xbus", 1)
+            .f("alu", 2)
+            .f("bmwa", 1);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 0xa_0000_0007;
+        sim.step().ok();
+        assert_eq!(sim.b_mem[0o361], 0xa_0000_0007);
+    }
+
+    #[test]
+    fn stkp_count_follows_amwa_11() {
+        let word = Word::new().f("stkp-count", 1).f("amwa", 0o4000);
+        let mut sim = machine(vec![(0, word)]);
+        sim.sp = 10;
+        sim.step().ok();
+        assert_eq!(sim.sp, 11);
+
+        let word = Word::new().f("stkp-count", 1).f("amwa", 0o0100);
+        let mut sim = machine(vec![(0, word)]);
+        sim.sp = 10;
+        sim.step().ok();
+        assert_eq!(sim.sp, 9);
+    }
+
+    #[test]
+    fn spec_loads_base_registers() {
+        let word = Word::new().f("amra", 0o100).f("spec", 0o3);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 0o4444;
+        sim.step().ok();
+        assert_eq!(sim.fp, 0o4444);
+
+        let word = Word::new().f("amra", 0o100).f("spec", 0o5);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 0b10110;
+        sim.step().ok();
+        assert_eq!(sim.stack_base, 2);
+        assert!(sim.sequence_break);
+        assert!(!sim.trace_1);
+        assert!(sim.trace_2);
+    }
+
+    #[test]
+    fn spec_halt_stops_machine() {
+        let mut sim = machine(vec![(0, Word::new().f("spec", 0o31)), (1, Word::new())]);
+        assert_eq!(sim.run(10).ok(), Some(StepResult::Halted));
+        assert_eq!(sim.cycles, 1);
+        assert_eq!(sim.step().ok(), Some(StepResult::Halted));
+    }
+
+    #[test]
+    fn mem_reads_writes_and_loads_vma() {
+        let mut sim = machine(vec![
+            // VMA <- A[100]
+            (0, Word::new().f("amra", 0o100).f("mem", 5)),
+            // memory[VMA] <- A[101]
+            (1, Word::new().f("amra", 0o101).f("mem", 3)),
+            // MD <- memory[VMA]
+            (2, Word::new().f("mem", 2)),
+            // A[200] <- MD
+            (
+                3,
+                Word::new()
+                    .f("amra-sel", 3)
+                    .f("amra", 3 << 9)
+                    .f("amwa", 0o200),
+            ),
+        ]);
+        sim.a_mem[0o100] = 0o1000;
+        sim.a_mem[0o101] = 0o55;
+        sim.run(4).ok();
+        assert_eq!(sim.vma, 0o1000);
+        assert_eq!(sim.memory.read(0o1000), 0o55);
+        assert_eq!(sim.a_mem[0o200], 0o55);
+    }
+
+    #[test]
+    fn cpc_sel_chooses_next_address() {
+        // NAF
+        let mut sim = machine(vec![(0, Word::new().f("cpc-sel", 0).f("naf", 0o40))]);
+        sim.step().ok();
+        assert_eq!(sim.cpc, 0o40);
+        assert_eq!(sim.npc, 0o41);
+
+        // NPC
+        let mut sim = machine(vec![(0, Word::new())]);
+        sim.npc = 0o77;
+        sim.step().ok();
+        assert_eq!(sim.cpc, 0o77);
+    }
+
+    #[test]
+    fn jump_masks_the_address() {
+        let mut sim = machine(vec![(0, Word::new())]);
+        sim.jump(0xffff);
+        assert_eq!(sim.cpc, 0o37777);
+        assert_eq!(sim.npc, 0);
+    }
+
+    #[test]
+    fn npc_sel_0_dispatches() {
+        let word = Word::new()
+            .f("amra", 0o100)
+            .f("npc-sel", 0)
+            .f("magic", 5)
+            .f("naf", 0o10000 | 0o7400 | 0o12);
+        let mut sim = machine(vec![(0, word)]);
+        sim.a_mem[0o100] = 6;
+        sim.step().ok();
+        assert_eq!(sim.npc, 0o10000 | (6 << 8) | 0o12);
+    }
+
+    #[test]
+    fn pushj_and_popj_use_control_stack() {
+        let mut sim = machine(vec![
+            // Call 100
+            (0, Word::new().f("cpc-sel", 0).f("naf", 0o100).f("seq", 1)),
+            (1, Word::new().f("spec", 0o31)),
+            // Return
+            (0o100, Word::new().f("cpc-sel", 1).f("seq", 3)),
+        ]);
+        sim.step().ok();
+        assert_eq!(sim.cpc, 0o100);
+        assert_eq!(sim.csp, 1);
+        assert_eq!(sim.ctos(), 1);
+        assert_eq!(sim.run(10).ok(), Some(StepResult::Halted));
+        assert_eq!(sim.csp, 0);
+        assert_eq!(sim.cycles, 3);
+    }
+
+    #[test]
+    fn cond_func_1_skips_on_false_condition() {
+        // Condition 14: ALU not = 0
+        let skip = |value| {
+            let word = Word::new()
+                .f("amra", 0o100)
+                .f("cpc-sel", 0)
+                .f("naf", 0o20)
+                .f("cond-sel", 0o14)
+                .f("cond-func", 1);
+            let mut sim = machine(vec![(0, word)]);
+            sim.a_mem[0o100] = value;
+            sim.step().ok();
+            sim.cpc
+        };
+        assert_eq!(skip(1), 0o20);
+        assert_eq!(skip(0), 0o10020);
+    }
+
+    #[test]
+    fn cond_func_2_traps_to_naf() {
+        let word = Word::new()
+            .f("amra", 0o100)
+            .f("amwa", 0o200)
+            .f("naf", 0o300)
+            .f("cond-sel", 0o13)
+            .f("cond-func", 2);
+        let mut sim = machine(vec![(5, word)]);
+        sim.jump(5);
+        assert_eq!(
+            sim.step().ok(),
+            Some(StepResult::Trapped { from: 5, to: 0o300 })
+        );
+        assert_eq!(sim.npc, 5);
+        // The trapping instruction was NOPed
+        assert_eq!(sim.a_mem[0o200], 0);
+    }
+
+    #[test]
+    fn arith_trap_dispatch_takes_address_bits_from_the_buses() {
+        let word = Word::new()
+            .f("amra", 0o100)
+            .f("bmra", 0o360)
+            .f("naf", 0o300)
+            .f("spec", 0o30)
+            .f("cond-sel", 0o13)
+            .f("cond-func", 2);
+        let mut sim = machine(vec![(5, word)]);
+        sim.a_mem[0o100] = 2 << 32;
+        sim.b_mem[0o360] = 1 << 32;
+        sim.jump(5);
+        assert_eq!(
+            sim.step().ok(),
+            Some(StepResult::Trapped {
+                from: 5,
+                to: 0o4700
+            })
+        );
+    }
+
+    #[test]
+    fn cond_sel_cdr_code() {
+        let cycle = |cdr: u64, sel| {
+            let word = Word::new().f("amra", 0o100).f("cond-sel", sel);
+            let cw = CWord::from_bits(0, word.0);
+            let mut sim = machine(vec![]);
+            sim.a_mem[0o100] = cdr << 34;
+            sim.compute(&MicroInstruction::new(&cw)).condition
+        };
+        assert!(!cycle(2, 2));
+        assert!(cycle(2, 1));
+    }
+
+    #[test]
+    fn dismiss_runs_extra_instructions() {
+        let mut sim = machine(vec![
+            (0, Word::new().f("seq", 2)),
+            (1, Word::new()),
+            (2, Word::new()),
+        ]);
+        sim.task = 3;
+        assert_eq!(sim.step().ok(), Some(StepResult::Executed));
+        assert_eq!(sim.step().ok(), Some(StepResult::Executed));
+        assert_eq!(sim.step().ok(), Some(StepResult::Dismissed));
+
+        for &task in &[0, 9] {
+            let mut sim = machine(vec![(0, Word::new().f("seq", 2)), (1, Word::new())]);
+            sim.task = task;
+            sim.step().ok();
+            assert_eq!(sim.step().ok(), Some(StepResult::Dismissed));
+        }
+    }
+
+    #[test]
+    fn disable_tasking_needs_two_in_a_row() {
+        let mut sim = machine(vec![
+            (0, Word::new().f("spec", 0o35)),
+            (1, Word::new()),
+            (2, Word::new().f("spec", 0o35)),
+            (3, Word::new().f("spec", 0o35)),
+        ]);
+        sim.run(2).ok();
+        assert!(!sim.tasking_disabled);
+        sim.run(2).ok();
+        assert!(sim.tasking_disabled);
+    }
+}
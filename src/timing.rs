@@ -0,0 +1,511 @@
+///
+/// Copyright 2017, Seth J. Morabito <web@loomcom.com>
+///
+/// This file is part of the Symbolics Microcode Explorer.
+///
+/// The Symbolics Microcode Explorer is free software: you can
+/// redistribute it and/or modify it under the terms of the GNU
+/// General Public License as published by the Free Software
+/// Foundation, either version 3 of the License, or (at your option)
+/// any later version.
+///
+/// The Symbolics Microcode Explorer is distributed in the hope that it
+/// will be useful, but WITHOUT ANY WARRANTY; without even the implied
+/// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+/// See the GNU General Public License for more details.
+///
+/// You should have received a copy of the GNU General Public License
+/// along with The Symbolics Microde Explorer.  If not, see
+/// <https://www.gnu.org/licenses/>.
+///
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Add;
+
+use fields::Seq;
+use flow::{self, EdgeKind, Flow, Npc, Routine};
+use sim::CONTROL_STACK_SIZE;
+use ucode::{MicroInstruction, Microcode, ADDRESS_MASK};
+
+//
+// Microcode timing.
+//
+// U SPEED picks the length of the cycle a word runs in. Adding those
+// up along a path gives the time it takes, and the shortest and
+// longest paths between two addresses bound the time of whatever runs
+// between them, as the handler of a macroinstruction does.
+//
+// Paths are walked a cycle at a time, carrying NPC along as the
+// hazard analysis does. A call counts the time its routine takes from
+// entry to return, then carries on at the return address. A word
+// with a loop bound runs at most that many times along one path, and
+// the rest of its loop as often as it does. A loop with no bound is
+// taken once and reported, so the worst case found is a lower bound
+// until every loop on the way has one. Calls nest no deeper than the
+// control stack holds. Traps are left out.
+//
+
+/// The cycle length for each U SPEED, in nanoseconds. The
+/// documentation gives these with some doubt.
+pub const CYCLE_NS: [u32; 4] = [180, 210, 225, 255];
+
+/// How many words a search walks before it gives up.
+const SEARCH_LIMIT: usize = 200_000;
+
+/// The length of the cycle a word runs in, in nanoseconds.
+pub fn cycle_ns(u: &MicroInstruction) -> u32 {
+    CYCLE_NS[(u.speed() & 3) as usize]
+}
+
+/// A number of cycles and the time they take.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Cost {
+    pub ns: u32,
+    pub cycles: u32,
+}
+
+impl Add for Cost {
+    type Output = Cost;
+
+    fn add(self, other: Cost) -> Cost {
+        Cost {
+            ns: self.ns + other.ns,
+            cycles: self.cycles + other.cycles,
+        }
+    }
+}
+
+fn word_cost(ucode: &Microcode, addr: u16) -> Cost {
+    Cost {
+        ns: ucode.c_word(addr).map_or(0, |w| cycle_ns(&w.decode())),
+        cycles: 1,
+    }
+}
+
+/// A run of words that always run one after the other.
+pub struct Block {
+    pub words: Vec<u16>,
+    pub cost: Cost,
+}
+
+/// The basic blocks of a routine, in address order.
+pub fn blocks(ucode: &Microcode, flow: &Flow, routine: &Routine) -> Vec<Block> {
+    let within: BTreeSet<u16> = routine.words.iter().cloned().collect();
+    let next = |addr: u16| -> Vec<u16> {
+        flow.successors(addr)
+            .iter()
+            .filter(|e| e.kind != EdgeKind::Trap)
+            .map(|e| e.to)
+            .collect()
+    };
+    let leader = |addr: u16| {
+        let before: Vec<u16> = flow
+            .predecessors(addr)
+            .iter()
+            .filter(|e| e.kind != EdgeKind::Trap)
+            .map(|e| e.from)
+            .collect();
+        addr == routine.entry
+            || before.len() != 1
+            || !within.contains(&before[0])
+            || next(before[0]).len() != 1
+    };
+
+    let mut blocks = Vec::new();
+    for &start in routine.words.iter().filter(|&&a| leader(a)) {
+        let mut words = vec![start];
+        loop {
+            let after = next(words[words.len() - 1]);
+            match after.first() {
+                Some(&a) if after.len() == 1 && within.contains(&a) && !leader(a) => {
+                    if words.contains(&a) {
+                        break;
+                    }
+                    words.push(a)
+                }
+                _ => break,
+            }
+        }
+        let cost = words
+            .iter()
+            .fold(Cost::default(), |c, &a| c + word_cost(ucode, a));
+        blocks.push(Block { words, cost });
+    }
+    blocks
+}
+
+/// The shortest and longest times along the paths found.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Bounds {
+    pub best: Option<Cost>,
+    pub worst: Option<Cost>,
+    /// The words along the shortest and longest paths, without the
+    /// words of the routines they call.
+    pub best_path: Vec<u16>,
+    pub worst_path: Vec<u16>,
+    /// Words that loop with no bound, taken once.
+    pub unbounded: BTreeSet<u16>,
+    /// The search gave up before it had walked every path.
+    pub incomplete: bool,
+}
+
+/// Works out times, remembering those of the routines called.
+pub struct Timer<'a> {
+    ucode: &'a Microcode,
+    flow: &'a Flow,
+    calls: BTreeMap<u16, Bounds>,
+    /// The routines being timed, innermost last.
+    active: Vec<u16>,
+    /// How many calls were left out for nesting too deep.
+    too_deep: usize,
+}
+
+impl<'a> Timer<'a> {
+    pub fn new(ucode: &'a Microcode, flow: &'a Flow) -> Timer<'a> {
+        Timer {
+            ucode,
+            flow,
+            calls: BTreeMap::new(),
+            active: Vec::new(),
+            too_deep: 0,
+        }
+    }
+
+    /// The times along the paths from `from` to `to`, counting both,
+    /// or given None, from `from` to wherever the path ends: a return,
+    /// or a jump whose target is not known.
+    pub fn between(&mut self, from: u16, to: Option<u16>) -> Bounds {
+        let mut npcs: Vec<Npc> = self
+            .flow
+            .npc_before(self.ucode, from)
+            .iter()
+            .map(|&(_, n)| n)
+            .collect();
+        npcs.sort();
+        npcs.dedup();
+        if npcs.is_empty() {
+            npcs.push(Npc::Unknown);
+        }
+
+        let mut search = Search {
+            timer: self,
+            to,
+            visits: BTreeMap::new(),
+            path: Vec::new(),
+            low: Cost::default(),
+            high: Cost::default(),
+            steps: 0,
+            bounds: Bounds::default(),
+        };
+        for npc in npcs {
+            search.walk(from, npc);
+        }
+        search.bounds
+    }
+
+    /// The times from the entry of the routine at `entry` to its
+    /// returns, worked out once.
+    fn call(&mut self, entry: u16) -> Option<Bounds> {
+        if let Some(b) = self.calls.get(&entry) {
+            return Some(b.clone());
+        }
+        if self.active.contains(&entry) {
+            // A recursive call, which could run any number of times.
+            return None;
+        }
+        if self.active.len() >= CONTROL_STACK_SIZE {
+            self.too_deep += 1;
+            return None;
+        }
+        let too_deep = self.too_deep;
+        self.active.push(entry);
+        let bounds = self.between(entry, None);
+        self.active.pop();
+        // Times cut short by the depth they were called at may be
+        // whole when the routine is called from nearer the top.
+        if self.too_deep == too_deep {
+            self.calls.insert(entry, bounds.clone());
+        }
+        Some(bounds)
+    }
+}
+
+struct Search<'a, 'b: 'a> {
+    timer: &'a mut Timer<'b>,
+    to: Option<u16>,
+    /// Where along the path each word has run.
+    visits: BTreeMap<u16, Vec<usize>>,
+    path: Vec<u16>,
+    /// The shortest and longest times so far along this path, which
+    /// differ once a call has been made.
+    low: Cost,
+    high: Cost,
+    steps: usize,
+    bounds: Bounds,
+}
+
+/// A word along the path being walked, and the cycles after it still
+/// to walk.
+struct Step {
+    addr: u16,
+    /// The times before the word ran, to go back to.
+    low: Cost,
+    high: Cost,
+    next: Vec<(u16, Npc)>,
+    taken: usize,
+}
+
+impl<'a, 'b> Search<'a, 'b> {
+    /// Walk every path from `addr`. Paths can be as long as the search
+    /// limit, so the words along them are kept on a stack of their own.
+    fn walk(&mut self, addr: u16, npc: Npc) {
+        let mut steps: Vec<Step> = Vec::new();
+        let mut cycle = Some((addr, npc));
+        loop {
+            if let Some((addr, npc)) = cycle.take() {
+                steps.extend(self.enter(addr, npc));
+            }
+            match steps.last_mut() {
+                Some(step) if step.taken < step.next.len() => {
+                    cycle = Some(step.next[step.taken]);
+                    step.taken += 1;
+                }
+                Some(_) => {
+                    let step = steps.pop().unwrap();
+                    self.leave(step);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Run the word at `addr` along the path, if it may run, and find
+    /// the cycles that can follow it.
+    fn enter(&mut self, addr: u16, npc: Npc) -> Option<Step> {
+        if self.steps >= SEARCH_LIMIT {
+            self.bounds.incomplete = true;
+            return None;
+        }
+        self.steps += 1;
+
+        let ucode = self.timer.ucode;
+        let u = ucode.c_word(addr)?.decode();
+        if !self.may_run(addr) {
+            return None;
+        }
+
+        let (low, high) = (self.low, self.high);
+        self.visits.entry(addr).or_default().push(self.path.len());
+        self.path.push(addr);
+        self.low = self.low + word_cost(ucode, addr);
+        self.high = self.high + word_cost(ucode, addr);
+
+        let mut next = Vec::new();
+        if Some(addr) == self.to {
+            self.finish();
+        } else {
+            next = flow::next_cycles(ucode, addr, &u, npc);
+            if u.seq() == Seq::Pushj {
+                next = self.call(&next, npc);
+            }
+            if next.is_empty() && self.to.is_none() {
+                self.finish();
+            }
+        }
+        Some(Step {
+            addr,
+            low,
+            high,
+            next,
+            taken: 0,
+        })
+    }
+
+    /// Take the word back off the path once every cycle after it has
+    /// been walked.
+    fn leave(&mut self, step: Step) {
+        self.path.pop();
+        if let Some(v) = self.visits.get_mut(&step.addr) {
+            v.pop();
+        }
+        self.low = step.low;
+        self.high = step.high;
+    }
+
+    /// Whether the word at `addr` may run again along this path. A
+    /// word with a loop bound runs that many times. One without runs
+    /// once, or again when the loop back to it passes a word with a
+    /// bound, which then limits it.
+    fn may_run(&mut self, addr: u16) -> bool {
+        let bounds = &self.timer.ucode.annotations.loop_bounds;
+        let visits = self.visits.get(&addr).map_or(&[][..], |v| &v[..]);
+        match (bounds.get(&addr), visits.last()) {
+            (_, None) => true,
+            (Some(&n), _) => (visits.len() as u32) < n,
+            (None, Some(&last)) => {
+                let bounded = self.path[last..].iter().any(|a| bounds.contains_key(a));
+                if !bounded {
+                    self.bounds.unbounded.insert(addr);
+                }
+                bounded
+            }
+        }
+    }
+
+    /// Count the time of the routine called and return where it
+    /// returns to, the address NPC held when the call was made.
+    fn call(&mut self, entries: &[(u16, Npc)], npc: Npc) -> Vec<(u16, Npc)> {
+        let back = match npc {
+            Npc::Next(a) if self.timer.ucode.c_word(a).is_some() => a,
+            _ => return Vec::new(),
+        };
+        let mut low: Option<Cost> = None;
+        let mut high: Option<Cost> = None;
+        for &(entry, _) in entries {
+            let callee = match self.timer.call(entry) {
+                Some(b) => b,
+                None => {
+                    self.bounds.incomplete = true;
+                    continue;
+                }
+            };
+            self.bounds.unbounded.extend(&callee.unbounded);
+            self.bounds.incomplete |= callee.incomplete;
+            if let (Some(b), Some(w)) = (callee.best, callee.worst) {
+                low = Some(low.map_or(b, |l| l.min(b)));
+                high = Some(high.map_or(w, |h| h.max(w)));
+            }
+        }
+        match (low, high) {
+            (Some(l), Some(h)) => {
+                self.low = self.low + l;
+                self.high = self.high + h;
+                vec![(back, Npc::Next(back.wrapping_add(1) & ADDRESS_MASK))]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.bounds.best.is_none_or(|b| self.low < b) {
+            self.bounds.best = Some(self.low);
+            self.bounds.best_path = self.path.clone();
+        }
+        if self.bounds.worst.is_none_or(|w| self.high > w) {
+            self.bounds.worst = Some(self.high);
+            self.bounds.worst_path = self.path.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::image;
+
+    #[test]
+    fn speeds_set_the_cycle() {
+        let ucode = image(
+            "        .org 10\n\
+             npc-sel=next naf=11 speed=0\n\
+             npc-sel=next naf=12 speed=3\n\
+             npc-sel=next cpc-sel=ctos speed=1\n",
+        );
+        let flow = Flow::new(&ucode);
+        let bounds = Timer::new(&ucode, &flow).between(0o10, None);
+        let cost = Cost {
+            ns: 180 + 255 + 210,
+            cycles: 3,
+        };
+        assert_eq!(bounds.best, Some(cost));
+        assert_eq!(bounds.worst, Some(cost));
+        assert_eq!(bounds.worst_path, vec![0o10, 0o11, 0o12]);
+
+        let blocks = blocks(&ucode, &flow, flow.routine(0o10).unwrap());
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].cost, cost);
+    }
+
+    #[test]
+    fn skips_and_calls() {
+        let ucode = image(
+            "        .org 20\n\
+             npc-sel=next naf=10021 cond-func=skip\n\
+             npc-sel=next naf=40 seq=pushj\n\
+             npc-sel=next naf=24 speed=3\n\
+             .org 24\n\
+             npc-sel=next cpc-sel=ctos\n\
+             .org 40\n\
+             npc-sel=next naf=41\n\
+             npc-sel=next cpc-sel=ctos\n\
+             .org 10021\n\
+             npc-sel=next naf=24\n",
+        );
+        let flow = Flow::new(&ucode);
+        let bounds = Timer::new(&ucode, &flow).between(0o20, Some(0o24));
+        // 20, 10021, 24 or 20, 21, the call to 40 and 41, 22, 24.
+        assert_eq!(bounds.best.unwrap().cycles, 3);
+        assert_eq!(bounds.worst.unwrap().cycles, 6);
+        assert_eq!(bounds.worst.unwrap().ns, 5 * 180 + 255);
+        assert_eq!(bounds.worst_path, vec![0o20, 0o21, 0o22, 0o24]);
+    }
+
+    #[test]
+    fn loops_run_to_their_bound() {
+        let mut ucode = image(
+            "        .org 30\n\
+             npc-sel=next naf=31\n\
+             npc-sel=next naf=10030 cond-func=skip\n\
+             .org 10030\n\
+             npc-sel=next cpc-sel=ctos\n",
+        );
+        let flow = Flow::new(&ucode);
+        let bounds = Timer::new(&ucode, &flow).between(0o30, None);
+        assert_eq!(bounds.worst.unwrap().cycles, 3);
+        assert!(bounds.unbounded.contains(&0o30));
+
+        ucode.annotations.set_loop_bound(0o30, Some(4));
+        let bounds = Timer::new(&ucode, &flow).between(0o30, None);
+        assert_eq!(bounds.best.unwrap().cycles, 3);
+        assert_eq!(bounds.worst.unwrap().cycles, 9);
+        assert!(bounds.unbounded.is_empty());
+    }
+
+    #[test]
+    fn long_loops_do_not_overflow_the_stack() {
+        let mut ucode = image(
+            "        .org 30\n\
+             npc-sel=next naf=10030 cond-func=skip\n\
+             .org 10030\n\
+             npc-sel=next cpc-sel=ctos\n",
+        );
+        ucode.annotations.set_loop_bound(0o30, Some(20_000));
+        let flow = Flow::new(&ucode);
+        let bounds = Timer::new(&ucode, &flow).between(0o30, None);
+        assert_eq!(bounds.worst.unwrap().cycles, 20_001);
+        assert!(!bounds.incomplete);
+    }
+
+    #[test]
+    fn calls_nest_no_deeper_than_the_control_stack() {
+        // Each routine calls the next, twenty deep.
+        let mut source = String::new();
+        for i in 0..20 {
+            source.push_str(&format!(
+                ".org {:o}\nnpc-sel=next naf={:o}\nnpc-sel=next naf={:o} seq=pushj\n\
+                 npc-sel=next cpc-sel=ctos\n",
+                0o100 + 4 * i,
+                0o100 + 4 * i + 1,
+                0o100 + 4 * (i + 1)
+            ));
+        }
+        source.push_str(".org 220\nnpc-sel=next cpc-sel=ctos\n");
+        let ucode = image(&source);
+        let flow = Flow::new(&ucode);
+        let mut timer = Timer::new(&ucode, &flow);
+        assert!(timer.between(0o100, None).incomplete);
+        // The routines nearer the bottom were not remembered cut short.
+        let bounds = timer.between(0o200, None);
+        assert!(!bounds.incomplete);
+        assert_eq!(bounds.worst.unwrap().cycles, 3 * 4 + 1);
+    }
+}
@@ -17,18 +17,32 @@
 /// along with The Symbolics Microde Explorer.  If not, see
 /// <https://www.gnu.org/licenses/>.
 ///
-extern crate clap;
-extern crate shellexpand;
-
+use std::collections::BTreeMap;
+use std::error;
 use std::fmt;
 use std::fs::File;
 use std::io::Error;
 use std::io::Read;
+use std::io::Write;
+use std::slice;
+use std::sync::Arc;
+
+use fields::{
+    AbusSource, AluFunction, AmraSel, AmwaSel, BmemSource, ByteFunction, CondFunc, CondSel, CpcSel,
+    FieldValue, LtypeSel, MemOp, NpcSel, ObusCdr, ObusHtype, Seq, SpecialFunction, XybusSel,
+};
+use layout::{self, Field, Layout};
+use project::Annotations;
+use radix;
 
 //
 // Error handling
 //
 
+/// Why an image could not be read or written. More variants may be
+/// added, so matches need a wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum MicrocodeError {
     Io(Error),
     InvalidHeader,
@@ -63,6 +77,15 @@ impl fmt::Display for MicrocodeError {
     }
 }
 
+impl error::Error for MicrocodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            MicrocodeError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 //
 // Microcode State
 //
@@ -153,153 +176,304 @@ const SEC_TYPEMAP: u8 = 7;
 const SEC_EOF: u8 = 8;
 const SEC_PICOSTORE: u8 = 10;
 
+/// A word of A memory or B memory: 36 bits of data and a tag.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ABWord {
-    address: u16,
-    data: u64,
+    pub(crate) address: u16,
+    pub(crate) data: u64,
+}
+
+impl ABWord {
+    pub fn new(address: u16, data: u64) -> ABWord {
+        ABWord { address, data }
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// The 40 bits of the word as stored in the image.
+    pub fn data(&self) -> u64 {
+        self.data
+    }
 }
 
+/// The width of a control memory address.
+pub const ADDRESS_BITS: u32 = 14;
+
+/// The highest control memory address, and the mask for one.
+pub const ADDRESS_MASK: u16 = (1 << ADDRESS_BITS) - 1;
+
+/// A number as a control memory address, if it is one.
+pub fn control_address(addr: u64) -> Option<u16> {
+    if addr <= u64::from(ADDRESS_MASK) {
+        Some(addr as u16)
+    } else {
+        None
+    }
+}
+
+/// A control memory word: 112 bits of microinstruction at an address.
+#[derive(Clone, Copy, PartialEq)]
 pub struct CWord {
-    address: u16,
-    data_l: u64,
-    data_h: u64,
+    pub(crate) address: u16,
+    pub(crate) data_l: u64,
+    pub(crate) data_h: u64,
 }
 
 impl CWord {
-    fn new(address: u16, data_l: u64, data_h: u64) -> CWord {
+    /// A word from its low 64 bits and its high 48 bits.
+    pub fn new(address: u16, data_l: u64, data_h: u64) -> CWord {
         CWord {
-            address: address,
-            data_l: data_l,
-            data_h: data_h,
+            address,
+            data_l,
+            data_h,
         }
     }
+
+    /// A word from all of its bits.
+    pub fn from_bits(address: u16, bits: u128) -> CWord {
+        CWord::new(address, bits as u64, (bits >> 64) as u64)
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// The 112 bits of the word.
+    pub fn bits(&self) -> u128 {
+        (self.data_l as u128) | ((self.data_h as u128) << 64)
+    }
+
+    /// The word decoded with the current layout.
+    pub fn decode(&self) -> MicroInstruction {
+        MicroInstruction::new(self)
+    }
 }
 
-/// All the fields of a Microinstruction
-#[allow(dead_code)]
+/// B-memory addresses below this read the macroinstruction immediate.
+pub const B_MEM_IMMEDIATES: u16 = 0o10;
+
+/// B-memory addresses from this up are the scratchpad, which every
+/// word writes unless it extends the write address.
+pub const B_MEM_SCRATCHPAD: u16 = 0o360;
+
+/// The magic number of the crock that extends the B-memory write
+/// address.
+pub const EXTENDED_BMWA: u8 = 0o10;
+
+/// A microinstruction: the bits of a control word, read through the
+/// typed accessors below. Where each accessor's field lies is taken
+/// from the layout the word was decoded with.
+#[derive(Clone, Copy)]
 pub struct MicroInstruction {
-    u_amra: u16,          // bits 11-0:    A Mem Read Address (0-7777)
-    u_r_base: u8,         // bits 10-9:    A Mem R Base Register Select (0-3)
-    u_abus_src: u8,       // bits 8-6:     ABus Source (0-7)
-    u_amra_sel: u8,       // bits 13-12:   A Mem Read Address interpretation (0-3)
-    u_xybus_sel: u8,      // bit  14:      X & Y Bus Select (0-1)
-    u_stkp_count: u8,     // bit  15:      Stack Pointer Count true/false (0-1)
-    u_amwa: u16,          // bits 27-16:   A Mem Write Address (0-7777)
-    lbus_dev: u16,        // bits 25-16:   LBUS dev (0-1777)
-    u_w_base: u8,         // bits 26-25:   A Mem W Base Register Select (0-3)
-    u_stkp_count_dir: u8, // bit  27:      Same as bit 26 (0-1)
-    u_amwa_sel: u8,       // bits 29-28:   A Mem Write Address interpretation (0-3)
-    u_seq: u8,            // bits 31-30:   Sequencer Function (0-3)
-    u_bmra: u8,           // bits 39-32:   B Memory Read Address (0-377)
-    u_bmwa: u8,           // bits 43-40:   B Memory Write Address (0-17)
-    u_bmem_from_xbus: u8, // bit  44:      B Memory Write Data Select (0-1)
-    u_mem: u8,            // bits 47-45:   Memory Control Function (0-7)
-    u_spec: u8,           // bits 52-48:   Special Function (0-37)
-    u_magic: u8,          // bits 56-53:   Magic Number (0-17)
-    u_cond_sel: u8,       // bits 61-57:   Condition Select (0-37)
-    u_cond_func: u8,      // bits 63-62:   Condition Function (0-3)
-    u_alu: u8,            // bits 67-64:   ALU Function (0-17)
-    u_byte_f: u8,         // bits 69-68:   Byte Function (0-3)
-    u_obus_cdr: u8,       // bits 72-70:   Obus CDR code select (0-7)
-    u_obus_htype: u8,     // bits 75-73:   Obus high type field select (0-7)
-    u_obus_ltype_sel: u8, // bit  76:      Obus low type field select (0-1)
-    u_cpc_sel: u8,        // bits 78-77:   Next microprogram address select (0-3)
-    u_npc_sel: u8,        // bit  79:      Next next micro addres select (0-1)
-    u_naf: u16,           // bits 93-80:   Next Address Field (0-37777)
-    u_speed: u8,          // bits 95-94:   Clock speed control (0-3)
-    u_type_map_sel: u8,   // bits 101-96:  Type map select (0-77)
-    u_au_op: u8,          // bits 109-102: FPA control (0-377)
-    u_spare: u8,          // bit  110:     Spare bit
-    u_parity_bit: u8,     // bit  111:     Parity bit
+    word: u128,
+    /// The low bit and width of each accessor's field, by `Slot`. The
+    /// width is 0 for a field the layout does not define.
+    slots: [(u8, u8); SLOTS],
 }
 
 impl MicroInstruction {
-    fn new(cword: &CWord) -> MicroInstruction {
-        MicroInstruction {
-            // Fields from the low 64-bits
-            u_amra: (cword.data_l & 0xfff) as u16,
-            u_r_base: ((cword.data_l >> 9) & 0x3) as u8,
-            u_abus_src: ((cword.data_l >> 6) & 0x7) as u8,
-            u_amra_sel: ((cword.data_l >> 12) & 0x3) as u8,
-            u_xybus_sel: ((cword.data_l >> 14) & 0x1) as u8,
-            u_stkp_count: ((cword.data_l >> 15) & 0x1) as u8,
-            u_amwa: ((cword.data_l >> 16) & 0xfff) as u16,
-            lbus_dev: ((cword.data_l >> 16) & 0x3ff) as u16,
-            u_w_base: ((cword.data_l >> 25) & 0x3) as u8,
-            u_stkp_count_dir: ((cword.data_l >> 27) & 0x1) as u8,
-            u_amwa_sel: ((cword.data_l >> 28) & 0x3) as u8,
-            u_seq: ((cword.data_l >> 30) & 0x3) as u8,
-            u_bmra: ((cword.data_l >> 32) & 0xff) as u8,
-            u_bmwa: ((cword.data_l >> 40) & 0xf) as u8,
-            u_bmem_from_xbus: ((cword.data_l >> 44) & 0x1) as u8,
-            u_mem: ((cword.data_l >> 45) & 0x7) as u8,
-            u_spec: ((cword.data_l >> 48) & 0x1f) as u8,
-            u_magic: ((cword.data_l >> 53) & 0xf) as u8,
-            u_cond_sel: ((cword.data_l >> 57) & 0x1f) as u8,
-            u_cond_func: ((cword.data_l >> 62) & 0x3) as u8,
-
-            // Fields from the high 64-bits
-            u_alu: (cword.data_h & 0xf) as u8,
-            u_byte_f: ((cword.data_h >> 4) & 0x3) as u8,
-            u_obus_cdr: ((cword.data_h >> 6) & 0x7) as u8,
-            u_obus_htype: ((cword.data_h >> 9) & 0x7) as u8,
-            u_obus_ltype_sel: ((cword.data_h >> 12) & 0x1) as u8,
-            u_cpc_sel: ((cword.data_h >> 13) & 0x3) as u8,
-            u_npc_sel: ((cword.data_h >> 15) & 0x1) as u8,
-            u_naf: ((cword.data_h >> 16) & 0x3fff) as u16,
-            u_speed: ((cword.data_h >> 30) & 0x3) as u8,
-            u_type_map_sel: ((cword.data_h >> 32) & 0x3f) as u8,
-            u_au_op: ((cword.data_h >> 38) & 0xff) as u8,
-            u_spare: ((cword.data_h >> 46) & 0x1) as u8,
-            u_parity_bit: ((cword.data_h >> 47) & 0x1) as u8,
-        }
-    }
-
-    fn amem_source(&self) -> String {
-        match self.u_abus_src {
-            0 => "Memory Data (MD)".to_string(),
-            1 => "Other Memory Data (OTHER-MD)".to_string(),
-            2 => "VMA".to_string(),
-            3 => "EPC".to_string(),
-            4 => "Memory Data Advance".to_string(),
-            5 => "PHTA-ASN/IIR".to_string(),
-            6 => "MAP".to_string(),
-            7 => "MAP #0".to_string(),
-            _ => "<INVALID>".to_string(),
-        }
-    }
-
-    fn amem_read_address(&self) -> String {
-        match self.u_amra_sel {
-            0 => format!("Immediate Address {:04o}", self.u_amra),
-            1 => format!("A-Memory Address {:04o}", self.u_amra),
-            2 => format!(
-                "Base Register {:02o} plus offset {:03o}",
-                self.u_r_base & 0x3,
-                self.u_amra & 0xff
-            ),
-            3 => match self.u_r_base {
-                0 => "Stack Pointer".to_string(),
-                1 => "Frame Pointer".to_string(),
-                2 => "Extra Base".to_string(),
-                3 => format!("Other Abus Source: {}", self.amem_source()),
-                _ => "<INVALID>".to_string(),
-            },
-            _ => "<INVALID>".to_string(),
-        }
-    }
-}
-
-#[allow(dead_code)]
+    /// Decode a control word using the current layout.
+    pub fn new(cword: &CWord) -> MicroInstruction {
+        MicroInstruction::decode(&layout::current(), cword.bits())
+    }
+
+    /// Decode a word using the given layout. Fields the layout does
+    /// not define are zero.
+    pub fn decode(layout: &Layout, word: u128) -> MicroInstruction {
+        let mut slots = [(0, 0); SLOTS];
+        for (slot, name) in slots.iter_mut().zip(FIELDS) {
+            if let Some(field) = layout.field(name) {
+                *slot = (field.lo as u8, field.width as u8);
+            }
+        }
+        MicroInstruction { word, slots }
+    }
+
+    fn get(&self, slot: Slot) -> u64 {
+        let (lo, width) = self.slots[slot as usize];
+        ((self.word >> lo) & ((1u128 << width) - 1)) as u64
+    }
+
+    /// The value of a field or alias by its layout name.
+    pub fn field_value(&self, name: &str) -> Option<u64> {
+        layout::current().field(name).map(|f| f.get(self.word))
+    }
+
+    /// Encode the instruction back into a control word. Every bit is
+    /// kept as decoded, including those of fields the typed accessors
+    /// do not know about. The parity bit is written as it stands.
+    pub fn encode(&self, address: u16) -> CWord {
+        CWord::from_bits(address, self.word)
+    }
+
+    /// Set one field (or alias). Every field that shares its bits
+    /// changes with it.
+    pub fn set_field(&mut self, field: &Field, value: u64) {
+        self.word = field.set(self.word, value);
+    }
+
+    /// Recompute the parity bit for odd parity over the whole word.
+    pub fn fix_parity(&mut self) {
+        self.word = layout::current().with_parity(self.word);
+    }
+
+    /// The B-memory address this word writes. That is a scratchpad
+    /// location, unless the crock that extends the write address takes
+    /// the high four bits from AMWA.
+    pub fn b_write_address(&self) -> u16 {
+        let high = if self.spec() == SpecialFunction::Crocks && self.magic() == EXTENDED_BMWA {
+            ((self.amwa() >> 4) & 0xf) << 4
+        } else {
+            B_MEM_SCRATCHPAD
+        };
+        high | u16::from(self.bmwa())
+    }
+
+    /// Set a field to one of its symbolic values, as `set_field`
+    /// does. Nothing changes if the layout has no such field.
+    pub fn set<V: FieldValue>(&mut self, value: V) {
+        if let Some(field) = layout::current().field(V::FIELD) {
+            self.set_field(field, value.code() as u64);
+        }
+    }
+}
+
+// Typed accessors for every field, named after the fields of the
+// layout. Fields the layout overlaps, as LBUS DEV within U AMWA, are
+// views of the same bits. Fields with symbolic values return the
+// enums of fields.rs.
+macro_rules! accessors {
+    ($($(#[$doc:meta])* $name:ident: $field:expr, $raw:ident -> $ty:ty;)*) => {
+        /// The layout names of the accessors' fields, by `Slot`.
+        const FIELDS: &[&str] = &[$($field,)*];
+        const SLOTS: usize = FIELDS.len();
+
+        #[allow(non_camel_case_types)]
+        #[derive(Clone, Copy)]
+        enum Slot {
+            $($name,)*
+        }
+
+        impl MicroInstruction {
+            $(
+                $(#[$doc])*
+                pub fn $name(&self) -> $ty {
+                    <$ty>::from(self.get(Slot::$name) as $raw)
+                }
+            )*
+        }
+    };
+}
+
+accessors! {
+    /// U AMRA: the A memory read address.
+    amra: "amra", u16 -> u16;
+    /// U R BASE: the base register for base-relative A memory reads.
+    r_base: "r-base", u8 -> u8;
+    /// U ABUS SRC: where the A bus comes from.
+    abus_src: "abus-src", u8 -> AbusSource;
+    /// U AMRA SEL: how U AMRA is interpreted.
+    amra_sel: "amra-sel", u8 -> AmraSel;
+    /// U XYBUS SEL: the X and Y bus sources.
+    xybus_sel: "xybus-sel", u8 -> XybusSel;
+    /// U STKP COUNT: whether the stack pointer counts.
+    stkp_count: "stkp-count", u8 -> u8;
+    /// U AMWA: the A memory write address.
+    amwa: "amwa", u16 -> u16;
+    /// U LBUS DEV: the LBUS device.
+    lbus_dev: "lbus-dev", u16 -> u16;
+    /// U W BASE: the base register for base-relative A memory writes.
+    w_base: "w-base", u8 -> u8;
+    /// U STKP DIR: the direction the stack pointer counts.
+    stkp_count_dir: "stkp-dir", u8 -> u8;
+    /// U AMWA SEL: how U AMWA is interpreted.
+    amwa_sel: "amwa-sel", u8 -> AmwaSel;
+    /// U SEQ: the sequencer function.
+    seq: "seq", u8 -> Seq;
+    /// U BMRA: the B memory read address.
+    bmra: "bmra", u8 -> u8;
+    /// U BMWA: the B memory write address.
+    bmwa: "bmwa", u8 -> u8;
+    /// U BMEM FROM XBUS: the B memory write data select.
+    bmem_from_xbus: "bmem-from-xbus", u8 -> BmemSource;
+    /// U MEM: the memory control function.
+    mem: "mem", u8 -> MemOp;
+    /// U SPEC: the special function.
+    spec: "spec", u8 -> SpecialFunction;
+    /// U MAGIC: the magic number.
+    magic: "magic", u8 -> u8;
+    /// U COND SEL: the condition select.
+    cond_sel: "cond-sel", u8 -> CondSel;
+    /// U COND FUNC: what the condition does.
+    cond_func: "cond-func", u8 -> CondFunc;
+    /// U ALU: the ALU function.
+    alu: "alu", u8 -> AluFunction;
+    /// U BYTE F: the byte function.
+    byte_f: "byte-f", u8 -> ByteFunction;
+    /// U OBUS CDR: the O bus CDR code select.
+    obus_cdr: "obus-cdr", u8 -> ObusCdr;
+    /// U OBUS HTYPE: the O bus high type select.
+    obus_htype: "obus-htype", u8 -> ObusHtype;
+    /// U OBUS LTYPE SEL: the O bus low type select.
+    obus_ltype_sel: "obus-ltype-sel", u8 -> LtypeSel;
+    /// U CPC SEL: where the next address comes from.
+    cpc_sel: "cpc-sel", u8 -> CpcSel;
+    /// U NPC SEL: where the address after that comes from.
+    npc_sel: "npc-sel", u8 -> NpcSel;
+    /// U NAF: the next address field.
+    naf: "naf", u16 -> u16;
+    /// U SPEED: the clock speed.
+    speed: "speed", u8 -> u8;
+    /// U TYPE MAP SEL: the type map select.
+    type_map_sel: "type-map-sel", u8 -> u8;
+    /// U AU OP: the floating point accelerator control.
+    au_op: "au-op", u8 -> u8;
+    /// U SPARE: the spare bit.
+    spare: "spare", u8 -> u8;
+    /// The parity bit.
+    parity: "parity", u8 -> u8;
+}
+
+/// A type map entry.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub struct TypeWord {
-    data: u8,
+    pub(crate) data: u8,
+}
+
+impl TypeWord {
+    pub fn new(data: u8) -> TypeWord {
+        TypeWord { data }
+    }
+
+    pub fn data(&self) -> u8 {
+        self.data
+    }
 }
 
-#[allow(dead_code)]
+/// A word of the floating point accelerator's pico store.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub struct PicoStoreWord {
-    address: u16,
-    data: u32,
+    pub(crate) address: u16,
+    pub(crate) data: u32,
+}
+
+impl PicoStoreWord {
+    pub fn new(address: u16, data: u32) -> PicoStoreWord {
+        PicoStoreWord { address, data }
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    pub fn data(&self) -> u32 {
+        self.data
+    }
 }
 
+/// The words of one memory, in the order they were read.
 pub struct Mem<T> {
     pub mem: Vec<T>,
 }
@@ -320,8 +494,32 @@ impl<T> Mem<T> {
     pub fn len(&self) -> usize {
         self.mem.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.mem.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.mem.iter()
+    }
+}
+
+impl<T> Default for Mem<T> {
+    fn default() -> Mem<T> {
+        Mem::new()
+    }
+}
+
+/// One change to a control memory word, kept for undo and redo.
+#[derive(Clone, Copy)]
+pub struct Edit {
+    pub address: u16,
+    pub before: CWord,
+    pub after: CWord,
 }
 
+/// A microcode image: its memories as loaded or edited, with the
+/// project annotations that go with it.
 pub struct Microcode {
     pub path: Option<String>,
     pub version: u16,
@@ -331,6 +529,12 @@ pub struct Microcode {
     pub c_mem: Mem<CWord>,
     pub type_map: Mem<TypeWord>,
     pub pico_store: Mem<PicoStoreWord>,
+    /// Labels, comments and entry points from the project file.
+    pub annotations: Annotations,
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+    original: BTreeMap<u16, CWord>,
+    c_extra: BTreeMap<u16, Vec<u8>>,
 }
 
 impl fmt::Display for Microcode {
@@ -338,30 +542,47 @@ impl fmt::Display for Microcode {
         write!(
             f,
             "valid ucode\n\
-             version=0x{:04x}\n\
+             version={}\n\
              comment='{}'\n\
              a-mem length={}\n\
              b-mem length={}\n\
              c-mem length={}\n\
              type-map length={}\n\
              pico-store length={}\n\n\
-             CMEM:\n\
-             {:?}",
-            self.version,
+             CMEM:",
+            radix::padded(self.version as u64, 16),
             self.comment,
             self.a_mem.len(),
             self.b_mem.len(),
             self.c_mem.len(),
             self.type_map.len(),
             self.pico_store.len(),
-            self.c_mem,
-        )
+        )?;
+
+        // Labels and comments go before the word they annotate.
+        for cword in &self.c_mem.mem {
+            writeln!(f)?;
+            if let Some(label) = self.annotations.label(cword.address) {
+                writeln!(f, "{}:", label)?;
+            }
+            if let Some(comment) = self.annotations.comment(cword.address) {
+                writeln!(f, "; {}", comment)?;
+            }
+            write!(f, "{:?}", cword)?;
+        }
+
+        Ok(())
     }
 }
 
 impl fmt::Display for ABWord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:04x}: {:010x}", self.address, self.data)
+        write!(
+            f,
+            "{}: {}",
+            radix::padded(self.address as u64, 16),
+            radix::padded(self.data, 40)
+        )
     }
 }
 
@@ -370,8 +591,10 @@ impl fmt::Display for CWord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{:05o}> {:022o} {:022o}",
-            self.address, self.data_h, self.data_l
+            "{}> {} {}",
+            radix::address(self.address),
+            radix::padded(self.data_h, 64),
+            radix::padded(self.data_l, 64)
         )
     }
 }
@@ -380,9 +603,9 @@ impl fmt::Debug for CWord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{:05o}>\n{:?}",
-            self.address,
-            MicroInstruction::new(self)
+            "{}>\n{}",
+            radix::address(self.address),
+            layout::current().describe(self.bits())
         )
     }
 }
@@ -390,69 +613,7 @@ impl fmt::Debug for CWord {
 /// Verbose debugging for Control Memory Words
 impl fmt::Debug for MicroInstruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "U AMRA:          {:04o}\n\
-             > U R BASE:      {:o}\n\
-             U AMRA SEL:      {:o}\n\
-             U XYBUS SEL:     {:o}\n\
-             U STKP CNT:      {:o}\n\
-             U AMWA:          {:04o}\n\
-             > LBUS DEV:      {:04o}\n\
-             > U W BASE:      {:o}\n\
-             > U STKP DIR:    {:o}\n\
-             U AMWA SEL:      {:o}\n\
-             U SEQ:           {:o}\n\
-             U BMRA:          {:03o}\n\
-             U BMWA:          {:02o}\n\
-             BMEM FROM XBUS:  {:o}\n\
-             U MEM:           {:o}\n\
-             U SPEC:          {:02o}\n\
-             U MAGIC:         {:02o}\n\
-             U COND SEL:      {:02o}\n\
-             U COND FUNC:     {:o}\n\
-             U ALU            {:02o}\n\
-             U BYTE F         {:o}\n\
-             U OBUS CDR       {:o}\n\
-             U OBUS HTYPE     {:o}\n\
-             U OBUS LTYPE SEL {:o}\n\
-             U CPC SEL        {:o}\n\
-             U NPC SEL        {:o}\n\
-             U NAF            {:05o}\n\
-             U SPEED          {:o}\n\
-             U TYPE MAP SEL   {:02o}\n\
-             U AU OP          {:03o}\n",
-            self.u_amra,
-            self.u_r_base,
-            self.u_amra_sel,
-            self.u_xybus_sel,
-            self.u_stkp_count,
-            self.u_amwa,
-            self.lbus_dev,
-            self.u_w_base,
-            self.u_stkp_count_dir,
-            self.u_amwa_sel,
-            self.u_seq,
-            self.u_bmra,
-            self.u_bmwa,
-            self.u_bmem_from_xbus,
-            self.u_mem,
-            self.u_spec,
-            self.u_magic,
-            self.u_cond_sel,
-            self.u_cond_func,
-            self.u_alu,
-            self.u_byte_f,
-            self.u_obus_cdr,
-            self.u_obus_htype,
-            self.u_obus_ltype_sel,
-            self.u_cpc_sel,
-            self.u_npc_sel,
-            self.u_naf,
-            self.u_speed,
-            self.u_type_map_sel,
-            self.u_au_op
-        )
+        write!(f, "{}", layout::current().describe(self.word))
     }
 }
 
@@ -477,6 +638,36 @@ impl fmt::Debug for Mem<CWord> {
     }
 }
 
+/// An iterator over decoded control memory words and their addresses.
+pub struct Instructions<'a> {
+    words: slice::Iter<'a, CWord>,
+    layout: Arc<Layout>,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = (u16, MicroInstruction);
+
+    fn next(&mut self) -> Option<(u16, MicroInstruction)> {
+        let cword = self.words.next()?;
+        Some((
+            cword.address,
+            MicroInstruction::decode(&self.layout, cword.bits()),
+        ))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.words.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for Instructions<'a> {}
+
+impl Default for Microcode {
+    fn default() -> Microcode {
+        Microcode::new()
+    }
+}
+
 /// Control store for Microcode
 impl Microcode {
     pub fn new() -> Microcode {
@@ -489,6 +680,11 @@ impl Microcode {
             c_mem: Mem::new(),
             type_map: Mem::new(),
             pico_store: Mem::new(),
+            annotations: Annotations::default(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            original: BTreeMap::new(),
+            c_extra: BTreeMap::new(),
         }
     }
 
@@ -501,6 +697,11 @@ impl Microcode {
         self.c_mem.clear();
         self.type_map.clear();
         self.pico_store.clear();
+        self.annotations.clear();
+        self.undo.clear();
+        self.redo.clear();
+        self.original.clear();
+        self.c_extra.clear();
     }
 
     pub fn load(&mut self, path: &str) -> Result<(), MicrocodeError> {
@@ -510,7 +711,7 @@ impl Microcode {
 
         self.path = Some(expanded.to_string());
 
-        let file = File::open(&expanded.to_string())?;
+        let file = File::open(expanded.to_string())?;
 
         self.read_header(&file)?;
         self.read_version(&file)?;
@@ -524,6 +725,80 @@ impl Microcode {
         Ok(())
     }
 
+    /// Store a control memory word, replacing any word already at
+    /// its address. New words are kept in address order.
+    pub fn store_c_word(&mut self, cword: CWord) {
+        let mem = &mut self.c_mem.mem;
+        match mem.iter().position(|w| w.address >= cword.address) {
+            Some(i) if mem[i].address == cword.address => mem[i] = cword,
+            Some(i) => mem.insert(i, cword),
+            None => mem.push(cword),
+        }
+    }
+
+    /// The control memory words decoded with the current layout, with
+    /// their addresses, in the order they are kept.
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            words: self.c_mem.iter(),
+            layout: layout::current(),
+        }
+    }
+
+    /// The control memory word at an address, if there is one.
+    pub fn c_word(&self, address: u16) -> Option<&CWord> {
+        self.c_mem.mem.iter().find(|w| w.address == address)
+    }
+
+    /// Replace an existing control memory word, recording the change
+    /// so it can be undone. Returns false if there is no word at the
+    /// address.
+    pub fn edit_c_word(&mut self, cword: CWord) -> bool {
+        let before = match self.c_word(cword.address) {
+            Some(w) => *w,
+            None => return false,
+        };
+        self.original.entry(cword.address).or_insert(before);
+        self.store_c_word(cword);
+        self.undo.push(Edit {
+            address: cword.address,
+            before,
+            after: cword,
+        });
+        self.redo.clear();
+        true
+    }
+
+    /// Undo the most recent edit.
+    pub fn undo(&mut self) -> Option<Edit> {
+        let edit = self.undo.pop()?;
+        self.store_c_word(edit.before);
+        self.redo.push(edit);
+        Some(edit)
+    }
+
+    /// Redo the most recently undone edit.
+    pub fn redo(&mut self) -> Option<Edit> {
+        let edit = self.redo.pop()?;
+        self.store_c_word(edit.after);
+        self.undo.push(edit);
+        Some(edit)
+    }
+
+    /// Words that differ from the image as loaded, with their
+    /// original contents, in address order.
+    pub fn modified(&self) -> Vec<(&CWord, &CWord)> {
+        self.original
+            .values()
+            .filter_map(|orig| match self.c_word(orig.address) {
+                Some(cur) if cur.data_l != orig.data_l || cur.data_h != orig.data_h => {
+                    Some((orig, cur))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn path(&mut self) -> &str {
         match self.path {
             Some(ref mut s) => s.as_str(),
@@ -531,6 +806,78 @@ impl Microcode {
         }
     }
 
+    /// Write the image in the same format `load` reads.
+    pub fn save(&self, path: &str) -> Result<(), MicrocodeError> {
+        let mut out: Vec<u8> = Vec::new();
+
+        out.push(SEC_HEADER);
+        out.push(HEADER_MAGIC);
+
+        out.push(SEC_VERSION);
+        write_u16(&mut out, self.version);
+
+        // The comment is a length byte and one byte per character.
+        let comment: Vec<u8> = self
+            .comment
+            .chars()
+            .map(|c| {
+                if (c as u32) <= 0xff {
+                    Some(c as u8)
+                } else {
+                    None
+                }
+            })
+            .collect::<Option<_>>()
+            .ok_or(MicrocodeError::InvalidComment)?;
+        if comment.len() > 0xff {
+            return Err(MicrocodeError::InvalidComment);
+        }
+        out.push(SEC_COMMENT);
+        out.push(comment.len() as u8);
+        out.extend(comment);
+
+        out.push(SEC_AMEM);
+        write_a_or_b_mem(&mut out, &self.a_mem);
+        out.push(SEC_BMEM);
+        write_a_or_b_mem(&mut out, &self.b_mem);
+
+        out.push(SEC_CMEM);
+        for run in runs(self.c_mem.mem.iter().map(|w| w.address)) {
+            write_u16(&mut out, run.1 as u16);
+            write_u16(&mut out, self.c_mem.mem[run.0].address);
+            for cword in &self.c_mem.mem[run.0..run.0 + run.1] {
+                write_bytes(&mut out, cword.data_l, 8);
+                write_bytes(&mut out, cword.data_h, 6);
+                if let Some(extra) = self.c_extra.get(&cword.address) {
+                    out.extend(extra);
+                }
+                out.push(0);
+            }
+        }
+        write_u16(&mut out, 0);
+
+        out.push(SEC_TYPEMAP);
+        write_u16(&mut out, self.type_map.len() as u16);
+        write_u16(&mut out, 0);
+        out.extend(self.type_map.mem.iter().map(|t| t.data));
+        write_u16(&mut out, 0);
+
+        if !self.pico_store.is_empty() {
+            out.push(SEC_PICOSTORE);
+            for word in &self.pico_store.mem {
+                write_u16(&mut out, word.address);
+                write_bytes(&mut out, word.data as u64, 4);
+            }
+            write_u16(&mut out, 0xffff);
+        }
+        out.push(SEC_EOF);
+
+        let expanded = shellexpand::tilde(path);
+        File::create(expanded.to_string())?.write_all(&out)?;
+
+        Ok(())
+    }
+
     /// Read and validate the microcode header.
     fn read_header(&mut self, mut file: &File) -> Result<(), MicrocodeError> {
         // Grab the section ID
@@ -642,13 +989,18 @@ impl Microcode {
 
                 // But there's more!
 
+                let mut extra = Vec::new();
                 loop {
-                    // Just consume them for now.
+                    // Keep them so the image can be written back.
                     let code = read_u8!(file);
                     // Done reading extra bytes
                     if code == 0 {
                         break;
                     }
+                    extra.push(code);
+                }
+                if !extra.is_empty() {
+                    self.c_extra.insert(start + i, extra);
                 }
             }
         }
@@ -716,3 +1068,237 @@ impl Microcode {
         Ok(())
     }
 }
+
+//
+// Image writing
+//
+
+fn write_bytes(out: &mut Vec<u8>, value: u64, count: usize) {
+    for i in 0..count {
+        out.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn write_u16(out: &mut Vec<u8>, value: u16) {
+    write_bytes(out, value as u64, 2);
+}
+
+/// Split a sequence of addresses into (index, length) runs of
+/// consecutive addresses, as the file format stores them.
+fn runs<I: Iterator<Item = u16>>(addresses: I) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    let mut last: Option<u16> = None;
+    for (i, address) in addresses.enumerate() {
+        match runs.last_mut() {
+            Some(run) if last.map(|l| l.wrapping_add(1)) == Some(address) && run.1 < 0xffff => {
+                run.1 += 1
+            }
+            _ => runs.push((i, 1)),
+        }
+        last = Some(address);
+    }
+    runs
+}
+
+fn write_a_or_b_mem(out: &mut Vec<u8>, mem: &Mem<ABWord>) {
+    for run in runs(mem.mem.iter().map(|w| w.address)) {
+        write_u16(out, run.1 as u16);
+        write_u16(out, mem.mem[run.0].address);
+        for word in &mem.mem[run.0..run.0 + run.1] {
+            write_bytes(out, word.data, 5);
+        }
+    }
+    write_u16(out, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn field_named(name: &str) -> Field {
+        layout::current().field(name).unwrap().clone()
+    }
+
+    fn image(words: &[(u16, u64, u64)]) -> Microcode {
+        let mut ucode = Microcode::new();
+        for &(address, data_l, data_h) in words {
+            ucode.c_mem.push(CWord::new(address, data_l, data_h));
+        }
+        ucode
+    }
+
+    #[test]
+    fn encode_inverts_decode() {
+        let cword = CWord::new(0o1234, 0xfedc_ba98_7654_3210, 0xabcd_ef01_2345);
+        let encoded = MicroInstruction::new(&cword).encode(0o1234);
+        assert_eq!(encoded.address, 0o1234);
+        assert_eq!(encoded.data_l, cword.data_l);
+        assert_eq!(encoded.data_h, cword.data_h);
+    }
+
+    #[test]
+    fn decodes_fields_at_documented_positions() {
+        let inst = MicroInstruction::new(&CWord::new(0, 0o377 << 32 | 0o3 << 9, 0o37777 << 16));
+        assert_eq!(inst.bmra(), 0o377);
+        assert_eq!(inst.r_base(), 3);
+        assert_eq!(inst.amra(), 0o3000);
+        assert_eq!(inst.naf(), 0o37777);
+        assert_eq!(inst.bmwa(), 0);
+        assert_eq!(inst.speed(), 0);
+    }
+
+    #[test]
+    fn iterates_over_decoded_instructions() {
+        let ucode = image(&[(0o10, 0, 0o1234 << 16), (0o20, 0o2 << 30, 0)]);
+        let decoded: Vec<(u16, u16, Seq)> = ucode
+            .instructions()
+            .map(|(address, inst)| (address, inst.naf(), inst.seq()))
+            .collect();
+        assert_eq!(
+            decoded,
+            vec![(0o10, 0o1234, Seq::None), (0o20, 0, Seq::Dismiss)]
+        );
+        assert_eq!(ucode.instructions().len(), 2);
+        assert_eq!(ucode.c_mem.iter().next().unwrap().decode().naf(), 0o1234);
+    }
+
+    #[test]
+    fn set_field_keeps_aliases_consistent() {
+        let mut inst = MicroInstruction::new(&CWord::new(0, 0, 0));
+
+        inst.set_field(&field_named("lbus-dev"), 0o1777);
+        assert_eq!(inst.amwa(), 0o1777);
+        assert_eq!(inst.w_base(), 1);
+
+        inst.set_field(&field_named("amwa"), 0o4000);
+        assert_eq!(inst.lbus_dev(), 0);
+        assert_eq!(inst.stkp_count_dir(), 1);
+
+        inst.set_field(&field_named("r-base"), 3);
+        assert_eq!(inst.amra(), 0o3000);
+        inst.set_field(&field_named("amra"), 0o0300);
+        assert_eq!(inst.abus_src().code(), 3);
+        assert_eq!(inst.r_base(), 0);
+    }
+
+    #[test]
+    fn typed_values_round_trip() {
+        let mut inst = MicroInstruction::new(&CWord::new(0, 0, 0));
+        inst.set(SpecialFunction::Halt);
+        inst.set(MemOp::BlockRead);
+        inst.set(CondSel::Reserved27);
+        let inst = inst.encode(0).decode();
+        assert_eq!(inst.spec(), SpecialFunction::Halt);
+        assert_eq!(inst.mem(), MemOp::BlockRead);
+        assert_eq!(inst.cond_sel(), CondSel::Reserved27);
+        assert_eq!(inst.field_value("spec"), Some(0o31));
+    }
+
+    #[test]
+    fn edits_keep_fields_the_accessors_do_not_know() {
+        let builtin = include_str!("../docs/microword.toml");
+        let renamed = Layout::parse(&builtin.replace("\"au-op\"", "\"fpa-op\"")).unwrap();
+        let word = renamed.field("fpa-op").unwrap().set(0, 0o123);
+
+        let mut inst = MicroInstruction::decode(&renamed, word);
+        inst.set_field(renamed.field("alu").unwrap(), 3);
+        inst.fix_parity();
+
+        let bits = inst.encode(0).bits();
+        assert_eq!(renamed.value("fpa-op", bits), 0o123);
+        assert_eq!(renamed.value("alu", bits), 3);
+        assert_eq!(bits.count_ones() % 2, 1);
+    }
+
+    #[test]
+    fn fix_parity_gives_odd_parity() {
+        let mut inst = MicroInstruction::new(&CWord::new(0, 0o17, 0));
+        inst.fix_parity();
+        let cword = inst.encode(0);
+        assert_eq!(cword.bits().count_ones() % 2, 1);
+    }
+
+    #[test]
+    fn edits_undo_redo_and_list_modified() {
+        let mut ucode = image(&[(0, 1, 0), (1, 2, 0)]);
+
+        assert!(ucode.edit_c_word(CWord::new(1, 3, 0)));
+        assert!(ucode.edit_c_word(CWord::new(1, 4, 0)));
+        assert!(!ucode.edit_c_word(CWord::new(2, 5, 0)));
+        assert_eq!(ucode.modified().len(), 1);
+
+        assert_eq!(ucode.undo().map(|e| e.before.data_l), Some(3));
+        assert_eq!(ucode.c_word(1).unwrap().data_l, 3);
+        ucode.undo();
+        assert_eq!(ucode.c_word(1).unwrap().data_l, 2);
+        assert!(ucode.modified().is_empty());
+        assert!(ucode.undo().is_none());
+
+        ucode.redo();
+        assert_eq!(ucode.c_word(1).unwrap().data_l, 3);
+        ucode.edit_c_word(CWord::new(0, 9, 0));
+        assert!(ucode.redo().is_none());
+        assert_eq!(ucode.modified().len(), 2);
+    }
+
+    #[test]
+    fn save_writes_what_load_reads() {
+        let mut bytes = vec![SEC_HEADER, HEADER_MAGIC, SEC_VERSION, 0x34, 0x12];
+        bytes.extend(&[SEC_COMMENT, 4, b'T', b'E', b'S', b'T']);
+        bytes.extend(&[
+            SEC_AMEM, 2, 0, 0o100, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0, 0,
+        ]);
+        bytes.extend(&[SEC_BMEM, 1, 0, 7, 0, 5, 0, 0, 0, 0, 0, 0]);
+        bytes.extend(&[SEC_CMEM, 2, 0, 0o20, 0]);
+        for i in 0..2u8 {
+            bytes.extend(&[i; 14]);
+            bytes.extend(&[1, 2, 3, 0]);
+        }
+        bytes.extend(&[1, 0, 0o40, 0]);
+        bytes.extend(&[0xff; 14]);
+        bytes.extend(&[0, 0, 0]);
+        bytes.extend(&[SEC_TYPEMAP, 3, 0, 0, 0, 1, 2, 3, 0, 0, SEC_EOF]);
+
+        let dir = ::std::env::temp_dir();
+        let input = dir.join(format!("uc-explorer-{}-in.mic", ::std::process::id()));
+        let output = dir.join(format!("uc-explorer-{}-out.mic", ::std::process::id()));
+        File::create(&input).unwrap().write_all(&bytes).unwrap();
+
+        let mut ucode = Microcode::new();
+        assert!(ucode.load(input.to_str().unwrap()).is_ok());
+        assert_eq!(ucode.c_mem.len(), 3);
+        assert!(ucode.save(output.to_str().unwrap()).is_ok());
+
+        let mut written = Vec::new();
+        File::open(&output)
+            .unwrap()
+            .read_to_end(&mut written)
+            .unwrap();
+        let _ = ::std::fs::remove_file(&input);
+        let _ = ::std::fs::remove_file(&output);
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn comments_must_fit_the_image() {
+        let dir = ::std::env::temp_dir();
+        let path = dir.join(format!("uc-explorer-{}-c.mic", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut ucode = Microcode::new();
+        for comment in &["caf\u{e9}".to_string(), "x".repeat(255)] {
+            ucode.comment = comment.clone();
+            assert!(ucode.save(path).is_ok());
+            let mut loaded = Microcode::new();
+            assert!(loaded.load(path).is_ok());
+            assert_eq!(&loaded.comment, comment);
+        }
+        let _ = ::std::fs::remove_file(path);
+
+        for comment in &["\u{20ac}".to_string(), "x".repeat(256)] {
+            ucode.comment = comment.clone();
+            match ucode.save(path) {
+                Err(MicrocodeError::InvalidComment) => {}
+                _ => panic!("saved the comment {:?}", comment),
+            }
+        }
+    }
+}